@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_nes::cartridge::Rom;
+
+// Any input is fair game: the loader should reject garbage with an Err, never panic.
+fuzz_target!(|data: &[u8]| {
+    let _ = Rom::new(&data.to_vec());
+});
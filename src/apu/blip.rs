@@ -0,0 +1,152 @@
+use std::f64::consts::PI;
+
+use super::filter::{AudioFilter, Filter};
+
+/// Samples each step is spread across
+const WIDTH: usize = 32;
+/// Positions a step can land on between two samples
+const PHASES: usize = 256;
+/// Highest frequency let through, as a fraction of the sample rate
+const CUTOFF: f64 = 0.45;
+/// Finished samples to let build up before moving them over
+const CHUNK: usize = 256;
+
+/// Band-limited step synthesis, after blip_buf. The APU's output only ever moves in steps, so
+/// instead of sampling it, every change in level is drawn in as a step with everything over
+/// the cutoff taken out, at the CPU cycle it happened on. Adding up the steps gives samples
+/// without the aliasing that the raw edges leave when sampled or averaged.
+///
+/// Output lags the input by half of `WIDTH` samples.
+pub struct BlipBuffer {
+    /// Samples per CPU cycle at exactly the sample rate, and as adjusted
+    base_samples_per_cycle: f64,
+    samples_per_cycle: f64,
+    /// Where the current CPU cycle falls, in samples from the start of `deltas`
+    time: f64,
+    /// Changes in level, each spread over the `WIDTH` samples around where it happened
+    deltas: Vec<f32>,
+    /// The sum of every delta moved into `samples` so far
+    level: f32,
+    /// The input level as of the last cycle
+    input: f32,
+    /// The band-limited impulse for each phase, summing to 1
+    kernels: Vec<[f32; WIDTH]>,
+    /// Run over each sample on its way out, in order
+    filters: Vec<Filter>,
+    pub samples: Vec<f32>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_hz: f64, sample_rate: u32, filters: &[AudioFilter]) -> Self {
+        BlipBuffer {
+            base_samples_per_cycle: sample_rate as f64 / clock_hz,
+            samples_per_cycle: sample_rate as f64 / clock_hz,
+            time: 0.0,
+            deltas: vec![0.0; CHUNK + WIDTH],
+            level: 0.0,
+            input: 0.0,
+            kernels: (0..PHASES).map(kernel).collect(),
+            filters: filters
+                .iter()
+                .map(|&filter| Filter::new(filter, sample_rate))
+                .collect(),
+            samples: Vec::new(),
+        }
+    }
+
+    /// Makes `adjustment` times as many samples as the sample rate calls for.
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.samples_per_cycle = self.base_samples_per_cycle * adjustment;
+    }
+
+    /// Adds one CPU cycle's level.
+    pub fn add(&mut self, level: f32) {
+        if level != self.input {
+            self.add_delta(level - self.input);
+            self.input = level;
+        }
+        self.time += self.samples_per_cycle;
+        if self.time >= CHUNK as f64 {
+            self.finish(self.time as usize);
+        }
+    }
+
+    fn add_delta(&mut self, delta: f32) {
+        let start = self.time as usize;
+        let phase = ((self.time - start as f64) * PHASES as f64) as usize;
+        if self.deltas.len() < start + WIDTH {
+            self.deltas.resize(start + WIDTH, 0.0);
+        }
+        let taps = self.deltas[start..start + WIDTH].iter_mut();
+        for (tap, weight) in taps.zip(self.kernels[phase].iter()) {
+            *tap += delta * weight;
+        }
+    }
+
+    /// Moves the first `count` samples, which no later step can reach, out to `samples`.
+    fn finish(&mut self, count: usize) {
+        if self.deltas.len() < count + WIDTH {
+            self.deltas.resize(count + WIDTH, 0.0);
+        }
+        for delta in self.deltas.drain(..count) {
+            self.level += delta;
+            let sample = self
+                .filters
+                .iter_mut()
+                .fold(self.level, |sample, filter| filter.process(sample));
+            self.samples.push(sample);
+        }
+        self.time -= count as f64;
+    }
+}
+
+/// A Blackman windowed sinc for a step `phase / PHASES` of the way past a sample.
+fn kernel(phase: usize) -> [f32; WIDTH] {
+    let center = (WIDTH / 2 - 1) as f64 + phase as f64 / PHASES as f64;
+    let mut taps = [0.0f64; WIDTH];
+    for (i, tap) in taps.iter_mut().enumerate() {
+        let x = i as f64 - center;
+        let sinc = if x == 0.0 {
+            1.0
+        } else {
+            (PI * 2.0 * CUTOFF * x).sin() / (PI * 2.0 * CUTOFF * x)
+        };
+        let w = 2.0 * PI * (x / WIDTH as f64 + 0.5);
+        let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+        *tap = sinc * window.max(0.0);
+    }
+    let sum: f64 = taps.iter().sum();
+    taps.map(|tap| (tap / sum) as f32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_steps_without_aliasing() {
+        let mut blip = BlipBuffer::new(1_789_773.0, 48_000, &[]);
+        for cycle in 0..1_789_773 {
+            blip.add(if cycle < 1000 { 0.0 } else { 1.0 });
+        }
+        // A second's worth, less what's still waiting to fill a chunk
+        let samples = blip.samples.len();
+        assert!((48_000 - CHUNK..=48_000).contains(&samples), "{}", samples);
+        // A clean step, ringing a little around the edge like any band-limited one
+        assert!(blip.samples[..10].iter().all(|&sample| sample == 0.0));
+        assert!(blip.samples.iter().all(|&sample| sample.abs() < 1.1));
+        assert!(blip.samples[100..]
+            .iter()
+            .all(|&sample| (sample - 1.0).abs() < 1e-4));
+
+        // A square wave far over the cutoff comes out as its average, where sampling it
+        // would fold it back down into something audible
+        let mut blip = BlipBuffer::new(1_789_773.0, 48_000, &[]);
+        for cycle in 0..200_000 {
+            blip.add(if cycle % 20 < 10 { 0.0 } else { 1.0 });
+        }
+        assert!(blip.samples[100..]
+            .iter()
+            .all(|&sample| (sample - 0.5).abs() < 0.01));
+    }
+}
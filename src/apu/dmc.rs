@@ -0,0 +1,242 @@
+use crate::{
+    region::Region,
+    save_state::{StateReader, StateWriter},
+};
+
+/// The delta modulation channel, $4010-$4013. Plays 1-bit delta encoded samples out of
+/// cartridge memory, one bit nudging a 7-bit level up or down, or takes the level straight
+/// from $4011.
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    /// Index into `rates`, from $4010
+    rate: u8,
+    rates: &'static [u16; 16],
+    timer: u16,
+    level: u8,
+
+    /// Where the sample starts and how long it is, as last written
+    sample_address: u16,
+    sample_length: u16,
+    /// Where the next byte comes from, and how many are left to fetch
+    address: u16,
+    bytes_remaining: u16,
+    /// The byte fetched for the output unit, once it's done with the one it has
+    buffer: Option<u8>,
+
+    shift: u8,
+    bits_remaining: u8,
+    /// No byte was ready when the last one ran out, so the level holds
+    silence: bool,
+    /// Set when a sample ends with IRQs enabled, cleared by writing $4010 or $4015
+    pub interrupt: bool,
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Dmc::new()
+    }
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            rate: 0,
+            rates: Region::NTSC.dmc_rates(),
+            timer: 0,
+            level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            interrupt: false,
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.rates = region.dmc_rates();
+    }
+
+    /// Writes register 0-3 of the channel.
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                self.looping = value & 0x40 != 0;
+                self.rate = value & 0x0F;
+                if !self.irq_enabled {
+                    self.interrupt = false;
+                }
+            }
+            1 => self.level = value & 0x7F,
+            // Sample address $C000 + A * 64
+            2 => self.sample_address = 0xC000 | (value as u16) << 6,
+            // Sample length L * 16 + 1 bytes
+            _ => self.sample_length = (value as u16) << 4 | 1,
+        }
+    }
+
+    /// Starts the sample over when enabled through $4015 while it isn't playing; disabling
+    /// drops the rest of it.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.interrupt = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    /// Whether there are sample bytes left to fetch, for $4015 reads.
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    fn restart(&mut self) {
+        self.address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// The address of the next sample byte, when the buffer wants one.
+    pub fn fetch_address(&self) -> Option<u16> {
+        (self.buffer.is_none() && self.bytes_remaining > 0).then_some(self.address)
+    }
+
+    /// Takes the byte read from `fetch_address`.
+    pub fn fill(&mut self, byte: u8) {
+        self.buffer = Some(byte);
+        // The address wraps around to $8000, not $0000
+        self.address = self.address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.interrupt = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rates[self.rate as usize] - 1;
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(byte) => {
+                    self.shift = byte;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    /// The channel's level, 0-127.
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.irq_enabled);
+        writer.bool(self.looping);
+        writer.u8(self.rate);
+        writer.u16(self.timer);
+        writer.u8(self.level);
+        writer.u16(self.sample_address);
+        writer.u16(self.sample_length);
+        writer.u16(self.address);
+        writer.u16(self.bytes_remaining);
+        writer.bool(self.buffer.is_some());
+        writer.u8(self.buffer.unwrap_or(0));
+        writer.u8(self.shift);
+        writer.u8(self.bits_remaining);
+        writer.bool(self.silence);
+        writer.bool(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.irq_enabled = reader.bool()?;
+        self.looping = reader.bool()?;
+        self.rate = reader.u8()? & 0x0F;
+        self.timer = reader.u16()?;
+        self.level = reader.u8()? & 0x7F;
+        self.sample_address = reader.u16()?;
+        self.sample_length = reader.u16()?;
+        self.address = reader.u16()?;
+        self.bytes_remaining = reader.u16()?;
+        let buffered = reader.bool()?;
+        let byte = reader.u8()?;
+        self.buffer = buffered.then_some(byte);
+        self.shift = reader.u8()?;
+        self.bits_remaining = reader.u8()?.clamp(1, 8);
+        self.silence = reader.bool()?;
+        self.interrupt = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sample_playback() {
+        let mut dmc = Dmc::new();
+        // IRQ on, fastest rate, one byte at $FFC0
+        dmc.write(0, 0x8F);
+        dmc.write(1, 64);
+        dmc.write(2, 0xFF);
+        dmc.write(3, 0x00);
+        dmc.set_enabled(true);
+        assert_eq!(dmc.fetch_address(), Some(0xFFC0));
+        dmc.fill(0b0000_1111);
+        assert_eq!(dmc.fetch_address(), None);
+        assert!(dmc.interrupt, "sample over");
+
+        // The first 8 bits are played out of the empty shift register, silently
+        let mut levels = Vec::new();
+        for _ in 0..16 * dmc.rates[15] {
+            dmc.clock_timer();
+            levels.push(dmc.output());
+        }
+        levels.dedup();
+        assert_eq!(levels, [64, 66, 68, 70, 72, 70, 68, 66, 64]);
+
+        // Reading past $FFFF wraps to $8000
+        dmc.write(0, 0x40);
+        dmc.write(3, 0x04);
+        dmc.set_enabled(true);
+        for _ in 0..0x40 {
+            dmc.fill(0);
+            dmc.buffer = None;
+        }
+        assert_eq!(dmc.fetch_address(), Some(0x8000));
+        assert!(dmc.active());
+        dmc.set_enabled(false);
+        assert!(!dmc.active());
+        assert_eq!(dmc.fetch_address(), None);
+    }
+}
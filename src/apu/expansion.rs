@@ -0,0 +1,8 @@
+/// Sound channels on the cartridge, like the VRC6's or the N163's, which the console mixes in
+/// with its own through the cartridge connector.
+pub trait ExpansionAudio {
+    /// Runs the chip for `cycles` CPU cycles and returns its level afterwards, on the APU's
+    /// scale: a pulse channel at full volume is about 0.12. Each chip sets its own balance
+    /// against the APU, the user's `expansion_volume` goes on top.
+    fn mix_audio(&mut self, cycles: u8) -> f32;
+}
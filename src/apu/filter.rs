@@ -0,0 +1,86 @@
+use std::f32::consts::PI;
+
+/// A first-order filter on the output, like the RC stages between the 2A03 and the TV. The
+/// front-loading NES has high-passes at 90 and 440 Hz and a low-pass at 14 kHz.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioFilter {
+    /// Cuts frequencies below the cutoff in Hz, and with them the mixer's DC offset
+    HighPass(f32),
+    /// Cuts frequencies above the cutoff in Hz
+    LowPass(f32),
+}
+
+impl AudioFilter {
+    /// The console's own output stage.
+    pub const NES: [AudioFilter; 3] = [
+        AudioFilter::HighPass(90.0),
+        AudioFilter::HighPass(440.0),
+        AudioFilter::LowPass(14_000.0),
+    ];
+}
+
+/// An `AudioFilter` running at a sample rate.
+pub struct Filter {
+    high_pass: bool,
+    alpha: f32,
+    last_in: f32,
+    last_out: f32,
+}
+
+impl Filter {
+    pub fn new(filter: AudioFilter, sample_rate: u32) -> Self {
+        let (high_pass, cutoff) = match filter {
+            AudioFilter::HighPass(cutoff) => (true, cutoff),
+            AudioFilter::LowPass(cutoff) => (false, cutoff),
+        };
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        Filter {
+            high_pass,
+            alpha: if high_pass {
+                rc / (rc + dt)
+            } else {
+                dt / (rc + dt)
+            },
+            last_in: 0.0,
+            last_out: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.last_out = if self.high_pass {
+            self.alpha * (self.last_out + sample - self.last_in)
+        } else {
+            self.last_out + self.alpha * (sample - self.last_out)
+        };
+        self.last_in = sample;
+        self.last_out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The peak level of a sine at `frequency` after the filter settles.
+    fn gain(filter: AudioFilter, frequency: f32) -> f32 {
+        let mut filter = Filter::new(filter, 48_000);
+        (0..48_000)
+            .map(|i| filter.process((2.0 * PI * frequency * i as f32 / 48_000.0).sin()))
+            .skip(24_000)
+            .fold(0.0, |peak: f32, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn test_cutoffs() {
+        let high_pass = AudioFilter::HighPass(440.0);
+        assert!(gain(high_pass, 50.0) < 0.2);
+        assert!((gain(high_pass, 440.0) - 0.707).abs() < 0.05);
+        assert!(gain(high_pass, 5000.0) > 0.95);
+
+        let low_pass = AudioFilter::LowPass(1000.0);
+        assert!(gain(low_pass, 100.0) > 0.95);
+        assert!((gain(low_pass, 1000.0) - 0.707).abs() < 0.05);
+        assert!(gain(low_pass, 10_000.0) < 0.2);
+    }
+}
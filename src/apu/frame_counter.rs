@@ -0,0 +1,159 @@
+use crate::{
+    region::Region,
+    save_state::{StateReader, StateWriter},
+};
+
+/// What the frame counter clocked this cycle.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct FrameClock {
+    /// Envelopes and the triangle's linear counter
+    pub quarter: bool,
+    /// Length counters and sweeps
+    pub half: bool,
+}
+
+/// The frame counter at $4017, which clocks the channels' envelopes, sweeps and length
+/// counters about 240 times a second, and in 4-step mode raises an IRQ at the end of every
+/// sequence.
+pub struct FrameCounter {
+    five_step: bool,
+    irq_inhibit: bool,
+    /// Set at the end of a 4-step sequence unless inhibited. Cleared by reading $4015 or
+    /// setting the inhibit flag.
+    pub interrupt: bool,
+    /// CPU cycles into the sequence
+    cycle: u32,
+    /// CPU cycles until a $4017 write restarts the sequence
+    reset_delay: u8,
+    /// When each step comes, see `Region::frame_counter_steps`
+    steps: &'static [u32; 5],
+}
+
+impl Default for FrameCounter {
+    fn default() -> Self {
+        FrameCounter::new()
+    }
+}
+
+impl FrameCounter {
+    pub fn new() -> Self {
+        FrameCounter {
+            five_step: false,
+            irq_inhibit: false,
+            interrupt: false,
+            cycle: 0,
+            reset_delay: 0,
+            steps: Region::NTSC.frame_counter_steps(),
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.steps = region.frame_counter_steps();
+    }
+
+    /// Handles a $4017 write, `MI-- ----`. The sequence restarts 3 or 4 cycles later,
+    /// depending on whether it landed on an APU cycle.
+    pub fn write(&mut self, value: u8, odd_cycle: bool) {
+        self.five_step = value & 0x80 != 0;
+        self.irq_inhibit = value & 0x40 != 0;
+        if self.irq_inhibit {
+            self.interrupt = false;
+        }
+        self.reset_delay = if odd_cycle { 4 } else { 3 };
+    }
+
+    /// Advances by one CPU cycle.
+    pub fn tick(&mut self) -> FrameClock {
+        if self.reset_delay > 0 {
+            self.reset_delay -= 1;
+            if self.reset_delay == 0 {
+                self.cycle = 0;
+                // 5-step mode clocks everything straight away
+                return FrameClock {
+                    quarter: self.five_step,
+                    half: self.five_step,
+                };
+            }
+        }
+
+        self.cycle += 1;
+        // 5-step mode skips the fourth step; the last one is a cycle before the sequence ends
+        let last = if self.five_step { 4 } else { 3 };
+        // The flag goes up on the last three cycles of the sequence
+        if !self.five_step && !self.irq_inhibit && self.cycle >= self.steps[3] - 1 {
+            self.interrupt = true;
+        }
+        let clock = match self.steps.iter().position(|&at| at == self.cycle) {
+            Some(step) if step < 3 || step == last => FrameClock {
+                quarter: true,
+                half: step == 1 || step == last,
+            },
+            _ => FrameClock::default(),
+        };
+        if self.cycle > self.steps[last] {
+            self.cycle = 0;
+        }
+        clock
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.five_step);
+        writer.bool(self.irq_inhibit);
+        writer.bool(self.interrupt);
+        writer.u32(self.cycle);
+        writer.u8(self.reset_delay);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.five_step = reader.bool()?;
+        self.irq_inhibit = reader.bool()?;
+        self.interrupt = reader.bool()?;
+        self.cycle = reader.u32()? % (self.steps[4] + 1);
+        self.reset_delay = reader.u8()?.min(4);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Runs a whole sequence and returns the cycles with a quarter and a half frame.
+    fn run(counter: &mut FrameCounter, cycles: u16) -> (Vec<u16>, Vec<u16>) {
+        let (mut quarters, mut halves) = (Vec::new(), Vec::new());
+        for cycle in 1..=cycles {
+            let clock = counter.tick();
+            if clock.quarter {
+                quarters.push(cycle);
+            }
+            if clock.half {
+                halves.push(cycle);
+            }
+        }
+        (quarters, halves)
+    }
+
+    #[test]
+    fn test_sequences_and_irq() {
+        let mut counter = FrameCounter::new();
+        let (quarters, halves) = run(&mut counter, 29827);
+        assert_eq!(quarters, [7457, 14913, 22371]);
+        assert_eq!(halves, [14913]);
+        assert!(!counter.interrupt);
+        run(&mut counter, 1);
+        assert!(counter.interrupt);
+
+        // Inhibiting clears the flag, and 5-step mode clocks right after the write
+        counter.write(0xC0, false);
+        assert!(!counter.interrupt);
+        let (quarters, halves) = run(&mut counter, 37282 + 3);
+        assert_eq!(quarters, [3, 3 + 7457, 3 + 14913, 3 + 22371, 3 + 37281]);
+        assert_eq!(halves, [3, 3 + 14913, 3 + 37281]);
+        assert!(!counter.interrupt);
+
+        // No IRQ in 4-step mode while inhibited either
+        counter.write(0x40, true);
+        run(&mut counter, 29830 * 2);
+        assert!(!counter.interrupt);
+    }
+}
@@ -0,0 +1,406 @@
+mod blip;
+mod dmc;
+mod expansion;
+mod filter;
+mod frame_counter;
+mod noise;
+mod panning;
+mod pulse;
+mod triangle;
+mod units;
+
+use crate::{
+    region::Region,
+    save_state::{StateReader, StateWriter},
+};
+
+pub use self::{expansion::ExpansionAudio, filter::AudioFilter, panning::Panning};
+
+use self::{
+    blip::BlipBuffer, dmc::Dmc, frame_counter::FrameCounter, noise::Noise, panning::Gains,
+    pulse::Pulse, triangle::Triangle,
+};
+
+/// Every channel at full level, for mono
+const CENTER: Gains = [1.0; 6];
+
+/// The audio processing unit inside the 2A03, clocked along with the CPU.
+pub struct Apu {
+    pulse: [Pulse; 2],
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    /// Channel timers run at half the CPU's rate, on odd cycles
+    odd_cycle: bool,
+    region: Region,
+    /// The rate to make samples for the speakers at, once there is one
+    sample_rate: Option<u32>,
+    /// One for mono, left and right for stereo
+    blips: Vec<BlipBuffer>,
+    filters: Vec<AudioFilter>,
+    /// Left and right gains for each channel in stereo
+    panning: Option<[Gains; 2]>,
+    /// The cartridge's level as of the last `set_expansion_level`, scaled by the volume
+    expansion: f32,
+    expansion_volume: f32,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            pulse: [Pulse::new(0), Pulse::new(1)],
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            frame_counter: FrameCounter::new(),
+            odd_cycle: false,
+            region: Region::default(),
+            sample_rate: None,
+            blips: Vec::new(),
+            filters: AudioFilter::NES.to_vec(),
+            panning: None,
+            expansion: 0.0,
+            expansion_volume: 1.0,
+        }
+    }
+
+    /// Switches to the region's noise and DMC periods and frame counter timing.
+    pub fn set_region(&mut self, region: Region) {
+        self.noise.set_region(region);
+        self.dmc.set_region(region);
+        self.frame_counter.set_region(region);
+        self.region = region;
+        self.reset_output();
+    }
+
+    /// Silences everything like turning the console off and on. The region and the output
+    /// settings stay.
+    pub fn power_cycle(&mut self) {
+        let region = self.region;
+        *self = Apu {
+            sample_rate: self.sample_rate,
+            filters: std::mem::take(&mut self.filters),
+            panning: self.panning,
+            expansion_volume: self.expansion_volume,
+            ..Apu::new()
+        };
+        self.set_region(region);
+    }
+
+    /// Starts making samples at `sample_rate`, to be collected with `take_samples`.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = Some(sample_rate);
+        self.reset_output();
+    }
+
+    /// Makes `adjustment` times as many samples as the sample rate calls for, a little over or
+    /// under 1 to keep the audio device's queue from running dry or filling up.
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        for blip in self.blips.iter_mut() {
+            blip.set_rate_adjustment(adjustment);
+        }
+    }
+
+    /// Changes the filters run over the samples, `AudioFilter::NES` to begin with.
+    pub fn set_filters(&mut self, filters: &[AudioFilter]) {
+        self.filters = filters.to_vec();
+        self.reset_output();
+    }
+
+    /// Makes stereo samples with the channels panned, or mono ones with `None`, the default.
+    pub fn set_panning(&mut self, panning: Option<Panning>) {
+        self.panning = panning.map(|panning| panning.gains());
+        self.reset_output();
+    }
+
+    /// Starts the samples over for new output settings.
+    fn reset_output(&mut self) {
+        let channels = if self.panning.is_some() { 2 } else { 1 };
+        self.blips = match self.sample_rate {
+            Some(sample_rate) => (0..channels)
+                .map(|_| BlipBuffer::new(self.region.cpu_clock_hz(), sample_rate, &self.filters))
+                .collect(),
+            None => Vec::new(),
+        };
+    }
+
+    /// Swaps the samples made since the last call into `out`, replacing what it held. Stereo
+    /// samples come left first, then right.
+    pub fn take_samples(&mut self, out: &mut Vec<f32>) {
+        out.clear();
+        match self.blips.as_mut_slice() {
+            [mono] => std::mem::swap(out, &mut mono.samples),
+            [left, right] => {
+                // Both sides make a sample on the same cycles
+                let pairs = left.samples.iter().zip(right.samples.iter());
+                out.extend(pairs.flat_map(|(&left, &right)| [left, right]));
+                left.samples.clear();
+                right.samples.clear();
+            }
+            _ => {}
+        }
+    }
+
+    /// Mixes in the cartridge's sound at `level`, from `ExpansionAudio::mix_audio`, until the
+    /// next call.
+    pub fn set_expansion_level(&mut self, level: f32) {
+        self.expansion = level * self.expansion_volume;
+    }
+
+    /// How loud the cartridge's sound is against the APU's, 1 for the console's own balance.
+    pub fn set_expansion_volume(&mut self, volume: f32) {
+        self.expansion_volume = volume;
+    }
+
+    /// Writes one of the registers at $4000-$4013, $4015 or $4017.
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse[0].write(address & 0x03, value),
+            0x4004..=0x4007 => self.pulse[1].write(address & 0x03, value),
+            0x4008..=0x400B => self.triangle.write(address & 0x03, value),
+            0x400C..=0x400F => self.noise.write(address & 0x03, value),
+            0x4010..=0x4013 => self.dmc.write(address & 0x03, value),
+            0x4015 => {
+                self.pulse[0].length.set_enabled(value & 0x01 != 0);
+                self.pulse[1].length.set_enabled(value & 0x02 != 0);
+                self.triangle.length.set_enabled(value & 0x04 != 0);
+                self.noise.length.set_enabled(value & 0x08 != 0);
+                self.dmc.set_enabled(value & 0x10 != 0);
+            }
+            0x4017 => self.frame_counter.write(value, self.odd_cycle),
+            _ => {}
+        }
+    }
+
+    /// Reads $4015, `IF-D NT21`: which channels still have notes (or DMC bytes) left, and the
+    /// DMC and frame interrupt flags. Reading acknowledges the frame interrupt.
+    pub fn read_status(&mut self) -> u8 {
+        let status = (self.pulse[0].length.active() as u8)
+            | (self.pulse[1].length.active() as u8) << 1
+            | (self.triangle.length.active() as u8) << 2
+            | (self.noise.length.active() as u8) << 3
+            | (self.dmc.active() as u8) << 4
+            | (self.frame_counter.interrupt as u8) << 6
+            | (self.dmc.interrupt as u8) << 7;
+        self.frame_counter.interrupt = false;
+        status
+    }
+
+    /// Advances by `cycles` CPU cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.odd_cycle {
+            for pulse in self.pulse.iter_mut() {
+                pulse.clock_timer();
+            }
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        let clock = self.frame_counter.tick();
+        if clock.quarter {
+            for pulse in self.pulse.iter_mut() {
+                pulse.quarter_frame();
+            }
+            self.triangle.quarter_frame();
+            self.noise.quarter_frame();
+        }
+        if clock.half {
+            for pulse in self.pulse.iter_mut() {
+                pulse.half_frame();
+            }
+            self.triangle.half_frame();
+            self.noise.half_frame();
+        }
+
+        if !self.blips.is_empty() {
+            let levels = match &self.panning {
+                Some([left, right]) => [self.mix(left), self.mix(right)],
+                None => [self.output(), 0.0],
+            };
+            for (blip, level) in self.blips.iter_mut().zip(levels) {
+                blip.add(level);
+            }
+        }
+    }
+
+    /// The address the DMC wants its next sample byte from, if it wants one. The bus reads
+    /// it and hands it over with `dmc_fill`.
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        self.dmc.fetch_address()
+    }
+
+    pub fn dmc_fill(&mut self, byte: u8) {
+        self.dmc.fill(byte);
+    }
+
+    /// Whether the frame counter or the DMC is asking for an interrupt.
+    pub fn irq(&self) -> bool {
+        self.frame_counter.interrupt || self.dmc.interrupt
+    }
+
+    /// The mixed output level right now, from 0 to about 1, using the console's non-linear
+    /// mixer, plus the cartridge's sound.
+    pub fn output(&self) -> f32 {
+        self.mix(&CENTER)
+    }
+
+    /// Mixes the channels with each one's level scaled by its gain, for one side in stereo.
+    fn mix(&self, gains: &Gains) -> f32 {
+        let pulse =
+            self.pulse[0].output() as f32 * gains[0] + self.pulse[1].output() as f32 * gains[1];
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+        let tnd = self.triangle.output() as f32 * gains[2] / 8227.0
+            + self.noise.output() as f32 * gains[3] / 12241.0
+            + self.dmc.output() as f32 * gains[4] / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse_out + tnd_out + self.expansion * gains[5]
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for pulse in &self.pulse {
+            pulse.save_state(writer);
+        }
+        self.triangle.save_state(writer);
+        self.noise.save_state(writer);
+        self.dmc.save_state(writer);
+        self.frame_counter.save_state(writer);
+        writer.bool(self.odd_cycle);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        for pulse in self.pulse.iter_mut() {
+            pulse.load_state(reader)?;
+        }
+        self.triangle.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.dmc.load_state(reader)?;
+        self.frame_counter.load_state(reader)?;
+        self.odd_cycle = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Runs `cycles` CPU cycles and returns how far the output swung. A stopped triangle
+    /// holds a level, so silence isn't always 0.
+    fn swing(apu: &mut Apu, cycles: usize) -> f32 {
+        let (mut high, mut low) = (0.0f32, 1.0f32);
+        for _ in 0..cycles {
+            apu.tick(1);
+            high = high.max(apu.output());
+            low = low.min(apu.output());
+        }
+        high - low
+    }
+
+    #[test]
+    fn test_pulse_plays_until_its_length_runs_out() {
+        let mut apu = Apu::new();
+        // 50% duty, constant volume 15, a period of 0x100
+        apu.write_register(0x4000, 0x9F);
+        apu.write_register(0x4002, 0x00);
+        apu.write_register(0x4003, 0x01);
+        assert_eq!(swing(&mut apu, 1000), 0.0, "silent until enabled");
+
+        apu.write_register(0x4015, 0x01);
+        // Length index 1 is 254 half frames, index 0 is 10
+        apu.write_register(0x4003, 0x09);
+        assert!(swing(&mut apu, 2000) > 0.1);
+
+        apu.write_register(0x4003, 0x01);
+        swing(&mut apu, 29830 * 5 + 1);
+        assert_eq!(swing(&mut apu, 1000), 0.0, "note over after 10 half frames");
+
+        // Periods under 8 are muted
+        apu.write_register(0x4002, 0x07);
+        apu.write_register(0x4003, 0x08);
+        assert_eq!(swing(&mut apu, 1000), 0.0);
+        apu.write_register(0x4002, 0x08);
+        assert!(swing(&mut apu, 1000) > 0.0);
+
+        // Disabling stops the note right away
+        apu.write_register(0x4015, 0x00);
+        assert_eq!(swing(&mut apu, 1000), 0.0);
+    }
+
+    #[test]
+    fn test_stereo_panning() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(48_000);
+        apu.set_filters(&[]);
+        apu.set_panning(Some(Panning {
+            pulse1: -1.0,
+            ..Panning::default()
+        }));
+        apu.write_register(0x4015, 0x01);
+        apu.write_register(0x4000, 0x9F);
+        apu.write_register(0x4002, 0x00);
+        apu.write_register(0x4003, 0x09);
+        for _ in 0..20_000 {
+            apu.tick(1);
+        }
+        let mut samples = Vec::new();
+        apu.take_samples(&mut samples);
+        assert_eq!(samples.len() % 2, 0);
+
+        // Pulse 1 is all the way left; the right only has the idle triangle's level
+        let swing = |side: usize| {
+            let side = samples[200..].iter().skip(side).step_by(2);
+            let (low, high) = side.fold((1.0f32, 0.0f32), |(low, high), &sample| {
+                (low.min(sample), high.max(sample))
+            });
+            high - low
+        };
+        assert!(swing(0) > 0.1);
+        assert!(swing(1) < 1e-4);
+    }
+
+    #[test]
+    fn test_status() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x0F);
+        for channel in [0x4003, 0x4007, 0x400B, 0x400F] {
+            apu.write_register(channel, 0x08);
+        }
+        assert_eq!(apu.read_status(), 0x0F);
+        apu.write_register(0x4015, 0x05);
+        assert_eq!(apu.read_status(), 0x05);
+
+        // The frame interrupt is acknowledged by reading it, the DMC's isn't
+        apu.write_register(0x4010, 0x80);
+        apu.write_register(0x4015, 0x15);
+        assert_eq!(apu.read_status(), 0x15);
+        apu.dmc_fill(0);
+        for _ in 0..30000 {
+            apu.tick(1);
+        }
+        assert_eq!(apu.read_status(), 0xC5);
+        assert_eq!(apu.read_status(), 0x85);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::{
+    region::Region,
+    save_state::{StateReader, StateWriter},
+};
+
+use super::units::{Envelope, LengthCounter};
+
+/// The noise channel, $400C-$400F: pseudo-random bits from a 15-bit shift register, for
+/// drums, explosions and wind.
+pub struct Noise {
+    /// Short mode taps bit 6 instead of bit 1, repeating every 93 bits for a metallic buzz
+    short_mode: bool,
+    shift: u16,
+    /// Index into `periods`, from $400E
+    rate: u8,
+    periods: &'static [u16; 16],
+    timer: u16,
+    envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise::new()
+    }
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise {
+            short_mode: false,
+            // Loaded with 1 at power on; all zeros would never change
+            shift: 1,
+            rate: 0,
+            periods: Region::NTSC.noise_periods(),
+            timer: 0,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.periods = region.noise_periods();
+    }
+
+    /// Writes register 0-3 of the channel.
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            // --LC VVVV
+            0 => {
+                self.length.halt = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            1 => {}
+            // M--- PPPP
+            2 => {
+                self.short_mode = value & 0x80 != 0;
+                self.rate = value & 0x0F;
+            }
+            // LLLL L---
+            _ => {
+                self.length.load(value);
+                self.envelope.restart();
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.periods[self.rate as usize] - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = self.shift >> 1 | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    /// The channel's level, 0-15.
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.shift & 1 != 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.short_mode);
+        writer.u16(self.shift);
+        writer.u8(self.rate);
+        writer.u16(self.timer);
+        self.envelope.save_state(writer);
+        self.length.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.short_mode = reader.bool()?;
+        self.shift = reader.u16()? & 0x7FFF;
+        self.rate = reader.u8()? & 0x0F;
+        self.timer = reader.u16()?;
+        self.envelope.load_state(reader)?;
+        self.length.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Bits the shift register goes through before repeating
+    fn sequence_length(short_mode: bool) -> usize {
+        let mut noise = Noise::new();
+        noise.write(2, if short_mode { 0x80 } else { 0x00 });
+        noise.clock_timer();
+        let start = noise.shift;
+        let mut length = 0;
+        loop {
+            for _ in 0..noise.periods[0] {
+                noise.clock_timer();
+            }
+            length += 1;
+            if noise.shift == start {
+                return length;
+            }
+        }
+    }
+
+    #[test]
+    fn test_lfsr_periods() {
+        assert_eq!(sequence_length(false), 32767);
+        assert_eq!(sequence_length(true), 93);
+    }
+}
@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+
+/// Where each channel sits in stereo, from -1 (left) through 0 (center) to 1 (right). The
+/// console only ever had mono out; this spreads the channels apart for headphones.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct Panning {
+    pub pulse1: f32,
+    pub pulse2: f32,
+    pub triangle: f32,
+    pub noise: f32,
+    pub dmc: f32,
+    /// Sound from the cartridge
+    pub expansion: f32,
+}
+
+impl Default for Panning {
+    fn default() -> Self {
+        Panning {
+            pulse1: -0.5,
+            pulse2: 0.5,
+            triangle: 0.0,
+            noise: 0.0,
+            dmc: 0.0,
+            expansion: 0.0,
+        }
+    }
+}
+
+/// How much of each channel goes into one side, in the order of `Panning`'s fields.
+pub type Gains = [f32; 6];
+
+impl Panning {
+    /// The left and right gains. A centered channel is at full level on both sides, like in
+    /// mono, and fades out of one side as it's panned to the other.
+    pub fn gains(&self) -> [Gains; 2] {
+        let pans = [
+            self.pulse1,
+            self.pulse2,
+            self.triangle,
+            self.noise,
+            self.dmc,
+            self.expansion,
+        ];
+        [
+            pans.map(|pan| (1.0 - pan).clamp(0.0, 1.0)),
+            pans.map(|pan| (1.0 + pan).clamp(0.0, 1.0)),
+        ]
+    }
+}
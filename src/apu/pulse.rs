@@ -0,0 +1,204 @@
+use crate::save_state::{StateReader, StateWriter};
+
+use super::units::{Envelope, LengthCounter};
+
+/// The 8 steps of each duty cycle: 12.5%, 25%, 50% and 25% inverted
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// Bends a pulse channel's pitch by adding or subtracting a fraction of its period every few
+/// half frames.
+#[derive(Default)]
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+}
+
+/// One of the two square wave channels, $4000-$4003 and $4004-$4007.
+pub struct Pulse {
+    /// Pulse 1 negates with ones' complement, so its sweeps go one step further down
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    /// Timer period in APU cycles, minus one
+    period: u16,
+    timer: u16,
+    sweep: Sweep,
+    envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Pulse {
+    /// `channel` is 0 for pulse 1 and 1 for pulse 2.
+    pub fn new(channel: usize) -> Self {
+        Pulse {
+            ones_complement: channel == 0,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            sweep: Sweep::default(),
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    /// Writes register 0-3 of the channel.
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            // DDLC VVVV
+            0 => {
+                self.duty = value >> 6;
+                self.length.halt = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            // EPPP NSSS
+            1 => {
+                self.sweep.enabled = value & 0x80 != 0;
+                self.sweep.period = (value >> 4) & 0x07;
+                self.sweep.negate = value & 0x08 != 0;
+                self.sweep.shift = value & 0x07;
+                self.sweep.reload = true;
+            }
+            2 => self.period = self.period & 0x0700 | value as u16,
+            // LLLL LHHH
+            _ => {
+                self.period = self.period & 0x00FF | ((value & 0x07) as u16) << 8;
+                self.length.load(value);
+                self.envelope.restart();
+                self.step = 0;
+            }
+        }
+    }
+
+    /// Clocked every APU cycle, every other CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn half_frame(&mut self) {
+        self.length.clock();
+        let sweep = &self.sweep;
+        if sweep.divider == 0 && sweep.enabled && sweep.shift > 0 && !self.sweep_mutes() {
+            self.period = self.sweep_target() as u16;
+        }
+        let sweep = &mut self.sweep;
+        if sweep.divider == 0 || sweep.reload {
+            sweep.divider = sweep.period;
+            sweep.reload = false;
+        } else {
+            sweep.divider -= 1;
+        }
+    }
+
+    /// The period the sweep is heading for, worked out all the time whether it's enabled or
+    /// not.
+    fn sweep_target(&self) -> i32 {
+        let change = (self.period >> self.sweep.shift) as i32;
+        if !self.sweep.negate {
+            self.period as i32 + change
+        } else if self.ones_complement {
+            self.period as i32 - change - 1
+        } else {
+            self.period as i32 - change
+        }
+    }
+
+    /// Periods too high to play, or a sweep that would take the period past 11 bits, silence
+    /// the channel even with the sweep off.
+    fn sweep_mutes(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    /// The channel's level, 0-15.
+    pub fn output(&self) -> u8 {
+        if !self.length.active()
+            || self.sweep_mutes()
+            || DUTY_TABLE[self.duty as usize][self.step as usize] == 0
+        {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.duty);
+        writer.u8(self.step);
+        writer.u16(self.period);
+        writer.u16(self.timer);
+        writer.bool(self.sweep.enabled);
+        writer.u8(self.sweep.period);
+        writer.bool(self.sweep.negate);
+        writer.u8(self.sweep.shift);
+        writer.u8(self.sweep.divider);
+        writer.bool(self.sweep.reload);
+        self.envelope.save_state(writer);
+        self.length.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.duty = reader.u8()? & 0x03;
+        self.step = reader.u8()? & 0x07;
+        self.period = reader.u16()? & 0x07FF;
+        self.timer = reader.u16()?;
+        self.sweep.enabled = reader.bool()?;
+        self.sweep.period = reader.u8()?;
+        self.sweep.negate = reader.bool()?;
+        self.sweep.shift = reader.u8()? & 0x07;
+        self.sweep.divider = reader.u8()?;
+        self.sweep.reload = reader.bool()?;
+        self.envelope.load_state(reader)?;
+        self.length.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sweeps() {
+        let mut pulse = [Pulse::new(0), Pulse::new(1)];
+        for pulse in pulse.iter_mut() {
+            pulse.length.set_enabled(true);
+            pulse.write(0, 0x3F);
+            pulse.write(2, 0x00);
+            pulse.write(3, 0x02);
+            // Down, shift 1, divider period 0
+            pulse.write(1, 0x89);
+            pulse.half_frame();
+        }
+        // 0x200 - 0x100 - 1 for pulse 1, 0x200 - 0x100 for pulse 2
+        assert_eq!(pulse[0].period, 0xFF);
+        assert_eq!(pulse[1].period, 0x100);
+
+        // An upward sweep past 11 bits mutes the channel even while disabled
+        let mut up = Pulse::new(1);
+        up.length.set_enabled(true);
+        up.write(0, 0xBF);
+        up.write(1, 0x00);
+        up.write(2, 0x00);
+        up.write(3, 0x04);
+        assert!((0..16).all(|_| {
+            up.clock_timer();
+            up.output() == 0
+        }));
+    }
+}
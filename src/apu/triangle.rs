@@ -0,0 +1,143 @@
+use crate::save_state::{StateReader, StateWriter};
+
+use super::units::LengthCounter;
+
+/// The 32 steps of the triangle wave
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+/// The triangle wave channel, $4008-$400B. It has no volume control, only the length counter
+/// and a finer grained linear counter to cut notes short.
+#[derive(Default)]
+pub struct Triangle {
+    /// Halts the length counter and keeps reloading the linear counter
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    step: u8,
+    /// Timer period in CPU cycles, minus one
+    period: u16,
+    timer: u16,
+    pub length: LengthCounter,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Triangle::default()
+    }
+
+    /// Writes register 0-3 of the channel.
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            // CRRR RRRR
+            0 => {
+                self.control = value & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = value & 0x7F;
+            }
+            1 => {}
+            2 => self.period = self.period & 0x0700 | value as u16,
+            // LLLL LHHH
+            _ => {
+                self.period = self.period & 0x00FF | ((value & 0x07) as u16) << 8;
+                self.length.load(value);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle, twice as often as the other channels' timers, so the triangle
+    /// plays an octave lower than a pulse with the same period would.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            // Periods under 2 are ultrasonic, and games use them to silence the channel.
+            // Holding the step there avoids the hiss, like most emulators do.
+            if self.linear_counter > 0 && self.length.active() && self.period >= 2 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    /// The channel's level, 0-15. A stopped triangle holds its last step instead of going
+    /// quiet, like the real one.
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.control);
+        writer.u8(self.linear_reload_value);
+        writer.u8(self.linear_counter);
+        writer.bool(self.linear_reload);
+        writer.u8(self.step);
+        writer.u16(self.period);
+        writer.u16(self.timer);
+        self.length.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.control = reader.bool()?;
+        self.linear_reload_value = reader.u8()? & 0x7F;
+        self.linear_counter = reader.u8()?;
+        self.linear_reload = reader.bool()?;
+        self.step = reader.u8()? & 0x1F;
+        self.period = reader.u16()? & 0x07FF;
+        self.timer = reader.u16()?;
+        self.length.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_linear_counter_stops_the_wave() {
+        let mut triangle = Triangle::new();
+        triangle.length.set_enabled(true);
+        // Linear counter of 2 quarter frames
+        triangle.write(0, 0x02);
+        triangle.write(2, 0x10);
+        triangle.write(3, 0x08);
+        triangle.quarter_frame();
+        assert_eq!(triangle.linear_counter, 2);
+
+        let mut steps = Vec::new();
+        for _ in 0..0x11 * 3 {
+            triangle.clock_timer();
+            steps.push(triangle.output());
+        }
+        steps.dedup();
+        assert_eq!(steps, [14, 13, 12]);
+
+        triangle.quarter_frame();
+        triangle.quarter_frame();
+        assert_eq!(triangle.linear_counter, 0);
+        let held = triangle.output();
+        for _ in 0..0x11 * 3 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), held);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::save_state::{StateReader, StateWriter};
+
+/// Note lengths in half frames, indexed by the top 5 bits of a channel's length register
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// Volume that either stays put or fades from 15 to 0, one step per divider period of
+/// quarter frames.
+#[derive(Default)]
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    /// The constant volume, or the fade's divider period
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    /// Takes the low 6 bits of a channel's first register: `--LC VVVV`.
+    pub fn write(&mut self, value: u8) {
+        self.looping = value & 0x20 != 0;
+        self.constant = value & 0x10 != 0;
+        self.volume = value & 0x0F;
+    }
+
+    /// Starts the fade over, on a write to the channel's length register.
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.start);
+        writer.bool(self.looping);
+        writer.bool(self.constant);
+        writer.u8(self.volume);
+        writer.u8(self.divider);
+        writer.u8(self.decay);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.start = reader.bool()?;
+        self.looping = reader.bool()?;
+        self.constant = reader.bool()?;
+        self.volume = reader.u8()? & 0x0F;
+        self.divider = reader.u8()?;
+        self.decay = reader.u8()?;
+        Ok(())
+    }
+}
+
+/// Silences a channel once its note has played for the loaded number of half frames, unless
+/// halted.
+#[derive(Default)]
+pub struct LengthCounter {
+    /// Cleared through $4015, which also stops the note
+    enabled: bool,
+    pub halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    /// Takes the channel's length register; the length is in the top 5 bits.
+    pub fn load(&mut self, value: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(value >> 3) as usize];
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    /// Whether the note is still playing.
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.bool(self.halt);
+        writer.u8(self.counter);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.enabled = reader.bool()?;
+        self.halt = reader.bool()?;
+        self.counter = reader.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,3 @@
+pub mod output;
+pub mod rate_control;
+pub mod ring_buffer;
@@ -0,0 +1,116 @@
+use sdl2::{
+    audio::{AudioCallback, AudioDevice, AudioSpecDesired, AudioStatus},
+    AudioSubsystem,
+};
+
+use super::{
+    rate_control::RateControl,
+    ring_buffer::{ring_buffer, AudioStats, Consumer, Producer},
+};
+
+/// Device buffers' worth of frames to keep queued, for the rate control to aim at
+const QUEUED_BUFFERS: usize = 3;
+
+struct Sink {
+    consumer: Consumer,
+}
+
+impl AudioCallback for Sink {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.consumer.fill(out);
+    }
+}
+
+/// Plays samples through the default audio device. The emulator pushes each frame's samples
+/// and SDL's audio thread pulls them as the device needs them.
+///
+/// The device waits until the queue is full enough before it starts, and stops to refill
+/// after running dry, so one underrun doesn't turn into a stutter of them.
+pub struct AudioOutput {
+    device: AudioDevice<Sink>,
+    producer: Producer,
+    rate_control: RateControl,
+    /// Samples to have queued before playing
+    target: usize,
+    /// Underruns as of the last push
+    underruns: u64,
+}
+
+impl AudioOutput {
+    /// Opens the default device with 1 or 2 `channels` at `sample_rate`, or as close as it
+    /// goes. Stereo samples are pushed left first, then right. The device asks for
+    /// `buffer_size` frames at a time: smaller is less lag, bigger is fewer underruns on a
+    /// busy machine.
+    pub fn open(
+        audio: &AudioSubsystem,
+        sample_rate: u32,
+        channels: u8,
+        buffer_size: u16,
+    ) -> Result<AudioOutput, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(sample_rate as i32),
+            channels: Some(channels),
+            samples: Some(buffer_size),
+        };
+        let mut producer = None;
+        let device = audio
+            .open_playback(None, &desired, |spec| {
+                // At least a quarter of a second of room, so a slow frame doesn't drop samples
+                let frames =
+                    (spec.freq as usize / 4).max(QUEUED_BUFFERS * 2 * spec.samples as usize);
+                let (queue, consumer) = ring_buffer(frames, spec.channels as usize);
+                producer = Some(queue);
+                Sink { consumer }
+            })
+            .map_err(|e| format!("Failed to open the audio device: {}", e))?;
+        let spec = device.spec();
+        let target = QUEUED_BUFFERS * spec.samples as usize * spec.channels as usize;
+        Ok(AudioOutput {
+            device,
+            producer: producer.expect("the callback was created"),
+            rate_control: RateControl::new(target),
+            target,
+            underruns: 0,
+        })
+    }
+
+    /// The rate the device actually plays at.
+    pub fn sample_rate(&self) -> u32 {
+        self.device.spec().freq as u32
+    }
+
+    /// 1 for mono or 2 for stereo, whichever the device took.
+    pub fn channels(&self) -> u8 {
+        self.device.spec().channels
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        self.producer.push_slice(samples);
+        let stats = self.producer.stats();
+        if stats.underruns != self.underruns {
+            self.underruns = stats.underruns;
+            self.device.pause();
+        }
+        if self.device.status() != AudioStatus::Playing && stats.buffered >= self.target {
+            self.device.resume();
+        }
+    }
+
+    /// Stops playing, e.g. while the game runs faster or slower than the sound can follow.
+    /// The next `push` that fills the queue starts it again.
+    pub fn pause(&mut self) {
+        self.device.pause();
+    }
+
+    /// What to scale the sample rate by to keep the queue at its target, to be asked once a
+    /// frame. See `RateControl`.
+    pub fn rate_adjustment(&mut self) -> f64 {
+        self.rate_control.update(self.producer.buffered())
+    }
+
+    pub fn stats(&self) -> AudioStats {
+        self.producer.stats()
+    }
+}
@@ -0,0 +1,59 @@
+/// Furthest the sample rate is bent either way, 0.5%. Too little to hear as a change in pitch.
+const MAX_ADJUSTMENT: f64 = 0.005;
+/// How much each new reading moves the average buffer level
+const SMOOTHING: f64 = 0.05;
+
+/// Dynamic rate control. The emulator's frame pacing and the audio device's clock never
+/// quite agree, so over a long session the buffer between them slowly empties (crackle) or
+/// fills (lag). This watches how full it is and makes slightly more samples when it's low
+/// and fewer when it's high, holding it around a target.
+pub struct RateControl {
+    /// Samples to keep buffered
+    target: f64,
+    /// Samples buffered, averaged over the last few readings. The device takes them in
+    /// chunks, so single readings jump around.
+    average: f64,
+}
+
+impl RateControl {
+    pub fn new(target: usize) -> Self {
+        RateControl {
+            target: target as f64,
+            average: target as f64,
+        }
+    }
+
+    /// Takes a reading of how many samples are buffered and returns what to scale the sample
+    /// rate by until the next one.
+    pub fn update(&mut self, buffered: usize) -> f64 {
+        self.average += (buffered as f64 - self.average) * SMOOTHING;
+        let error = ((self.target - self.average) / self.target).clamp(-1.0, 1.0);
+        1.0 + error * MAX_ADJUSTMENT
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buffer_settles_at_target() {
+        // A device playing 800 samples a frame against an emulator making 0.1% too many
+        let mut control = RateControl::new(3000);
+        let mut buffered = 0.0;
+        let mut adjustment = 1.0;
+        for _ in 0..6000 {
+            buffered = (buffered + 800.0 * 1.001 * adjustment - 800.0f64).max(0.0);
+            adjustment = control.update(buffered as usize);
+        }
+        assert!(adjustment < 1.0);
+        assert!((buffered - 3000.0).abs() < 3000.0 * 0.25, "{}", buffered);
+        // Steady, not drifting
+        let before = buffered;
+        for _ in 0..600 {
+            buffered += 800.0 * 1.001 * adjustment - 800.0;
+            adjustment = control.update(buffered as usize);
+        }
+        assert!((buffered - before).abs() < 100.0, "{} {}", before, buffered);
+    }
+}
@@ -0,0 +1,234 @@
+use std::sync::{
+    atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Counters for diagnosing crackle. All counts are since the buffer was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AudioStats {
+    /// Times the audio callback asked for more samples than were buffered
+    pub underruns: u64,
+    /// Samples the callback had to make up by repeating the last one
+    pub underrun_samples: u64,
+    /// Samples the emulator produced while the buffer was full
+    pub dropped_samples: u64,
+    /// Samples currently waiting to be played
+    pub buffered: usize,
+}
+
+struct Shared {
+    // Samples are stored as `f32::to_bits` so the slots can be plain atomics
+    slots: Box<[AtomicU32]>,
+    /// Samples per frame, 2 for stereo. Only whole frames go in and out.
+    channels: usize,
+    // Total samples ever written / read. Only the producer stores `head`, only the consumer
+    // stores `tail`, so neither side needs a lock.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    underruns: AtomicU64,
+    underrun_samples: AtomicU64,
+    dropped_samples: AtomicU64,
+}
+
+impl Shared {
+    fn buffered(&self) -> usize {
+        self.head
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.tail.load(Ordering::Acquire))
+    }
+}
+
+/// Emulation thread side of the ring buffer.
+pub struct Producer {
+    shared: Arc<Shared>,
+}
+
+/// Audio callback side of the ring buffer.
+pub struct Consumer {
+    shared: Arc<Shared>,
+    last_frame: Vec<f32>,
+}
+
+/// Creates a single-producer single-consumer sample queue holding up to `capacity` frames of
+/// `channels` interleaved samples. The emulator pushes into the producer as it runs and the
+/// audio callback drains the consumer, so a slow frame only eats into the buffered samples
+/// instead of blocking either side.
+pub fn ring_buffer(capacity: usize, channels: usize) -> (Producer, Consumer) {
+    assert!(
+        capacity > 0 && channels > 0,
+        "Ring buffer needs room for at least one sample"
+    );
+    let shared = Arc::new(Shared {
+        slots: (0..capacity * channels)
+            .map(|_| AtomicU32::new(0))
+            .collect(),
+        channels,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        underruns: AtomicU64::new(0),
+        underrun_samples: AtomicU64::new(0),
+        dropped_samples: AtomicU64::new(0),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer {
+            shared,
+            last_frame: vec![0.0; channels],
+        },
+    )
+}
+
+impl Producer {
+    /// Queues as many of `samples` as fit, in whole frames, and returns how many samples that
+    /// was. The rest are counted as dropped.
+    pub fn push_slice(&mut self, samples: &[f32]) -> usize {
+        let shared = &*self.shared;
+        let capacity = shared.slots.len();
+        let head = shared.head.load(Ordering::Relaxed);
+        let tail = shared.tail.load(Ordering::Acquire);
+        let free = capacity - head.wrapping_sub(tail);
+
+        let count = samples.len().min(free) / shared.channels * shared.channels;
+        for (i, sample) in samples[..count].iter().enumerate() {
+            shared.slots[head.wrapping_add(i) % capacity]
+                .store(sample.to_bits(), Ordering::Relaxed);
+        }
+        shared
+            .head
+            .store(head.wrapping_add(count), Ordering::Release);
+
+        if count < samples.len() {
+            shared
+                .dropped_samples
+                .fetch_add((samples.len() - count) as u64, Ordering::Relaxed);
+        }
+        count
+    }
+
+    pub fn push(&mut self, sample: f32) -> bool {
+        self.push_slice(&[sample]) == 1
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.shared.buffered()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+
+    pub fn stats(&self) -> AudioStats {
+        stats(&self.shared)
+    }
+}
+
+impl Consumer {
+    /// Fills `out` with queued samples. If there aren't enough, the rest of `out` repeats the
+    /// last frame played, which is much less audible than dropping to silence. Returns how
+    /// many real samples were read.
+    pub fn fill(&mut self, out: &mut [f32]) -> usize {
+        let shared = &*self.shared;
+        let capacity = shared.slots.len();
+        let tail = shared.tail.load(Ordering::Relaxed);
+        let head = shared.head.load(Ordering::Acquire);
+
+        let channels = shared.channels;
+        let count = out.len().min(head.wrapping_sub(tail)) / channels * channels;
+        for (i, sample) in out[..count].iter_mut().enumerate() {
+            *sample = f32::from_bits(
+                shared.slots[tail.wrapping_add(i) % capacity].load(Ordering::Relaxed),
+            );
+        }
+        shared
+            .tail
+            .store(tail.wrapping_add(count), Ordering::Release);
+
+        if count > 0 {
+            self.last_frame
+                .copy_from_slice(&out[count - channels..count]);
+        }
+        if count < out.len() {
+            for frame in out[count..].chunks_mut(channels) {
+                frame.copy_from_slice(&self.last_frame[..frame.len()]);
+            }
+            shared.underruns.fetch_add(1, Ordering::Relaxed);
+            shared
+                .underrun_samples
+                .fetch_add((out.len() - count) as u64, Ordering::Relaxed);
+        }
+        count
+    }
+
+    pub fn stats(&self) -> AudioStats {
+        stats(&self.shared)
+    }
+}
+
+fn stats(shared: &Shared) -> AudioStats {
+    AudioStats {
+        underruns: shared.underruns.load(Ordering::Relaxed),
+        underrun_samples: shared.underrun_samples.load(Ordering::Relaxed),
+        dropped_samples: shared.dropped_samples.load(Ordering::Relaxed),
+        buffered: shared.buffered(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_underrun_repeats_last_sample() {
+        let (mut producer, mut consumer) = ring_buffer(4, 1);
+        assert_eq!(producer.push_slice(&[0.1, 0.2, 0.3, 0.4, 0.5]), 4);
+
+        let mut out = [0.0; 6];
+        assert_eq!(consumer.fill(&mut out), 4);
+        assert_eq!(out, [0.1, 0.2, 0.3, 0.4, 0.4, 0.4]);
+
+        let stats = consumer.stats();
+        assert_eq!(stats.underruns, 1);
+        assert_eq!(stats.underrun_samples, 2);
+        assert_eq!(stats.dropped_samples, 1);
+        assert_eq!(stats.buffered, 0);
+    }
+
+    #[test]
+    fn test_stereo_keeps_frames_whole() {
+        let (mut producer, mut consumer) = ring_buffer(2, 2);
+        assert_eq!(producer.push_slice(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6]), 4);
+
+        let mut out = [0.0; 3];
+        assert_eq!(consumer.fill(&mut out), 2);
+        assert_eq!(out, [0.1, 0.2, 0.1]);
+        let mut out = [0.0; 6];
+        assert_eq!(consumer.fill(&mut out), 2);
+        assert_eq!(out, [0.3, 0.4, 0.3, 0.4, 0.3, 0.4]);
+    }
+
+    #[test]
+    fn test_samples_arrive_in_order_across_threads() {
+        let (mut producer, mut consumer) = ring_buffer(64, 1);
+        let writer = std::thread::spawn(move || {
+            let mut next = 0;
+            while next < 10_000 {
+                if producer.push(next as f32) {
+                    next += 1;
+                }
+            }
+        });
+
+        let mut expected = 0;
+        let mut out = [0.0; 16];
+        while expected < 10_000 {
+            let count = consumer.fill(&mut out);
+            for &sample in &out[..count] {
+                assert_eq!(sample, expected as f32);
+                expected += 1;
+            }
+        }
+        writer.join().unwrap();
+    }
+}
@@ -1,7 +1,13 @@
 use crate::{
-    cartridge::Rom,
+    apu::{Apu, AudioFilter, Panning},
+    cartridge::{Console, Rom},
     cpu::Mem,
-    ppu::{NesPPU, PPU}, joypad::Joypad,
+    ppu::{NesPPU, PPU}, joypad::{ControllerType, Joypad, PowerPad},
+    mapper::{self, Mapper, Nrom},
+    region::Region,
+    error::EmulationError,
+    save_state::{StateReader, StateWriter},
+    vs_system::VsSystem,
 };
 
 const RAM: u16 = 0x0000;
@@ -19,6 +25,12 @@ const PPU_DATA: u16 = 0x2007;
 const PPU_REGISTERS_MIRRORS_START: u16 = 0x2008;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
 
+const CARTRIDGE: u16 = 0x4020;
+const CARTRIDGE_END: u16 = 0xFFFF;
+const CHR_END: u16 = 0x1FFF;
+/// Bits of $4016 and $4017 reads nothing on the console drives
+const OPEN_BUS_BITS: u8 = 0xE0;
+
 impl Mem for Bus<'_> {
     fn mem_read(&mut self, address: u16) -> u8 {
         match address {
@@ -32,14 +44,15 @@ impl Mem for Bus<'_> {
             PPU_STATUS => self.ppu.read_status(),
             PPU_OAM_DATA => self.ppu.read_oam_data(),
             PPU_DATA => self.ppu.read_data(),
-            0x4000..=0x4015 => 0, // APU
-            0x4016 => self.joypad1.read(),
-            0x4017 => 0,          // joypad 2
+            0x4000..=0x4013 => 0, // APU, write only
+            0x4015 => self.apu.read_status(),
+            0x4016 => self.read_controller(0) | self.port_upper_bits(address),
+            0x4017 => self.read_controller(1) | self.port_upper_bits(address),
             PPU_REGISTERS_MIRRORS_START..=PPU_REGISTERS_MIRRORS_END => {
                 let miror_down_address = address & 0x2007;
                 self.mem_read(miror_down_address)
             }
-            0x8000..=0xFFFF => self.read_prg_rom(address),
+            CARTRIDGE..=CARTRIDGE_END => self.mapper.cpu_read(address),
             _ => {
                 eprintln!("Invalid memory address: {:#X}", address);
                 0
@@ -59,10 +72,25 @@ impl Mem for Bus<'_> {
             PPU_OAM_DATA => self.ppu.write_to_oam_data(value),
             PPU_SCROLL => self.ppu.write_to_scroll(value),
             PPU_ADDR => self.ppu.write_to_ppu_addr(value),
-            PPU_DATA => self.ppu.write_to_data(value),
-            0x4000..=0x4013 | 0x4015 => {} // APU
-            0x4016 => self.joypad1.write(value),
-            0x4017 => {}                   // joypad 2
+            PPU_DATA => {
+                let ppu_address = self.ppu.addr.get();
+                self.ppu.write_to_data(value);
+                if ppu_address <= CHR_END {
+                    self.mapper.ppu_write(ppu_address, value);
+                }
+            }
+            0x4000..=0x4013 | 0x4015 => self.apu.write_register(address, value),
+            0x4016 => {
+                // The strobe line goes to both ports
+                for joypad in self.joypads.iter_mut() {
+                    joypad.write(value);
+                }
+                self.power_pad.write(value);
+                if self.mapper.write_4016(value) {
+                    self.sync_cartridge();
+                }
+            }
+            0x4017 => self.apu.write_register(address, value),
             0x4014 => {
                 let mut buffer: [u8; 256] = [0; 256];
                 let hi: u16 = (value as u16) << 8;
@@ -75,7 +103,11 @@ impl Mem for Bus<'_> {
                 let miror_down_address = address & 0x2007;
                 self.mem_write(miror_down_address, value);
             }
-            0x8000..=0xFFFF => panic!("Cannot write to ROM"),
+            CARTRIDGE..=CARTRIDGE_END => {
+                if self.mapper.cpu_write(address, value) {
+                    self.sync_cartridge();
+                }
+            }
             _ => eprintln!("Invalid memory address: {:#X}", address),
         }
     }
@@ -83,66 +115,581 @@ impl Mem for Bus<'_> {
 
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
-    rom: Vec<u8>,
+    mapper: Box<dyn Mapper>,
+    battery: bool,
     ppu: NesPPU,
+    apu: Apu,
+    region: Region,
+    /// Fifths of a PPU dot owed from the last CPU cycle, with PAL's 3.2 dots per cycle
+    dot_fraction: u16,
+    /// Where in CHR each 1KB of the PPU's copy came from, see `Mapper::chr_slot`
+    chr_slots: [usize; 8],
 
     cycles: usize,
-    game_loop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad) + 'call>,
-    joypad1: Joypad,
+    frames: usize,
+    game_loop_callback: Box<dyn FnMut(&NesPPU, &mut [Joypad; 2]) + 'call>,
+    joypads: [Joypad; 2],
+    ports: [ControllerType; 2],
+    /// Read from whichever port has `ControllerType::PowerPad`
+    power_pad: PowerPad,
+    /// The arcade hardware, when running a Vs. System game
+    vs: Option<VsSystem>,
 }
 
 impl<'a> Bus<'a> {
+    /// Runs boards whose mapper isn't emulated as NROM, see `try_new`.
     pub fn new<'call, F>(rom: Rom, game_loop_callback: F) -> Bus<'call>
     where
-        F: FnMut(&NesPPU, &mut Joypad) + 'call,
+        F: FnMut(&NesPPU, &mut [Joypad; 2]) + 'call,
+    {
+        let mapper = mapper::for_rom(&rom).unwrap_or_else(|_| Box::new(Nrom::new(&rom)));
+        Bus::with_mapper(rom, mapper, game_loop_callback)
+    }
+
+    fn with_mapper<'call, F>(rom: Rom, mapper: Box<dyn Mapper>, game_loop_callback: F) -> Bus<'call>
+    where
+        F: FnMut(&NesPPU, &mut [Joypad; 2]) + 'call,
     {
-        let ppu = NesPPU::new(rom.chr_rom, rom.mirroring);
-        Bus {
+        // The PPU keeps a copy of the CHR banked in to draw from, filled by `sync_cartridge`.
+        // With CHR RAM it takes writes too.
+        let chr = if mapper.memory().chr_is_ram() {
+            Vec::new()
+        } else {
+            vec![0; CHR_END as usize + 1]
+        };
+        let ppu = NesPPU::new(chr, rom.mirroring);
+        let mut bus = Bus {
             cpu_vram: [0; 2048],
-            rom: rom.prg_rom,
+            mapper,
+            battery: rom.battery,
             ppu,
+            apu: Apu::new(),
+            region: Region::default(),
+            dot_fraction: 0,
+            chr_slots: [usize::MAX; 8],
             cycles: 0,
+            frames: 0,
             game_loop_callback: Box::from(game_loop_callback),
-            joypad1: Joypad::new(),
+            joypads: [Joypad::new(), Joypad::new()],
+            ports: [ControllerType::Joypad; 2],
+            power_pad: PowerPad::new(),
+            vs: rom.is_vs_system().then(VsSystem::new),
+        };
+        bus.sync_cartridge();
+        bus
+    }
+
+    /// Like `new`, but refuses cartridges whose mapper isn't emulated instead of running them
+    /// into garbage.
+    pub fn try_new<'call, F>(rom: Rom, game_loop_callback: F) -> Result<Bus<'call>, EmulationError>
+    where
+        F: FnMut(&NesPPU, &mut [Joypad; 2]) + 'call,
+    {
+        let unsupported_console = match rom.console {
+            Console::PlayChoice10 => Some("PlayChoice-10".to_string()),
+            Console::Vs { dual: true, .. } => Some("Vs. DualSystem".to_string()),
+            Console::Extended(console) => {
+                Some(format!("NES 2.0 extended console type {}", console))
+            }
+            _ => None,
+        };
+        if let Some(console) = unsupported_console {
+            return Err(EmulationError::UnsupportedConsole(console));
         }
+        let mapper = mapper::for_rom(&rom)?;
+        Ok(Bus::with_mapper(rom, mapper, game_loop_callback))
     }
 
-    fn read_prg_rom(&self, mut address: u16) -> u8 {
-        address -= 0x8000;
-        if self.rom.len() == 0x4000 {
-            address %= 0x4000;
+    /// Shows the PPU the CHR banks and mirroring the mapper has switched to. Only the 1KB banks
+    /// that moved are copied.
+    fn sync_cartridge(&mut self) {
+        for slot in 0..self.chr_slots.len() {
+            let offset = self.mapper.chr_slot(slot);
+            if self.chr_slots[slot] != offset {
+                self.chr_slots[slot] = offset;
+                let bank = &self.mapper.memory().chr()[offset..offset + 0x400];
+                self.ppu.load_chr_bank(slot, bank);
+            }
+        }
+        self.ppu.set_mirroring(self.mapper.mirroring());
+    }
+
+    /// Like `sync_cartridge`, but copies every bank, for when the CHR behind them changed.
+    fn reload_cartridge(&mut self) {
+        self.chr_slots = [usize::MAX; 8];
+        self.sync_cartridge();
+    }
+
+    /// Clears RAM and the PPU like turning the console off and on. Battery-backed PRG RAM
+    /// survives, like it does on a real cartridge.
+    pub fn power_cycle(&mut self) {
+        self.cpu_vram = [0; 2048];
+        self.mapper.power_cycle();
+        if !self.battery {
+            self.mapper.memory_mut().prg_ram.fill(0);
+        }
+        self.ppu.power_cycle();
+        self.apu.power_cycle();
+        if let Some(vs) = self.vs.as_mut() {
+            vs.power_cycle();
+        }
+        self.reload_cartridge();
+    }
+
+    /// What the reset button does to the chips on the bus: the APU's channels go quiet and the
+    /// PPU's registers clear, see `NesPPU::soft_reset`.
+    pub fn soft_reset(&mut self) {
+        self.apu.write_register(0x4015, 0);
+        self.ppu.soft_reset();
+    }
+
+    /// The console's RAM and counters, the PPU, the APU and the cartridge, each as its own
+    /// section.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.section(b"RAM ", 2, |writer| {
+            writer.bytes(&self.cpu_vram);
+            writer.u64(self.cycles as u64);
+            writer.u64(self.frames as u64);
+            writer.u16(self.dot_fraction);
+        });
+        writer.section(b"PPU ", 2, |writer| self.ppu.save_state(writer));
+        writer.section(b"APU ", 1, |writer| self.apu.save_state(writer));
+        writer.section(b"CART", 1, |writer| self.mapper.save_state(writer));
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.section(b"RAM ", |reader| {
+            reader.bytes_into(&mut self.cpu_vram)?;
+            self.cycles = reader.u64()? as usize;
+            self.frames = reader.u64()? as usize;
+            // Version 1 didn't keep PAL's part of a dot
+            self.dot_fraction = if reader.version() >= 2 { reader.u16()? } else { 0 };
+            Ok(())
+        })?;
+        reader.section(b"PPU ", |reader| self.ppu.load_state(reader))?;
+        reader.section(b"APU ", |reader| self.apu.load_state(reader))?;
+        reader.section(b"CART", |reader| self.mapper.load_state(reader))?;
+        self.reload_cartridge();
+        Ok(())
+    }
+
+    /// The controllers as the game sees them, including how far through a read they are.
+    pub fn joypads(&self) -> &[Joypad; 2] {
+        &self.joypads
+    }
+
+    pub fn set_joypads(&mut self, joypads: [Joypad; 2]) {
+        self.joypads = joypads;
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    /// Makes the APU produce audio at `sample_rate`, see `Apu::take_samples`.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.set_sample_rate(sample_rate);
+    }
+
+    pub fn set_audio_filters(&mut self, filters: &[AudioFilter]) {
+        self.apu.set_filters(filters);
+    }
+
+    pub fn set_audio_rate_adjustment(&mut self, adjustment: f64) {
+        self.apu.set_rate_adjustment(adjustment);
+    }
+
+    pub fn set_panning(&mut self, panning: Option<Panning>) {
+        self.apu.set_panning(panning);
+    }
+
+    pub fn set_expansion_volume(&mut self, volume: f32) {
+        self.apu.set_expansion_volume(volume);
+    }
+
+    pub fn take_samples(&mut self, out: &mut Vec<f32>) {
+        self.apu.take_samples(out);
+    }
+
+    /// RAM at $6000-$7FFF. Holds the save game on battery-backed carts.
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.mapper.memory().prg_ram
+    }
+
+    /// Replaces the contents of PRG RAM, e.g. with a save file. Fails if `data` is a
+    /// different size.
+    pub fn load_prg_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        let prg_ram = &mut self.mapper.memory_mut().prg_ram;
+        if data.len() != prg_ram.len() {
+            return Err(format!(
+                "Save RAM is {} bytes, this cartridge has {}",
+                data.len(),
+                prg_ram.len()
+            ));
+        }
+        prg_ram.copy_from_slice(data);
+        Ok(())
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    /// The coin slots and DIP switches, when running a Vs. System game.
+    pub fn vs_system(&mut self) -> Option<&mut VsSystem> {
+        self.vs.as_mut()
+    }
+
+    /// CRC32 of the CPU's RAM and the cartridge's PRG RAM, to tell whether two runs are still
+    /// in the same state.
+    pub fn ram_checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.cpu_vram);
+        hasher.update(self.prg_ram());
+        hasher.finalize()
+    }
+
+    /// Reads memory without side effects, for debuggers. I/O registers, whose reads change
+    /// state, read as 0.
+    pub fn peek(&self, address: u16) -> u8 {
+        match address {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(address & 0x07FF) as usize],
+            CARTRIDGE..=CARTRIDGE_END => self.mapper.cpu_peek(address),
+            _ => 0,
         }
-        self.rom[address as usize]
     }
 
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
-        let new_frame = self.ppu.tick(cycles * 3);
+        self.mapper.tick(cycles);
+        // Sound stands still through overclocking lines so the music keeps its tempo
+        if !self.ppu.in_extra_line() {
+            if let Some(chip) = self.mapper.expansion_audio() {
+                self.apu.set_expansion_level(chip.mix_audio(cycles));
+            }
+            self.apu.tick(cycles);
+            // The CPU really stalls for a few cycles while the DMC reads, which isn't emulated
+            if let Some(address) = self.apu.dmc_fetch_address() {
+                let byte = self.mem_read(address);
+                self.apu.dmc_fill(byte);
+            }
+        }
+        let (numerator, denominator) = self.region.dots_per_cpu_cycle();
+        let dots = cycles as u16 * numerator + self.dot_fraction;
+        self.dot_fraction = dots % denominator;
+        let new_frame = self.ppu.tick((dots / denominator) as u8);
         if new_frame {
-            (self.game_loop_callback)(&self.ppu, &mut self.joypad1);
+            self.frames += 1;
+            if let Some(vs) = self.vs.as_mut() {
+                vs.end_frame();
+            }
+            (self.game_loop_callback)(&self.ppu, &mut self.joypads);
+        }
+    }
+
+    /// Calls the frame callback again for the frame that already ended, without running
+    /// anything, so a paused frontend can go back to waiting after handling something between
+    /// frames.
+    pub fn repeat_frame_callback(&mut self) {
+        (self.game_loop_callback)(&self.ppu, &mut self.joypads);
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.dot_fraction = 0;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+
+    /// Emulate the PPU's buggy sprite overflow scan, see `NesPPU::sprite_overflow_bug`.
+    pub fn set_sprite_overflow_bug(&mut self, on: bool) {
+        self.ppu.sprite_overflow_bug = on;
+    }
+
+    /// Gives the CPU `lines` more scanlines of time each frame, see `NesPPU::extra_lines`.
+    pub fn set_overclock_lines(&mut self, lines: u16) {
+        self.ppu.extra_lines = lines;
+    }
+
+    fn read_controller(&mut self, port: usize) -> u8 {
+        match self.ports[port] {
+            ControllerType::Joypad => self.joypads[port].read(),
+            ControllerType::Unplugged => 0,
+            ControllerType::PowerPad => self.power_pad.read(),
+        }
+    }
+
+    /// The Power Pad buttons held, bit n - 1 for button n.
+    pub fn set_power_pad(&mut self, buttons: u16) {
+        self.power_pad.set_buttons(buttons);
+    }
+
+    /// The bits of a $4016 or $4017 read above the controller's. A Vs. System puts its DIP
+    /// switches and coin slots there. On the console bits 5-7 float and keep the last value on
+    /// the data bus, which for `LDA $4016` is the address's high byte, so games see $40 or
+    /// $41; some check for exactly that.
+    fn port_upper_bits(&self, address: u16) -> u8 {
+        match &self.vs {
+            Some(vs) if address == 0x4016 => vs.read_4016(),
+            Some(vs) => vs.read_4017(),
+            None => (address >> 8) as u8 & OPEN_BUS_BITS,
         }
     }
 
+    /// Plugs `controller` into port 0 or 1.
+    pub fn set_controller(&mut self, port: usize, controller: ControllerType) {
+        self.ports[port] = controller;
+    }
+
+    /// CPU cycles since power on.
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// Number of frames the PPU has completed since power on.
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
         self.ppu.poll_nmi_interrupt()
     }
+
+    /// Whether anything is holding the IRQ line. It stays held until the source is
+    /// acknowledged, unlike the NMI.
+    pub fn irq_pending(&self) -> bool {
+        self.apu.irq() || self.mapper.irq_pending()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::cartridge::test;
+    use crate::apu::ExpansionAudio;
+    use crate::cartridge::{test, Mirroring};
+    use crate::joypad::JoypadButton;
 
     #[test]
     fn test_mem_read_write_to_ram() {
-        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {});
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
         bus.mem_write(0x01, 0x55);
         assert_eq!(bus.mem_read(0x01), 0x55);
     }
 
+    #[test]
+    fn test_pal_frame_length() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        bus.set_region(Region::PAL);
+        let mut cycles_to_frame = |frame| {
+            let mut cycles = 0;
+            while bus.frame_count() < frame {
+                bus.tick(1);
+                cycles += 1;
+            }
+            cycles
+        };
+        cycles_to_frame(1);
+        // 312 lines of 341 dots at 3.2 dots a cycle is 33247.5 cycles a frame
+        assert_eq!(cycles_to_frame(3), 66_495);
+    }
+
+    #[test]
+    fn test_pal_state_keeps_dot_phase() {
+        let new_bus = || {
+            let callback = |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {};
+            let mut bus = Bus::new(test::test_rom(), callback);
+            bus.set_region(Region::PAL);
+            bus
+        };
+        let mut bus = new_bus();
+        bus.tick(3);
+        let mut writer = StateWriter::new();
+        bus.save_state(&mut writer);
+        let state = writer.finish();
+
+        let mut loaded = new_bus();
+        loaded.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(loaded.dot_fraction, bus.dot_fraction);
+        // 3.2 dots a cycle only lands on whole dots again with the fifths carried over
+        bus.tick(2);
+        loaded.tick(2);
+        assert_eq!(loaded.ppu.dot(), bus.ppu.dot());
+    }
+
+    #[test]
+    fn test_overclock_lines() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        bus.apu.set_sample_rate(44_100);
+        let run_frame = |bus: &mut Bus| {
+            let (start, mut nmi_at, mut samples) = (bus.cycles, None, Vec::new());
+            bus.ppu.write_to_ctrl(0x80);
+            let frame = bus.frame_count();
+            while bus.frame_count() == frame {
+                bus.tick(1);
+                if nmi_at.is_none() && bus.ppu.nmi_interrupt.is_some() {
+                    nmi_at = Some(bus.cycles - start);
+                }
+            }
+            bus.apu.take_samples(&mut samples);
+            (bus.cycles - start, nmi_at.unwrap(), samples.len())
+        };
+        run_frame(&mut bus);
+        let (cycles, nmi_at, samples) = run_frame(&mut bus);
+
+        // 30 more lines of 341 dots at 3 dots a cycle, all before vblank, with no more sound
+        bus.set_overclock_lines(30);
+        run_frame(&mut bus);
+        let overclocked = run_frame(&mut bus);
+        assert_eq!(overclocked.0, cycles + 3410);
+        assert_eq!(overclocked.1, nmi_at + 3410);
+        assert!(overclocked.2.abs_diff(samples) <= 1);
+    }
+
+    #[test]
+    fn test_unsupported_mapper_is_rejected() {
+        let rom = test::test_rom();
+        assert_eq!(rom.mapper, 3);
+        let bus = Bus::try_new(rom, |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        assert_eq!(bus.err(), Some(EmulationError::UnsupportedMapper(3)));
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        bus.mem_write(0x6001, 0x42);
+        assert_eq!(bus.mem_read(0x6001), 0x42);
+        assert_eq!(bus.prg_ram()[1], 0x42);
+
+        assert!(bus.load_prg_ram(&[0; 16]).is_err());
+        bus.load_prg_ram(&vec![7; 0x2000]).unwrap();
+        assert_eq!(bus.peek(0x7FFF), 7);
+    }
+
+    #[test]
+    fn test_power_cycle_clears_ram() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        bus.mem_write(0x10, 1);
+        bus.mem_write(0x6000, 2);
+        bus.mem_write(0x2003, 0x55);
+        bus.power_cycle();
+        assert_eq!(bus.peek(0x10), 0);
+        assert_eq!(bus.peek(0x6000), 0);
+        assert_eq!(bus.ppu.oam_addr, 0);
+
+        bus.battery = true;
+        bus.mem_write(0x6000, 2);
+        bus.power_cycle();
+        assert_eq!(bus.peek(0x6000), 2);
+    }
+
+    /// A chip that counts the cycles it's run for
+    struct Counter(u32);
+
+    impl ExpansionAudio for Counter {
+        fn mix_audio(&mut self, cycles: u8) -> f32 {
+            self.0 += cycles as u32;
+            self.0 as f32 / 100.0
+        }
+    }
+
+    /// NROM with a `Counter` on the board
+    struct CounterBoard(Nrom, Counter);
+
+    impl Mapper for CounterBoard {
+        fn memory(&self) -> &mapper::Memory {
+            self.0.memory()
+        }
+
+        fn memory_mut(&mut self) -> &mut mapper::Memory {
+            self.0.memory_mut()
+        }
+
+        fn cpu_peek(&self, address: u16) -> u8 {
+            self.0.cpu_peek(address)
+        }
+
+        fn cpu_write(&mut self, address: u16, value: u8) -> bool {
+            self.0.cpu_write(address, value)
+        }
+
+        fn chr_slot(&self, slot: usize) -> usize {
+            self.0.chr_slot(slot)
+        }
+
+        fn mirroring(&self) -> Mirroring {
+            self.0.mirroring()
+        }
+
+        fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+            Some(&mut self.1)
+        }
+    }
+
+    #[test]
+    fn test_expansion_audio_is_mixed_in() {
+        let rom = test::test_rom();
+        let mapper = Box::new(CounterBoard(Nrom::new(&rom), Counter(0)));
+        let mut bus = Bus::with_mapper(rom, mapper, |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        let silence = bus.apu().output();
+        bus.tick(7);
+        bus.tick(3);
+        assert!((bus.apu().output() - silence - 0.1).abs() < 1e-6);
+
+        bus.set_expansion_volume(0.5);
+        bus.tick(10);
+        assert!((bus.apu().output() - silence - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_chr_bank_switch() {
+        let mut rom = test::test_rom();
+        rom.mapper = 206;
+        // Each 1KB bank filled with its own number
+        rom.chr_rom = (0..0x10000).map(|i| (i / 0x400) as u8).collect();
+        let mut bus = Bus::new(rom, |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        assert_eq!(bus.ppu().chr_rom[0x1400], 0);
+        let stamp = bus.ppu().chr_stamp;
+
+        // R3, the 1KB bank at $1400
+        bus.mem_write(0x8000, 3);
+        bus.mem_write(0x8001, 9);
+        assert_eq!(bus.ppu().chr_rom[0x13FF], 0);
+        assert_eq!(bus.ppu().chr_rom[0x1400..0x1800], [9; 0x400]);
+        assert_eq!(bus.ppu().chr_rom[0x1800], 0);
+        assert_eq!(bus.ppu().tile_cache.tile(0x1400)[4], 3, "both bitplanes of 9 set bit 3");
+        assert!(bus.ppu().chr_stamp > stamp);
+    }
+
+    #[test]
+    fn test_second_joypad() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        bus.joypads[1].press(JoypadButton::A);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        // With open bus above the button
+        assert_eq!(bus.mem_read(0x4016), 0x40);
+        assert_eq!(bus.mem_read(0x4017), 0x41);
+
+        bus.set_controller(1, ControllerType::Unplugged);
+        bus.mem_write(0x4016, 1);
+        assert_eq!(bus.mem_read(0x4017), 0x40);
+
+        bus.set_controller(1, ControllerType::PowerPad);
+        bus.set_power_pad(0b10);
+        assert_eq!(bus.mem_read(0x4017), 0x48, "button 2 comes first");
+    }
+
     #[test]
     fn test_mem_write_to_oam() {
-        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {});
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
         bus.mem_write(0x2003, 0x55);
         assert_eq!(bus.ppu.oam_addr, 0x55);
         bus.mem_write(0x2004, 0x66);
@@ -1,55 +1,238 @@
+use serde::{Deserialize, Serialize};
+
+use crate::region::Region;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+pub const PRG_RAM_PAGE_SIZE: usize = 8192;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Mirroring {
     HORIZONTAL,
     VERTICAL,
+    /// 2KB of extra VRAM on the cartridge gives every nametable its own memory
     FOURSCREEN,
+    /// All four nametables show the first or the second 1KB of VRAM. Set by mappers, iNES
+    /// headers can't express it.
+    SINGLESCREEN0,
+    SINGLESCREEN1,
+}
+
+impl Mirroring {
+    /// Which 1KB of VRAM each logical nametable ($2000, $2400, $2800, $2C00) uses.
+    pub fn nametable_banks(self) -> [u16; 4] {
+        match self {
+            Mirroring::HORIZONTAL => [0, 0, 1, 1],
+            Mirroring::VERTICAL => [0, 1, 0, 1],
+            Mirroring::FOURSCREEN => [0, 1, 2, 3],
+            Mirroring::SINGLESCREEN0 => [0, 0, 0, 0],
+            Mirroring::SINGLESCREEN1 => [1, 1, 1, 1],
+        }
+    }
+}
+
+/// The PPU on a Vs. System board. The RGB PPUs show colors straight from RGB tables instead of
+/// generating a TV signal; the RP2C04s also shuffle the color numbers, differently per chip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VsPpu {
+    /// iNES headers don't say which PPU the board has
+    Unknown,
+    /// RP2C03 or RC2C03: the standard color numbers with RGB output
+    Rgb,
+    /// RP2C04-0001 to -0004, by number
+    Rp2c04(u8),
+    /// RC2C05-01 to -05, by number. Standard colors, with some registers swapped around.
+    Rc2c05(u8),
+}
+
+/// The machine a ROM was made for. Arcade boards run NES games with extra hardware around them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Console {
+    #[default]
+    Nes,
+    /// Nintendo Vs. System: coin slots, DIP switches and an RGB PPU. `dual` boards run two
+    /// games side by side for two cabinets.
+    Vs { ppu: VsPpu, dual: bool },
+    /// PlayChoice-10: NES games with a Z80 running the menu and the timer
+    PlayChoice10,
+    /// An NES 2.0 extended console type, e.g. famiclones with extra CPU opcodes
+    Extended(u8),
+}
+
+impl Console {
+    /// Reads the console type from byte 7 of the header, and for NES 2.0 byte 13.
+    fn from_header(raw: &[u8], nes2: bool) -> Console {
+        let extra = if nes2 { raw[13] } else { 0 };
+        match raw[7] & 0x03 {
+            0 => Console::Nes,
+            1 => Console::Vs {
+                ppu: match (nes2, extra & 0x0F) {
+                    (false, _) => VsPpu::Unknown,
+                    (true, 0 | 1 | 6 | 7) => VsPpu::Rgb,
+                    (true, n @ 2..=5) => VsPpu::Rp2c04(n - 1),
+                    (true, n @ 8..=0xC) => VsPpu::Rc2c05(n - 7),
+                    (true, _) => VsPpu::Unknown,
+                },
+                dual: nes2 && matches!(extra >> 4, 5 | 6),
+            },
+            // iNES only has a bit for each; both set is meaningless there
+            2 => Console::PlayChoice10,
+            _ if nes2 => Console::Extended(extra & 0x0F),
+            _ => Console::Nes,
+        }
+    }
 }
 
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
-    pub mapper: u8,
+    pub mapper: u16,
     pub mirroring: Mirroring,
+    /// TV system declared by the header, if it declares one
+    pub region: Option<Region>,
+    /// CRC32 of PRG + CHR without the header, which identifies a dump regardless of how its
+    /// header was filled in
+    pub crc32: u32,
+    /// CRC32s of PRG and CHR on their own, as ROM databases list them
+    pub prg_crc32: u32,
+    pub chr_crc32: u32,
+    /// Bytes of RAM at $6000-$7FFF
+    pub prg_ram_size: usize,
+    /// Whether PRG RAM is battery backed, i.e. holds save games
+    pub battery: bool,
+    pub console: Console,
+}
+
+/// A NES 2.0 RAM size byte: volatile and battery-backed sizes, each as a shift count of 64
+/// bytes.
+pub fn nes2_ram_size(byte: u8) -> usize {
+    let shift_size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+    shift_size(byte & 0x0F) + shift_size(byte >> 4)
+}
+
+/// Old dumping tools wrote their name ("DiskDude!" being the famous one) over bytes 7-15,
+/// which iNES left unused. Bytes 12-15 are zero in any clean iNES header, so when they aren't,
+/// only the low nibble of the mapper in byte 6 can be trusted.
+pub(crate) fn is_dirty_header(header: &[u8]) -> bool {
+    header[7] & 0x0C != 0x08 && header[12..HEADER_SIZE].iter().any(|&byte| byte != 0)
 }
 
 impl Rom {
+    /// Whether this runs on a Vs. System. Mapper 99 is only found on Vs. boards, whatever the
+    /// header says.
+    pub fn is_vs_system(&self) -> bool {
+        matches!(self.console, Console::Vs { .. }) || self.mapper == 99
+    }
+
     pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != &NES_TAG {
+        if raw.len() < HEADER_SIZE || &raw[0..4] != &NES_TAG {
             return Err("Invalid NES file".to_string());
         }
 
-        let mapper = (raw[7] & 0xF0) | (raw[6] >> 4);
-        let ines_version = raw[7] >> 2 & 0x3;
-        if ines_version != 0 {
-            return Err("Unsupported iNES version".to_string());
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&raw[..HEADER_SIZE]);
+        if is_dirty_header(&header) {
+            header[7..].fill(0);
         }
 
-        let four_screen = raw[6] & 0x8 != 0;
-        let vertical_mirroring = raw[6] & 0x1 != 0;
+        let ines_version = header[7] >> 2 & 0x3;
+        let nes2 = match ines_version {
+            0 => false,
+            2 => true,
+            _ => return Err("Unsupported iNES version".to_string()),
+        };
+        let mut mapper = (header[7] & 0xF0 | header[6] >> 4) as u16;
+        if nes2 {
+            // NES 2.0 adds the top 4 of 12 bits
+            mapper |= (header[8] as u16 & 0x0F) << 8;
+        }
+
+        let region = if nes2 {
+            match header[12] & 0x3 {
+                0 => Some(Region::NTSC),
+                1 => Some(Region::PAL),
+                3 => Some(Region::Dendy),
+                // Multi-region carts: leave it to the other heuristics
+                _ => None,
+            }
+        } else if header[9] & 0x1 != 0 {
+            Some(Region::PAL)
+        } else {
+            // 0 is what nearly every iNES dumper writes, so it says nothing
+            None
+        };
+
+        let four_screen = header[6] & 0x8 != 0;
+        let vertical_mirroring = header[6] & 0x1 != 0;
         let mirroring = match (four_screen, vertical_mirroring) {
             (true, _) => Mirroring::FOURSCREEN,
             (false, true) => Mirroring::VERTICAL,
             (false, false) => Mirroring::HORIZONTAL,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let (prg_pages, chr_pages) = if nes2 {
+            if header[9] & 0x0F == 0x0F || header[9] & 0xF0 == 0xF0 {
+                return Err("Unsupported NES 2.0 exponent ROM size".to_string());
+            }
+            (
+                (header[9] as usize & 0x0F) << 8 | header[4] as usize,
+                (header[9] as usize & 0xF0) << 4 | header[5] as usize,
+            )
+        } else {
+            (header[4] as usize, header[5] as usize)
+        };
+        let prg_rom_size = prg_pages * PRG_ROM_PAGE_SIZE;
+        let chr_rom_size = chr_pages * CHR_ROM_PAGE_SIZE;
+        if prg_rom_size == 0 {
+            return Err("The header declares no PRG ROM".to_string());
+        }
 
-        let skip_trainer = raw[6] & 0x4 != 0;
+        let battery = header[6] & 0x2 != 0;
+        let prg_ram_size = if nes2 {
+            nes2_ram_size(header[10])
+        } else {
+            // Byte 8 is rarely set; 0 means the usual 8KB
+            header[8].max(1) as usize * PRG_RAM_PAGE_SIZE
+        };
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
+        let skip_trainer = header[6] & 0x4 != 0;
+
+        let prg_rom_start = HEADER_SIZE + if skip_trainer { TRAINER_SIZE } else { 0 };
         let prg_rom_end = prg_rom_start + prg_rom_size;
         let chr_rom_end = prg_rom_end + chr_rom_size;
 
+        if raw.len() < chr_rom_end {
+            return Err(format!(
+                "Truncated ROM: {}KB of PRG and {}KB of CHR need a {} byte file, this one is {}",
+                prg_rom_size / 1024,
+                chr_rom_size / 1024,
+                chr_rom_end,
+                raw.len()
+            ));
+        }
+
+        let prg_rom = raw[prg_rom_start..prg_rom_end].to_vec();
+        let chr_rom = raw[prg_rom_end..chr_rom_end].to_vec();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&prg_rom);
+        hasher.update(&chr_rom);
+
         Ok(Rom {
-            prg_rom: raw[prg_rom_start..prg_rom_end].to_vec(),
-            chr_rom: raw[prg_rom_end..chr_rom_end].to_vec(),
+            prg_crc32: crc32fast::hash(&prg_rom),
+            chr_crc32: crc32fast::hash(&chr_rom),
+            prg_rom,
+            chr_rom,
             mapper,
             mirroring,
+            region,
+            crc32: hasher.finalize(),
+            prg_ram_size,
+            battery,
+            console: Console::from_header(&header, nes2),
         })
     }
 }
@@ -150,10 +333,71 @@ pub mod test {
     }
 
     #[test]
-    fn test_nes2_is_not_supported() {
+    fn test_nes2_mapper() {
+        // Submapper 5, mapper $103
+        let mut header = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x30, 0x08, 0x51];
+        header.extend([0; 7]);
+        let rom = Rom::new(&create_rom(TestRom {
+            header,
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        }))
+        .unwrap();
+        assert_eq!(rom.mapper, 0x103);
+    }
+
+    #[test]
+    fn test_nes2_region() {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 0x01, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.region, Some(Region::PAL));
+
+        let mut dendy = test_rom.clone();
+        dendy[12] = 0x03;
+        assert_eq!(Rom::new(&dendy).unwrap().region, Some(Region::Dendy));
+    }
+
+    #[test]
+    fn test_battery_prg_ram() {
+        let ines = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x02, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        let rom = Rom::new(&ines).unwrap();
+        assert!(rom.battery);
+        assert_eq!(rom.prg_ram_size, 0x2000);
+
+        // NES 2.0: 2KB of work RAM plus 32KB of battery-backed RAM
+        let nes2 = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x02, 0x8, 00, 00, 0x95, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        let rom = Rom::new(&nes2).unwrap();
+        assert_eq!(rom.prg_ram_size, 0x800 + 0x8000);
+    }
+
+    #[test]
+    fn test_archaic_ines_is_not_supported() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x4, 00, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
             pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
@@ -165,4 +409,79 @@ pub mod test {
             Result::Err(str) => assert_eq!(str, "Unsupported iNES version"),
         }
     }
+
+    #[test]
+    fn test_dirty_header() {
+        let mut header = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x21];
+        header.extend(b"DiskDude!");
+        let rom = Rom::new(&create_rom(TestRom {
+            header,
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        }))
+        .unwrap();
+        assert_eq!(rom.mapper, 2);
+        assert_eq!(rom.region, None);
+        assert_eq!(rom.console, Console::Nes);
+    }
+
+    #[test]
+    fn test_truncated_rom() {
+        let raw = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x00, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 100],
+        });
+        let error = Rom::new(&raw).err().unwrap();
+        assert!(error.starts_with("Truncated ROM"), "{}", error);
+        assert_eq!(
+            Rom::new(&raw[..10].to_vec()).err().unwrap(),
+            "Invalid NES file"
+        );
+    }
+
+    #[test]
+    fn test_console_types() {
+        let console = |byte7: u8, byte13: u8| {
+            let raw = create_rom(TestRom {
+                header: vec![
+                    0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, byte7, 00, 00, 00, 00, 00, byte13,
+                    00, 00,
+                ],
+                trainer: None,
+                pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+                chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+            });
+            Rom::new(&raw).unwrap().console
+        };
+        assert_eq!(console(0x00, 0x00), Console::Nes);
+        assert_eq!(
+            console(0x01, 0x00),
+            Console::Vs {
+                ppu: VsPpu::Unknown,
+                dual: false
+            }
+        );
+        assert_eq!(console(0x02, 0x00), Console::PlayChoice10);
+        // NES 2.0: RP2C04-0004, and an RC2C03B on a DualSystem
+        assert_eq!(
+            console(0x09, 0x05),
+            Console::Vs {
+                ppu: VsPpu::Rp2c04(4),
+                dual: false
+            }
+        );
+        assert_eq!(
+            console(0x09, 0x56),
+            Console::Vs {
+                ppu: VsPpu::Rgb,
+                dual: true
+            }
+        );
+        assert_eq!(console(0x0B, 0x03), Console::Extended(3));
+    }
 }
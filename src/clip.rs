@@ -0,0 +1,292 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ppu::NesPPU,
+    render::{
+        self,
+        frame::{Frame, PixelFormat},
+        palette::Colors,
+    },
+};
+
+/// Shortest time a GIF frame can be shown for, in seconds. GIF delays are in hundredths, and
+/// viewers show 1 as 10.
+const MIN_GIF_DELAY: f64 = 0.02;
+
+/// What `ClipRecorder` writes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipFormat {
+    /// Streamed to the file as it's recorded, at up to 50 frames a second
+    #[default]
+    Gif,
+    /// Every frame, but kept in memory until the recording stops, as the frame count comes
+    /// first in the file
+    Apng,
+}
+
+impl ClipFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ClipFormat::Gif => "gif",
+            ClipFormat::Apng => "png",
+        }
+    }
+
+    fn min_delay(self) -> f64 {
+        match self {
+            ClipFormat::Gif => MIN_GIF_DELAY,
+            ClipFormat::Apng => 0.0,
+        }
+    }
+}
+
+/// A frame's NES color indices and how many seconds it's shown for.
+pub(crate) type ClipFrame = (Vec<u8>, f64);
+
+/// Records the picture to an animated GIF or PNG, for sharing a clip of a bug or some play.
+/// Dropping it stops the recording. Frames are encoded on another thread. A frame is held
+/// until the next one differs, so a still picture costs one frame however long it lasts, and
+/// frames that would show for less than the format allows are merged into the one before.
+pub struct ClipRecorder {
+    format: ClipFormat,
+    sender: Sender<ClipFrame>,
+    /// Pixels are kept from every `downscale`th row and column
+    downscale: usize,
+    full: Frame,
+    held: Option<ClipFrame>,
+}
+
+impl ClipRecorder {
+    /// Starts writing a clip to `path` in `colors`, `downscale` times smaller than the NES
+    /// picture. The writing thread, returned to wait for before quitting, prints how it went
+    /// once the recording stops.
+    pub fn start(
+        path: PathBuf,
+        format: ClipFormat,
+        downscale: usize,
+        colors: &Colors,
+    ) -> Result<(Self, JoinHandle<()>), String> {
+        let file = File::create(&path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        let downscale = downscale.clamp(1, 4);
+        let size = (Frame::WIDTH / downscale, Frame::HEIGHT / downscale);
+        let palette: Vec<u8> = colors.iter().flat_map(|&(r, g, b)| [r, g, b]).collect();
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::spawn(move || {
+            let result = match format {
+                ClipFormat::Gif => write_gif(file, size, &palette, receiver),
+                ClipFormat::Apng => write_apng(file, size, &palette, receiver),
+            };
+            match result {
+                Ok(frames) => println!("Saved {} frames of video to {}", frames, path.display()),
+                Err(e) => eprintln!("Failed to write {}: {}", path.display(), e),
+            }
+        });
+        Ok((ClipRecorder::new(format, downscale, sender), writer))
+    }
+
+    fn new(format: ClipFormat, downscale: usize, sender: Sender<ClipFrame>) -> Self {
+        ClipRecorder {
+            format,
+            sender,
+            downscale,
+            full: Frame::with_format(Frame::WIDTH, Frame::HEIGHT, PixelFormat::Indexed),
+            held: None,
+        }
+    }
+
+    /// Call once a frame with how long the frame lasts. Returns false once the writing
+    /// thread has given up, which it has printed why.
+    pub fn capture(&mut self, ppu: &NesPPU, seconds: f64) -> bool {
+        render::render(ppu, &mut self.full);
+        let pixels = self.scaled_pixels();
+        match self.held.as_mut() {
+            Some((held, shown)) if *held == pixels || *shown < self.format.min_delay() => {
+                *shown += seconds
+            }
+            _ => {
+                if let Some(held) = self.held.replace((pixels, seconds)) {
+                    return self.sender.send(held).is_ok();
+                }
+            }
+        }
+        true
+    }
+
+    fn scaled_pixels(&self) -> Vec<u8> {
+        let step = self.downscale;
+        self.full
+            .data
+            .chunks_exact(Frame::WIDTH)
+            .step_by(step)
+            .take(Frame::HEIGHT / step)
+            .flat_map(|row| row.iter().step_by(step).take(Frame::WIDTH / step))
+            .map(|color| color & 0x3F)
+            .collect()
+    }
+}
+
+/// Stopping the recording sends the last frame and lets the writing thread finish the file.
+impl Drop for ClipRecorder {
+    fn drop(&mut self) {
+        if let Some(held) = self.held.take() {
+            let _ = self.sender.send(held);
+        }
+    }
+}
+
+/// Turns frame lengths into whole `unit`s, carrying what's left over to the next frame so the
+/// clip doesn't drift.
+struct Delays {
+    unit: f64,
+    carried: f64,
+}
+
+impl Delays {
+    fn new(unit: f64) -> Self {
+        Delays { unit, carried: 0.0 }
+    }
+
+    fn next(&mut self, seconds: f64) -> u16 {
+        let units = ((seconds + self.carried) / self.unit).round().max(1.0);
+        self.carried += seconds - units * self.unit;
+        units.min(u16::MAX as f64) as u16
+    }
+}
+
+/// Writes `frames` to `file` as a looping GIF, returning how many there were.
+pub(crate) fn write_gif(
+    file: File,
+    (width, height): (usize, usize),
+    palette: &[u8],
+    frames: impl IntoIterator<Item = ClipFrame>,
+) -> Result<usize, String> {
+    let error = |e: gif::EncodingError| e.to_string();
+    let mut encoder = gif::Encoder::new(BufWriter::new(file), width as u16, height as u16, palette)
+        .map_err(error)?;
+    encoder.set_repeat(gif::Repeat::Infinite).map_err(error)?;
+    let mut delays = Delays::new(0.01);
+    let mut count = 0;
+    for (pixels, seconds) in frames {
+        let mut frame = gif::Frame::from_indexed_pixels(width as u16, height as u16, pixels, None);
+        frame.delay = delays.next(seconds);
+        encoder.write_frame(&frame).map_err(error)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn write_apng(
+    file: File,
+    (width, height): (usize, usize),
+    palette: &[u8],
+    frames: Receiver<ClipFrame>,
+) -> Result<usize, String> {
+    let frames: Vec<ClipFrame> = frames.into_iter().collect();
+    if frames.is_empty() {
+        return Err("no frames were recorded".to_string());
+    }
+    let error = |e: png::EncodingError| e.to_string();
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(palette.to_vec());
+    encoder
+        .set_animated(frames.len() as u32, 0)
+        .map_err(error)?;
+    let mut writer = encoder.write_header().map_err(error)?;
+    let mut delays = Delays::new(0.001);
+    for (pixels, seconds) in &frames {
+        writer
+            .set_frame_delay(delays.next(*seconds), 1000)
+            .map_err(error)?;
+        writer.write_image_data(pixels).map_err(error)?;
+    }
+    writer.finish().map_err(error)?;
+    Ok(frames.len())
+}
+
+/// `<dir>/<rom file stem>-clip-<unix time>.<extension>` for a new recording.
+pub fn clip_file(dir: &Path, rom_path: &str, format: ClipFormat) -> Result<PathBuf, String> {
+    crate::paths::timestamped_file(dir, rom_path, "clip", format.extension())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cartridge::Mirroring, render::palette::SYSTEM_PALLETE};
+
+    #[test]
+    fn test_records_changes_at_the_format_rate() {
+        let mut ppu = NesPPU::new(vec![0; 0x2000], Mirroring::HORIZONTAL);
+        let palette: Vec<u8> = SYSTEM_PALLETE
+            .iter()
+            .flat_map(|&(r, g, b)| [r, g, b])
+            .collect();
+        for format in [ClipFormat::Gif, ClipFormat::Apng] {
+            let (sender, receiver) = mpsc::channel();
+            let mut clip = ClipRecorder::new(format, 2, sender);
+            // Two frames the same, then a change every frame at 60 frames a second
+            for color in [1, 1, 2, 3, 4, 5] {
+                ppu.palette_table[0] = color;
+                assert!(clip.capture(&ppu, 1.0 / 60.0));
+            }
+            drop(clip);
+            let frames: Vec<ClipFrame> = receiver.iter().collect();
+            let lengths: Vec<u32> = frames
+                .iter()
+                .map(|(_, seconds)| (seconds * 60.0).round() as u32)
+                .collect();
+            let colors: Vec<u8> = frames.iter().map(|(pixels, _)| pixels[0]).collect();
+            assert_eq!(frames[0].0.len(), 128 * 120);
+            match format {
+                // GIF can't show a frame for less than 1/50 of a second
+                ClipFormat::Gif => {
+                    assert_eq!(colors, [1, 2, 4]);
+                    assert_eq!(lengths, [2, 2, 2]);
+                }
+                ClipFormat::Apng => {
+                    assert_eq!(colors, [1, 2, 3, 4, 5]);
+                    assert_eq!(lengths, [2, 1, 1, 1, 1]);
+                }
+            }
+
+            let path =
+                std::env::temp_dir().join(format!("rustnes_clip_test.{}", format.extension()));
+            let file = File::create(&path).unwrap();
+            let (sender, receiver) = mpsc::channel();
+            for frame in frames {
+                sender.send(frame).unwrap();
+            }
+            drop(sender);
+            let written = match format {
+                ClipFormat::Gif => write_gif(file, (128, 120), &palette, receiver),
+                ClipFormat::Apng => write_apng(file, (128, 120), &palette, receiver),
+            };
+            assert_eq!(written, Ok(colors.len()));
+            let bytes = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let magic: &[u8] = match format {
+                ClipFormat::Gif => b"GIF89a",
+                ClipFormat::Apng => b"\x89PNG",
+            };
+            assert_eq!(&bytes[..magic.len()], magic);
+        }
+    }
+
+    #[test]
+    fn test_delays_carry_fractions() {
+        let mut delays = Delays::new(0.01);
+        let total: u32 = (0..60).map(|_| delays.next(1.0 / 30.0) as u32).sum();
+        assert_eq!(total, 200);
+    }
+}
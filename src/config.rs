@@ -0,0 +1,539 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    apu::{AudioFilter, Panning},
+    clip::ClipFormat,
+    region::Region,
+    render::{crt::CrtPreset, palette::PaletteName},
+    video_recorder::Container,
+};
+
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+pub const RECENT_FILE_NAME: &str = "recent.toml";
+/// Output sample rates to pick from
+pub const SAMPLE_RATES: [u32; 3] = [44_100, 48_000, 96_000];
+/// How many ROMs `RecentRoms` remembers
+const RECENT_ROMS: usize = 10;
+
+/// User settings, read from `config.toml` in the config directory. Every field has a default,
+/// so a missing file or a file with only a few keys is fine.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub paths: PathsConfig,
+    pub video: VideoConfig,
+    pub audio: AudioConfig,
+    pub emulation: EmulationConfig,
+    pub states: StatesConfig,
+    pub recording: RecordingConfig,
+    pub input: InputConfig,
+    pub status: StatusConfig,
+    pub macros: Vec<MacroConfig>,
+}
+
+/// Overrides for where the emulator writes its files. Relative paths are relative to the
+/// working directory.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PathsConfig {
+    /// Base directory for saves, states and screenshots
+    pub data_dir: Option<PathBuf>,
+    pub saves: Option<PathBuf>,
+    pub states: Option<PathBuf>,
+    pub screenshots: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct VideoConfig {
+    /// Only draw 8 sprites per scanline like the real hardware. Off removes sprite flicker.
+    pub sprite_limit: bool,
+    /// Window size as a multiple of the NES's 256x240
+    pub scale: f32,
+    pub fullscreen: bool,
+    /// Show frames on the display's vertical blank, for smooth scrolling without tearing
+    pub vsync: bool,
+    /// How the picture is smoothed when it is scaled up
+    pub filter: ScaleFilter,
+    /// How the picture is fitted to the window
+    pub scale_mode: ScaleMode,
+    pub palette: PaletteName,
+    /// A .pal file of 64 RGB colors, or 512 with emphasis, for `palette = "file"`
+    pub palette_file: Option<PathBuf>,
+    /// CRT look: off, scanlines, grille (scanlines and an aperture grille) or curved (both on
+    /// a bulging screen)
+    pub crt: CrtPreset,
+    /// Seconds of the picture kept for Shift+F10 to save as a GIF, 0 for none. Each second
+    /// takes about 1.2MB.
+    pub replay_seconds: u32,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig {
+            sprite_limit: true,
+            scale: 3.0,
+            fullscreen: false,
+            vsync: true,
+            filter: ScaleFilter::Nearest,
+            scale_mode: ScaleMode::Fit,
+            palette: PaletteName::Default,
+            palette_file: None,
+            crt: CrtPreset::Off,
+            replay_seconds: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ScaleFilter {
+    /// Sharp square pixels
+    #[default]
+    Nearest,
+    /// Bilinear, softer but with even pixel sizes at any scale
+    Linear,
+}
+
+impl ScaleFilter {
+    /// The value for SDL's `SDL_RENDER_SCALE_QUALITY` hint.
+    pub fn sdl_hint(self) -> &'static str {
+        match self {
+            ScaleFilter::Nearest => "nearest",
+            ScaleFilter::Linear => "linear",
+        }
+    }
+}
+
+impl FromStr for ScaleFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nearest" => Ok(ScaleFilter::Nearest),
+            "linear" => Ok(ScaleFilter::Linear),
+            _ => Err(format!("Unknown filter: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ScaleMode {
+    /// As big as fits, with square pixels
+    #[default]
+    Fit,
+    /// Only whole multiples of 256x240, so every pixel is the same size
+    Integer,
+    /// As big as fits, with pixels 8:7 as wide as tall like on a TV
+    Aspect,
+}
+
+impl ScaleMode {
+    pub const ALL: [ScaleMode; 3] = [ScaleMode::Fit, ScaleMode::Integer, ScaleMode::Aspect];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ScaleMode::Fit => "fit",
+            ScaleMode::Integer => "integer",
+            ScaleMode::Aspect => "aspect",
+        }
+    }
+
+    pub fn next(self) -> ScaleMode {
+        let index = ScaleMode::ALL.iter().position(|&m| m == self).unwrap_or(0);
+        ScaleMode::ALL[(index + 1) % ScaleMode::ALL.len()]
+    }
+
+    /// How wide the 240 lines high picture is before scaling: 256 pixels, or 293 when they're
+    /// 8:7.
+    pub fn width(self) -> u32 {
+        match self {
+            ScaleMode::Aspect => (256 * 8 + 3) / 7,
+            _ => 256,
+        }
+    }
+}
+
+impl FromStr for ScaleMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        ScaleMode::ALL
+            .into_iter()
+            .find(|mode| mode.name() == name)
+            .ok_or_else(|| format!("Unknown scale mode: {}", s))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct AudioConfig {
+    /// One of `SAMPLE_RATES`, asked of the audio device
+    pub sample_rate: u32,
+    /// Frames the audio device plays at a time, from 64 to 8192. Three of these are kept
+    /// queued, so smaller is less lag and bigger survives a busy machine better.
+    pub buffer_size: u16,
+    /// High-pass cutoffs in Hz. The console's are 90 and 440 Hz, which also take out the
+    /// mixer's DC offset.
+    pub high_pass: Vec<f32>,
+    /// Low-pass cutoff in Hz, 14 kHz on the console. 0 turns it off.
+    pub low_pass: f32,
+    /// How loud sound chips on the cartridge are against the console's, 1 for the balance
+    /// they had on the real thing
+    pub expansion_volume: f32,
+    /// Play in stereo with the channels placed by `pan`, instead of mono like the console
+    pub stereo: bool,
+    pub pan: Panning,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            sample_rate: 48_000,
+            buffer_size: 1024,
+            high_pass: vec![90.0, 440.0],
+            low_pass: 14_000.0,
+            expansion_volume: 1.0,
+            stereo: false,
+            pan: Panning::default(),
+        }
+    }
+}
+
+impl AudioConfig {
+    /// The filters to run the output through, in order.
+    pub fn filters(&self) -> Vec<AudioFilter> {
+        let high_passes = self.high_pass.iter().map(|&hz| AudioFilter::HighPass(hz));
+        let low_pass = (self.low_pass > 0.0).then_some(AudioFilter::LowPass(self.low_pass));
+        high_passes.chain(low_pass).collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct EmulationConfig {
+    /// Emulation speed in percent of the real console, from 25 to 400
+    pub speed: u32,
+    /// Set the sprite overflow flag with the real PPU's buggy OAM scan. Off sets it whenever a
+    /// scanline has more than 8 sprites, which is what the flag was meant to do.
+    pub sprite_overflow_bug: bool,
+    /// Frames left undrawn after each one drawn while fast-forwarding, which leaves more time
+    /// for emulating
+    pub fast_forward_skip: u32,
+    /// ntsc, pal or dendy for every game, instead of going by the header, the overrides and
+    /// the file name
+    pub region: Option<Region>,
+    /// Scanlines of extra CPU time added to each frame, which cuts slowdown in games that
+    /// have too much to do. 0 runs at the console's speed.
+    pub overclock_lines: u16,
+    /// Pause, which mutes the sound, while the window is in the background, and carry on
+    /// when it comes back
+    pub pause_in_background: bool,
+}
+
+impl Default for EmulationConfig {
+    fn default() -> Self {
+        EmulationConfig {
+            speed: 100,
+            sprite_overflow_bug: true,
+            fast_forward_skip: 3,
+            region: None,
+            overclock_lines: 0,
+            pause_in_background: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct StatesConfig {
+    /// How many older versions of each slot to keep when it's overwritten
+    pub history: usize,
+    /// Save the game's state on exit and offer to pick up from there next time
+    pub auto_save: bool,
+}
+
+impl Default for StatesConfig {
+    fn default() -> Self {
+        StatesConfig {
+            history: 3,
+            auto_save: false,
+        }
+    }
+}
+
+/// Clips recorded with Ctrl+F10, and videos with Ctrl+Shift+F10.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RecordingConfig {
+    /// gif, or apng for every frame and exact timing
+    pub format: ClipFormat,
+    /// 1 for the full 256x240 picture, 2 for half as wide and high, up to 4
+    pub downscale: usize,
+    /// What ffmpeg encodes videos into: mp4 or mkv
+    pub container: Container,
+    /// The ffmpeg program, by path or found on PATH. Empty leaves videos as the Y4M and WAV
+    /// files they're recorded to.
+    pub ffmpeg: PathBuf,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        RecordingConfig {
+            format: ClipFormat::Gif,
+            downscale: 1,
+            container: Container::Mp4,
+            ffmpeg: PathBuf::from("ffmpeg"),
+        }
+    }
+}
+
+/// Keyboard keys to change from the defaults, by button name and SDL key name, e.g.
+/// `a = "J"` or `start = "Keypad Enter"`. An empty key name unbinds the button.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct InputConfig {
+    /// Never let the game see Up+Down or Left+Right, which a real D-pad can't press. The
+    /// direction pressed last wins.
+    pub block_opposing: bool,
+    pub player1: BTreeMap<String, String>,
+    pub player2: BTreeMap<String, String>,
+    /// Keys for the Power Pad's buttons, by button number, e.g. `1 = "Q"`
+    pub power_pad: BTreeMap<String, String>,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        InputConfig {
+            block_opposing: true,
+            player1: BTreeMap::new(),
+            player2: BTreeMap::new(),
+            power_pad: BTreeMap::new(),
+        }
+    }
+}
+
+/// Where to publish what's being played, for stream overlays and chat apps. Both are off
+/// unless set.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct StatusConfig {
+    /// Address to serve the status as JSON on, e.g. "127.0.0.1:7878"
+    pub http: Option<String>,
+    /// Application ID from the Discord developer portal, to show the game as Rich Presence
+    pub discord_client_id: Option<String>,
+}
+
+/// ROMs opened lately, newest first, offered when the emulator starts without one. Kept up
+/// to date by the emulator in `recent.toml` in the data directory, away from `config.toml` so
+/// opening a ROM doesn't rewrite the user's config.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RecentRoms {
+    pub roms: Vec<String>,
+}
+
+impl RecentRoms {
+    /// Loads the list at `path`, empty if it doesn't exist yet.
+    pub fn load(path: &Path) -> Result<RecentRoms, String> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text)
+                .map_err(|e| format!("Invalid recent ROM list {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(RecentRoms::default()),
+            Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let text = toml::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, text).map_err(|e| e.to_string())
+    }
+
+    /// Puts `rom` at the top, moving it up if it's already there and dropping the oldest
+    /// past `RECENT_ROMS`.
+    pub fn add(&mut self, rom: &str) {
+        self.roms.retain(|recent| recent != rom);
+        self.roms.insert(0, rom.to_string());
+        self.roms.truncate(RECENT_ROMS);
+    }
+}
+
+/// A recorded input sequence replayed by pressing `key` (an SDL key name). `inputs` are
+/// input script lines, e.g. `["3: RIGHT", "A RIGHT"]`.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct MacroConfig {
+    pub key: String,
+    pub inputs: Vec<String>,
+}
+
+impl Config {
+    /// Loads the config at `path`, falling back to the defaults if it doesn't exist.
+    pub fn load(path: &Path) -> Result<Config, String> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text)
+                .map_err(|e| format!("Invalid config {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("Failed to read config {}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let text = toml::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, text).map_err(|e| e.to_string())
+    }
+}
+
+/// Notices when the config file is edited, so settings can be re-applied while a game runs.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+}
+
+impl ConfigWatcher {
+    const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(path: PathBuf) -> Self {
+        let modified = ConfigWatcher::modified(&path);
+        ConfigWatcher {
+            path,
+            modified,
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Takes the file as it is now as seen, after the emulator wrote it itself.
+    pub fn forget_changes(&mut self) {
+        self.modified = ConfigWatcher::modified(&self.path);
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    /// The new config if the file changed since the last call. Cheap enough to call every
+    /// frame: the file is only looked at once a second.
+    pub fn poll(&mut self) -> Option<Result<Config, String>> {
+        if self.last_check.elapsed() < ConfigWatcher::CHECK_INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+        self.check()
+    }
+
+    fn check(&mut self) -> Option<Result<Config, String>> {
+        let modified = ConfigWatcher::modified(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        Some(Config::load(&self.path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_partial_config_uses_defaults() {
+        let config = Config::parse("[paths]\nsaves = \"/tmp/saves\"\n").unwrap();
+        assert_eq!(config.paths.saves, Some(PathBuf::from("/tmp/saves")));
+        assert_eq!(config.paths.states, None);
+        assert!(config.video.sprite_limit);
+        assert_eq!(config.states.history, 3);
+        assert_eq!(config.recording.format, ClipFormat::Gif);
+        assert_eq!(config.emulation.speed, 100);
+        assert_eq!(config.emulation.region, None);
+        assert_eq!(config.emulation.overclock_lines, 0);
+        assert!(config.emulation.sprite_overflow_bug);
+        assert!(config.input.block_opposing);
+        assert!(config.input.player2.is_empty());
+        assert_eq!(config.status, StatusConfig::default());
+        assert!(config.macros.is_empty());
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+
+        let config =
+            Config::parse("[video]\npalette = \"grayscale\"\nfilter = \"linear\"\n").unwrap();
+        assert_eq!(config.video.palette, PaletteName::Grayscale);
+        assert_eq!(config.video.filter, ScaleFilter::Linear);
+        assert_eq!(config.video.scale, 3.0);
+        assert_eq!(config.video.scale_mode, ScaleMode::Fit);
+        let config = Config::parse("[video]\nscale_mode = \"aspect\"\n").unwrap();
+        assert_eq!(config.video.scale_mode, ScaleMode::Aspect);
+        assert_eq!(config.video.scale_mode.width(), 293);
+        assert_eq!("Integer".parse(), Ok(ScaleMode::Integer));
+        assert_eq!(config.audio.filters(), AudioFilter::NES);
+
+        let config = Config::parse("[audio]\nhigh_pass = []\nlow_pass = 0\n").unwrap();
+        assert!(config.audio.filters().is_empty());
+        assert_eq!(config.audio.sample_rate, 48_000);
+
+        let config = Config::parse("[audio.pan]\ntriangle = 1\n").unwrap();
+        assert_eq!(config.audio.pan.triangle, 1.0);
+        assert_eq!(config.audio.pan.pulse1, -0.5);
+
+        let mut config = Config::default();
+        config.macros.push(MacroConfig {
+            key: "F12".to_string(),
+            inputs: vec!["3: RIGHT".to_string(), "A RIGHT".to_string()],
+        });
+        let text = toml::to_string_pretty(&config).unwrap();
+        assert_eq!(Config::parse(&text).unwrap(), config);
+    }
+
+    #[test]
+    fn test_recent_roms_newest_first() {
+        let mut recent = RecentRoms::default();
+        for n in 0..12 {
+            recent.add(&format!("{}.nes", n));
+        }
+        recent.add("5.nes");
+        assert_eq!(recent.roms.len(), RECENT_ROMS);
+        assert_eq!(recent.roms[..3], ["5.nes", "11.nes", "10.nes"]);
+        assert_eq!(recent.roms.last().unwrap(), "2.nes");
+
+        let file = std::env::temp_dir().join("rustnes_recent_test.toml");
+        recent.save(&file).unwrap();
+        assert_eq!(RecentRoms::load(&file).unwrap(), recent);
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(RecentRoms::load(&file).unwrap(), RecentRoms::default());
+    }
+
+    #[test]
+    fn test_watcher_sees_edits() {
+        let path = std::env::temp_dir().join("rustnes_config_watch_test.toml");
+        std::fs::write(&path, "").unwrap();
+        let mut watcher = ConfigWatcher::new(path.clone());
+        assert_eq!(watcher.check(), None);
+
+        std::fs::write(&path, "[video]\nsprite_limit = false\n").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+        let config = watcher.check().unwrap().unwrap();
+        assert!(!config.video.sprite_limit);
+        assert_eq!(watcher.check(), None);
+    }
+}
@@ -2,12 +2,17 @@ use std::{collections::HashMap, fmt::Display};
 
 use nes_macro::{match_all, opcode};
 
-use crate::{bus::Bus, opcodes};
+use crate::{
+    bus::Bus,
+    opcodes,
+    save_state::{StateReader, StateWriter},
+};
 
 const STACK: u16 = 0x0100;
 const STACK_START: u8 = 0xFD;
 
-const PROGRAM_START: u16 = 0x0600;
+/// Where the tutorial-style toy programs are loaded, see `simple`
+pub const PROGRAM_START: u16 = 0x0600;
 // const PROGRAM_START: u16 = 0x8000;
 
 bitflags! {
@@ -98,8 +103,10 @@ fn page_crossed(addr1: u16, addr2: u16) -> bool {
 
 mod interrupt {
     #[derive(PartialEq, Eq)]
+    #[allow(clippy::upper_case_acronyms)]
     pub enum InterruptType {
         NMI,
+        IRQ,
     }
 
     #[derive(PartialEq, Eq)]
@@ -116,6 +123,13 @@ mod interrupt {
         b_flag_mask: 0b0010_0000,
         cpu_cycles: 2,
     };
+
+    pub(super) const IRQ: Interrupt = Interrupt {
+        itype: InterruptType::IRQ,
+        vector_addr: 0xFFFE,
+        b_flag_mask: 0b0010_0000,
+        cpu_cycles: 7,
+    };
 }
 
 pub struct CPU<'a> {
@@ -173,6 +187,48 @@ impl<'a> CPU<'a> {
         self.program_counter = self.u16_mem_read(0xFFFC);
     }
 
+    /// Presses the console's reset button. RAM and the registers are left as they are, except
+    /// that the stack pointer moves down three as if for an interrupt, IRQs are masked and the
+    /// APU's channels are silenced and the PPU's registers cleared, then the program starts again
+    /// from the reset vector.
+    pub fn soft_reset(&mut self) {
+        self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+        self.status.insert(StatusFlags::INTERRUPT_DISABLE);
+        self.bus.soft_reset();
+        self.program_counter = self.u16_mem_read(0xFFFC);
+    }
+
+    /// Turns the console off and on again, then starts the program from the reset vector.
+    pub fn power_cycle(&mut self) {
+        self.bus.power_cycle();
+        self.reset();
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.section(b"CPU ", 1, |writer| {
+            writer.u8(self.register_a);
+            writer.u8(self.register_x);
+            writer.u8(self.register_y);
+            writer.u8(self.status.bits());
+            writer.u8(self.stack_pointer);
+            writer.u16(self.program_counter);
+        });
+        self.bus.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.section(b"CPU ", |reader| {
+            self.register_a = reader.u8()?;
+            self.register_x = reader.u8()?;
+            self.register_y = reader.u8()?;
+            self.status = StatusFlags::from_bits_retain(reader.u8()?);
+            self.stack_pointer = reader.u8()?;
+            self.program_counter = reader.u16()?;
+            Ok(())
+        })?;
+        self.bus.load_state(reader)
+    }
+
     pub fn load(&mut self, program: Vec<u8>) {
         for (i, byte) in program.iter().enumerate() {
             self.mem_write(PROGRAM_START + i as u16, *byte);
@@ -980,35 +1036,64 @@ impl<'a> CPU<'a> {
     }
 
     pub fn run_with_callback<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&mut CPU),
+    {
+        while self.step_with_callback(&mut callback) {}
+    }
+
+    /// Jumps to the NMI handler if the PPU has raised an NMI, as `step` does before every
+    /// instruction.
+    pub fn service_nmi(&mut self) {
+        if let Some(_nmi) = self.bus.poll_nmi_status() {
+            self.interrupt(interrupt::NMI);
+        }
+    }
+
+    /// Jumps to the IRQ handler if something is holding the IRQ line and interrupts aren't
+    /// disabled.
+    fn service_irq(&mut self) {
+        if self.bus.irq_pending() && !self.status.contains(StatusFlags::INTERRUPT_DISABLE) {
+            self.interrupt(interrupt::IRQ);
+        }
+    }
+
+    /// Executes a single instruction, servicing a pending NMI or IRQ first.
+    /// Returns `false` once a BRK has been executed.
+    pub fn step(&mut self) -> bool {
+        self.step_with_callback(&mut |_| {})
+    }
+
+    /// Like `step`, but calls `callback` right before the instruction is fetched, after any
+    /// pending interrupt has been serviced.
+    pub fn step_with_callback<F>(&mut self, callback: &mut F) -> bool
     where
         F: FnMut(&mut CPU),
     {
         let ref opcode_map: HashMap<u8, &opcodes::OpCode> = *opcodes::CPU_OPS_CODES_MAP;
-        loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt(interrupt::NMI);
-            }
+        self.service_nmi();
+        self.service_irq();
 
-            callback(self);
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let original_pc = self.program_counter;
+        callback(self);
+        let code = self.mem_read(self.program_counter);
+        self.program_counter += 1;
+        let original_pc = self.program_counter;
 
-            let opcode = opcode_map
-                .get(&code)
-                .expect(&format!("opcode not found: {}", code));
+        let opcode = opcode_map
+            .get(&code)
+            .expect(&format!("opcode not found: {}", code));
 
-            match_all!(code);
+        match_all!(code);
 
-            if self.status.contains(StatusFlags::BREAK) {
-                break;
-            }
+        if self.status.contains(StatusFlags::BREAK) {
+            return false;
+        }
 
-            self.bus.tick(opcode.cycles);
+        self.bus.tick(opcode.cycles);
 
-            if original_pc == self.program_counter {
-                self.program_counter += opcode.bytes as u16 - 1;
-            }
+        if original_pc == self.program_counter {
+            self.program_counter += opcode.bytes as u16 - 1;
         }
+        true
     }
 }
@@ -0,0 +1,177 @@
+use std::{collections::VecDeque, fmt::Write};
+
+use crate::{
+    cpu::CPU,
+    error::{CpuSnapshot, EmulationError},
+    opcodes::CPU_OPS_CODES_MAP,
+    save_state,
+};
+
+// Instructions kept for the dump, the most recent last
+const TRACE_LENGTH: usize = 256;
+const HEX_BYTES_PER_LINE: usize = 16;
+const STATE_SECTION: &str = "[state]";
+
+#[derive(Clone, Copy)]
+struct TraceEntry {
+    program_counter: u16,
+    // The opcode and the two bytes after it; how many belong to the instruction is looked up
+    // when the dump is written
+    bytes: [u8; 3],
+    register_a: u8,
+    register_x: u8,
+    register_y: u8,
+    status: u8,
+    stack_pointer: u8,
+}
+
+/// The last few hundred instructions executed, cheap enough to record all the time.
+#[derive(Default)]
+pub struct TraceLog {
+    entries: VecDeque<TraceEntry>,
+}
+
+impl TraceLog {
+    pub fn new() -> Self {
+        TraceLog::default()
+    }
+
+    /// Records the instruction `cpu` is about to execute.
+    pub fn record(&mut self, cpu: &CPU) {
+        if self.entries.len() == TRACE_LENGTH {
+            self.entries.pop_front();
+        }
+        let pc = cpu.program_counter;
+        self.entries.push_back(TraceEntry {
+            program_counter: pc,
+            bytes: [0, 1, 2].map(|i| cpu.bus.peek(pc.wrapping_add(i))),
+            register_a: cpu.register_a,
+            register_x: cpu.register_x,
+            register_y: cpu.register_y,
+            status: cpu.status.bits(),
+            stack_pointer: cpu.stack_pointer,
+        });
+    }
+
+    /// One line per instruction, oldest first, in a nestest-like layout.
+    pub fn lines(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|entry| {
+                let (name, len) = CPU_OPS_CODES_MAP
+                    .get(&entry.bytes[0])
+                    .map_or(("???", 1), |op| (op.name, op.bytes as usize));
+                let hex: Vec<String> = entry.bytes[..len]
+                    .iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect();
+                format!(
+                    "{:04X}  {:<8} {:<4}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+                    entry.program_counter,
+                    hex.join(" "),
+                    name,
+                    entry.register_a,
+                    entry.register_x,
+                    entry.register_y,
+                    entry.status,
+                    entry.stack_pointer
+                )
+            })
+            .collect()
+    }
+}
+
+fn hex_dump(out: &mut String, title: &str, bytes: &[u8]) {
+    writeln!(out, "\n[{}]", title).unwrap();
+    for (i, line) in bytes.chunks(HEX_BYTES_PER_LINE).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02X}", b)).collect();
+        writeln!(out, "{:04X}: {}", i * HEX_BYTES_PER_LINE, hex.join(" ")).unwrap();
+    }
+}
+
+/// A text report of everything needed to look into a crash: the error, registers, recent
+/// instructions, RAM, VRAM, OAM and palettes, and at the end a save state of the machine as
+/// hex that `state_from_dump` reads back.
+pub fn dump(
+    error: &EmulationError,
+    cpu: &CPU,
+    rom_path: &str,
+    rom_crc: u32,
+    trace: &TraceLog,
+) -> String {
+    let mut out = String::new();
+    writeln!(out, "RustNES {} crash dump", env!("CARGO_PKG_VERSION")).unwrap();
+    writeln!(out, "ROM: {} (CRC32 {:08X})", rom_path, rom_crc).unwrap();
+    writeln!(out, "{}", error).unwrap();
+    // Crashes carry the registers already
+    if !matches!(error, EmulationError::Crash { .. }) {
+        writeln!(out, "{}", CpuSnapshot::of(cpu)).unwrap();
+    }
+
+    writeln!(out, "\n[trace]").unwrap();
+    for line in trace.lines() {
+        writeln!(out, "{}", line).unwrap();
+    }
+
+    let ram: Vec<u8> = (0..0x800).map(|addr| cpu.bus.peek(addr)).collect();
+    hex_dump(&mut out, "ram", &ram);
+    if !cpu.bus.prg_ram().is_empty() {
+        hex_dump(&mut out, "prg ram", cpu.bus.prg_ram());
+    }
+    let ppu = cpu.bus.ppu();
+    hex_dump(&mut out, "vram", &ppu.vram);
+    hex_dump(&mut out, "oam", &ppu.oam_data);
+    hex_dump(&mut out, "palette", &ppu.palette_table);
+
+    writeln!(out, "\n{}", STATE_SECTION).unwrap();
+    for line in save_state::save(cpu, rom_crc).chunks(32) {
+        let hex: String = line.iter().map(|b| format!("{:02X}", b)).collect();
+        writeln!(out, "{}", hex).unwrap();
+    }
+    out
+}
+
+/// The save state at the end of a crash dump, to load and replay from.
+pub fn state_from_dump(text: &str) -> Option<Vec<u8>> {
+    let (_, state) = text.split_once(STATE_SECTION)?;
+    let hex: String = state.split_whitespace().collect();
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bus::Bus, cartridge::test::test_rom, joypad::Joypad, ppu::NesPPU};
+
+    #[test]
+    fn test_dump_contains_trace_and_state() {
+        let bus = Bus::new(test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        let mut trace = TraceLog::new();
+        for _ in 0..TRACE_LENGTH + 10 {
+            trace.record(&cpu);
+        }
+        assert_eq!(trace.lines().len(), TRACE_LENGTH);
+
+        let error = EmulationError::Crash {
+            message: "Test crash".to_string(),
+            cpu: CpuSnapshot::of(&cpu),
+        };
+        let text = dump(&error, &cpu, "test.nes", 42, &trace);
+        assert!(text.contains("Test crash"));
+        assert!(text.contains("[trace]\n0101  00       BRK "));
+        assert!(text.contains("\n[vram]\n0000: 00 00"));
+
+        let state = state_from_dump(&text).unwrap();
+        assert_eq!(state, save_state::save(&cpu, 42));
+        assert!(save_state::load(&mut cpu, 42, &state).is_ok());
+        assert_eq!(state_from_dump("no state here"), None);
+    }
+}
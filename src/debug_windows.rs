@@ -0,0 +1,229 @@
+use sdl2::{
+    event::{Event, WindowEvent},
+    keyboard::Keycode,
+    pixels::PixelFormatEnum,
+    render::{Texture, WindowCanvas},
+    VideoSubsystem,
+};
+
+use crate::{
+    ppu::NesPPU,
+    render::{self, frame::Frame, palette::Colors, OAM_CELL},
+};
+
+/// A live view of the PPU's memory that can be opened in its own window while playing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugView {
+    PatternTables,
+    Nametables,
+    Oam,
+}
+
+impl DebugView {
+    pub fn title(self) -> &'static str {
+        match self {
+            DebugView::PatternTables => "CHR",
+            DebugView::Nametables => "Nametables",
+            DebugView::Oam => "OAM",
+        }
+    }
+
+    /// Size of the view in NES pixels.
+    pub fn size(self) -> (usize, usize) {
+        match self {
+            DebugView::PatternTables => (256, 128),
+            DebugView::Nametables => (2 * Frame::WIDTH, 2 * Frame::HEIGHT),
+            DebugView::Oam => (8 * OAM_CELL, 8 * OAM_CELL),
+        }
+    }
+
+    /// How much the window scales the view up when it opens.
+    fn scale(self) -> u32 {
+        match self {
+            DebugView::Nametables => 1,
+            _ => 3,
+        }
+    }
+
+    /// Game frames between redraws. Sprites move every frame; tiles rarely change, and
+    /// drawing all four nametables takes a while.
+    fn refresh_frames(self) -> usize {
+        match self {
+            DebugView::PatternTables => 10,
+            DebugView::Nametables => 4,
+            DebugView::Oam => 1,
+        }
+    }
+
+    pub fn render(self, ppu: &NesPPU, colors: &Colors) -> Frame {
+        match self {
+            DebugView::PatternTables => render::pattern_tables(ppu, colors),
+            DebugView::Nametables => render::nametable_map(ppu, colors),
+            DebugView::Oam => render::oam_sheet(ppu, colors),
+        }
+    }
+}
+
+struct DebugWindow {
+    view: DebugView,
+    /// Made once, the size of the view. Declared before `canvas` so it's destroyed while the
+    /// renderer it belongs to is still there.
+    texture: Texture<'static>,
+    canvas: WindowCanvas,
+    /// Game frames until the next redraw
+    countdown: usize,
+}
+
+impl DebugWindow {
+    fn open(video: &VideoSubsystem, view: DebugView) -> Result<Self, String> {
+        let (width, height) = view.size();
+        let window = video
+            .window(
+                view.title(),
+                width as u32 * view.scale(),
+                height as u32 * view.scale(),
+            )
+            .resizable()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        canvas
+            .set_logical_size(width as u32, height as u32)
+            .map_err(|e| e.to_string())?;
+        let creator = canvas.texture_creator();
+        let texture = creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, width as u32, height as u32)
+            .map_err(|e| e.to_string())?;
+        // SAFETY: the lifetime only stands for the renderer staying alive, and `canvas` keeps
+        // it alive too. It goes in the same struct, dropped after the texture by field order.
+        let texture = unsafe { std::mem::transmute::<Texture<'_>, Texture<'static>>(texture) };
+        Ok(DebugWindow {
+            view,
+            texture,
+            canvas,
+            countdown: 0,
+        })
+    }
+
+    fn id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    fn draw(&mut self, frame: &Frame) -> Result<(), String> {
+        self.texture
+            .update(None, &frame.data, frame.pitch())
+            .map_err(|e| e.to_string())?;
+        self.canvas.copy(&self.texture, None, None)?;
+        self.canvas.present();
+        Ok(())
+    }
+}
+
+/// The debug windows open next to the game. Each is redrawn from the PPU on its own schedule
+/// and closes by itself, without stopping the game.
+pub struct DebugWindows {
+    video: VideoSubsystem,
+    windows: Vec<DebugWindow>,
+}
+
+impl DebugWindows {
+    pub fn new(video: VideoSubsystem) -> Self {
+        DebugWindows {
+            video,
+            windows: Vec::new(),
+        }
+    }
+
+    pub fn is_open(&self, view: DebugView) -> bool {
+        self.windows.iter().any(|window| window.view == view)
+    }
+
+    /// Opens `view` in a new window, or closes it if it's already open.
+    pub fn toggle(&mut self, view: DebugView) -> Result<(), String> {
+        if self.is_open(view) {
+            self.windows.retain(|window| window.view != view);
+        } else {
+            self.windows.push(DebugWindow::open(&self.video, view)?);
+        }
+        Ok(())
+    }
+
+    pub fn close_all(&mut self) {
+        self.windows.clear();
+    }
+
+    fn owns_window(&self, window_id: u32) -> bool {
+        self.windows.iter().any(|window| window.id() == window_id)
+    }
+
+    /// Whether `event` is for a debug window rather than the game: something happening to
+    /// the window itself, or Esc pressed while it has focus. Other keys pressed in a debug
+    /// window still go to the game, so it can be played with either window focused.
+    pub fn owns(&self, event: &Event) -> bool {
+        match event {
+            Event::Window { window_id, .. }
+            | Event::KeyDown {
+                window_id,
+                keycode: Some(Keycode::Escape),
+                ..
+            } => self.owns_window(*window_id),
+            _ => false,
+        }
+    }
+
+    /// Handles an event that `owns` claimed.
+    pub fn handle_event(&mut self, event: &Event) {
+        match event {
+            Event::Window {
+                window_id,
+                win_event: WindowEvent::Close,
+                ..
+            }
+            | Event::KeyDown { window_id, .. } => {
+                self.windows.retain(|window| window.id() != *window_id)
+            }
+            // Uncovered or resized windows are redrawn right away instead of when next due
+            Event::Window { window_id, .. } => {
+                for window in &mut self.windows {
+                    if window.id() == *window_id {
+                        window.countdown = 0;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Redraws the windows that are due. Called once per game frame.
+    pub fn refresh(&mut self, ppu: &NesPPU, colors: &Colors) {
+        for window in &mut self.windows {
+            if window.countdown > 0 {
+                window.countdown -= 1;
+                continue;
+            }
+            window.countdown = window.view.refresh_frames() - 1;
+            if let Err(e) = window.draw(&window.view.render(ppu, colors)) {
+                eprintln!("Failed to draw the {} window: {}", window.view.title(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cartridge::Mirroring, render::palette::SYSTEM_PALLETE};
+
+    #[test]
+    fn test_views_match_their_size() {
+        let ppu = NesPPU::new(vec![0; 0x2000], Mirroring::HORIZONTAL);
+        for view in [
+            DebugView::PatternTables,
+            DebugView::Nametables,
+            DebugView::Oam,
+        ] {
+            let frame = view.render(&ppu, &SYSTEM_PALLETE);
+            assert_eq!((frame.width, frame.height), view.size(), "{:?}", view);
+        }
+    }
+}
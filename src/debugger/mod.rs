@@ -0,0 +1,590 @@
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fs::File,
+    io::{BufRead, BufWriter, Write},
+    path::PathBuf,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+    time::Duration,
+};
+
+use crate::{cpu::CPU, joypad::Joypad, save_state, trace::trace};
+
+use self::watch::Watch;
+
+pub mod watch;
+
+const HELP: &str = "\
+commands:
+  break <addr>        stop before executing <addr>
+  delete <addr>       remove a breakpoint
+  breaks              list breakpoints
+  step [n]            execute n instructions (default 1), printing each
+  back [n]            go back n instructions (default 1)
+  backframe [n]       go back to the start of the nth frame before this point (default 1)
+  continue            resume until the next breakpoint
+  pause               stop at the next instruction
+  reg                 show CPU registers
+  mem <addr> [len]    hex dump len bytes (default 16)
+  reset               press the reset button, keeping RAM
+  power               turn the console off and on again
+  watch <expr>        re-evaluate <expr> every frame, e.g. watch w[$10] + x * 2
+  unwatch <n>         remove watch number n
+  watches             show watches and their values
+  log [file|off]      log watch values to stdout or a file on frames where they change
+addresses are hex with an optional $ or 0x prefix, counts are decimal
+going back works within the last 600 frames";
+
+/// Frames of snapshots kept for going back, 10 seconds at 60 FPS
+const HISTORY_FRAMES: usize = 600;
+// Snapshots never leave the debugger, so they don't need the real ROM's CRC
+const SNAPSHOT_CRC: u32 = 0;
+/// How often the idle callback runs while waiting for a command
+const IDLE_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Break(u16),
+    Delete(u16),
+    Breaks,
+    Step(usize),
+    Back(usize),
+    BackFrame(usize),
+    Continue,
+    Pause,
+    Reg,
+    Mem(u16, usize),
+    Reset,
+    Power,
+    Watch(String),
+    Unwatch(usize),
+    Watches,
+    Log(LogTarget),
+    Help,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    Off,
+    Stdout,
+    File(PathBuf),
+}
+
+fn parse_address(arg: Option<&str>) -> Result<u16, String> {
+    let arg = arg.ok_or("Missing address")?;
+    let hex = arg
+        .strip_prefix('$')
+        .or_else(|| arg.strip_prefix("0x"))
+        .unwrap_or(arg);
+    u16::from_str_radix(hex, 16).map_err(|_| format!("Invalid address: {}", arg))
+}
+
+fn parse_count(arg: Option<&str>, default: usize) -> Result<usize, String> {
+    match arg {
+        Some(arg) => arg.parse().map_err(|_| format!("Invalid count: {}", arg)),
+        None => Ok(default),
+    }
+}
+
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return Err("Empty command".to_string());
+    };
+    if name == "watch" || name == "w" {
+        // The expression may contain spaces
+        let expr = line.trim_start()[name.len()..].trim();
+        if expr.is_empty() {
+            return Err("Missing expression".to_string());
+        }
+        return Ok(Command::Watch(expr.to_string()));
+    }
+    let command = match name {
+        "break" | "b" => Command::Break(parse_address(words.next())?),
+        "delete" | "d" => Command::Delete(parse_address(words.next())?),
+        "breaks" => Command::Breaks,
+        "step" | "s" => Command::Step(parse_count(words.next(), 1)?),
+        "back" | "bs" => Command::Back(parse_count(words.next(), 1)?),
+        "backframe" | "bf" => Command::BackFrame(parse_count(words.next(), 1)?),
+        "continue" | "c" => Command::Continue,
+        "pause" | "p" => Command::Pause,
+        "reg" | "r" => Command::Reg,
+        "mem" | "m" => Command::Mem(parse_address(words.next())?, parse_count(words.next(), 16)?),
+        "reset" => Command::Reset,
+        "power" => Command::Power,
+        "unwatch" => Command::Unwatch(parse_count(words.next(), 0)?),
+        "watches" => Command::Watches,
+        "log" => Command::Log(match words.next() {
+            None => LogTarget::Stdout,
+            Some("off") => LogTarget::Off,
+            Some(path) => LogTarget::File(PathBuf::from(path)),
+        }),
+        "help" | "h" | "?" => Command::Help,
+        _ => return Err(format!("Unknown command: {} (try help)", name)),
+    };
+    match words.next() {
+        Some(extra) => Err(format!("Unexpected argument: {}", extra)),
+        None => Ok(command),
+    }
+}
+
+/// `len` bytes from `start` as 16-byte hex rows, read without side effects.
+pub fn dump_memory(cpu: &CPU, start: u16, len: usize) -> String {
+    let bytes: Vec<u8> = (0..len)
+        .map(|i| cpu.bus.peek(start.wrapping_add(i as u16)))
+        .collect();
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(row, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+            format!(
+                "${:04X}: {}",
+                start.wrapping_add(row as u16 * 16),
+                hex.join(" ")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn registers(cpu: &CPU) -> String {
+    format!(
+        "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{} FRAME:{}",
+        cpu.program_counter,
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status.bits(),
+        cpu.stack_pointer,
+        cpu.bus.cycles(),
+        cpu.bus.frame_count()
+    )
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum State {
+    Running,
+    Paused,
+    Stepping(usize),
+}
+
+/// The machine at the start of a frame, to go back from.
+struct Snapshot {
+    /// Instructions run before it was taken
+    instruction: u64,
+    state: Vec<u8>,
+    // Not part of save states, but a game reading the controllers mid-frame depends on them
+    joypads: [Joypad; 2],
+}
+
+/// Command-line debugger fed by lines of text, normally from stdin. Call `before_instruction`
+/// before every instruction; while paused it blocks there waiting for commands, which
+/// `before_instruction_with` breaks up to let the caller keep its window going.
+///
+/// Going back restores the last snapshot from before the target and runs forward from there.
+/// Snapshots are taken as each frame starts, so running forward never reaches the end of a
+/// frame, where the frontend would feed in new input.
+pub struct Debugger {
+    commands: Receiver<String>,
+    // Set once the command source is gone, after which the game just runs
+    closed: bool,
+    state: State,
+    breakpoints: BTreeSet<u16>,
+    watches: Vec<Watch>,
+    watch_log: Option<Box<dyn Write>>,
+    last_frame: usize,
+    /// Instructions run since the debugger started
+    instructions: u64,
+    history: VecDeque<Snapshot>,
+}
+
+impl Debugger {
+    pub fn new(commands: Receiver<String>) -> Self {
+        Debugger {
+            commands,
+            closed: false,
+            state: State::Running,
+            breakpoints: BTreeSet::new(),
+            watches: Vec::new(),
+            watch_log: None,
+            last_frame: 0,
+            instructions: 0,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Reads commands from stdin on a background thread.
+    pub fn stdin() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        println!("Debug console ready, type help for commands");
+        Debugger::new(receiver)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state == State::Paused
+    }
+
+    pub fn before_instruction(&mut self, cpu: &mut CPU) {
+        self.before_instruction_with(cpu, || true)
+    }
+
+    /// Like `before_instruction`, and calls `idle` every `IDLE_INTERVAL` while waiting for a
+    /// command. Once it returns false the debugger stops waiting for good, as if the commands
+    /// had run out.
+    pub fn before_instruction_with(&mut self, cpu: &mut CPU, mut idle: impl FnMut() -> bool) {
+        if cpu.bus.frame_count() != self.last_frame {
+            self.last_frame = cpu.bus.frame_count();
+            self.update_watches(cpu);
+            self.take_snapshot(cpu);
+        }
+
+        if self.state == State::Running && !self.closed {
+            loop {
+                match self.commands.try_recv() {
+                    Ok(line) => self.handle(&line, cpu),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.closed = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Checked before waiting for commands, so `continue` runs the instruction we
+        // stopped at instead of hitting the same breakpoint again
+        let pc = cpu.program_counter;
+        if self.state != State::Paused && self.breakpoints.contains(&pc) {
+            println!("Breakpoint at ${:04X}", pc);
+            println!("{}", trace(cpu));
+            self.state = State::Paused;
+        }
+        if self.state == State::Stepping(0) {
+            self.state = State::Paused;
+        }
+        while self.state == State::Paused && !self.closed {
+            match self.commands.recv_timeout(IDLE_INTERVAL) {
+                Ok(line) => self.handle(&line, cpu),
+                Err(RecvTimeoutError::Timeout) => self.closed = !idle(),
+                Err(RecvTimeoutError::Disconnected) => self.closed = true,
+            }
+        }
+        if let State::Stepping(left) = self.state {
+            println!("{}", trace(cpu));
+            self.state = State::Stepping(left - 1);
+        }
+        self.instructions += 1;
+    }
+
+    fn take_snapshot(&mut self, cpu: &CPU) {
+        if self.history.len() == HISTORY_FRAMES {
+            self.history.pop_front();
+        }
+        self.history.push_back(Snapshot {
+            instruction: self.instructions,
+            state: save_state::save(cpu, SNAPSHOT_CRC),
+            joypads: cpu.bus.joypads().clone(),
+        });
+    }
+
+    /// Puts the machine back to right before instruction number `target` ran.
+    fn go_back_to(&mut self, target: u64, cpu: &mut CPU) -> Result<(), String> {
+        let index = self
+            .history
+            .iter()
+            .rposition(|snapshot| snapshot.instruction <= target)
+            .ok_or_else(|| {
+                format!(
+                    "Can't go back that far, the oldest snapshot is {} instructions back",
+                    self.instructions - self.history.front().map_or(0, |s| s.instruction)
+                )
+            })?;
+        // Later snapshots are of a future that may not happen again
+        self.history.truncate(index + 1);
+        let snapshot = &self.history[index];
+        save_state::load(cpu, SNAPSHOT_CRC, &snapshot.state)?;
+        cpu.bus.set_joypads(snapshot.joypads.clone());
+        for _ in snapshot.instruction..target {
+            cpu.step();
+        }
+        // The snapshot was taken with any NMI before its instruction already taken; after
+        // running forward, one may be due before the target
+        if target > snapshot.instruction {
+            cpu.service_nmi();
+        }
+        self.instructions = target;
+        self.last_frame = cpu.bus.frame_count();
+        Ok(())
+    }
+
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    fn update_watches(&mut self, cpu: &CPU) {
+        let mut changed = false;
+        for watch in self.watches.iter_mut() {
+            changed |= watch.update(cpu);
+        }
+        if let (true, Some(log)) = (changed, &mut self.watch_log) {
+            let values: Vec<String> = self.watches.iter().map(|w| w.to_string()).collect();
+            if writeln!(log, "frame {}: {}", self.last_frame, values.join(", ")).is_err() {
+                println!("Failed to write watch log, logging stopped");
+                self.watch_log = None;
+            }
+        }
+    }
+
+    /// After a reset from the console. Snapshots from before it can't be run forward past it.
+    fn restarted(&mut self, cpu: &mut CPU) {
+        self.history.clear();
+        println!("{}", trace(cpu));
+    }
+
+    fn handle(&mut self, line: &str, cpu: &mut CPU) {
+        if line.trim().is_empty() {
+            return;
+        }
+        let command = match parse_command(line) {
+            Ok(command) => command,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        match command {
+            Command::Break(addr) => {
+                self.breakpoints.insert(addr);
+            }
+            Command::Delete(addr) => {
+                if !self.breakpoints.remove(&addr) {
+                    println!("No breakpoint at ${:04X}", addr);
+                }
+            }
+            Command::Breaks => {
+                for addr in &self.breakpoints {
+                    println!("${:04X}", addr);
+                }
+            }
+            Command::Step(0) => {}
+            Command::Step(count) => self.state = State::Stepping(count),
+            Command::Back(count) => {
+                let target = self.instructions.saturating_sub(count as u64);
+                match self.go_back_to(target, cpu) {
+                    Ok(()) => {
+                        println!("{}", trace(cpu));
+                        self.state = State::Paused;
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Command::BackFrame(count) => {
+                let start = self
+                    .history
+                    .iter()
+                    .rev()
+                    .filter(|snapshot| snapshot.instruction < self.instructions)
+                    .nth(count.saturating_sub(1))
+                    .map(|snapshot| snapshot.instruction);
+                match start.map(|start| self.go_back_to(start, cpu)) {
+                    Some(Ok(())) => {
+                        println!("{}", trace(cpu));
+                        self.state = State::Paused;
+                    }
+                    Some(Err(e)) => println!("{}", e),
+                    None => println!("Only {} frames to go back to", self.history.len()),
+                }
+            }
+            Command::Continue => self.state = State::Running,
+            Command::Pause => {
+                if self.state == State::Running {
+                    println!("{}", trace(cpu));
+                    self.state = State::Paused;
+                }
+            }
+            Command::Reg => println!("{}", registers(cpu)),
+            Command::Mem(addr, len) => println!("{}", dump_memory(cpu, addr, len)),
+            Command::Reset => {
+                cpu.soft_reset();
+                self.restarted(cpu);
+            }
+            Command::Power => {
+                cpu.power_cycle();
+                self.restarted(cpu);
+            }
+            Command::Watch(text) => match Watch::new(&text) {
+                Ok(mut watch) => {
+                    watch.update(cpu);
+                    println!("{}: {}", self.watches.len(), watch);
+                    self.watches.push(watch);
+                }
+                Err(e) => println!("{}", e),
+            },
+            Command::Unwatch(index) => {
+                if index < self.watches.len() {
+                    self.watches.remove(index);
+                } else {
+                    println!("No watch {}", index);
+                }
+            }
+            Command::Watches => {
+                for (index, watch) in self.watches.iter_mut().enumerate() {
+                    watch.update(cpu);
+                    println!("{}: {}", index, watch);
+                }
+            }
+            Command::Log(LogTarget::Off) => self.watch_log = None,
+            Command::Log(LogTarget::Stdout) => self.watch_log = Some(Box::new(std::io::stdout())),
+            Command::Log(LogTarget::File(path)) => match File::create(&path) {
+                Ok(file) => self.watch_log = Some(Box::new(BufWriter::new(file))),
+                Err(e) => println!("Failed to create {}: {}", path.display(), e),
+            },
+            Command::Help => println!("{}", HELP),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bus::Bus, cartridge::test::test_rom, cpu::Mem, joypad::Joypad, ppu::NesPPU};
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("break $C123"), Ok(Command::Break(0xC123)));
+        assert_eq!(parse_command("b 0xc123"), Ok(Command::Break(0xC123)));
+        assert_eq!(parse_command("mem $0300 32"), Ok(Command::Mem(0x0300, 32)));
+        assert_eq!(parse_command("step"), Ok(Command::Step(1)));
+        assert_eq!(parse_command("step 10"), Ok(Command::Step(10)));
+        assert_eq!(parse_command("back"), Ok(Command::Back(1)));
+        assert_eq!(parse_command("bf 2"), Ok(Command::BackFrame(2)));
+        assert_eq!(parse_command("reset"), Ok(Command::Reset));
+        assert_eq!(parse_command("power"), Ok(Command::Power));
+        assert!(parse_command("break").is_err());
+        assert!(parse_command("reg now").is_err());
+        assert!(parse_command("jump $C000").is_err());
+        assert_eq!(
+            parse_command("watch w[$10] + 1"),
+            Ok(Command::Watch("w[$10] + 1".to_string()))
+        );
+        assert_eq!(parse_command("log off"), Ok(Command::Log(LogTarget::Off)));
+    }
+
+    #[test]
+    fn test_breakpoint_pauses_before_instruction() {
+        let bus = Bus::new(test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = CPU::new(bus);
+        for (i, byte) in [0xE8, 0xE8, 0xE8, 0x00].into_iter().enumerate() {
+            cpu.mem_write(0x0600 + i as u16, byte); // INX; INX; INX; BRK
+        }
+        cpu.program_counter = 0x0600;
+
+        let (sender, receiver) = mpsc::channel();
+        sender.send("break $0602".to_string()).unwrap();
+        drop(sender);
+        let mut debugger = Debugger::new(receiver);
+
+        let mut paused_with_x = None;
+        cpu.run_with_callback(|cpu| {
+            debugger.before_instruction(cpu);
+            if debugger.is_paused() && paused_with_x.is_none() {
+                paused_with_x = Some(cpu.register_x);
+            }
+        });
+        assert_eq!(paused_with_x, Some(2));
+    }
+
+    #[test]
+    fn test_idles_while_paused() {
+        let bus = Bus::new(test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = CPU::new(bus);
+        for (i, byte) in [0xE8, 0xE8, 0xE8, 0x00].into_iter().enumerate() {
+            cpu.mem_write(0x0600 + i as u16, byte); // INX; INX; INX; BRK
+        }
+        cpu.program_counter = 0x0600;
+
+        // The sender stays, so only the idle callback can end the wait
+        let (sender, receiver) = mpsc::channel();
+        sender.send("break $0602".to_string()).unwrap();
+        let mut debugger = Debugger::new(receiver);
+        let mut idled = 0;
+        cpu.run_with_callback(|cpu| {
+            debugger.before_instruction_with(cpu, || {
+                idled += 1;
+                idled < 3
+            })
+        });
+        assert_eq!(idled, 3);
+        assert_eq!(cpu.register_x, 3);
+    }
+
+    #[test]
+    fn test_going_back() {
+        let bus = Bus::new(test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = CPU::new(bus);
+        // INX; BNE -3; INY; JMP $0600
+        for (i, byte) in [0xE8, 0xD0, 0xFD, 0xC8, 0x4C, 0x00, 0x06]
+            .into_iter()
+            .enumerate()
+        {
+            cpu.mem_write(0x0600 + i as u16, byte);
+        }
+        cpu.program_counter = 0x0600;
+
+        let (_sender, receiver) = mpsc::channel();
+        let mut debugger = Debugger::new(receiver);
+        // Registers and cycle count before each instruction
+        let mut seen = Vec::new();
+        while cpu.bus.frame_count() < 3 {
+            cpu.step_with_callback(&mut |cpu: &mut CPU| {
+                debugger.before_instruction(cpu);
+                seen.push(registers(cpu));
+            });
+        }
+        for _ in 0..100 {
+            cpu.step_with_callback(&mut |cpu: &mut CPU| {
+                debugger.before_instruction(cpu);
+                seen.push(registers(cpu));
+            });
+        }
+        // Where the next instruction starts
+        seen.push(registers(&cpu));
+
+        debugger.handle("back 3", &mut cpu);
+        assert_eq!(registers(&cpu), seen[seen.len() - 4]);
+        // Back past the start of the frame, to an earlier snapshot
+        debugger.handle("back 200", &mut cpu);
+        assert_eq!(registers(&cpu), seen[seen.len() - 204]);
+        assert!(debugger.is_paused());
+
+        debugger.handle("backframe", &mut cpu);
+        let frame_start = registers(&cpu);
+        assert!(frame_start.ends_with("FRAME:2"), "{}", frame_start);
+        assert!(seen.contains(&frame_start));
+        debugger.handle("backframe", &mut cpu);
+        assert!(registers(&cpu).ends_with("FRAME:1"));
+        assert_ne!(registers(&cpu), frame_start);
+    }
+
+    #[test]
+    fn test_dump_memory() {
+        let bus = Bus::new(test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x0300, 0xAB);
+        cpu.mem_write(0x0310, 0xCD);
+
+        let dump = dump_memory(&cpu, 0x0300, 17);
+        assert_eq!(
+            dump,
+            "$0300: AB 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n$0310: CD"
+        );
+    }
+}
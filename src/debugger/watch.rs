@@ -0,0 +1,295 @@
+use crate::cpu::CPU;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    SP,
+    PC,
+    P,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+}
+
+/// Expression over CPU state, e.g. `w[$10] + x * 2`. `[addr]` reads a byte and `w[addr]` a
+/// little-endian word; memory is read without side effects.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Expr {
+    Number(i64),
+    Register(Register),
+    Byte(Box<Expr>),
+    Word(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(char),
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '$' || c.is_ascii_digit() {
+            let mut word = String::new();
+            chars.next();
+            if c != '$' {
+                word.push(c);
+            }
+            while let Some(&c) = chars.peek() {
+                if !c.is_ascii_alphanumeric() {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            let value = if c == '$' {
+                i64::from_str_radix(&word, 16)
+            } else if let Some(hex) = word.strip_prefix("0x") {
+                i64::from_str_radix(hex, 16)
+            } else {
+                word.parse()
+            };
+            tokens.push(Token::Number(
+                value.map_err(|_| format!("Invalid number: {}{}", c, word))?,
+            ));
+        } else if c.is_ascii_alphabetic() {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if !c.is_ascii_alphanumeric() {
+                    break;
+                }
+                word.push(c.to_ascii_lowercase());
+                chars.next();
+            }
+            tokens.push(Token::Ident(word));
+        } else if "+-*/&|()[]".contains(c) {
+            tokens.push(Token::Op(c));
+            chars.next();
+        } else {
+            return Err(format!("Unexpected character: {}", c));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<char> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(*op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: char) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("Expected {}", op))
+        }
+    }
+
+    // Loosest to tightest: | then & then + - then * /
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        const LEVELS: [&[(char, BinOp)]; 4] = [
+            &[('|', BinOp::Or)],
+            &[('&', BinOp::And)],
+            &[('+', BinOp::Add), ('-', BinOp::Sub)],
+            &[('*', BinOp::Mul), ('/', BinOp::Div)],
+        ];
+        if level == LEVELS.len() {
+            return self.atom();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(&(_, op)) = LEVELS[level]
+            .iter()
+            .find(|(c, _)| Some(*c) == self.peek_op())
+        {
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or("Unexpected end of expression")?;
+        self.pos += 1;
+        match token {
+            Token::Number(value) => Ok(Expr::Number(value)),
+            Token::Op('(') => {
+                let expr = self.binary(0)?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Token::Op('[') => {
+                let addr = self.binary(0)?;
+                self.expect(']')?;
+                Ok(Expr::Byte(Box::new(addr)))
+            }
+            Token::Ident(name) if name == "w" && self.peek_op() == Some('[') => {
+                self.pos += 1;
+                let addr = self.binary(0)?;
+                self.expect(']')?;
+                Ok(Expr::Word(Box::new(addr)))
+            }
+            Token::Ident(name) => {
+                let register = match name.as_str() {
+                    "a" => Register::A,
+                    "x" => Register::X,
+                    "y" => Register::Y,
+                    "sp" => Register::SP,
+                    "pc" => Register::PC,
+                    "p" => Register::P,
+                    _ => return Err(format!("Unknown register: {}", name)),
+                };
+                Ok(Expr::Register(register))
+            }
+            Token::Op(op) => Err(format!("Unexpected {}", op)),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+        };
+        let expr = parser.binary(0)?;
+        if parser.pos != parser.tokens.len() {
+            return Err("Unexpected input after expression".to_string());
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, cpu: &CPU) -> i64 {
+        match self {
+            Expr::Number(value) => *value,
+            Expr::Register(register) => match register {
+                Register::A => cpu.register_a as i64,
+                Register::X => cpu.register_x as i64,
+                Register::Y => cpu.register_y as i64,
+                Register::SP => cpu.stack_pointer as i64,
+                Register::PC => cpu.program_counter as i64,
+                Register::P => cpu.status.bits() as i64,
+            },
+            Expr::Byte(addr) => cpu.bus.peek(addr.eval(cpu) as u16) as i64,
+            Expr::Word(addr) => {
+                let addr = addr.eval(cpu) as u16;
+                let lo = cpu.bus.peek(addr) as i64;
+                let hi = cpu.bus.peek(addr.wrapping_add(1)) as i64;
+                hi << 8 | lo
+            }
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.eval(cpu), right.eval(cpu));
+                match op {
+                    BinOp::Add => left.wrapping_add(right),
+                    BinOp::Sub => left.wrapping_sub(right),
+                    BinOp::Mul => left.wrapping_mul(right),
+                    BinOp::Div => left.checked_div(right).unwrap_or(0),
+                    BinOp::And => left & right,
+                    BinOp::Or => left | right,
+                }
+            }
+        }
+    }
+}
+
+/// An expression the debugger re-evaluates once per frame.
+pub struct Watch {
+    pub text: String,
+    pub expr: Expr,
+    pub value: Option<i64>,
+}
+
+impl Watch {
+    pub fn new(text: &str) -> Result<Watch, String> {
+        Ok(Watch {
+            text: text.trim().to_string(),
+            expr: Expr::parse(text)?,
+            value: None,
+        })
+    }
+
+    /// Re-evaluates the expression and returns whether the value changed.
+    pub fn update(&mut self, cpu: &CPU) -> bool {
+        let value = self.expr.eval(cpu);
+        let changed = self.value != Some(value);
+        self.value = Some(value);
+        changed
+    }
+}
+
+impl std::fmt::Display for Watch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.value {
+            Some(value) => write!(f, "{} = ${:X} ({})", self.text, value, value),
+            None => write!(f, "{} = ?", self.text),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bus::Bus, cartridge::test::test_rom, cpu::Mem, joypad::Joypad, ppu::NesPPU};
+
+    #[test]
+    fn test_parse_precedence() {
+        assert_eq!(
+            Expr::parse("1 + 2 * x").unwrap(),
+            Expr::Binary(
+                BinOp::Add,
+                Box::new(Expr::Number(1)),
+                Box::new(Expr::Binary(
+                    BinOp::Mul,
+                    Box::new(Expr::Number(2)),
+                    Box::new(Expr::Register(Register::X))
+                ))
+            )
+        );
+        assert!(Expr::parse("[$10").is_err());
+        assert!(Expr::parse("q").is_err());
+        assert!(Expr::parse("1 2").is_err());
+    }
+
+    #[test]
+    fn test_eval_memory_and_registers() {
+        let bus = Bus::new(test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x10, 0x34);
+        cpu.mem_write(0x11, 0x12);
+        cpu.register_x = 1;
+
+        let eval = |text: &str| Expr::parse(text).unwrap().eval(&cpu);
+        assert_eq!(eval("w[$10]"), 0x1234);
+        assert_eq!(eval("[$10 + x]"), 0x12);
+        assert_eq!(eval("([$10] & $F0) / 16"), 3);
+        assert_eq!(eval("0x10 - 17"), -1);
+    }
+}
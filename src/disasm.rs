@@ -0,0 +1,282 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
+
+use crate::{
+    cpu::AddressingMode,
+    opcodes::{OpCode, CPU_OPS_CODES_MAP},
+};
+
+const BANK_SIZE: usize = 0x4000;
+const VECTORS: u16 = 0xFFFA;
+const VECTOR_NAMES: [&str; 3] = ["NMI", "RESET", "IRQ"];
+// Most bytes per `.byte` line
+const DATA_BYTES_PER_LINE: usize = 8;
+
+// Code/data log flags, one byte per PRG ROM byte, as written by FCEUX and Mesen
+const CDL_CODE: u8 = 0x01;
+
+enum Item {
+    Instruction(&'static OpCode, Vec<u8>),
+    Data(Vec<u8>),
+    Vector(usize, u16),
+}
+
+struct Bank<'a> {
+    index: usize,
+    base: u16,
+    bytes: &'a [u8],
+}
+
+/// Where each 16KB bank of PRG ROM is mapped: the last one at $C000 where the vectors are,
+/// all others at $8000. Right for NROM, and a fair guess for most banked boards.
+fn banks(prg_rom: &[u8]) -> Vec<Bank<'_>> {
+    let count = prg_rom.len().div_ceil(BANK_SIZE);
+    prg_rom
+        .chunks(BANK_SIZE)
+        .enumerate()
+        .map(|(index, bytes)| Bank {
+            index,
+            base: if index + 1 == count { 0xC000 } else { 0x8000 },
+            bytes,
+        })
+        .collect()
+}
+
+/// Splits a bank into instructions and data. Without a CDL everything that decodes is taken
+/// for code.
+fn decode(bank: &Bank, offset: usize, cdl: Option<&[u8]>, last: bool) -> Vec<(u16, Item)> {
+    let is_code = |i: usize| cdl.is_none_or(|cdl| cdl[offset + i] & CDL_CODE != 0);
+    let vectors = if last {
+        (VECTORS - bank.base) as usize
+    } else {
+        bank.bytes.len()
+    };
+
+    let mut items: Vec<(u16, Item)> = Vec::new();
+    let mut i = 0;
+    while i < bank.bytes.len() {
+        let addr = bank.base.wrapping_add(i as u16);
+        if i >= vectors && i + 2 <= bank.bytes.len() {
+            let target = u16::from_le_bytes([bank.bytes[i], bank.bytes[i + 1]]);
+            items.push((addr, Item::Vector((i - vectors) / 2, target)));
+            i += 2;
+            continue;
+        }
+
+        let opcode = CPU_OPS_CODES_MAP
+            .get(&bank.bytes[i])
+            .filter(|op| is_code(i) && i + op.bytes as usize <= vectors);
+        if let Some(op) = opcode {
+            let len = op.bytes as usize;
+            items.push((addr, Item::Instruction(op, bank.bytes[i..i + len].to_vec())));
+            i += len;
+            continue;
+        }
+
+        match items.last_mut() {
+            Some((_, Item::Data(bytes))) if bytes.len() < DATA_BYTES_PER_LINE => {
+                bytes.push(bank.bytes[i])
+            }
+            _ => items.push((addr, Item::Data(vec![bank.bytes[i]]))),
+        }
+        i += 1;
+    }
+    items
+}
+
+/// The address an instruction jumps or branches to, if it has a fixed one.
+fn jump_target(op: &OpCode, bytes: &[u8], addr: u16) -> Option<u16> {
+    match (op.name, bytes.len()) {
+        ("JMP", 3) if op.opcode == 0x4C => Some(u16::from_le_bytes([bytes[1], bytes[2]])),
+        ("JSR", 3) => Some(u16::from_le_bytes([bytes[1], bytes[2]])),
+        (_, 2) if matches!(op.addr_mode, AddressingMode::NoneAddressing) => {
+            Some(addr.wrapping_add(2).wrapping_add(bytes[1] as i8 as u16))
+        }
+        _ => None,
+    }
+}
+
+fn operand(op: &OpCode, bytes: &[u8], addr: u16, label: &dyn Fn(u16) -> Option<String>) -> String {
+    let word = |bytes: &[u8]| u16::from_le_bytes([bytes[1], bytes[2]]);
+    let absolute = |target: u16| label(target).unwrap_or_else(|| format!("${:04X}", target));
+    match op.addr_mode {
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Immediate => format!("#${:02X}", bytes[1]),
+        AddressingMode::ZeroPage => format!("${:02X}", bytes[1]),
+        AddressingMode::ZeroPageX => format!("${:02X},X", bytes[1]),
+        AddressingMode::ZeroPageY => format!("${:02X},Y", bytes[1]),
+        AddressingMode::Absolute => absolute(word(bytes)),
+        AddressingMode::AbsoluteX => format!("{},X", absolute(word(bytes))),
+        AddressingMode::AbsoluteY => format!("{},Y", absolute(word(bytes))),
+        AddressingMode::IndirectX => format!("(${:02X},X)", bytes[1]),
+        AddressingMode::IndirectY => format!("(${:02X}),Y", bytes[1]),
+        AddressingMode::NoneAddressing => match bytes.len() {
+            1 => String::new(),
+            3 if op.opcode == 0x6C => format!("(${:04X})", word(bytes)),
+            _ => absolute(jump_target(op, bytes, addr).unwrap_or(0)),
+        },
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    hex.join(" ")
+}
+
+/// Disassembles PRG ROM into labeled assembly in ca65 syntax, one `.org` per 16KB bank.
+/// `cdl` is a code/data log from FCEUX or Mesen; bytes it doesn't mark as code are written
+/// as `.byte`. Unofficial opcodes are written without the `*` and commented.
+pub fn disassemble(prg_rom: &[u8], cdl: Option<&[u8]>) -> Result<String, String> {
+    if let Some(cdl) = cdl {
+        if cdl.len() < prg_rom.len() {
+            return Err(format!(
+                "Code/data log covers {} bytes, PRG ROM is {}",
+                cdl.len(),
+                prg_rom.len()
+            ));
+        }
+    }
+
+    let banks = banks(prg_rom);
+    let decoded: Vec<Vec<(u16, Item)>> = banks
+        .iter()
+        .map(|bank| {
+            let last = bank.index + 1 == banks.len();
+            decode(bank, bank.index * BANK_SIZE, cdl, last)
+        })
+        .collect();
+
+    // Jump targets and vectors get labels, as long as they point at the start of an
+    // instruction. Targets at $C000 and up are in the last bank, anything else is taken to
+    // be in the bank that jumps there.
+    let bank_of = |from: usize, target: u16| {
+        if target >= 0xC000 {
+            banks.len() - 1
+        } else {
+            from
+        }
+    };
+    let mut labels: HashMap<(usize, u16), String> = HashMap::new();
+    let single_bank_space = banks.len() <= 2;
+    for (index, items) in decoded.iter().enumerate() {
+        for (addr, item) in items {
+            let target = match item {
+                Item::Instruction(op, bytes) => jump_target(op, bytes, *addr),
+                Item::Vector(_, target) => Some(*target),
+                Item::Data(_) => None,
+            };
+            if let Some(target) = target.filter(|&target| target >= 0x8000) {
+                let bank = bank_of(index, target);
+                let name = if single_bank_space {
+                    format!("L_{:04X}", target)
+                } else {
+                    format!("B{}_{:04X}", bank, target)
+                };
+                labels.entry((bank, target)).or_insert(name);
+            }
+        }
+    }
+    for (_, item) in decoded.last().into_iter().flatten() {
+        if let Item::Vector(vector, target) = item {
+            labels.insert(
+                (banks.len() - 1, *target),
+                VECTOR_NAMES[*vector].to_string(),
+            );
+        }
+    }
+    let starts: Vec<HashSet<u16>> = decoded
+        .iter()
+        .map(|items| {
+            items
+                .iter()
+                .filter(|(_, item)| matches!(item, Item::Instruction(..)))
+                .map(|(addr, _)| *addr)
+                .collect()
+        })
+        .collect();
+    labels.retain(|(bank, addr), _| starts[*bank].contains(addr));
+
+    let mut out = String::new();
+    for (bank, items) in banks.iter().zip(&decoded) {
+        let label = |target: u16| labels.get(&(bank_of(bank.index, target), target)).cloned();
+        writeln!(out, "; PRG bank {}", bank.index).unwrap();
+        writeln!(out, ".org ${:04X}", bank.base).unwrap();
+        for (addr, item) in items {
+            if let Some(name) = labels.get(&(bank.index, *addr)) {
+                writeln!(out, "{}:", name).unwrap();
+            }
+            let (code, comment) = match item {
+                Item::Instruction(op, bytes) => {
+                    let name = op.name.trim_start_matches('*');
+                    let operand = operand(op, bytes, *addr, &label);
+                    let unofficial = if op.name.starts_with('*') {
+                        "  unofficial"
+                    } else {
+                        ""
+                    };
+                    (
+                        format!("{} {}", name, operand).trim_end().to_string(),
+                        format!("${:04X}: {}{}", addr, hex_bytes(bytes), unofficial),
+                    )
+                }
+                Item::Data(bytes) => {
+                    let values: Vec<String> = bytes.iter().map(|b| format!("${:02X}", b)).collect();
+                    (
+                        format!(".byte {}", values.join(", ")),
+                        format!("${:04X}", addr),
+                    )
+                }
+                Item::Vector(vector, target) => (
+                    format!(
+                        ".word {}",
+                        label(*target).unwrap_or_else(|| format!("${:04X}", target))
+                    ),
+                    format!("${:04X}: {} vector", addr, VECTOR_NAMES[*vector]),
+                ),
+            };
+            writeln!(out, "    {:<24}; {}", code, comment).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_labels_and_vectors() {
+        let mut prg = vec![0xFF; BANK_SIZE];
+        let code = [
+            0x78, // SEI
+            0xA9, 0x10, // LDA #$10
+            0xD0, 0xFB, // BNE $C000
+            0x20, 0x00, 0xC0, // JSR $C000
+            0x04, 0x44, // *NOP $44
+            0x02, // jams a real 6502, a NOP here
+        ];
+        prg[..code.len()].copy_from_slice(&code);
+        prg[0x3FFA..].copy_from_slice(&[0x00, 0xC0, 0x00, 0xC0, 0x09, 0xC0]);
+
+        let asm = disassemble(&prg, None).unwrap();
+        let lines: Vec<&str> = asm.lines().map(str::trim).collect();
+        assert!(lines.contains(&".org $C000"));
+        assert!(lines.contains(&"RESET:"));
+        assert!(asm.contains("BNE RESET"));
+        assert!(asm.contains("JSR RESET"));
+        assert!(asm.contains("NOP $44                 ; $C008: 04 44  unofficial"));
+        assert!(asm.contains("$C00A: 02  unofficial"));
+        assert!(asm.contains(".word RESET"));
+        // $C009 is the middle of the NOP, so the IRQ vector stays a plain address
+        assert!(asm.contains(".word $C009"));
+
+        let mut cdl = vec![0; BANK_SIZE];
+        cdl[0] = CDL_CODE;
+        let asm = disassemble(&prg, Some(&cdl)).unwrap();
+        assert!(asm.contains(".byte $A9, $10"));
+        assert!(disassemble(&prg, Some(&cdl[..10])).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use std::fmt;
+
+use crate::cpu::CPU;
+
+/// CPU registers at the moment emulation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuSnapshot {
+    pub program_counter: u16,
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub status: u8,
+    pub stack_pointer: u8,
+    pub cycles: usize,
+    pub frame: usize,
+}
+
+impl CpuSnapshot {
+    pub fn of(cpu: &CPU) -> Self {
+        CpuSnapshot {
+            program_counter: cpu.program_counter,
+            register_a: cpu.register_a,
+            register_x: cpu.register_x,
+            register_y: cpu.register_y,
+            status: cpu.status.bits(),
+            stack_pointer: cpu.stack_pointer,
+            cycles: cpu.bus.cycles(),
+            frame: cpu.bus.frame_count(),
+        }
+    }
+}
+
+impl fmt::Display for CpuSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}\nCYC:{} FRAME:{}",
+            self.program_counter,
+            self.register_a,
+            self.register_x,
+            self.register_y,
+            self.status,
+            self.stack_pointer,
+            self.cycles,
+            self.frame
+        )
+    }
+}
+
+/// Why a game can't be started or can't continue.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmulationError {
+    /// The file couldn't be read or isn't a ROM we understand
+    InvalidRom {
+        path: String,
+        reason: String,
+    },
+    UnsupportedMapper(u16),
+    /// Made for hardware other than the NES and the Vs. System, e.g. the PlayChoice-10
+    UnsupportedConsole(String),
+    /// Emulation hit something it can't recover from, e.g. a jammed CPU or a write to ROM
+    Crash {
+        message: String,
+        cpu: CpuSnapshot,
+    },
+}
+
+impl EmulationError {
+    /// Builds a `Crash` from a caught panic's payload.
+    pub fn from_panic(payload: &(dyn std::any::Any + Send), cpu: &CPU) -> Self {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "Unknown error".to_string()
+        };
+        EmulationError::Crash {
+            message,
+            cpu: CpuSnapshot::of(cpu),
+        }
+    }
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulationError::InvalidRom { path, reason } => {
+                write!(f, "Failed to load {}: {}", path, reason)
+            }
+            EmulationError::UnsupportedMapper(mapper) => {
+                write!(f, "Mapper {} is not supported", mapper)
+            }
+            EmulationError::UnsupportedConsole(console) => write!(
+                f,
+                "This ROM is for the {}, which isn't supported. Only NES and Vs. System games run",
+                console
+            ),
+            EmulationError::Crash { message, cpu } => write!(f, "{}\n{}", message, cpu),
+        }
+    }
+}
+
+impl std::error::Error for EmulationError {}
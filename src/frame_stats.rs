@@ -0,0 +1,200 @@
+use std::{
+    fmt::Write as _,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Time spent on one frame: running the emulator up to it, then drawing and presenting it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameTime {
+    pub emulation: Duration,
+    pub present: Duration,
+}
+
+/// Per-frame timings of a session, for spotting slow frames and performance regressions.
+/// Time spent waiting on the frame limiter isn't counted.
+#[derive(Default)]
+pub struct FrameStats {
+    frames: Vec<FrameTime>,
+    /// When emulation last picked up after a frame, or `None` before the first frame
+    resumed: Option<Instant>,
+}
+
+impl FrameStats {
+    pub fn new() -> Self {
+        FrameStats::default()
+    }
+
+    /// Call when emulation (re)starts, right after a frame's wait.
+    pub fn resume(&mut self) {
+        self.resumed = Some(Instant::now());
+    }
+
+    /// Records a frame that finished emulating at `emulated` and was presented at `presented`.
+    pub fn record(&mut self, emulated: Instant, presented: Instant) {
+        if let Some(resumed) = self.resumed.take() {
+            self.frames.push(FrameTime {
+                emulation: emulated.saturating_duration_since(resumed),
+                present: presented.saturating_duration_since(emulated),
+            });
+        }
+    }
+
+    pub fn push(&mut self, frame: FrameTime) {
+        self.frames.push(frame);
+    }
+
+    pub fn frames(&self) -> &[FrameTime] {
+        &self.frames
+    }
+
+    /// A table of the mean, median, 90th and 99th percentile and slowest frame, in
+    /// milliseconds.
+    pub fn summary(&self) -> String {
+        if self.frames.is_empty() {
+            return "No frames timed yet".to_string();
+        }
+        let mut out = format!(
+            "Frame times over {} frames (ms)\n{:10} {:>7} {:>7} {:>7} {:>7} {:>7}\n",
+            self.frames.len(),
+            "",
+            "mean",
+            "p50",
+            "p90",
+            "p99",
+            "max"
+        );
+        let rows = [
+            ("emulation", self.sorted(|frame| frame.emulation)),
+            ("present", self.sorted(|frame| frame.present)),
+            (
+                "total",
+                self.sorted(|frame| frame.emulation + frame.present),
+            ),
+        ];
+        for (name, times) in rows {
+            let mean = times.iter().sum::<Duration>() / times.len() as u32;
+            let _ = write!(out, "{:10}", name);
+            for value in [
+                mean,
+                percentile(&times, 50),
+                percentile(&times, 90),
+                percentile(&times, 99),
+                times[times.len() - 1],
+            ] {
+                let _ = write!(out, " {:7.2}", value.as_secs_f64() * 1000.0);
+            }
+            out.push('\n');
+        }
+        out.pop();
+        out
+    }
+
+    fn sorted(&self, time: impl Fn(&FrameTime) -> Duration) -> Vec<Duration> {
+        let mut times: Vec<Duration> = self.frames.iter().map(time).collect();
+        times.sort();
+        times
+    }
+
+    /// Writes one `frame,emulation_us,present_us` row per frame.
+    pub fn write_csv(&self, path: &Path) -> Result<(), String> {
+        let mut csv = String::from("frame,emulation_us,present_us\n");
+        for (n, frame) in self.frames.iter().enumerate() {
+            let _ = writeln!(
+                csv,
+                "{},{},{}",
+                n + 1,
+                frame.emulation.as_micros(),
+                frame.present.as_micros()
+            );
+        }
+        std::fs::write(path, csv).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+/// Counts frames a second at a time, for showing how fast a game is really running.
+#[derive(Default)]
+pub struct FpsMeter {
+    /// When the current second started, or `None` before its first frame
+    start: Option<Instant>,
+    frames: u32,
+}
+
+impl FpsMeter {
+    pub fn new() -> Self {
+        FpsMeter::default()
+    }
+
+    /// Starts counting again from the next frame, after a pause or loading another game.
+    pub fn restart(&mut self) {
+        self.start = None;
+    }
+
+    /// Counts a frame shown at `now`. A second after counting started, returns the frames
+    /// per second since then and starts a new second.
+    pub fn frame(&mut self, now: Instant) -> Option<f64> {
+        let Some(start) = self.start else {
+            self.start = Some(now);
+            self.frames = 0;
+            return None;
+        };
+        self.frames += 1;
+        let elapsed = now.saturating_duration_since(start);
+        if elapsed < Duration::from_secs(1) {
+            return None;
+        }
+        self.start = Some(now);
+        let fps = self.frames as f64 / elapsed.as_secs_f64();
+        self.frames = 0;
+        Some(fps)
+    }
+}
+
+/// The value `percent`% of the way through `sorted`, nearest rank.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent).div_ceil(100);
+    sorted[rank.saturating_sub(1)]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let times: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&times, 50), Duration::from_millis(50));
+        assert_eq!(percentile(&times, 99), Duration::from_millis(99));
+        assert_eq!(percentile(&times[..1], 90), Duration::from_millis(1));
+
+        let mut stats = FrameStats::new();
+        for ms in [2, 4, 6] {
+            stats.push(FrameTime {
+                emulation: Duration::from_millis(ms),
+                present: Duration::from_millis(1),
+            });
+        }
+        let summary = stats.summary();
+        let total = summary.lines().last().unwrap();
+        assert_eq!(
+            total.split_whitespace().collect::<Vec<_>>(),
+            ["total", "5.00", "5.00", "7.00", "7.00", "7.00"]
+        );
+    }
+
+    #[test]
+    fn test_fps_meter() {
+        let start = Instant::now();
+        let mut meter = FpsMeter::new();
+        let at = |frame: u32| start + Duration::from_millis(frame as u64 * 1000 / 50);
+        // 50 frames a second, measured from the first frame
+        let readings: Vec<f64> = (0..=100)
+            .filter_map(|frame| meter.frame(at(frame)))
+            .collect();
+        assert_eq!(readings, [50.0, 50.0]);
+
+        meter.restart();
+        assert_eq!(meter.frame(at(500)), None);
+        assert_eq!(meter.frame(at(600)), Some(0.5));
+    }
+}
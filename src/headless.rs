@@ -0,0 +1,127 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+use crate::{
+    bus::Bus,
+    cartridge::Rom,
+    cpu::CPU,
+    input_script::ConsoleEvent,
+    joypad::Joypad,
+    ppu::NesPPU,
+    region::Region,
+    render::{frame::Frame, Renderer},
+};
+
+/// Runs `rom` without opening a window for `frames` frames and returns the last rendered frame.
+/// Fails on cartridges the emulator doesn't support.
+pub fn run_frames(rom: Rom, frames: usize) -> Result<Frame, String> {
+    run_frames_with(rom, Region::NTSC, frames, |_, _, _| None)
+}
+
+/// Like `run_frames`, but calls `on_frame` with the frame number (starting at 1), the picture
+/// and both players' joypads after every rendered frame. Buttons set on the joypads are held
+/// during the next frame, and a returned event happens before it starts.
+pub fn run_frames_with<F>(
+    rom: Rom,
+    region: Region,
+    frames: usize,
+    on_frame: F,
+) -> Result<Frame, String>
+where
+    F: FnMut(usize, &Frame, &mut [Joypad; 2]) -> Option<ConsoleEvent>,
+{
+    run_frames_checked(rom, region, frames, on_frame, |_, _| true).map(|(frame, _)| frame)
+}
+
+/// Like `run_frames_with`, and calls `after_frame` with the frame number and the machine once
+/// `on_frame` has returned, before its event happens. Stops early if `after_frame` returns
+/// false, or if the CPU stops, so the number of frames that actually ran is returned with the
+/// last one.
+pub fn run_frames_checked<F, G>(
+    rom: Rom,
+    region: Region,
+    frames: usize,
+    mut on_frame: F,
+    mut after_frame: G,
+) -> Result<(Frame, usize), String>
+where
+    F: FnMut(usize, &Frame, &mut [Joypad; 2]) -> Option<ConsoleEvent>,
+    G: FnMut(usize, &CPU) -> bool,
+{
+    let frame = Rc::new(RefCell::new(Frame::new()));
+    let target = Rc::clone(&frame);
+    let event = Rc::new(Cell::new(None));
+    let next_event = Rc::clone(&event);
+    let mut renderer = Renderer::new();
+    let mut rendered = 0;
+
+    let bus = Bus::try_new(rom, move |ppu: &NesPPU, joypads: &mut [Joypad; 2]| {
+        let mut target = target.borrow_mut();
+        renderer.render(ppu, &mut target);
+        rendered += 1;
+        next_event.set(on_frame(rendered, &target, joypads));
+    })
+    .map_err(|e| e.to_string())?;
+    let mut cpu = CPU::new(bus);
+    cpu.bus.set_region(region);
+    cpu.reset();
+    let mut last_frame = 0;
+    while cpu.bus.frame_count() < frames {
+        if !cpu.step() {
+            break;
+        }
+        if cpu.bus.frame_count() != last_frame {
+            last_frame = cpu.bus.frame_count();
+            if !after_frame(last_frame, &cpu) {
+                break;
+            }
+        }
+        match event.take() {
+            Some(ConsoleEvent::Reset) => cpu.soft_reset(),
+            Some(ConsoleEvent::Power) => cpu.power_cycle(),
+            None => {}
+        }
+    }
+
+    Ok((frame.take(), cpu.bus.frame_count()))
+}
+
+/// Runs `rom` without a window for `frames` frames and returns the pattern tables and palette
+/// RAM at the end, for games that load their tiles into CHR RAM or set colors at runtime.
+pub fn run_to_ppu(rom: Rom, region: Region, frames: usize) -> Result<(Vec<u8>, [u8; 32]), String> {
+    let state = Rc::new(RefCell::new((rom.chr_rom.clone(), [0; 32])));
+    let target = Rc::clone(&state);
+
+    let bus = Bus::try_new(rom, move |ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {
+        let mut target = target.borrow_mut();
+        target.0.clone_from(&ppu.chr_rom);
+        target.1 = ppu.palette_table;
+    })
+    .map_err(|e| e.to_string())?;
+    let mut cpu = CPU::new(bus);
+    cpu.bus.set_region(region);
+    cpu.reset();
+    while cpu.bus.frame_count() < frames {
+        if !cpu.step() {
+            break;
+        }
+    }
+    drop(cpu);
+
+    Ok(Rc::try_unwrap(state)
+        .map(RefCell::into_inner)
+        .unwrap_or_else(|state| state.borrow().clone()))
+}
+
+/// FNV-1a hash of the frame's pixel data. Stable across platforms and compiler versions,
+/// unlike `DefaultHasher`, so it can be stored in golden files.
+pub fn frame_hash(frame: &Frame) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in frame.data.iter() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+
+use sdl2::{controller::Button, event::Event, keyboard::Keycode};
+
+use crate::{
+    config::InputConfig,
+    joypad::{Joypad, JoypadButton},
+};
+
+/// Default keyboard layouts for players 1 and 2, far enough apart that two people can share
+/// one keyboard: W/A/S/D, Space/Return and 1/2 on the left, the arrows and numpad on the
+/// right.
+const KEYBOARD_LAYOUTS: [[(JoypadButton, Keycode); 8]; 2] = [
+    [
+        (JoypadButton::UP, Keycode::W),
+        (JoypadButton::LEFT, Keycode::A),
+        (JoypadButton::DOWN, Keycode::S),
+        (JoypadButton::RIGHT, Keycode::D),
+        (JoypadButton::SELECT, Keycode::Space),
+        (JoypadButton::START, Keycode::Return),
+        (JoypadButton::A, Keycode::Num1),
+        (JoypadButton::B, Keycode::Num2),
+    ],
+    [
+        (JoypadButton::UP, Keycode::Up),
+        (JoypadButton::LEFT, Keycode::Left),
+        (JoypadButton::DOWN, Keycode::Down),
+        (JoypadButton::RIGHT, Keycode::Right),
+        (JoypadButton::SELECT, Keycode::Kp0),
+        (JoypadButton::START, Keycode::KpEnter),
+        (JoypadButton::A, Keycode::Kp1),
+        (JoypadButton::B, Keycode::Kp2),
+    ],
+];
+
+/// Default keys for Power Pad buttons 1-12, a block on the right of the keyboard laid out
+/// like the mat
+const POWER_PAD_KEYS: [Keycode; 12] = [
+    Keycode::Y,
+    Keycode::U,
+    Keycode::I,
+    Keycode::O,
+    Keycode::H,
+    Keycode::J,
+    Keycode::K,
+    Keycode::L,
+    Keycode::N,
+    Keycode::M,
+    Keycode::Comma,
+    Keycode::Period,
+];
+
+/// A physical key or button that can be bound to a joypad button.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum InputSource {
+    Key(Keycode),
+    /// Button on the n-th connected game controller
+    Pad(usize, Button),
+}
+
+/// Maps physical inputs to (player, joypad button) pairs. Built once up front instead of
+/// on every event.
+pub struct InputMap {
+    bindings: HashMap<InputSource, (usize, JoypadButton)>,
+    // SDL instance ids of connected controllers, in the order they were connected
+    pads: Vec<u32>,
+    /// See `InputConfig::block_opposing`
+    pub block_opposing: bool,
+    // Buttons each player is physically holding, including directions hidden from the game
+    held: [JoypadButton; 2],
+    /// Keys for the Power Pad's buttons, by button number
+    power_pad_keys: HashMap<Keycode, u8>,
+    /// Whether a Power Pad is plugged in, which gives its keys to it
+    pub power_pad_enabled: bool,
+    power_pad: u16,
+}
+
+impl InputMap {
+    pub fn new() -> Self {
+        InputMap {
+            bindings: HashMap::new(),
+            pads: Vec::new(),
+            block_opposing: true,
+            held: [JoypadButton::empty(); 2],
+            power_pad_keys: HashMap::new(),
+            power_pad_enabled: false,
+            power_pad: 0,
+        }
+    }
+
+    pub fn bind(&mut self, source: InputSource, player: usize, button: JoypadButton) {
+        self.bindings.insert(source, (player, button));
+    }
+
+    pub fn unbind(&mut self, source: InputSource) {
+        self.bindings.remove(&source);
+    }
+
+    pub fn binding(&self, source: InputSource) -> Option<(usize, JoypadButton)> {
+        self.bindings.get(&source).copied()
+    }
+
+    /// The default bindings with the keyboard keys changed in `config` swapped in.
+    pub fn from_config(config: &InputConfig) -> Result<Self, String> {
+        let mut map = InputMap::default();
+        for (player, keys) in [&config.player1, &config.player2].into_iter().enumerate() {
+            for (button_name, key_name) in keys {
+                let button = JoypadButton::from_name(&button_name.to_ascii_uppercase())
+                    .ok_or_else(|| format!("Unknown button in [input]: {}", button_name))?;
+                map.bindings.retain(|source, binding| {
+                    !matches!(source, InputSource::Key(_)) || *binding != (player, button)
+                });
+                if key_name.is_empty() {
+                    continue;
+                }
+                let key = Keycode::from_name(key_name)
+                    .ok_or_else(|| format!("Unknown key in [input]: {}", key_name))?;
+                map.bind(InputSource::Key(key), player, button);
+            }
+        }
+        for (button_name, key_name) in &config.power_pad {
+            let button = button_name
+                .parse()
+                .ok()
+                .filter(|button| (1..=12).contains(button))
+                .ok_or_else(|| format!("Unknown Power Pad button in [input]: {}", button_name))?;
+            map.power_pad_keys.retain(|_, bound| *bound != button);
+            if key_name.is_empty() {
+                continue;
+            }
+            let key = Keycode::from_name(key_name)
+                .ok_or_else(|| format!("Unknown key in [input]: {}", key_name))?;
+            map.power_pad_keys.insert(key, button);
+        }
+        map.block_opposing = config.block_opposing;
+        Ok(map)
+    }
+
+    /// Swaps in the bindings from a reloaded config. Connected controllers stay connected.
+    pub fn reload(&mut self, config: &InputConfig) -> Result<(), String> {
+        let map = InputMap::from_config(config)?;
+        self.bindings = map.bindings;
+        self.power_pad_keys = map.power_pad_keys;
+        self.block_opposing = map.block_opposing;
+        Ok(())
+    }
+
+    /// Registers a newly opened game controller. Controllers are numbered in connection order.
+    pub fn connect_pad(&mut self, instance_id: u32) {
+        if !self.pads.contains(&instance_id) {
+            self.pads.push(instance_id);
+        }
+    }
+
+    pub fn disconnect_pad(&mut self, instance_id: u32) {
+        self.pads.retain(|&id| id != instance_id);
+    }
+
+    /// Applies a key or controller event to the joypads, indexed by player. Returns whether the
+    /// event was bound to anything.
+    pub fn handle_event(&mut self, event: &Event, joypads: &mut [&mut Joypad]) -> bool {
+        let (source, pressed) = match *event {
+            Event::KeyDown {
+                keycode: Some(keycode),
+                ..
+            } => (InputSource::Key(keycode), true),
+            Event::KeyUp {
+                keycode: Some(keycode),
+                ..
+            } => (InputSource::Key(keycode), false),
+            Event::ControllerButtonDown { which, button, .. } => match self.pad_index(which) {
+                Some(pad) => (InputSource::Pad(pad, button), true),
+                None => return false,
+            },
+            Event::ControllerButtonUp { which, button, .. } => match self.pad_index(which) {
+                Some(pad) => (InputSource::Pad(pad, button), false),
+                None => return false,
+            },
+            _ => return false,
+        };
+
+        if let InputSource::Key(key) = source {
+            if let Some(&button) = self
+                .power_pad_keys
+                .get(&key)
+                .filter(|_| self.power_pad_enabled)
+            {
+                let bit = 1 << (button - 1);
+                if pressed {
+                    self.power_pad |= bit;
+                } else {
+                    self.power_pad &= !bit;
+                }
+                return true;
+            }
+        }
+        let Some((player, button)) = self.binding(source) else {
+            return false;
+        };
+        if let Some(held) = self.held.get_mut(player) {
+            held.set(button, pressed);
+        }
+        let Some(joypad) = joypads.get_mut(player) else {
+            return true;
+        };
+        if pressed {
+            joypad.press(button);
+        } else {
+            joypad.release(button);
+        }
+        if self.block_opposing {
+            let opposite = opposite_direction(button);
+            // The newer direction hides the other one until it's let go
+            if pressed {
+                joypad.release(opposite);
+            } else if self.held[player].contains(opposite) {
+                joypad.press(opposite);
+            }
+        }
+        true
+    }
+
+    /// The Power Pad buttons held, bit n - 1 for button n.
+    pub fn power_pad(&self) -> u16 {
+        self.power_pad
+    }
+
+    fn pad_index(&self, instance_id: u32) -> Option<usize> {
+        self.pads.iter().position(|&id| id == instance_id)
+    }
+}
+
+/// The D-pad direction opposite `button`, or no buttons for the rest.
+fn opposite_direction(button: JoypadButton) -> JoypadButton {
+    match button {
+        JoypadButton::UP => JoypadButton::DOWN,
+        JoypadButton::DOWN => JoypadButton::UP,
+        JoypadButton::LEFT => JoypadButton::RIGHT,
+        JoypadButton::RIGHT => JoypadButton::LEFT,
+        _ => JoypadButton::empty(),
+    }
+}
+
+impl Default for InputMap {
+    /// `KEYBOARD_LAYOUTS` on the keyboard, and the first two game controllers for players 1
+    /// and 2.
+    fn default() -> Self {
+        let mut map = InputMap::new();
+        for (player, layout) in KEYBOARD_LAYOUTS.iter().enumerate() {
+            for &(button, key) in layout {
+                map.bind(InputSource::Key(key), player, button);
+            }
+        }
+        for (button, key) in (1..).zip(POWER_PAD_KEYS) {
+            map.power_pad_keys.insert(key, button);
+        }
+
+        // Positional layout: the pad's right face button is NES A, the bottom one NES B
+        let pad_layout = [
+            (Button::DPadUp, JoypadButton::UP),
+            (Button::DPadDown, JoypadButton::DOWN),
+            (Button::DPadLeft, JoypadButton::LEFT),
+            (Button::DPadRight, JoypadButton::RIGHT),
+            (Button::B, JoypadButton::A),
+            (Button::A, JoypadButton::B),
+            (Button::Back, JoypadButton::SELECT),
+            (Button::Start, JoypadButton::START),
+        ];
+        for pad in 0..2 {
+            for (button, joypad_button) in pad_layout {
+                map.bind(InputSource::Pad(pad, button), pad, joypad_button);
+            }
+        }
+        map
+    }
+}
+
+/// Buttons in the order `Rebinding` asks for them
+const REBIND_ORDER: [JoypadButton; 8] = [
+    JoypadButton::UP,
+    JoypadButton::DOWN,
+    JoypadButton::LEFT,
+    JoypadButton::RIGHT,
+    JoypadButton::A,
+    JoypadButton::B,
+    JoypadButton::SELECT,
+    JoypadButton::START,
+];
+
+/// Asks for a player's buttons one at a time and collects the keys pressed for them, so the
+/// controls can be set up without knowing SDL's key names.
+pub struct Rebinding {
+    player: usize,
+    keys: Vec<Keycode>,
+}
+
+impl Rebinding {
+    pub fn new(player: usize) -> Self {
+        Rebinding {
+            player,
+            keys: Vec::new(),
+        }
+    }
+
+    pub fn player(&self) -> usize {
+        self.player
+    }
+
+    /// The button the next key goes to, or `None` once every button has one.
+    pub fn button(&self) -> Option<JoypadButton> {
+        REBIND_ORDER.get(self.keys.len()).copied()
+    }
+
+    /// Gives `key` to the current button. A key already given to another of the player's
+    /// buttons is refused.
+    pub fn press(&mut self, key: Keycode) -> bool {
+        if self.button().is_none() || self.keys.contains(&key) {
+            return false;
+        }
+        self.keys.push(key);
+        true
+    }
+
+    /// Writes the keys pressed so far into the player's section of `config`. Other players'
+    /// buttons on the same keys are unbound, since a key only drives one button.
+    pub fn apply(&self, config: &mut InputConfig) {
+        for (player, keys) in [&mut config.player1, &mut config.player2]
+            .into_iter()
+            .enumerate()
+        {
+            if player == self.player {
+                for (&button, key) in REBIND_ORDER.iter().zip(&self.keys) {
+                    keys.insert(button_name(button), key.name());
+                }
+                continue;
+            }
+            // Both the keys the player set and the default layout's
+            for (button, default) in KEYBOARD_LAYOUTS[player] {
+                let name = button_name(button);
+                let key = match keys.get(&name) {
+                    Some(key) => Keycode::from_name(key),
+                    None => Some(default),
+                };
+                if key.is_some_and(|key| self.keys.contains(&key)) {
+                    keys.insert(name, String::new());
+                }
+            }
+        }
+    }
+}
+
+/// A button's name in `[input]`, e.g. "start".
+pub fn button_name(button: JoypadButton) -> String {
+    button
+        .iter_names()
+        .next()
+        .map_or_else(String::new, |(name, _)| name.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sdl2::keyboard::Mod;
+
+    fn key_event(keycode: Keycode, pressed: bool) -> Event {
+        if pressed {
+            Event::KeyDown {
+                timestamp: 0,
+                window_id: 0,
+                keycode: Some(keycode),
+                scancode: None,
+                keymod: Mod::NOMOD,
+                repeat: false,
+            }
+        } else {
+            Event::KeyUp {
+                timestamp: 0,
+                window_id: 0,
+                keycode: Some(keycode),
+                scancode: None,
+                keymod: Mod::NOMOD,
+                repeat: false,
+            }
+        }
+    }
+
+    #[test]
+    fn test_bound_keys_reach_the_right_player() {
+        let mut map = InputMap::new();
+        map.bind(InputSource::Key(Keycode::Up), 1, JoypadButton::UP);
+        let mut player1 = Joypad::new();
+        let mut player2 = Joypad::new();
+
+        assert!(map.handle_event(
+            &key_event(Keycode::Up, true),
+            &mut [&mut player1, &mut player2]
+        ));
+        assert!(!map.handle_event(
+            &key_event(Keycode::W, true),
+            &mut [&mut player1, &mut player2]
+        ));
+
+        player2.write(1);
+        assert_eq!(player2.read(), 0); // A
+        player2.write(0);
+        for _ in 0..4 {
+            player2.read();
+        }
+        assert_eq!(player2.read(), 1); // UP
+        player1.write(1);
+        player1.write(0);
+        for _ in 0..8 {
+            assert_eq!(player1.read(), 0);
+        }
+    }
+
+    #[test]
+    fn test_opposing_directions() {
+        let mut map = InputMap::default();
+        let mut player1 = Joypad::new();
+        let mut player2 = Joypad::new();
+        let mut send = |map: &mut InputMap, key, pressed| {
+            map.handle_event(&key_event(key, pressed), &mut [&mut player1, &mut player2]);
+            player1.buttons()
+        };
+
+        assert_eq!(send(&mut map, Keycode::A, true), JoypadButton::LEFT);
+        assert_eq!(send(&mut map, Keycode::D, true), JoypadButton::RIGHT);
+        assert_eq!(
+            send(&mut map, Keycode::W, true),
+            JoypadButton::RIGHT | JoypadButton::UP
+        );
+        // Letting go of right brings back the left still held
+        assert_eq!(
+            send(&mut map, Keycode::D, false),
+            JoypadButton::LEFT | JoypadButton::UP
+        );
+        assert_eq!(send(&mut map, Keycode::A, false), JoypadButton::UP);
+        send(&mut map, Keycode::W, false);
+
+        map.block_opposing = false;
+        send(&mut map, Keycode::A, true);
+        assert_eq!(
+            send(&mut map, Keycode::D, true),
+            JoypadButton::LEFT | JoypadButton::RIGHT
+        );
+    }
+
+    #[test]
+    fn test_power_pad_keys() {
+        let mut config = InputConfig::default();
+        config.power_pad.insert("2".to_string(), String::new());
+        let mut map = InputMap::from_config(&config).unwrap();
+        let mut joypad = Joypad::new();
+        let mut send = |map: &mut InputMap, key, pressed| {
+            map.handle_event(&key_event(key, pressed), &mut [&mut joypad]);
+            map.power_pad()
+        };
+
+        // Only once one is plugged in
+        assert_eq!(send(&mut map, Keycode::Y, true), 0);
+        map.power_pad_enabled = true;
+        assert_eq!(send(&mut map, Keycode::Y, true), 0b1);
+        assert_eq!(send(&mut map, Keycode::Period, true), 0b1000_0000_0001);
+        assert_eq!(
+            send(&mut map, Keycode::U, true),
+            0b1000_0000_0001,
+            "unbound"
+        );
+        assert_eq!(send(&mut map, Keycode::Y, false), 0b1000_0000_0000);
+
+        config.power_pad.insert("13".to_string(), String::new());
+        assert!(InputMap::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_config_changes_only_listed_keys() {
+        let mut config = InputConfig::default();
+        config.player2.insert("a".to_string(), String::new());
+        let map = InputMap::from_config(&config).unwrap();
+        assert_eq!(map.binding(InputSource::Key(Keycode::Kp1)), None);
+        assert_eq!(
+            map.binding(InputSource::Key(Keycode::Kp2)),
+            Some((1, JoypadButton::B))
+        );
+        assert_eq!(
+            map.binding(InputSource::Key(Keycode::Num1)),
+            Some((0, JoypadButton::A))
+        );
+        assert_eq!(
+            map.binding(InputSource::Pad(1, Button::B)),
+            Some((1, JoypadButton::A))
+        );
+
+        config.player1.insert("turbo".to_string(), String::new());
+        assert!(InputMap::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_rebinding_takes_keys_from_other_players() {
+        let mut rebinding = Rebinding::new(0);
+        let keys = [
+            Keycode::I,
+            Keycode::K,
+            Keycode::J,
+            Keycode::L,
+            Keycode::Kp1,
+            Keycode::X,
+            Keycode::C,
+            Keycode::V,
+        ];
+        assert_eq!(rebinding.button(), Some(JoypadButton::UP));
+        for key in keys {
+            assert!(rebinding.press(key));
+            assert!(!rebinding.press(key), "already taken");
+        }
+        assert_eq!(rebinding.button(), None);
+
+        let mut config = InputConfig::default();
+        rebinding.apply(&mut config);
+        assert_eq!(config.player1["up"], "I");
+        assert_eq!(config.player1["start"], "V");
+        assert_eq!(config.player2.len(), 1);
+        assert_eq!(config.player2["a"], "");
+        let map = InputMap::from_config(&config).unwrap();
+        assert_ne!(
+            map.binding(InputSource::Key(Keycode::Kp1)),
+            Some((1, JoypadButton::A))
+        );
+        assert_eq!(map.binding(InputSource::Key(Keycode::W)), None);
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+
+use sdl2::keyboard::Keycode;
+
+use crate::{
+    config::MacroConfig,
+    input_script::{format_line, parse_line, FrameInput, Line},
+    joypad::{Joypad, JoypadButton},
+};
+
+/// Longest macro that can be recorded, 10 seconds at 60 FPS. Recording stops by itself here.
+pub const MAX_MACRO_FRAMES: usize = 600;
+
+/// Controller input for a fixed number of frames, replayed frame for frame.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputMacro {
+    frames: Vec<FrameInput>,
+}
+
+impl InputMacro {
+    /// Reads a macro from input script lines. Resets and power cycles aren't allowed in one.
+    pub fn parse(lines: &[String]) -> Result<InputMacro, String> {
+        let mut frames = Vec::new();
+        for line in lines {
+            match parse_line(line)? {
+                Some(Line::Input(input, count)) => frames.extend(std::iter::repeat_n(input, count)),
+                Some(Line::Event(_)) => return Err("Macros can't reset the console".to_string()),
+                Some(Line::Check(..)) | None => {}
+            }
+        }
+        Ok(InputMacro { frames })
+    }
+
+    /// The macro as input script lines, with runs of the same input on one line.
+    pub fn to_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut frames = self.frames.iter().peekable();
+        while let Some(&input) = frames.next() {
+            let mut count = 1;
+            while frames.next_if_eq(&&input).is_some() {
+                count += 1;
+            }
+            lines.push(format_line(input, count));
+        }
+        lines
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+/// Macros bound to keys, and the one being recorded or played.
+#[derive(Default)]
+pub struct Macros {
+    bindings: HashMap<Keycode, InputMacro>,
+    recording: Option<InputMacro>,
+    /// A finished recording waiting for the key to bind it to
+    unbound: Option<InputMacro>,
+    /// The macro being played and the next frame of it
+    playing: Option<(InputMacro, usize)>,
+}
+
+impl Macros {
+    pub fn from_config(config: &[MacroConfig]) -> Result<Self, String> {
+        let mut macros = Macros::default();
+        for entry in config {
+            let key = Keycode::from_name(&entry.key)
+                .ok_or_else(|| format!("Unknown key in [[macros]]: {}", entry.key))?;
+            let input_macro = InputMacro::parse(&entry.inputs)
+                .map_err(|e| format!("Macro on {}: {}", entry.key, e))?;
+            macros.bindings.insert(key, input_macro);
+        }
+        Ok(macros)
+    }
+
+    /// Swaps in the macros from a reloaded config. A recording or playback carries on.
+    pub fn reload(&mut self, config: &[MacroConfig]) -> Result<(), String> {
+        self.bindings = Macros::from_config(config)?.bindings;
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Whether a recording is waiting for `bind` or `discard`.
+    pub fn awaiting_key(&self) -> bool {
+        self.unbound.is_some()
+    }
+
+    pub fn is_bound(&self, key: Keycode) -> bool {
+        self.bindings.contains_key(&key)
+    }
+
+    /// Starts recording, or stops and keeps the recording for `bind`. Returns whether it is
+    /// recording now.
+    pub fn toggle_recording(&mut self) -> bool {
+        match self.recording.take() {
+            Some(recording) => {
+                self.unbound = Some(recording).filter(|recording| !recording.is_empty());
+                false
+            }
+            None => {
+                self.unbound = None;
+                self.recording = Some(InputMacro::default());
+                true
+            }
+        }
+    }
+
+    /// Adds the input held during the next frame to the recording, if there is one. Returns
+    /// false once the recording has hit `MAX_MACRO_FRAMES` and stopped.
+    pub fn record(&mut self, input: FrameInput) -> bool {
+        let Some(recording) = self.recording.as_mut() else {
+            return true;
+        };
+        recording.frames.push(input);
+        if recording.len() < MAX_MACRO_FRAMES {
+            return true;
+        }
+        self.toggle_recording();
+        false
+    }
+
+    /// Binds the finished recording to `key`, replacing what was bound to it, and returns it
+    /// so it can be saved.
+    pub fn bind(&mut self, key: Keycode) -> Option<InputMacro> {
+        let input_macro = self.unbound.take()?;
+        self.bindings.insert(key, input_macro.clone());
+        Some(input_macro)
+    }
+
+    pub fn discard(&mut self) {
+        self.unbound = None;
+    }
+
+    /// Starts playing the macro bound to `key` from the top.
+    pub fn play(&mut self, key: Keycode) {
+        if let Some(input_macro) = self.bindings.get(&key) {
+            self.playing = Some((input_macro.clone(), 0));
+        }
+    }
+
+    /// Sets `joypads` (indexed by player) to the playing macro's next frame. The macro
+    /// replaces the controllers' input while it plays, so a held key can't spoil a
+    /// frame-perfect input; when it ends the buttons are released for the keyboard to take
+    /// over again.
+    pub fn apply(&mut self, joypads: &mut [&mut Joypad]) {
+        let Some((input_macro, frame)) = self.playing.as_mut() else {
+            return;
+        };
+        let input = input_macro.frames.get(*frame).copied();
+        *frame += 1;
+        if input.is_none() {
+            self.playing = None;
+        }
+        let input = input.unwrap_or([JoypadButton::empty(); 2]);
+        for (joypad, buttons) in joypads.iter_mut().zip(input) {
+            joypad.set_buttons(buttons);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lines_round_trip() {
+        let lines: Vec<String> = ["3: RIGHT", "A RIGHT", "2:", "| B"]
+            .map(String::from)
+            .to_vec();
+        let input_macro = InputMacro::parse(&lines).unwrap();
+        assert_eq!(input_macro.len(), 7);
+        assert_eq!(input_macro.to_lines(), ["3: RIGHT", "A RIGHT", "2:", "| B"]);
+        assert!(InputMacro::parse(&["RESET".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_record_bind_and_play() {
+        let mut macros = Macros::default();
+        assert!(macros.toggle_recording());
+        macros.record([JoypadButton::B, JoypadButton::empty()]);
+        macros.record([JoypadButton::A, JoypadButton::UP]);
+        assert!(!macros.toggle_recording());
+        assert!(macros.awaiting_key());
+        macros.bind(Keycode::F12).unwrap();
+        assert!(!macros.awaiting_key());
+
+        let (mut p1, mut p2) = (Joypad::new(), Joypad::new());
+        p1.press(JoypadButton::RIGHT);
+        // Nothing plays until the key is pressed
+        macros.apply(&mut [&mut p1, &mut p2]);
+        assert_eq!(p1.buttons(), JoypadButton::RIGHT);
+
+        macros.play(Keycode::F12);
+        let mut played = Vec::new();
+        for _ in 0..4 {
+            macros.apply(&mut [&mut p1, &mut p2]);
+            played.push([p1.buttons(), p2.buttons()]);
+        }
+        let none = JoypadButton::empty();
+        assert_eq!(
+            played,
+            [
+                [JoypadButton::B, none],
+                [JoypadButton::A, JoypadButton::UP],
+                [none, none],
+                [none, none],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recording_stops_at_limit() {
+        let mut macros = Macros::default();
+        macros.toggle_recording();
+        for _ in 1..MAX_MACRO_FRAMES {
+            assert!(macros.record([JoypadButton::A, JoypadButton::empty()]));
+        }
+        assert!(!macros.record([JoypadButton::A, JoypadButton::empty()]));
+        assert!(!macros.is_recording());
+        assert_eq!(macros.bind(Keycode::F12).unwrap().len(), MAX_MACRO_FRAMES);
+    }
+}
@@ -0,0 +1,356 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use serde::Deserialize;
+
+use crate::joypad::{Joypad, JoypadButton};
+
+/// Buttons held by players 1 and 2 during one frame.
+pub type FrameInput = [JoypadButton; 2];
+
+/// Something done to the console itself rather than the controllers.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsoleEvent {
+    /// The reset button: the CPU restarts, RAM keeps its contents
+    Reset,
+    /// Turning the console off and on again: RAM and the PPU are cleared
+    Power,
+}
+
+/// One parsed line of an input script.
+#[derive(Debug, PartialEq)]
+pub enum Line {
+    /// Buttons to hold and for how many frames
+    Input(FrameInput, usize),
+    /// Happens right before the next frame's input
+    Event(ConsoleEvent),
+    /// A movie's checksum of RAM after the given frame, see `movie`
+    Check(usize, u32),
+}
+
+/// The JSON form of a line: `{"p1": ["A", "RIGHT"], "p2": [], "frames": 10}`, or
+/// `{"event": "reset"}`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonLine {
+    event: Option<ConsoleEvent>,
+    #[serde(default)]
+    p1: Vec<String>,
+    #[serde(default)]
+    p2: Vec<String>,
+    #[serde(default = "one")]
+    frames: usize,
+}
+
+fn one() -> usize {
+    1
+}
+
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|word| !word.is_empty())
+}
+
+fn parse_buttons<'a>(names: impl Iterator<Item = &'a str>) -> Result<JoypadButton, String> {
+    let mut buttons = JoypadButton::empty();
+    for name in names {
+        buttons |= JoypadButton::from_name(&name.to_ascii_uppercase())
+            .ok_or_else(|| format!("Unknown button: {}", name))?;
+    }
+    Ok(buttons)
+}
+
+/// Parses one line of an input script, or `None` for comments.
+///
+/// Text lines list player 1's buttons, then optionally `|` and player 2's, with an optional
+/// repeat count in front: `A RIGHT`, `START | A`, `60:` (nothing for 60 frames). A blank line
+/// is one frame with nothing pressed. `RESET` and `POWER` press the console's reset button
+/// and power cycle it before the next frame, and `CHECK <frame> <checksum>` is a movie's
+/// checksum to verify. Lines starting with `{` are JSON (see
+/// `JsonLine`) and lines starting with `[` are a JSON list of player 1's buttons.
+pub fn parse_line(line: &str) -> Result<Option<Line>, String> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return Ok(None);
+    }
+    if line.eq_ignore_ascii_case("reset") {
+        return Ok(Some(Line::Event(ConsoleEvent::Reset)));
+    }
+    if line.eq_ignore_ascii_case("power") {
+        return Ok(Some(Line::Event(ConsoleEvent::Power)));
+    }
+    if let Some(check) = line
+        .strip_prefix("CHECK ")
+        .or_else(|| line.strip_prefix("check "))
+    {
+        let invalid = || format!("Expected `CHECK <frame> <checksum>`, got {}", line);
+        let (frame, checksum) = check.trim().split_once(' ').ok_or_else(invalid)?;
+        let frame = frame.parse().map_err(|_| invalid())?;
+        let checksum = u32::from_str_radix(checksum.trim(), 16).map_err(|_| invalid())?;
+        return Ok(Some(Line::Check(frame, checksum)));
+    }
+    if line.starts_with('{') {
+        let json: JsonLine = serde_json::from_str(line).map_err(|e| e.to_string())?;
+        if let Some(event) = json.event {
+            if !json.p1.is_empty() || !json.p2.is_empty() {
+                return Err("Event lines can't press buttons".to_string());
+            }
+            return Ok(Some(Line::Event(event)));
+        }
+        let p1 = parse_buttons(json.p1.iter().map(String::as_str))?;
+        let p2 = parse_buttons(json.p2.iter().map(String::as_str))?;
+        return Ok(Some(Line::Input([p1, p2], json.frames)));
+    }
+    if line.starts_with('[') {
+        let names: Vec<String> = serde_json::from_str(line).map_err(|e| e.to_string())?;
+        let p1 = parse_buttons(names.iter().map(String::as_str))?;
+        return Ok(Some(Line::Input([p1, JoypadButton::empty()], 1)));
+    }
+
+    let (frames, buttons) = match line.split_once(':') {
+        Some((count, rest)) => (
+            count
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid repeat count: {}", count))?,
+            rest,
+        ),
+        None => (1, line),
+    };
+    let (p1, p2) = buttons.split_once('|').unwrap_or((buttons, ""));
+    Ok(Some(Line::Input(
+        [parse_buttons(words(p1))?, parse_buttons(words(p2))?],
+        frames,
+    )))
+}
+
+fn button_names(buttons: JoypadButton) -> String {
+    buttons
+        .iter_names()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Writes `frames` frames of `input` as a text line that `parse_line` reads back, e.g.
+/// `3: A RIGHT | B`.
+pub fn format_line(input: FrameInput, frames: usize) -> String {
+    let mut line = button_names(input[0]);
+    if !input[1].is_empty() {
+        line = format!("{} | {}", line, button_names(input[1]))
+            .trim_start()
+            .to_string();
+    }
+    if frames == 1 && !line.is_empty() {
+        line
+    } else {
+        format!("{}: {}", frames, line).trim_end().to_string()
+    }
+}
+
+/// Controller input read one frame at a time from a file or pipe, so other programs can play.
+/// Reads block, which keeps a driving program in lockstep with the emulator.
+pub struct InputScript {
+    reader: Box<dyn BufRead>,
+    line_number: usize,
+    current: FrameInput,
+    repeat: usize,
+    event: Option<ConsoleEvent>,
+    check: Option<(usize, u32)>,
+    finished: bool,
+}
+
+impl InputScript {
+    pub fn new(reader: impl BufRead + 'static) -> Self {
+        InputScript {
+            reader: Box::new(reader),
+            line_number: 0,
+            current: [JoypadButton::empty(); 2],
+            repeat: 0,
+            event: None,
+            check: None,
+            finished: false,
+        }
+    }
+
+    /// Opens a script file, or stdin for `-`.
+    pub fn open(path: &str) -> Result<Self, String> {
+        if path == "-" {
+            return Ok(InputScript::new(BufReader::new(std::io::stdin())));
+        }
+        let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        Ok(InputScript::new(BufReader::new(file)))
+    }
+
+    /// Input for the next frame, or `None` once the script has ended.
+    pub fn next_frame(&mut self) -> Result<Option<FrameInput>, String> {
+        while self.repeat == 0 {
+            if self.finished {
+                return Ok(None);
+            }
+            let mut line = String::new();
+            let read = self
+                .reader
+                .read_line(&mut line)
+                .map_err(|e| format!("Failed to read input script: {}", e))?;
+            if read == 0 {
+                self.finished = true;
+                return Ok(None);
+            }
+            self.line_number += 1;
+            match parse_line(&line)
+                .map_err(|e| format!("Input script line {}: {}", self.line_number, e))?
+            {
+                Some(Line::Input(input, frames)) => {
+                    self.current = input;
+                    self.repeat = frames;
+                }
+                Some(Line::Event(event)) => self.event = Some(event),
+                Some(Line::Check(frame, checksum)) => self.check = Some((frame, checksum)),
+                None => {}
+            }
+        }
+        self.repeat -= 1;
+        Ok(Some(self.current))
+    }
+
+    /// A reset or power cycle read since the last call, which should happen before the next
+    /// frame starts.
+    pub fn take_event(&mut self) -> Option<ConsoleEvent> {
+        self.event.take()
+    }
+
+    /// A checksum read since the last call, as (frame, checksum). It is read along with the
+    /// input for the frame after the one it's for.
+    pub fn take_check(&mut self) -> Option<(usize, u32)> {
+        self.check.take()
+    }
+
+    /// Sets `joypads` (indexed by player) to the next frame's input and returns any console
+    /// event to perform first. When the script has ended or is broken the buttons it held
+    /// are released and the joypads are left alone after that, so the keyboard takes over
+    /// again.
+    pub fn apply(&mut self, joypads: &mut [&mut Joypad]) -> Option<ConsoleEvent> {
+        match self.next_frame() {
+            Ok(Some(input)) => {
+                for (joypad, buttons) in joypads.iter_mut().zip(input) {
+                    joypad.set_buttons(buttons);
+                }
+            }
+            Ok(None) => self.release(joypads),
+            Err(e) => {
+                eprintln!("{}, ignoring the rest of it", e);
+                self.finished = true;
+                self.repeat = 0;
+                self.release(joypads);
+            }
+        }
+        self.take_event()
+    }
+
+    fn release(&mut self, joypads: &mut [&mut Joypad]) {
+        if self.current != [JoypadButton::empty(); 2] {
+            for joypad in joypads.iter_mut() {
+                joypad.set_buttons(JoypadButton::empty());
+            }
+            self.current = [JoypadButton::empty(); 2];
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let none = JoypadButton::empty();
+        assert_eq!(
+            parse_line("A right"),
+            Ok(Some(Line::Input(
+                [JoypadButton::A | JoypadButton::RIGHT, none],
+                1
+            )))
+        );
+        assert_eq!(
+            parse_line("30: START | B"),
+            Ok(Some(Line::Input(
+                [JoypadButton::START, JoypadButton::B],
+                30
+            )))
+        );
+        assert_eq!(
+            parse_line(r#"{"p2": ["UP"], "frames": 2}"#),
+            Ok(Some(Line::Input([none, JoypadButton::UP], 2)))
+        );
+        assert_eq!(
+            parse_line(r#"["a", "b"]"#),
+            Ok(Some(Line::Input(
+                [JoypadButton::A | JoypadButton::B, none],
+                1
+            )))
+        );
+        assert_eq!(parse_line(""), Ok(Some(Line::Input([none, none], 1))));
+        assert_eq!(parse_line("# comment"), Ok(None));
+        assert!(parse_line("JUMP").is_err());
+
+        assert_eq!(
+            parse_line("reset"),
+            Ok(Some(Line::Event(ConsoleEvent::Reset)))
+        );
+        assert_eq!(
+            parse_line(r#"{"event": "power"}"#),
+            Ok(Some(Line::Event(ConsoleEvent::Power)))
+        );
+        assert!(parse_line(r#"{"event": "reset", "p1": ["A"]}"#).is_err());
+        assert_eq!(
+            parse_line("CHECK 60 00c0ffee"),
+            Ok(Some(Line::Check(60, 0xc0ffee)))
+        );
+        assert!(parse_line("CHECK 60").is_err());
+    }
+
+    #[test]
+    fn test_format_line() {
+        let none = JoypadButton::empty();
+        for (input, frames, text) in [
+            ([JoypadButton::A | JoypadButton::RIGHT, none], 1, "A RIGHT"),
+            ([JoypadButton::START, JoypadButton::B], 30, "30: START | B"),
+            ([none, JoypadButton::UP], 2, "2: | UP"),
+            ([none, none], 1, "1:"),
+        ] {
+            assert_eq!(format_line(input, frames), text);
+            assert_eq!(parse_line(text), Ok(Some(Line::Input(input, frames))));
+        }
+    }
+
+    #[test]
+    fn test_script_repeats_and_ends() {
+        let mut script = InputScript::new("2: A\n# wait\n\nB\n".as_bytes());
+        let frames: Vec<JoypadButton> = std::iter::from_fn(|| script.next_frame().unwrap())
+            .map(|input| input[0])
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                JoypadButton::A,
+                JoypadButton::A,
+                JoypadButton::empty(),
+                JoypadButton::B
+            ]
+        );
+    }
+
+    #[test]
+    fn test_event_comes_with_the_next_frame() {
+        let mut joypad = Joypad::new();
+        let mut script = InputScript::new("A\nRESET\nB\n".as_bytes());
+        assert_eq!(script.apply(&mut [&mut joypad]), None);
+        assert_eq!(script.apply(&mut [&mut joypad]), Some(ConsoleEvent::Reset));
+        assert_eq!(joypad.buttons(), JoypadButton::B);
+        assert_eq!(script.apply(&mut [&mut joypad]), None);
+    }
+}
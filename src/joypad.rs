@@ -1,5 +1,7 @@
+use std::str::FromStr;
+
 bitflags! {
-    #[derive(Clone, Copy, Default)]
+    #[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
     pub struct JoypadButton: u8 {
         const A      = 0b00000001;
         const B      = 0b00000010;
@@ -12,6 +14,33 @@ bitflags! {
     }
 }
 
+/// What is plugged into a controller port.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControllerType {
+    #[default]
+    Joypad,
+    /// Nothing connected; reads return 0. Some games misbehave when they see a second pad.
+    Unplugged,
+    /// Bandai's floor mat, for World Class Track Meet, Dance Aerobics and the like
+    #[serde(rename = "powerpad")]
+    PowerPad,
+}
+
+impl FromStr for ControllerType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "joypad" => Ok(ControllerType::Joypad),
+            "unplugged" => Ok(ControllerType::Unplugged),
+            "powerpad" => Ok(ControllerType::PowerPad),
+            _ => Err(format!("Unknown controller: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Joypad {
     strobe: bool,
     button_index: u8,
@@ -52,4 +81,76 @@ impl Joypad {
     pub fn release(&mut self, button: JoypadButton) {
         self.button_status.remove(button);
     }
+
+    /// Replaces the set of held buttons.
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.button_status = buttons;
+    }
+
+    pub fn buttons(&self) -> JoypadButton {
+        self.button_status
+    }
+}
+
+/// Power Pad buttons 2, 1, 5, 9, 6, 10, 11 and 7 come out on D3 of the port, one per read
+const POWER_PAD_D3: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+/// and 4, 3, 12 and 8 on D4, which then reads 1
+const POWER_PAD_D4: [u8; 4] = [4, 3, 12, 8];
+
+/// The Power Pad: 12 buttons, numbered 1-12 left to right and top to bottom as on side B,
+/// read out two at a time over two data lines.
+#[derive(Clone, Default)]
+pub struct PowerPad {
+    strobe: bool,
+    index: u8,
+    /// Bit n - 1 is button n
+    buttons: u16,
+}
+
+impl PowerPad {
+    pub fn new() -> Self {
+        PowerPad::default()
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.strobe = value & 0x01 == 0x01;
+        if self.strobe {
+            self.index = 0;
+        }
+    }
+
+    pub fn read(&mut self) -> u8 {
+        let bit = |line: &[u8]| match line.get(self.index as usize) {
+            Some(&button) => (self.buttons >> (button - 1)) as u8 & 1,
+            None => 1,
+        };
+        let response = bit(&POWER_PAD_D3) << 3 | bit(&POWER_PAD_D4) << 4;
+        if !self.strobe && self.index < 8 {
+            self.index += 1;
+        }
+        response
+    }
+
+    pub fn set_buttons(&mut self, buttons: u16) {
+        self.buttons = buttons;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_power_pad_reads_two_lines() {
+        let mut pad = PowerPad::new();
+        // Buttons 1, 3 and 12
+        pad.set_buttons(0b1000_0000_0101);
+        pad.write(1);
+        pad.write(0);
+        let reads: Vec<u8> = (0..10).map(|_| pad.read()).collect();
+        assert_eq!(
+            reads,
+            [0x00, 0x18, 0x10, 0x00, 0x10, 0x10, 0x10, 0x10, 0x18, 0x18]
+        );
+    }
 }
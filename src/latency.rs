@@ -0,0 +1,169 @@
+use std::{cell::Cell, rc::Rc};
+
+use crate::{
+    bus::Bus,
+    cartridge::Rom,
+    cpu::CPU,
+    headless::frame_hash,
+    joypad::{Joypad, JoypadButton},
+    ppu::NesPPU,
+    region::Region,
+    render::{frame::Frame, Renderer},
+    save_state,
+};
+
+/// Frames the button is held each trial, for games that don't read the pads every frame
+const HOLD_FRAMES: usize = 4;
+/// Frames after the press to look for a change in before giving up
+const WINDOW_FRAMES: usize = 60;
+/// Frames between trials. Odd, so the press lands at a different point of games that only do
+/// some work every other frame.
+const TRIAL_SPACING: usize = 7;
+const SNAPSHOT_CRC: u32 = 0;
+
+/// What one press of the button did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trial {
+    /// Frame the button went down on
+    pub frame: usize,
+    /// Frames until the picture first differed from not pressing it, counting the frame the
+    /// button went down on as 1
+    pub screen: Option<usize>,
+    /// Same, for RAM
+    pub ram: Option<usize>,
+}
+
+/// Measures how many frames a game takes to react to `button`. Each trial saves a state,
+/// plays on without the press, rewinds and plays the same frames with it; the first frame
+/// where the picture or RAM differ between the two is the game's latency. The first trial
+/// starts after `warmup` frames, to get past the boot.
+pub fn measure(
+    rom: Rom,
+    region: Region,
+    button: JoypadButton,
+    warmup: usize,
+    trials: usize,
+) -> Result<Vec<Trial>, String> {
+    let held = Rc::new(Cell::new(JoypadButton::empty()));
+    let picture = Rc::new(Cell::new(0));
+    let (next_held, next_picture) = (Rc::clone(&held), Rc::clone(&picture));
+    let mut renderer = Renderer::new();
+    let mut frame = Frame::new();
+    let bus = Bus::try_new(rom, move |ppu: &NesPPU, joypads: &mut [Joypad; 2]| {
+        renderer.render(ppu, &mut frame);
+        next_picture.set(frame_hash(&frame));
+        joypads[0].set_buttons(next_held.get());
+    })
+    .map_err(|e| e.to_string())?;
+    let mut cpu = CPU::new(bus);
+    cpu.bus.set_region(region);
+    cpu.reset();
+
+    for _ in 0..warmup {
+        next_frame(&mut cpu, &picture)?;
+    }
+    let mut results = Vec::new();
+    for _ in 0..trials {
+        let state = save_state::save(&cpu, SNAPSHOT_CRC);
+        let joypads = cpu.bus.joypads().clone();
+        let mut baseline = Vec::with_capacity(WINDOW_FRAMES);
+        for _ in 0..WINDOW_FRAMES {
+            baseline.push(next_frame(&mut cpu, &picture)?);
+        }
+
+        save_state::load(&mut cpu, SNAPSHOT_CRC, &state)?;
+        let [mut p1, p2] = joypads.clone();
+        p1.set_buttons(button);
+        cpu.bus.set_joypads([p1, p2]);
+        let mut trial = Trial {
+            frame: cpu.bus.frame_count() + 1,
+            screen: None,
+            ram: None,
+        };
+        for (n, &(baseline_picture, baseline_ram)) in baseline.iter().enumerate() {
+            let frames = n + 1;
+            held.set(if frames < HOLD_FRAMES {
+                button
+            } else {
+                JoypadButton::empty()
+            });
+            let (picture, ram) = next_frame(&mut cpu, &picture)?;
+            if trial.screen.is_none() && picture != baseline_picture {
+                trial.screen = Some(frames);
+            }
+            if trial.ram.is_none() && ram != baseline_ram {
+                trial.ram = Some(frames);
+            }
+            if trial.screen.is_some() && trial.ram.is_some() {
+                break;
+            }
+        }
+        held.set(JoypadButton::empty());
+        results.push(trial);
+
+        // Back to the timeline without the press for the next trial
+        save_state::load(&mut cpu, SNAPSHOT_CRC, &state)?;
+        cpu.bus.set_joypads(joypads);
+        for _ in 0..TRIAL_SPACING {
+            next_frame(&mut cpu, &picture)?;
+        }
+    }
+    Ok(results)
+}
+
+/// Runs to the end of the next frame and returns the hash of its picture and the RAM checksum.
+fn next_frame(cpu: &mut CPU, picture: &Cell<u64>) -> Result<(u64, u32), String> {
+    let frame = cpu.bus.frame_count();
+    while cpu.bus.frame_count() == frame {
+        if !cpu.step() {
+            return Err(format!("The game hit a BRK on frame {}", frame + 1));
+        }
+    }
+    // Taken now rather than on the next step, so states saved here are where the frame ends
+    cpu.service_nmi();
+    Ok((picture.get(), cpu.bus.ram_checksum()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::{Console, Mirroring};
+
+    #[test]
+    fn test_input_read_each_frame_shows_up_in_ram() {
+        let mut prg_rom = vec![0xEA; 0x8000];
+        let program = [
+            0xA9, 0x80, 0x8D, 0x00, 0x20, // LDA #$80; STA $2000 (NMI on)
+            0x4C, 0x05, 0x80, // JMP $8005
+            // NMI: strobe the pad and store its first bit (A) at $10
+            0xA9, 0x01, 0x8D, 0x16, 0x40, // LDA #1; STA $4016
+            0xA9, 0x00, 0x8D, 0x16, 0x40, // LDA #0; STA $4016
+            0xAD, 0x16, 0x40, 0x85, 0x10, // LDA $4016; STA $10
+            0x40, // RTI
+        ];
+        prg_rom[..program.len()].copy_from_slice(&program);
+        prg_rom[0x7FFA..0x7FFE].copy_from_slice(&[0x08, 0x80, 0x00, 0x80]);
+        let rom = Rom {
+            prg_rom,
+            chr_rom: vec![0; 0x2000],
+            mapper: 0,
+            mirroring: Mirroring::HORIZONTAL,
+            region: None,
+            crc32: 0,
+            prg_crc32: 0,
+            chr_crc32: 0,
+            prg_ram_size: 0,
+            battery: false,
+            console: Console::Nes,
+        };
+
+        let trials = measure(rom, Region::NTSC, JoypadButton::A, 10, 3).unwrap();
+        assert_eq!(trials.len(), 3);
+        assert_eq!(trials[1].frame, trials[0].frame + TRIAL_SPACING);
+        for trial in trials {
+            assert_eq!(trial.ram, Some(1), "{:?}", trial);
+            // Nothing is ever drawn
+            assert_eq!(trial.screen, None);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+pub mod apu;
+pub mod audio;
+pub mod bus;
+pub mod cartridge;
+pub mod clip;
+pub mod config;
+pub mod crash_dump;
+pub mod cpu;
+pub mod debug_windows;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod frame_stats;
+pub mod headless;
+pub mod input;
+pub mod input_macro;
+pub mod input_script;
+pub mod joypad;
+pub mod latency;
+pub mod limiter;
+pub mod mapper;
+pub mod movie;
+pub mod opcodes;
+pub mod overrides;
+pub mod patch;
+pub mod paths;
+pub mod playlist;
+pub mod ppu;
+pub mod region;
+pub mod replay;
+pub mod render;
+pub mod save_state;
+pub mod screenshot;
+pub mod simple;
+pub mod sram;
+pub mod state_history;
+pub mod status;
+pub mod test_suite;
+pub mod tile_viewer;
+pub mod trace;
+pub mod trace_format;
+pub mod video_recorder;
+pub mod vs_system;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate bitflags;
@@ -0,0 +1,248 @@
+use std::time::{Duration, Instant};
+
+/// Slowest and fastest emulation speed, in percent of the console's real speed
+pub const MIN_SPEED: u32 = 25;
+pub const MAX_SPEED: u32 = 400;
+/// How much the speed hotkeys change the speed by
+pub const SPEED_STEP: u32 = 25;
+/// How far apart the display's refresh rate and the game's frame rate can be for vsync to
+/// keep time on its own. NTSC's 60.1 frames a second on a 60Hz display is 0.2% off.
+const VSYNC_TOLERANCE: f64 = 0.02;
+/// The end of each wait is spent spinning rather than sleeping, as sleeps can overshoot by
+/// about this much
+const SPIN_TIME: Duration = Duration::from_millis(1);
+
+/// Keeps emulation at the console's frame rate, scaled by a speed percentage, or lets it run
+/// as fast as it goes while fast-forwarding. With vsync, presenting a frame waits for the
+/// display, which keeps time by itself when the two rates match; the timer is the fallback
+/// for when they don't, or the display stops waiting (a minimized window).
+pub struct FrameLimiter {
+    frame_rate: f64,
+    speed: u32,
+    fast_forward: bool,
+    /// When the current schedule started, and how many frames it has been given since.
+    /// Deadlines are counted from the start, so the fraction of a nanosecond each frame
+    /// takes past a whole number doesn't get lost.
+    schedule: Option<(Instant, u64)>,
+    /// The display's refresh rate when presenting waits for its vertical blank
+    display_rate: Option<f64>,
+    last_present: Option<Instant>,
+}
+
+impl FrameLimiter {
+    pub fn new(frame_rate: f64, speed: u32) -> Self {
+        FrameLimiter {
+            frame_rate,
+            speed: speed.clamp(MIN_SPEED, MAX_SPEED),
+            fast_forward: false,
+            schedule: None,
+            display_rate: None,
+            last_present: None,
+        }
+    }
+
+    /// Sets the refresh rate of a display that presenting waits on, or `None` without vsync.
+    pub fn set_vsync(&mut self, display_rate: Option<f64>) {
+        self.display_rate = display_rate;
+    }
+
+    /// Whether presenting frames keeps time by itself: vsync is on and the display refreshes
+    /// about as often as frames are due.
+    pub fn vsync_paced(&self) -> bool {
+        let frames_per_second = self.frame_rate * self.speed as f64 / 100.0;
+        !self.fast_forward
+            && self
+                .display_rate
+                .is_some_and(|rate| (rate / frames_per_second - 1.0).abs() < VSYNC_TOLERANCE)
+    }
+
+    /// Whether to draw a frame finished at `now`. Only matters with vsync while frames come
+    /// faster than the display refreshes, fast-forwarding or sped up, when presenting more
+    /// than once a refresh would slow emulation down to the display's rate.
+    pub fn present_due(&self, now: Instant) -> bool {
+        let frames_per_second = self.frame_rate * self.speed as f64 / 100.0;
+        match (self.display_rate, self.last_present) {
+            (Some(rate), Some(last))
+                if self.fast_forward || (frames_per_second > rate && !self.vsync_paced()) =>
+            {
+                now.saturating_duration_since(last).as_secs_f64() >= 1.0 / rate
+            }
+            _ => true,
+        }
+    }
+
+    /// Call after presenting a frame.
+    pub fn presented(&mut self, now: Instant) {
+        self.last_present = Some(now);
+    }
+
+    pub fn speed(&self) -> u32 {
+        self.speed
+    }
+
+    /// Sets the speed in percent, clamped to `MIN_SPEED..=MAX_SPEED`. Returns the new speed.
+    pub fn set_speed(&mut self, speed: u32) -> u32 {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        self.schedule = None;
+        self.speed
+    }
+
+    pub fn fast_forward(&self) -> bool {
+        self.fast_forward
+    }
+
+    /// Stops waiting between frames while `on`, whatever the speed is.
+    pub fn set_fast_forward(&mut self, on: bool) {
+        self.fast_forward = on;
+        self.schedule = None;
+    }
+
+    /// How many times the console's speed the game runs at, to play its sound that much
+    /// faster. `None` while fast-forwarding, which has no steady speed to play it at.
+    pub fn sound_speed(&self) -> Option<f64> {
+        (!self.fast_forward).then(|| self.speed as f64 / 100.0)
+    }
+
+    /// For switching between NTSC and PAL games.
+    pub fn set_frame_rate(&mut self, frame_rate: f64) {
+        self.frame_rate = frame_rate;
+        self.schedule = None;
+    }
+
+    /// How long one frame lasts at the current speed.
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs_f64(100.0 / (self.frame_rate * self.speed as f64))
+    }
+
+    /// How long to wait at `now` until the next frame is due. Frames are scheduled from the
+    /// previous deadline rather than from `now`, so sleeps that overshoot don't add up. After
+    /// falling more than a frame behind (a slow frame, the debugger pausing) the schedule
+    /// starts over instead of rushing to catch up. When vsync keeps time, only running more
+    /// than half a frame ahead waits, for a display that refreshes a little faster than the
+    /// game or stopped waiting.
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        if self.fast_forward {
+            return Duration::ZERO;
+        }
+        let frame = self.frame_duration();
+        let (start, frames) = match self.schedule {
+            Some((start, frames)) if now < self.deadline(start, frames) + frame => (start, frames),
+            _ => (now, 0),
+        };
+        self.schedule = Some((start, frames + 1));
+        let delay = self.deadline(start, frames).saturating_duration_since(now);
+        if self.vsync_paced() && delay < frame / 2 {
+            return Duration::ZERO;
+        }
+        delay
+    }
+
+    /// When frame `frames` of a schedule that started at `start` is due.
+    fn deadline(&self, start: Instant, frames: u64) -> Instant {
+        start
+            + Duration::from_secs_f64(frames as f64 * 100.0 / (self.frame_rate * self.speed as f64))
+    }
+
+    /// Waits until the next frame is due, sleeping most of the way and spinning the rest.
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        let delay = self.delay(now);
+        if delay > SPIN_TIME {
+            std::thread::sleep(delay - SPIN_TIME);
+        }
+        while Instant::now() < now + delay {
+            std::thread::yield_now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_speed_scales_frame_time() {
+        let mut limiter = FrameLimiter::new(50.0, 100);
+        assert_eq!(limiter.frame_duration(), Duration::from_millis(20));
+        limiter.set_speed(200);
+        assert_eq!(limiter.frame_duration(), Duration::from_millis(10));
+        assert_eq!(limiter.sound_speed(), Some(2.0));
+        assert_eq!(limiter.set_speed(1000), MAX_SPEED);
+        assert_eq!(limiter.set_speed(0), MIN_SPEED);
+    }
+
+    #[test]
+    fn test_delay_keeps_schedule() {
+        let mut limiter = FrameLimiter::new(50.0, 100);
+        let start = Instant::now();
+        assert_eq!(limiter.delay(start), Duration::ZERO);
+        // A frame that took 5ms waits out the other 15
+        let ms = Duration::from_millis;
+        assert_eq!(limiter.delay(start + ms(5)), ms(15));
+        // Waking up 2ms late leaves 2ms less for the next frame
+        assert_eq!(limiter.delay(start + ms(22)), ms(18));
+        // Far behind: start over without waiting
+        assert_eq!(limiter.delay(start + ms(500)), Duration::ZERO);
+        assert_eq!(limiter.delay(start + ms(505)), ms(15));
+
+        limiter.set_fast_forward(true);
+        assert_eq!(limiter.delay(start + ms(506)), Duration::ZERO);
+        assert_eq!(limiter.sound_speed(), None);
+        limiter.set_fast_forward(false);
+        assert_eq!(limiter.delay(start + ms(507)), Duration::ZERO);
+        assert_eq!(limiter.delay(start + ms(508)), ms(19));
+    }
+
+    #[test]
+    fn test_schedule_keeps_fractions() {
+        let mut limiter = FrameLimiter::new(60.0988, 100);
+        let start = Instant::now();
+        // 16639267ns, where a frame is 16639267.34
+        let frame = limiter.frame_duration();
+        for n in 0..6000 {
+            limiter.delay(start + frame * n);
+        }
+        // Adding up whole nanoseconds, frame 6000 would be due now, but the fractions make up
+        // two microseconds more
+        let delay = limiter.delay(start + frame * 6000);
+        assert!(delay > Duration::from_micros(2) && delay < Duration::from_micros(3));
+    }
+
+    #[test]
+    fn test_vsync_keeps_time_at_matching_rates() {
+        let mut limiter = FrameLimiter::new(50.0, 100);
+        limiter.set_vsync(Some(60.0));
+        assert!(!limiter.vsync_paced());
+        limiter.set_vsync(Some(50.5));
+        assert!(limiter.vsync_paced());
+
+        // Presenting took most of the frame: no need to wait out the rest
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        assert_eq!(limiter.delay(start), Duration::ZERO);
+        assert_eq!(limiter.delay(start + ms(15)), Duration::ZERO);
+        // The display stopped waiting, so the timer does
+        assert_eq!(limiter.delay(start + ms(16)), ms(24));
+        // At double speed the display is too slow to keep time
+        limiter.set_speed(200);
+        assert!(!limiter.vsync_paced());
+
+        // Fast-forwarding presents at most once a refresh
+        limiter.set_fast_forward(true);
+        assert!(limiter.present_due(start));
+        limiter.presented(start);
+        assert!(!limiter.present_due(start + ms(10)));
+        assert!(limiter.present_due(start + ms(20)));
+        limiter.set_vsync(None);
+        assert!(limiter.present_due(start + ms(10)));
+        // Sped up past the display's rate, frames are presented at most once a refresh too
+        let mut limiter = FrameLimiter::new(50.0, 200);
+        limiter.set_vsync(Some(50.5));
+        limiter.presented(start);
+        assert!(!limiter.present_due(start + ms(10)));
+        assert!(limiter.present_due(start + ms(20)));
+        // Slowed down, every frame is
+        limiter.set_speed(50);
+        assert!(limiter.present_due(start + ms(10)));
+    }
+}
@@ -1,112 +1,2883 @@
-pub mod bus;
-pub mod cartridge;
-pub mod cpu;
-pub mod opcodes;
-pub mod ppu;
-pub mod render;
-pub mod tile_viewer;
-pub mod trace;
-pub mod joypad;
-
-#[macro_use]
-extern crate lazy_static;
-
-#[macro_use]
-extern crate bitflags;
-
-use std::collections::HashMap;
-
-use bus::Bus;
-use cartridge::Rom;
-use cpu::CPU;
-use joypad::{JoypadButton, Joypad};
-use ppu::NesPPU;
-use render::frame::Frame;
-use sdl2::{event::Event, keyboard::Keycode, pixels::PixelFormatEnum};
-
-fn keymap() -> HashMap<Keycode, JoypadButton> {
-    let mut keymap = HashMap::new();
-    keymap.insert(Keycode::W, joypad::JoypadButton::UP);
-    keymap.insert(Keycode::A, joypad::JoypadButton::LEFT);
-    keymap.insert(Keycode::S, joypad::JoypadButton::DOWN);
-    keymap.insert(Keycode::D, joypad::JoypadButton::RIGHT);
-    keymap.insert(Keycode::Space, joypad::JoypadButton::SELECT);
-    keymap.insert(Keycode::Return, joypad::JoypadButton::START);
-    keymap.insert(Keycode::Num1, joypad::JoypadButton::A);
-    keymap.insert(Keycode::Num2, joypad::JoypadButton::B);
-    keymap
+use std::cell::{Cell, RefCell};
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use rand::Rng;
+use rust_nes::audio::output::AudioOutput;
+use rust_nes::bus::Bus;
+use rust_nes::cartridge::{nes2_ram_size, Console, Rom, VsPpu};
+use rust_nes::clip::{self, ClipRecorder};
+use rust_nes::config::{
+    AudioConfig, Config, ConfigWatcher, MacroConfig, RecentRoms, RecordingConfig, ScaleFilter,
+    ScaleMode, StatesConfig, VideoConfig, CONFIG_FILE_NAME, SAMPLE_RATES,
+};
+use rust_nes::cpu::{Mem, CPU};
+use rust_nes::crash_dump::{self, TraceLog};
+use rust_nes::debug_windows::{DebugView, DebugWindows};
+use rust_nes::debugger::Debugger;
+use rust_nes::disasm;
+use rust_nes::error::{CpuSnapshot, EmulationError};
+use rust_nes::frame_stats::{FpsMeter, FrameStats};
+use rust_nes::headless;
+use rust_nes::input::{self, InputMap, InputSource, Rebinding};
+use rust_nes::input_macro::{Macros, MAX_MACRO_FRAMES};
+use rust_nes::input_script::{ConsoleEvent, InputScript};
+use rust_nes::joypad::{ControllerType, Joypad, JoypadButton};
+use rust_nes::latency;
+use rust_nes::limiter::{FrameLimiter, SPEED_STEP};
+use rust_nes::mapper;
+use rust_nes::movie::{MovieChecker, MovieRecorder};
+use rust_nes::overrides::{GameOverride, OverrideDb, OVERRIDES_FILE_NAME};
+use rust_nes::patch;
+use rust_nes::paths::{self, Paths};
+use rust_nes::playlist::Playlist;
+use rust_nes::ppu::NesPPU;
+use rust_nes::region::{self, Region};
+use rust_nes::render::palette::{self, Colors, PaletteName};
+use rust_nes::render::{
+    self,
+    crt::{self, CrtFilter, CrtPreset},
+    error_screen::{error_screen, message_screen, rom_picker, state_browser, SlotPreview},
+    frame::Frame,
+    osd::Osd,
+    overlay, Renderer,
+};
+use rust_nes::replay::ReplayBuffer;
+use rust_nes::save_state;
+use rust_nes::screenshot;
+use rust_nes::simple;
+use rust_nes::sram;
+use rust_nes::state_history::{self, UndoLoad};
+use rust_nes::status::{self, StatusHub};
+use rust_nes::test_suite::{self, Outcome};
+use rust_nes::tile_viewer::{self, SheetPalette};
+use rust_nes::trace_format::{self, TraceFormat};
+use rust_nes::video_recorder::{AudioFormat, VideoRecorder};
+use sdl2::controller::GameController;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Texture, WindowCanvas};
+use sdl2::video::FullscreenType;
+use sdl2::{EventPump, GameControllerSubsystem, Sdl};
+
+#[derive(Parser)]
+#[command(about = "NES emulator")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to an iNES ROM, or a directory of them [default: pick one of the recent ROMs]
+    rom: Option<String>,
+
+    /// More ROMs or directories to switch to while playing, with Page Up and Page Down
+    #[arg(value_name = "MORE")]
+    more_roms: Vec<String>,
+
+    /// IPS or BPS patch to apply to the ROM [default: <rom name>.ips or .bps next to the ROM]
+    #[arg(long, value_name = "FILE")]
+    patch: Option<PathBuf>,
+
+    /// Force the TV system (ntsc, pal or dendy) instead of detecting it from the header, the
+    /// overrides and the file name [config: emulation.region]
+    #[arg(long)]
+    region: Option<Region>,
+
+    /// Directory holding config.toml [default: platform config dir]
+    #[arg(long)]
+    config_dir: Option<PathBuf>,
+
+    /// Directory for saves, states and screenshots, overriding the config file
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
+    /// Print where config and save files are kept, then exit
+    #[arg(long)]
+    show_paths: bool,
+
+    /// Run a tutorial-style toy program, like bins/snake.nes or a bare program to load at
+    /// $0600, on a 32x32 screen with a random number at $FE and the last key at $FF
+    #[arg(long, requires = "rom")]
+    simple: bool,
+
+    /// Read debugger commands (break, step, mem, reg, ...) from stdin while the game runs
+    #[arg(long)]
+    debug: bool,
+
+    /// Run this many frames without a window, print the final frame's hash and exit
+    #[arg(long, value_name = "N", requires = "rom")]
+    frames: Option<usize>,
+
+    /// With --frames, which frame hashes to print
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "all", requires = "frames")]
+    hash: Option<HashOutput>,
+
+    /// Read controller input for each frame from FILE, or stdin for -. One line per frame,
+    /// e.g. `A RIGHT`, `START | B` for both players, `60:` to wait 60 frames, or JSON like
+    /// {"p1": ["A"], "frames": 10}
+    #[arg(long, value_name = "FILE")]
+    input_script: Option<String>,
+
+    /// Record the controllers to FILE as an input script, with RAM checksums that
+    /// --input-script checks to find where a replay desyncs
+    #[arg(long, value_name = "FILE", conflicts_with = "load_state")]
+    record_movie: Option<PathBuf>,
+
+    /// On exit, print how long frames took to emulate and present, and write each frame's
+    /// times to CSV if given. Home prints them while playing
+    #[arg(long, value_name = "CSV", num_args = 0..=1)]
+    frame_stats: Option<Option<PathBuf>>,
+
+    /// Flash the screen white on the frame after a player 1 button goes down, to film the
+    /// controller and screen together and count the frames the frontend and display add
+    #[arg(long)]
+    latency_flash: bool,
+
+    /// What's plugged into controller port 2: joypad, unplugged or powerpad [default: joypad,
+    /// or what the game's override says]
+    #[arg(long, value_name = "TYPE")]
+    port2: Option<ControllerType>,
+
+    /// Vs. System DIP switches to turn on, e.g. 1,5. What they do depends on the game
+    #[arg(long, value_name = "N,...", value_delimiter = ',',
+          value_parser = clap::value_parser!(u8).range(1..=8))]
+    dip_switches: Vec<u8>,
+
+    /// Copy the ROM's battery save to FILE, e.g. for another emulator or a flash cart, and exit
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "import_sram",
+        requires = "rom"
+    )]
+    export_sram: Option<PathBuf>,
+
+    /// Replace the ROM's battery save with FILE and exit
+    #[arg(long, value_name = "FILE", requires = "rom")]
+    import_sram: Option<PathBuf>,
+
+    /// Start from a save state or the state in a crash dump, to reproduce a crash
+    #[arg(long, value_name = "FILE")]
+    load_state: Option<PathBuf>,
+
+    #[command(flatten)]
+    video: VideoArgs,
+
+    #[command(flatten)]
+    audio: AudioArgs,
+
+    #[command(flatten)]
+    states: StatesArgs,
+}
+
+impl Args {
+    /// The ROM from the command line. Everything but the window requires one, so elsewhere
+    /// it's always there.
+    fn rom(&self) -> &str {
+        self.rom.as_deref().unwrap_or_default()
+    }
+
+    /// Whether `rom_path` is the ROM from the command line, which options like --patch are
+    /// for, rather than one switched to or dropped on the window later.
+    fn is_command_line_rom(&self, rom_path: &str) -> bool {
+        self.rom.as_deref() == Some(rom_path)
+    }
+}
+
+/// Display settings that override the `[video]` section of config.toml for this run.
+#[derive(clap::Args, Clone)]
+struct VideoArgs {
+    /// Window size as a multiple of 256x240 [config: video.scale, default 3]
+    #[arg(long)]
+    scale: Option<f32>,
+
+    /// Start in fullscreen [config: video.fullscreen]
+    #[arg(long)]
+    fullscreen: bool,
+
+    /// Don't wait for the display's vertical blank to show frames [config: video.vsync]
+    #[arg(long)]
+    no_vsync: bool,
+
+    /// Scaling filter, nearest or linear [config: video.filter]
+    #[arg(long)]
+    filter: Option<ScaleFilter>,
+
+    /// How the picture fits the window: fit, integer (whole multiples only) or aspect (8:7
+    /// pixels) [config: video.scale_mode]
+    #[arg(long)]
+    scale_mode: Option<ScaleMode>,
+
+    /// Color palette: default, rgb, fceux, sony-cxa, grayscale, adjusted for color blindness
+    /// with deuteranopia, protanopia or tritanopia, or a .pal file [config: video.palette]
+    #[arg(long, value_parser = parse_palette)]
+    palette: Option<PaletteArg>,
+
+    /// CRT look: off, scanlines, grille or curved [config: video.crt]
+    #[arg(long)]
+    crt: Option<CrtPreset>,
+}
+
+#[derive(Clone)]
+enum PaletteArg {
+    Named(PaletteName),
+    File(PathBuf),
+}
+
+fn parse_palette(text: &str) -> Result<PaletteArg, String> {
+    match text.parse() {
+        Ok(palette) => Ok(PaletteArg::Named(palette)),
+        Err(_) if text.to_ascii_lowercase().ends_with(".pal") => Ok(PaletteArg::File(text.into())),
+        Err(e) => Err(e),
+    }
+}
+
+impl VideoArgs {
+    fn apply(&self, video: &mut VideoConfig) {
+        if let Some(scale) = self.scale {
+            video.scale = scale;
+        }
+        video.fullscreen |= self.fullscreen;
+        video.vsync &= !self.no_vsync;
+        if let Some(filter) = self.filter {
+            video.filter = filter;
+        }
+        if let Some(scale_mode) = self.scale_mode {
+            video.scale_mode = scale_mode;
+        }
+        match &self.palette {
+            Some(PaletteArg::Named(palette)) => video.palette = *palette,
+            Some(PaletteArg::File(path)) => {
+                video.palette = PaletteName::File;
+                video.palette_file = Some(path.clone());
+            }
+            None => {}
+        }
+        if let Some(crt) = self.crt {
+            video.crt = crt;
+        }
+    }
+}
+
+/// Sound settings that override the `[audio]` section of config.toml for this run.
+#[derive(clap::Args, Clone)]
+struct AudioArgs {
+    /// Output sample rate, 44100, 48000 or 96000 [config: audio.sample_rate, default 48000]
+    #[arg(long, value_name = "HZ", value_parser = parse_sample_rate)]
+    sample_rate: Option<u32>,
+
+    /// Frames the audio device plays at a time, 64 to 8192. Smaller is less lag, bigger
+    /// crackles less on a busy machine [config: audio.buffer_size, default 1024]
+    #[arg(long, value_name = "FRAMES",
+          value_parser = clap::value_parser!(u16).range(AUDIO_BUFFER_SIZES))]
+    audio_buffer: Option<u16>,
+
+    /// Play the APU's output as is, without the console's high and low-pass filters
+    /// [config: audio.high_pass, audio.low_pass]
+    #[arg(long)]
+    no_audio_filters: bool,
+
+    /// Play in stereo, with the channels panned [config: audio.stereo, audio.pan]
+    #[arg(long)]
+    stereo: bool,
+}
+
+impl AudioArgs {
+    fn apply(&self, audio: &mut AudioConfig) {
+        if let Some(sample_rate) = self.sample_rate {
+            audio.sample_rate = sample_rate;
+        }
+        if let Some(buffer_size) = self.audio_buffer {
+            audio.buffer_size = buffer_size;
+        }
+        if self.no_audio_filters {
+            audio.high_pass.clear();
+            audio.low_pass = 0.0;
+        }
+        audio.stereo |= self.stereo;
+    }
+}
+
+/// Save state settings that override the `[states]` section of config.toml for this run.
+#[derive(clap::Args, Clone)]
+struct StatesArgs {
+    /// Save the game's state on exit and offer to resume it the next time the same ROM is
+    /// opened [config: states.auto_save]
+    #[arg(long, conflicts_with = "no_auto_save")]
+    auto_save: bool,
+
+    /// Don't save on exit or offer to resume, whatever the config says
+    #[arg(long)]
+    no_auto_save: bool,
+}
+
+impl StatesArgs {
+    fn apply(&self, states: &mut StatesConfig) {
+        states.auto_save = (states.auto_save || self.auto_save) && !self.no_auto_save;
+    }
+}
+
+fn parse_sample_rate(text: &str) -> Result<u32, String> {
+    text.parse()
+        .ok()
+        .filter(|rate| SAMPLE_RATES.contains(rate))
+        .ok_or_else(|| format!("has to be one of {:?}", SAMPLE_RATES))
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Write a copy of a ROM with its header corrected from the override database
+    FixHeader {
+        rom: PathBuf,
+        /// Where to write the fixed ROM [default: <rom name>.fixed.nes next to it]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Print what a ROM's header says, its CRC32s and whether its mapper is supported
+    Info { rom: PathBuf },
+    /// Disassemble a ROM's PRG into labeled 6502 assembly
+    Disasm {
+        rom: PathBuf,
+        /// Where to write the assembly [default: stdout]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Code/data log from FCEUX or Mesen, to tell code from data
+        #[arg(long, value_name = "FILE")]
+        cdl: Option<PathBuf>,
+    },
+    /// Show a ROM's tiles, or export its pattern tables as PNG tile sheets
+    Tiles {
+        rom: PathBuf,
+        /// Pattern table to show in the viewer (0 or 1)
+        #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=1))]
+        bank: u8,
+        /// Write every pattern table as a 16x16 tile sheet to DIR instead of opening the viewer
+        #[arg(long, value_name = "DIR")]
+        export: Option<PathBuf>,
+        /// Colors for exported sheets: debug, a game palette (bg0-bg3, sp0-sp3) or four NES
+        /// colors in hex like 0F,16,27,30
+        #[arg(long, default_value = "debug", value_parser = SheetPalette::parse)]
+        palette: SheetPalette,
+        /// Run the game this many frames before exporting, for CHR RAM and game palettes
+        /// [default: 120 with a game palette, otherwise 0]
+        #[arg(long, value_name = "N")]
+        frames: Option<usize>,
+    },
+    /// Rewrite a CPU trace log from this emulator, FCEUX or Mesen in one of their layouts,
+    /// to diff it against another emulator's
+    ConvertTrace {
+        /// Trace log in any of the layouts, or - for stdin
+        log: PathBuf,
+        /// Layout to write: nestest (this emulator's), fceux or mesen
+        #[arg(long)]
+        to: TraceFormat,
+        /// Where to write the converted log [default: stdout]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Leave out the disassembly, which emulators write differently, so only addresses,
+        /// bytes and registers are compared
+        #[arg(long)]
+        no_disassembly: bool,
+    },
+    /// Measure how many frames a game takes to react to a button, by pressing it and
+    /// comparing with the same frames played without the press
+    Latency {
+        rom: PathBuf,
+        /// Player 1 button to press
+        #[arg(long, default_value = "START", value_parser = parse_button)]
+        button: JoypadButton,
+        /// Frames to run before the first press, to get to where the game takes input
+        #[arg(long, value_name = "N", default_value_t = 180)]
+        warmup: usize,
+        /// Presses to measure, a few frames apart
+        #[arg(long, value_name = "N", default_value_t = 5)]
+        trials: usize,
+    },
+    /// Run every .nes file in a directory without a window and report which tests pass
+    TestSuite {
+        dir: PathBuf,
+        /// Give up on a ROM that hasn't reported a result after this many frames
+        #[arg(long, value_name = "N", default_value_t = 1800)]
+        frames: usize,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum HashOutput {
+    /// One line per frame
+    All,
+    /// Only the last frame
+    Final,
 }
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() > 1 {
-        run(&args[1]);
-    } else {
-        run("bins/pacman.nes");
+    let args = Args::parse();
+
+    let config_dir = args
+        .config_dir
+        .clone()
+        .unwrap_or_else(Paths::default_config_dir);
+    let config_file = config_dir.join(CONFIG_FILE_NAME);
+    let config = Config::load(&config_file).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let paths = Paths::resolve(config_dir, &config.paths, args.data_dir.clone());
+
+    if args.show_paths {
+        println!("config:      {}", paths.config_file().display());
+        println!("saves:       {}", paths.saves.display());
+        println!("states:      {}", paths.states.display());
+        println!("screenshots: {}", paths.screenshots.display());
+        println!("crashes:     {}", paths.crashes.display());
+        println!("recent:      {}", paths.recent.display());
+        return;
     }
+
+    let mut script = args.input_script.as_deref().map(|path| {
+        if path == "-" && args.debug {
+            eprintln!("--debug already reads commands from stdin, use a file for --input-script");
+            std::process::exit(1);
+        }
+        InputScript::open(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    });
+
+    let overrides =
+        OverrideDb::load(&paths.config_dir.join(OVERRIDES_FILE_NAME)).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+
+    if args.simple {
+        if let Err(e) = run_simple(args.rom()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(command) = &args.command {
+        let result = match command {
+            Command::FixHeader { rom, output } => fix_header(rom, output.as_deref(), &overrides),
+            Command::Info { rom } => rom_info(rom, &overrides),
+            Command::Disasm { rom, output, cdl } => {
+                disassemble(rom, output.as_deref(), cdl.as_deref())
+            }
+            Command::Tiles {
+                rom,
+                export: Some(dir),
+                palette,
+                frames,
+                ..
+            } => export_tiles(rom, dir, *palette, *frames, args.region),
+            Command::Tiles { rom, bank, .. } => {
+                tile_viewer::display_tile_bank(&rom.to_string_lossy(), *bank as usize);
+                Ok(())
+            }
+            Command::ConvertTrace {
+                log,
+                to,
+                output,
+                no_disassembly,
+            } => convert_trace(log, *to, output.as_deref(), !no_disassembly),
+            Command::Latency {
+                rom,
+                button,
+                warmup,
+                trials,
+            } => measure_latency(rom, *button, *warmup, *trials, args.region),
+            Command::TestSuite { dir, frames } => run_test_suite(dir, *frames),
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.frames.is_some() || args.export_sram.is_some() || args.import_sram.is_some() {
+        let (cartridge, _) = load_rom(args.rom(), args.patch.as_deref(), &overrides)
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+        let result = if let Some(frames) = args.frames {
+            run_headless(&args, cartridge, frames, script.as_mut())
+        } else {
+            transfer_sram(&args, &paths, &cartridge)
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    run(&args, &config, &paths, &overrides, script);
 }
-fn run(rom_path: &str) {
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem
-        .window("Tile Viewer", (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
-        .position_centered()
-        .build()
-        .unwrap();
 
-    let mut canvas = window.into_canvas().build().unwrap();
-    let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(3.0, 3.0).unwrap();
+/// Reads the ROM and applies any override the database has for it.
+fn load_rom(
+    path: &str,
+    patch: Option<&Path>,
+    overrides: &OverrideDb,
+) -> Result<(Rom, Option<GameOverride>), EmulationError> {
+    let invalid = |reason: String| EmulationError::InvalidRom {
+        path: path.to_string(),
+        reason,
+    };
+    let mut raw_rom = std::fs::read(path).map_err(|e| invalid(e.to_string()))?;
+    let patch = patch
+        .map(Path::to_path_buf)
+        .or_else(|| patch::find_patch(Path::new(path)));
+    if let Some(patch) = patch {
+        let patched = std::fs::read(&patch)
+            .map_err(|e| e.to_string())
+            .and_then(|data| patch::apply(&raw_rom, &data))
+            .map_err(|e| invalid(format!("Can't apply {}: {}", patch.display(), e)))?;
+        eprintln!("Applied {}", patch.display());
+        raw_rom = patched;
+    }
+    let mut cartridge = Rom::new(&raw_rom).map_err(invalid)?;
 
-    let creator = canvas.texture_creator();
-    let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
-        .unwrap();
+    eprintln!(
+        "Loaded {} (CRC32 {:08X}, PRG {:08X}, CHR {:08X})",
+        path, cartridge.crc32, cartridge.prg_crc32, cartridge.chr_crc32
+    );
+    let game = overrides.lookup(cartridge.crc32).cloned();
+    if let Some(game) = &game {
+        eprintln!("Applying overrides for {}", game.name);
+        let fixes = game.header_fixes(&cartridge);
+        for fix in &fixes {
+            eprintln!("  bad header, {}", fix);
+        }
+        if !fixes.is_empty() {
+            eprintln!("  `fix-header {}` writes a corrected copy", path);
+        }
+        game.apply(&mut cartridge);
+    }
+    Ok((cartridge, game))
+}
 
-    // load snake.nes
-    let raw_rom: Vec<u8> = std::fs::read(rom_path).expect("Failed to read ROM");
-    let cartridge = Rom::new(&raw_rom).expect("Failed to load ROM");
+/// Runs a toy program with the memory-mapped screen, RNG and keyboard from the 6502 tutorials
+/// instead of the PPU and joypads.
+fn run_simple(rom_path: &str) -> Result<(), String> {
+    let raw = std::fs::read(rom_path).map_err(|e| format!("Failed to read {}: {}", rom_path, e))?;
+    let raw_program = simple::is_raw_program(&raw);
+    let rom = if raw_program {
+        simple::program_rom()
+    } else {
+        Rom::new(&raw)?
+    };
 
-    let mut frame = Frame::new();
+    let scale = 10;
+    let size = simple::SCREEN_SIZE as u32;
+    let sdl_context = sdl2::init()?;
+    let window = sdl_context
+        .video()?
+        .window("RustNES", size * scale, size * scale)
+        .position_centered()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+    canvas.set_scale(scale as f32, scale as f32)?;
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_streaming(PixelFormatEnum::RGB24, size, size)
+        .map_err(|e| e.to_string())?;
+    let mut event_pump = sdl_context.event_pump()?;
 
-    let bus = Bus::new(cartridge, move |ppu: &NesPPU, joypad: &mut Joypad| {
-        render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
+    let bus = Bus::try_new(rom, |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {})
+        .map_err(|e| e.to_string())?;
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    if raw_program {
+        simple::load_program(&mut cpu, &raw)?;
+    }
 
-        canvas.copy(&texture, None, None).unwrap();
-        canvas.present();
+    let mut frame = Frame::with_size(simple::SCREEN_SIZE, simple::SCREEN_SIZE);
+    let mut rng = rand::thread_rng();
+    let mut randomize = |cpu: &mut CPU| cpu.mem_write(simple::RNG, rng.gen_range(1..16));
+    let mut instructions = 0;
+    while cpu.step_with_callback(&mut randomize) {
+        instructions += 1;
+        if instructions % simple::INSTRUCTIONS_PER_FRAME != 0 {
+            continue;
+        }
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => {
-                    std::process::exit(0);
-                }
+                } => return Ok(()),
                 Event::KeyDown {
-                    keycode: Some(keycode),
-                    ..
+                    keycode: Some(key), ..
                 } => {
-                    if let Some(button) = keymap().get(&keycode) {
-                        joypad.press(*button);
+                    if let Some(code) = simple::key_code(key) {
+                        cpu.mem_write(simple::LAST_KEY, code);
                     }
                 }
-                Event::KeyUp {
-                    keycode: Some(keycode),
+                _ => {}
+            }
+        }
+        if simple::render_screen(&cpu, &mut frame) {
+            texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                frame.copy_to(buffer, pitch)
+            })?;
+            canvas.copy(&texture, None, None)?;
+            canvas.present();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(16));
+    }
+    Ok(())
+}
+
+fn disassemble(rom: &Path, output: Option<&Path>, cdl: Option<&Path>) -> Result<(), String> {
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+    };
+    let cartridge = Rom::new(&read(rom)?)?;
+    let cdl = cdl.map(read).transpose()?;
+    let asm = disasm::disassemble(&cartridge.prg_rom, cdl.as_deref())?;
+    match output {
+        Some(output) => std::fs::write(output, asm)
+            .map_err(|e| format!("Failed to write {}: {}", output.display(), e)),
+        None => std::io::stdout()
+            .write_all(asm.as_bytes())
+            .map_err(|e| e.to_string()),
+    }
+}
+
+fn parse_button(name: &str) -> Result<JoypadButton, String> {
+    JoypadButton::from_name(&name.to_ascii_uppercase())
+        .ok_or_else(|| format!("Unknown button: {}", name))
+}
+
+fn measure_latency(
+    rom: &Path,
+    button: JoypadButton,
+    warmup: usize,
+    trials: usize,
+    region: Option<Region>,
+) -> Result<(), String> {
+    let raw = std::fs::read(rom).map_err(|e| format!("Failed to read {}: {}", rom.display(), e))?;
+    let cartridge = Rom::new(&raw)?;
+    let region = region.unwrap_or_else(|| region::detect(cartridge.region, &rom.to_string_lossy()));
+    let results = latency::measure(cartridge, region, button, warmup, trials)?;
+    let frame_ms = 1000.0 / region.frame_rate();
+    let describe = |frames: Option<usize>| match frames {
+        Some(frames) => format!("{} frames ({:.1} ms)", frames, frames as f64 * frame_ms),
+        None => "no change".to_string(),
+    };
+    for trial in &results {
+        println!(
+            "Press on frame {}: screen {}, RAM {}",
+            trial.frame,
+            describe(trial.screen),
+            describe(trial.ram)
+        );
+    }
+    let screen: Vec<usize> = results.iter().filter_map(|trial| trial.screen).collect();
+    if screen.is_empty() {
+        return Err(
+            "The screen never changed, try another --button or a longer --warmup".to_string(),
+        );
+    }
+    let average = screen.iter().sum::<usize>() as f64 / screen.len() as f64;
+    println!(
+        "Screen latency: {} to {} frames, {:.1} ms on average",
+        screen.iter().min().unwrap(),
+        screen.iter().max().unwrap(),
+        average * frame_ms
+    );
+    Ok(())
+}
+
+fn convert_trace(
+    log: &Path,
+    format: TraceFormat,
+    output: Option<&Path>,
+    disassembly: bool,
+) -> Result<(), String> {
+    let log = if log == Path::new("-") {
+        std::io::read_to_string(std::io::stdin()).map_err(|e| e.to_string())?
+    } else {
+        std::fs::read_to_string(log)
+            .map_err(|e| format!("Failed to read {}: {}", log.display(), e))?
+    };
+    let (converted, skipped) = trace_format::convert(&log, format, disassembly);
+    if skipped > 0 {
+        eprintln!("Skipped {} lines that aren't instructions", skipped);
+    }
+    match output {
+        Some(output) => std::fs::write(output, converted)
+            .map_err(|e| format!("Failed to write {}: {}", output.display(), e)),
+        None => std::io::stdout()
+            .write_all(converted.as_bytes())
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/// Writes the pattern tables of `rom` as PNG tile sheets to `dir`. `frames` runs the game
+/// first, which picks up tiles it copies to CHR RAM and the colors it sets.
+fn export_tiles(
+    rom: &Path,
+    dir: &Path,
+    palette: SheetPalette,
+    frames: Option<usize>,
+    region: Option<Region>,
+) -> Result<(), String> {
+    let rom_path = rom.to_string_lossy();
+    let raw = std::fs::read(rom).map_err(|e| format!("Failed to read {}: {}", rom.display(), e))?;
+    let cartridge = Rom::new(&raw)?;
+    let frames = frames.unwrap_or(if palette.is_game() { 120 } else { 0 });
+    let (chr, palette_table) = if frames > 0 {
+        let region = region.unwrap_or_else(|| region::detect(cartridge.region, &rom_path));
+        headless::run_to_ppu(cartridge, region, frames)?
+    } else if palette.is_game() {
+        return Err("Game palettes are only set once the game runs, use --frames".to_string());
+    } else {
+        (cartridge.chr_rom, [0; 32])
+    };
+
+    let files = tile_viewer::export_sheets(&chr, &rom_path, dir, palette.colors(&palette_table))?;
+    for file in files {
+        println!("Wrote {}", file.display());
+    }
+    Ok(())
+}
+
+/// Runs the test ROMs in `dir` and prints a line per ROM and a summary. Fails if any ROM
+/// didn't pass, so it can gate a script.
+fn run_test_suite(dir: &Path, frames: usize) -> Result<(), String> {
+    let outcomes = test_suite::run_dir(dir, frames, |name, outcome| {
+        // Crashes come with a register dump on the following lines
+        let outcome = outcome.to_string().replace('\n', "\n      ");
+        println!("{:<40} {}", name, outcome);
+    })?;
+    if outcomes.is_empty() {
+        return Err(format!("No .nes files in {}", dir.display()));
+    }
+    let passed = outcomes
+        .iter()
+        .filter(|outcome| **outcome == Outcome::Passed)
+        .count();
+    println!("\n{} of {} passed", passed, outcomes.len());
+    if passed < outcomes.len() {
+        return Err(format!("{} didn't pass", outcomes.len() - passed));
+    }
+    Ok(())
+}
+
+/// Prints the header fields of `rom` and what the override database says about it.
+fn rom_info(rom: &Path, overrides: &OverrideDb) -> Result<(), String> {
+    let raw = std::fs::read(rom).map_err(|e| format!("Failed to read {}: {}", rom.display(), e))?;
+    let cartridge = Rom::new(&raw)?;
+    let nes2 = raw[7] & 0x0C == 0x08;
+
+    println!("{}", rom.display());
+    println!("  Format:     {}", if nes2 { "NES 2.0" } else { "iNES" });
+    println!(
+        "  Mapper:     {} ({}){}",
+        cartridge.mapper,
+        mapper::name(cartridge.mapper).unwrap_or("unknown"),
+        if mapper::for_rom(&cartridge).is_ok() {
+            ""
+        } else {
+            ", not supported"
+        }
+    );
+    if nes2 {
+        println!("  Submapper:  {}", raw[8] >> 4);
+    }
+    println!("  PRG ROM:    {}KB", cartridge.prg_rom.len() / 1024);
+    if cartridge.chr_rom.is_empty() {
+        let chr_ram = if nes2 { nes2_ram_size(raw[11]) } else { 0x2000 };
+        println!("  CHR RAM:    {}KB", chr_ram / 1024);
+    } else {
+        println!("  CHR ROM:    {}KB", cartridge.chr_rom.len() / 1024);
+    }
+    println!(
+        "  PRG RAM:    {}KB{}",
+        cartridge.prg_ram_size / 1024,
+        if cartridge.battery { ", battery" } else { "" }
+    );
+    println!("  Mirroring:  {:?}", cartridge.mirroring);
+    println!("  Trainer:    {}", raw[6] & 0x04 != 0);
+    println!(
+        "  Region:     {}",
+        match cartridge.region {
+            Some(region) => format!("{:?}", region),
+            None => "not given".to_string(),
+        }
+    );
+    println!("  Console:    {:?}", cartridge.console);
+    println!(
+        "  CRC32:      {:08X} (PRG {:08X}, CHR {:08X})",
+        cartridge.crc32, cartridge.prg_crc32, cartridge.chr_crc32
+    );
+    if let Some(game) = overrides.lookup(cartridge.crc32) {
+        println!("  Override:   {}", game.name);
+        for fix in game.header_fixes(&cartridge) {
+            println!("    bad header, {}", fix);
+        }
+    }
+    Ok(())
+}
+
+/// Writes a copy of `rom` with the mapper, mirroring and PRG RAM size from its override entry.
+fn fix_header(rom: &Path, output: Option<&Path>, overrides: &OverrideDb) -> Result<(), String> {
+    let mut raw =
+        std::fs::read(rom).map_err(|e| format!("Failed to read {}: {}", rom.display(), e))?;
+    let cartridge = Rom::new(&raw)?;
+    let game = overrides.lookup(cartridge.crc32).ok_or_else(|| {
+        format!(
+            "{} (CRC32 {:08X}) isn't in the override database",
+            rom.display(),
+            cartridge.crc32
+        )
+    })?;
+    let fixes = game.header_fixes(&cartridge);
+    if fixes.is_empty() {
+        println!("The header of {} is already correct", rom.display());
+        return Ok(());
+    }
+    for fix in &fixes {
+        println!("{}", fix);
+    }
+
+    game.fix_header(&mut raw)?;
+    let output = match output {
+        Some(output) => output.to_path_buf(),
+        None => {
+            let dir = rom.parent().unwrap_or(Path::new(""));
+            paths::rom_file(dir, &rom.to_string_lossy(), "fixed.nes")?
+        }
+    };
+    std::fs::write(&output, raw)
+        .map_err(|e| format!("Failed to write {}: {}", output.display(), e))?;
+    println!("Wrote {}", output.display());
+    Ok(())
+}
+
+/// Prints `<frame> <hash>` lines so scripts can diff emulator output across commits.
+/// Stops at the first desync if the input script is a movie with checksums.
+fn run_headless(
+    args: &Args,
+    cartridge: Rom,
+    frames: usize,
+    mut script: Option<&mut InputScript>,
+) -> Result<(), String> {
+    let region = args
+        .region
+        .unwrap_or_else(|| region::detect(cartridge.region, args.rom()));
+    let every_frame = args.hash == Some(HashOutput::All);
+    let recorder = RefCell::new(match &args.record_movie {
+        Some(path) => Some(MovieRecorder::create(path)?),
+        None => None,
+    });
+    // What the script did at the end of the last frame, for after it
+    let check = Cell::new(None);
+    let event = Cell::new(None);
+    let mut checker = MovieChecker::default();
+    let mut desync = None;
+
+    let (last, ran) = headless::run_frames_checked(
+        cartridge,
+        region,
+        frames,
+        |n, frame, [p1, p2]| {
+            if every_frame {
+                println!("{} {:016x}", n, headless::frame_hash(frame));
+            }
+            if let Some(script) = script.as_mut() {
+                event.set(script.apply(&mut [&mut *p1, &mut *p2]));
+                check.set(script.take_check());
+            }
+            record_movie(&recorder, |recorder| {
+                recorder.record_input([p1.buttons(), p2.buttons()])
+            });
+            event.get()
+        },
+        |n, cpu| {
+            let checksum = cpu.bus.ram_checksum();
+            record_movie(&recorder, |recorder| recorder.record_check(n, checksum));
+            if let Some(event) = event.take() {
+                record_movie(&recorder, |recorder| recorder.record_event(event));
+            }
+            if let Some(expected) = check.take() {
+                desync = checker.check(expected, n, checksum).err();
+            }
+            desync.is_none()
+        },
+    )?;
+    if let (Some(recorder), Some(path)) = (recorder.take(), &args.record_movie) {
+        finish_movie(recorder, path);
+    }
+    if let Some(desync) = desync {
+        return Err(desync);
+    }
+    if !every_frame {
+        println!("{} {:016x}", ran, headless::frame_hash(&last));
+    }
+    if ran < frames {
+        return Err(format!(
+            "The CPU stopped after {} of {} frames",
+            ran, frames
+        ));
+    }
+    Ok(())
+}
+
+/// Copies the ROM's .sav file out to `--export-sram` or in from `--import-sram`.
+fn transfer_sram(args: &Args, paths: &Paths, cartridge: &Rom) -> Result<(), String> {
+    if !cartridge.battery {
+        return Err(format!("{} has no battery-backed RAM", args.rom()));
+    }
+    let sav = sram::sav_file(&paths.saves, args.rom())?;
+    if let Some(target) = &args.export_sram {
+        let data = sram::read(&sav)?.ok_or_else(|| format!("No save at {}", sav.display()))?;
+        sram::write(target, &data)?;
+        println!("Exported {} to {}", sav.display(), target.display());
+    }
+    if let Some(source) = &args.import_sram {
+        let data =
+            sram::read(source)?.ok_or_else(|| format!("{} doesn't exist", source.display()))?;
+        if data.len() != cartridge.prg_ram_size {
+            return Err(format!(
+                "{} is {} bytes, {} expects {}",
+                source.display(),
+                data.len(),
+                args.rom(),
+                cartridge.prg_ram_size
+            ));
+        }
+        sram::write(&sav, &data)?;
+        println!("Imported {} to {}", source.display(), sav.display());
+    }
+    Ok(())
+}
+
+/// SDL state that outlives a single game, so it is still around to show an error screen after
+/// emulation stops.
+struct Frontend<'t> {
+    canvas: WindowCanvas,
+    texture: Texture<'t>,
+    /// `crt::SCALE` times the size of `texture`, for frames drawn through `crt`
+    crt_texture: Texture<'t>,
+    event_pump: EventPump,
+    controller_subsystem: GameControllerSubsystem,
+    controllers: Vec<GameController>,
+    input: InputMap,
+    macros: Macros,
+    /// Set while Shift+F2 is asking for keys
+    rebinding: Option<Rebinding>,
+    playlist: Playlist,
+    debug_windows: DebugWindows,
+    renderer: Renderer,
+    frame: Frame,
+    crt: CrtFilter,
+    /// The palette from the config or command line
+    palette: PaletteName,
+    /// The .pal file loaded for `PaletteName::File`
+    palette_file: Option<PathBuf>,
+    scale_mode: ScaleMode,
+    /// Window size as a multiple of the picture's, for fitting it to a new scale mode
+    window_scale: f32,
+    /// Seconds kept for the instant replay, from the next game on
+    replay_seconds: u32,
+    /// Whether the game runs on an arcade board's RGB PPU
+    rgb_ppu: bool,
+    ppu_overlay: bool,
+    sprite_zero_marker: bool,
+    script: Option<InputScript>,
+    config_file: PathBuf,
+    config_watcher: ConfigWatcher,
+    video_args: VideoArgs,
+    audio_args: AudioArgs,
+    states_args: StatesArgs,
+    limiter: FrameLimiter,
+    /// Frames not drawn after each drawn one while fast-forwarding
+    fast_forward_skip: u32,
+    /// Frames not drawn since the last drawn one
+    skipped: u32,
+    /// Stop after each frame until P or frame advance
+    paused: bool,
+    pause_in_background: bool,
+    /// Paused by the window losing focus, so getting it back carries on
+    focus_paused: bool,
+    auto_save: bool,
+    /// Older versions of each state slot to keep
+    state_history: usize,
+    sprite_overflow_bug: bool,
+    overclock_lines: u16,
+    /// The region from the config, for every game
+    region: Option<Region>,
+    /// The `[audio]` settings with the command line's on top
+    audio_config: AudioConfig,
+    recording: RecordingConfig,
+    /// Threads still writing recordings, waited for before quitting
+    writers: Vec<JoinHandle<()>>,
+    frame_stats: FrameStats,
+    fps: FpsMeter,
+    /// The game's name, for the window title
+    title: String,
+    latency_flash: bool,
+    /// Whether the next frame is drawn white, with `latency_flash`
+    flash: bool,
+    status: StatusHub,
+}
+
+impl Frontend<'_> {
+    /// Vs. System games get the RGB PPU's colors, unless another palette was picked.
+    fn update_palette(&mut self) {
+        self.renderer.set_palette(match self.palette {
+            PaletteName::Default if self.rgb_ppu => PaletteName::Rgb,
+            palette => palette,
+        });
+    }
+
+    /// Applies the settings that can change while a game runs. Options given on the command
+    /// line win over the file.
+    fn apply_config(&mut self, config: &Config) {
+        let mut video = config.video.clone();
+        self.video_args.apply(&mut video);
+        self.renderer.sprite_limit = video.sprite_limit;
+        self.palette = video.palette;
+        self.crt.set_preset(video.crt);
+        if video.palette_file != self.palette_file {
+            self.palette_file = video.palette_file;
+            let colors = self.palette_file.as_deref().and_then(|path| {
+                palette::load_file(path)
+                    .map_err(|e| eprintln!("{}, using the default colors", e))
+                    .ok()
+            });
+            self.renderer.set_file_palette(colors);
+        }
+        if video.scale_mode != self.scale_mode {
+            self.scale_mode = video.scale_mode;
+            self.apply_scale_mode();
+        }
+        self.replay_seconds = video.replay_seconds;
+        self.update_palette();
+        self.limiter.set_speed(config.emulation.speed);
+        self.fast_forward_skip = config.emulation.fast_forward_skip;
+        let mut states = config.states.clone();
+        self.states_args.apply(&mut states);
+        self.auto_save = states.auto_save;
+        self.state_history = states.history;
+        self.sprite_overflow_bug = config.emulation.sprite_overflow_bug;
+        self.overclock_lines = config.emulation.overclock_lines;
+        self.pause_in_background = config.emulation.pause_in_background;
+        self.region = config.emulation.region;
+        self.audio_config = config.audio.clone();
+        self.audio_args.apply(&mut self.audio_config);
+        self.recording = config.recording.clone();
+        if let Err(e) = self.input.reload(&config.input) {
+            eprintln!("{}, keeping the current controls", e);
+        }
+        if let Err(e) = self.macros.reload(&config.macros) {
+            eprintln!("{}, keeping the current macros", e);
+        }
+    }
+
+    /// Re-applies the config if the file was edited since the last check.
+    fn reload_config(&mut self) {
+        match self.config_watcher.poll() {
+            Some(Ok(config)) => {
+                self.apply_config(&config);
+                println!("Reloaded {}", CONFIG_FILE_NAME);
+            }
+            Some(Err(e)) => eprintln!("{}", e),
+            None => {}
+        }
+    }
+
+    /// Fits the picture to the window the way `scale_mode` says, and resizes the window to
+    /// match unless it's fullscreen.
+    fn apply_scale_mode(&mut self) {
+        let width = self.scale_mode.width();
+        let integer = self.scale_mode == ScaleMode::Integer;
+        if let Err(e) = self
+            .canvas
+            .set_logical_size(width, Frame::HEIGHT as u32)
+            .map_err(|e| e.to_string())
+            .and_then(|()| self.canvas.set_integer_scale(integer))
+        {
+            eprintln!("Failed to set the scale mode: {}", e);
+        }
+        let window = self.canvas.window_mut();
+        if window.fullscreen_state() == FullscreenType::Off {
+            let (width, height) = (
+                (width as f32 * self.window_scale) as u32,
+                (Frame::HEIGHT as f32 * self.window_scale) as u32,
+            );
+            if let Err(e) = window.set_size(width, height) {
+                eprintln!("Failed to resize the window: {}", e);
+            }
+        }
+    }
+
+    /// Switches to the next scale mode and saves it in the config for next time.
+    fn cycle_scale_mode(&mut self) -> ScaleMode {
+        self.scale_mode = self.scale_mode.next();
+        self.apply_scale_mode();
+        let mode = self.scale_mode;
+        let config_file = &self.config_file;
+        match Config::load(config_file).and_then(|mut config| {
+            config.video.scale_mode = mode;
+            config.save(config_file)
+        }) {
+            Ok(()) => self.config_watcher.forget_changes(),
+            Err(e) => eprintln!(
+                "Failed to save the scale mode to {}: {}",
+                config_file.display(),
+                e
+            ),
+        }
+        mode
+    }
+
+    /// Sets the emulation speed in percent and saves it in the config for next time. Returns
+    /// the speed, clamped to what the limiter allows.
+    fn set_speed(&mut self, speed: u32) -> u32 {
+        let speed = self.limiter.set_speed(speed);
+        let config_file = &self.config_file;
+        match Config::load(config_file).and_then(|mut config| {
+            config.emulation.speed = speed;
+            config.save(config_file)
+        }) {
+            Ok(()) => self.config_watcher.forget_changes(),
+            Err(e) => eprintln!(
+                "Failed to save the speed to {}: {}",
+                config_file.display(),
+                e
+            ),
+        }
+        speed
+    }
+
+    /// Keeps the window answering and drawn while the debugger waits for a command. Returns
+    /// false once it's closed.
+    fn idle(&mut self) -> bool {
+        for event in self.event_pump.poll_iter() {
+            match event {
+                event if self.debug_windows.owns(&event) => self.debug_windows.handle_event(&event),
+                Event::Quit { .. }
+                | Event::Window {
+                    win_event: WindowEvent::Close,
                     ..
-                } => {
-                    if let Some(button) = keymap().get(&keycode) {
-                        joypad.release(*button);
-                    }
-                }
+                } => return false,
                 _ => {}
             }
         }
-        let sleep_time = std::time::Duration::from_millis(10);
-        std::thread::sleep(sleep_time);
+        self.present();
+        true
+    }
+
+    /// Switches to the next CRT preset and saves it in the config for next time.
+    fn cycle_crt(&mut self) -> CrtPreset {
+        let preset = self.crt.preset().next();
+        self.crt.set_preset(preset);
+        let config_file = &self.config_file;
+        match Config::load(config_file).and_then(|mut config| {
+            config.video.crt = preset;
+            config.save(config_file)
+        }) {
+            Ok(()) => self.config_watcher.forget_changes(),
+            Err(e) => eprintln!(
+                "Failed to save the CRT preset to {}: {}",
+                config_file.display(),
+                e
+            ),
+        }
+        preset
+    }
+
+    fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let fullscreen = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        if let Err(e) = window.set_fullscreen(fullscreen) {
+            eprintln!("Failed to switch fullscreen: {}", e);
+        }
+    }
+
+    /// Titles the window with the game's name, and how fast it's running once that's known.
+    fn show_title(&mut self, speed: Option<(f64, f64)>) {
+        let title = match speed {
+            Some((fps, frame_rate)) => format!(
+                "{} - {:.1} FPS ({:.0}%) - RustNES",
+                self.title,
+                fps,
+                fps / frame_rate * 100.0
+            ),
+            None => format!("{} - RustNES", self.title),
+        };
+        // Only fails on a nul in the name
+        let _ = self.canvas.window_mut().set_title(&title);
+    }
+
+    fn present(&mut self) {
+        let (frame, texture) = match self.crt.preset() {
+            CrtPreset::Off => (&self.frame, &mut self.texture),
+            _ => (self.crt.apply(&self.frame), &mut self.crt_texture),
+        };
+        texture
+            .with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                frame.copy_to(buffer, pitch)
+            })
+            .unwrap();
+        self.canvas.copy(texture, None, None).unwrap();
+        self.canvas.present();
+        self.limiter.presented(Instant::now());
+    }
+}
+
+/// Prints `message` and shows it over the picture for a moment.
+fn notify(osd: &RefCell<Osd>, message: String) {
+    println!("{}", message);
+    osd.borrow_mut().show(message);
+}
+
+/// A small picture of the game for a save state, without any messages drawn over it.
+fn thumbnail(renderer: &mut Renderer, ppu: &NesPPU) -> Frame {
+    let mut frame = Frame::new();
+    renderer.render(ppu, &mut frame);
+    frame.downscale(save_state::THUMBNAIL_SCALE)
+}
+
+/// Keys with a fixed job during a game, which macros can't be bound to
+const HOTKEYS: [Keycode; 24] = [
+    Keycode::F1,
+    Keycode::F2,
+    Keycode::F3,
+    Keycode::F4,
+    Keycode::F5,
+    Keycode::F6,
+    Keycode::F7,
+    Keycode::F8,
+    Keycode::F9,
+    Keycode::F10,
+    Keycode::F11,
+    Keycode::F12,
+    Keycode::PageUp,
+    Keycode::PageDown,
+    Keycode::Home,
+    Keycode::Num5,
+    Keycode::Num6,
+    Keycode::Minus,
+    Keycode::Equals,
+    Keycode::Backspace,
+    Keycode::Tab,
+    Keycode::P,
+    Keycode::Backslash,
+    Keycode::Escape,
+];
+
+/// Frames between the snapshots kept in case the game crashes, with auto-save on
+const AUTO_SAVE_INTERVAL: usize = 60;
+/// Save state slots, 0-9, picked with Shift+F6
+const STATE_SLOTS: u8 = 10;
+/// Audio device buffer sizes to pick from, in frames
+const AUDIO_BUFFER_SIZES: std::ops::RangeInclusive<i64> = 64..=8192;
+/// How often events are checked while paused
+const PAUSE_POLL: Duration = Duration::from_millis(10);
+
+/// Requests from the frame callback that need the whole machine, handled between
+/// instructions.
+enum Action {
+    Quit,
+    Switch {
+        forward: bool,
+    },
+    ExportSram,
+    ImportSram(PathBuf),
+    Console(ConsoleEvent),
+    /// A movie's (frame, checksum) to compare with RAM
+    CheckMovie((usize, u32)),
+    /// Drop a coin into a Vs. System's slot 0 or 1
+    InsertCoin(usize),
+    SaveState {
+        slot: u8,
+        thumbnail: Frame,
+    },
+    LoadState(u8),
+    /// Go back to before the last `LoadState`
+    UndoLoad,
+}
+
+/// What the user picked on the error screen.
+enum ErrorAction {
+    Reset,
+    Open(String),
+}
+
+fn run(
+    args: &Args,
+    config: &Config,
+    paths: &Paths,
+    overrides: &OverrideDb,
+    script: Option<InputScript>,
+) {
+    let mut video = config.video.clone();
+    args.video.apply(&mut video);
+    if video.scale <= 0.0 {
+        eprintln!("Scale has to be above 0, got {}", video.scale);
+        std::process::exit(1);
+    }
+    let mut audio_config = config.audio.clone();
+    args.audio.apply(&mut audio_config);
+    if !SAMPLE_RATES.contains(&audio_config.sample_rate) {
+        eprintln!(
+            "Sample rate has to be one of {:?}, got {}",
+            SAMPLE_RATES, audio_config.sample_rate
+        );
+        std::process::exit(1);
+    }
+    if !AUDIO_BUFFER_SIZES.contains(&(audio_config.buffer_size as i64)) {
+        eprintln!(
+            "Audio buffer size has to be from 64 to 8192, got {}",
+            audio_config.buffer_size
+        );
+        std::process::exit(1);
+    }
+    let roms: Vec<String> = match &args.rom {
+        Some(rom) => std::iter::once(rom.clone())
+            .chain(args.more_roms.iter().cloned())
+            .collect(),
+        // The recent ROMs to pick from, and switch between once playing
+        None => RecentRoms::load(&paths.recent)
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                RecentRoms::default()
+            })
+            .roms
+            .iter()
+            .filter(|rom| Path::new(rom).exists())
+            .cloned()
+            .collect(),
+    };
+    let playlist = Playlist::new(&roms).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
     });
+
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", video.filter.sdl_hint());
+    let mut window = video_subsystem.window(
+        "RustNES",
+        (video.scale_mode.width() as f32 * video.scale) as u32,
+        (Frame::HEIGHT as f32 * video.scale) as u32,
+    );
+    window.position_centered().resizable();
+    if video.fullscreen {
+        window.fullscreen_desktop();
+    }
+    let mut window = window.build().unwrap();
+    // Any bigger size letterboxes the picture, through the canvas's logical size
+    if let Err(e) = window.set_minimum_size(Frame::WIDTH as u32, Frame::HEIGHT as u32) {
+        eprintln!("Failed to set the window's minimum size: {}", e);
+    }
+
+    let display_rate = window
+        .display_mode()
+        .ok()
+        .filter(|mode| mode.refresh_rate > 0)
+        .map(|mode| mode.refresh_rate as f64);
+    let mut canvas = window.into_canvas();
+    if video.vsync {
+        canvas = canvas.present_vsync();
+    }
+    let canvas = canvas.build().unwrap();
+    let creator = canvas.texture_creator();
+    let texture = creator
+        .create_texture_streaming(PixelFormatEnum::RGB24, 256, 240)
+        .unwrap();
+    let (crt_width, crt_height) = (Frame::WIDTH * crt::SCALE, Frame::HEIGHT * crt::SCALE);
+    let crt_texture = creator
+        .create_texture_streaming(PixelFormatEnum::RGB24, crt_width as u32, crt_height as u32)
+        .unwrap();
+
+    let mut frontend = Frontend {
+        canvas,
+        texture,
+        crt_texture,
+        event_pump: sdl_context.event_pump().unwrap(),
+        controller_subsystem: sdl_context.game_controller().unwrap(),
+        controllers: Vec::new(),
+        input: InputMap::default(),
+        macros: Macros::default(),
+        rebinding: None,
+        playlist,
+        debug_windows: DebugWindows::new(video_subsystem.clone()),
+        renderer: Renderer::new(),
+        frame: Frame::new(),
+        crt: CrtFilter::new(CrtPreset::Off),
+        palette: PaletteName::Default,
+        palette_file: None,
+        scale_mode: video.scale_mode,
+        window_scale: video.scale,
+        replay_seconds: 0,
+        rgb_ppu: false,
+        ppu_overlay: false,
+        sprite_zero_marker: false,
+        script,
+        config_file: paths.config_file(),
+        config_watcher: ConfigWatcher::new(paths.config_file()),
+        video_args: args.video.clone(),
+        audio_args: args.audio.clone(),
+        states_args: args.states.clone(),
+        limiter: FrameLimiter::new(Region::NTSC.frame_rate(), config.emulation.speed),
+        fast_forward_skip: 0,
+        skipped: 0,
+        paused: false,
+        pause_in_background: false,
+        focus_paused: false,
+        auto_save: false,
+        state_history: 0,
+        sprite_overflow_bug: true,
+        overclock_lines: 0,
+        region: None,
+        audio_config: AudioConfig::default(),
+        recording: RecordingConfig::default(),
+        writers: Vec::new(),
+        frame_stats: FrameStats::new(),
+        fps: FpsMeter::new(),
+        title: String::new(),
+        latency_flash: args.latency_flash,
+        flash: false,
+        status: StatusHub::new(),
+    };
+    // Keeps the picture's shape in fullscreen, with black bars around it
+    frontend.apply_scale_mode();
+    if video.vsync {
+        // An unknown refresh rate is most likely 60Hz
+        frontend
+            .limiter
+            .set_vsync(Some(display_rate.unwrap_or(60.0)));
+    }
+    frontend.apply_config(config);
+    if let Some(address) = &config.status.http {
+        match status::serve_http(address, frontend.status.clone()) {
+            Ok(()) => println!("Serving the status on http://{}/", address),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    if let Some(client_id) = &config.status.discord_client_id {
+        status::start_discord(client_id.clone(), frontend.status.clone());
+    }
+    let mut debugger = args.debug.then(Debugger::stdin);
+    let mut audio = open_audio(&sdl_context, &audio_config);
+
+    run_games(
+        &mut frontend,
+        args,
+        paths,
+        overrides,
+        &mut debugger,
+        &mut audio,
+    );
+    if frontend.writers.iter().any(|writer| !writer.is_finished()) {
+        println!("Finishing recordings");
+    }
+    for writer in frontend.writers.drain(..) {
+        writer.join().ok();
+    }
+    if let Some(csv) = &args.frame_stats {
+        println!("{}", frontend.frame_stats.summary());
+        if let Some(path) = csv {
+            match frontend.frame_stats.write_csv(path) {
+                Ok(()) => println!("Wrote frame times to {}", path.display()),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    }
+}
+
+/// Opens the default audio device. It may pick a sample rate other than the one asked for.
+fn open_audio(sdl_context: &Sdl, config: &AudioConfig) -> Option<AudioOutput> {
+    let channels = if config.stereo { 2 } else { 1 };
+    match sdl_context.audio().and_then(|audio| {
+        AudioOutput::open(&audio, config.sample_rate, channels, config.buffer_size)
+    }) {
+        Ok(audio) => Some(audio),
+        Err(e) => {
+            eprintln!("{}, playing without sound", e);
+            None
+        }
+    }
+}
+
+/// Plays the ROMs until the user quits.
+fn run_games(
+    frontend: &mut Frontend,
+    args: &Args,
+    paths: &Paths,
+    overrides: &OverrideDb,
+    debugger: &mut Option<Debugger>,
+    audio: &mut Option<AudioOutput>,
+) {
+    let mut rom_path = if args.rom.is_some() {
+        frontend.playlist.current().to_string()
+    } else {
+        match pick_rom(frontend) {
+            Some(path) => path,
+            None => return,
+        }
+    };
+    loop {
+        // --patch is for the ROM from the command line, not ones dropped on the window later
+        let patch = args
+            .patch
+            .as_deref()
+            .filter(|_| args.is_command_line_rom(&rom_path));
+        let result = load_rom(&rom_path, patch, overrides).and_then(|(cartridge, game)| {
+            remember_rom(&paths.recent, &rom_path);
+            emulate(
+                frontend, args, paths, &rom_path, cartridge, game, debugger, audio,
+            )
+        });
+        let error = match result {
+            Ok(Exit::Quit) => return,
+            Ok(Exit::Switch { forward }) => {
+                rom_path = frontend.playlist.step(forward).to_string();
+                println!(
+                    "ROM {} of {}: {}",
+                    frontend.playlist.position() + 1,
+                    frontend.playlist.len(),
+                    rom_path
+                );
+                continue;
+            }
+            Err(error) => error,
+        };
+        eprintln!("{}", error);
+        // The error screen only listens to the game's window
+        frontend.debug_windows.close_all();
+        match show_error(frontend, &error) {
+            Some(ErrorAction::Reset) => {}
+            Some(ErrorAction::Open(path)) => rom_path = path,
+            None => return,
+        }
+    }
+}
+
+/// Why a game stopped, other than an error.
+enum Exit {
+    Quit,
+    /// Go to the next or previous ROM in the playlist
+    Switch {
+        forward: bool,
+    },
+}
+
+/// Runs a game until the user quits or switches games (`Ok`) or it can't continue.
+#[allow(clippy::too_many_arguments)]
+fn emulate(
+    frontend: &mut Frontend,
+    args: &Args,
+    paths: &Paths,
+    rom_path: &str,
+    cartridge: Rom,
+    game: Option<GameOverride>,
+    debugger: &mut Option<Debugger>,
+    audio: &mut Option<AudioOutput>,
+) -> Result<Exit, EmulationError> {
+    let region = args
+        .region
+        .or(frontend.region)
+        .unwrap_or_else(|| region::detect(cartridge.region, rom_path));
+    if region != Region::NTSC {
+        println!("Region: {}", region.name());
+    }
+    let screenshot_dir = &paths.screenshots;
+    let actions = RefCell::new(Vec::new());
+    // Set when the frame callback returns with the game still paused, for the actions to be
+    // handled and the callback to go back to waiting
+    let hold_pause = Cell::new(false);
+    frontend.limiter.set_frame_rate(region.frame_rate());
+    // The cached background belongs to the last game's PPU
+    frontend.renderer.invalidate();
+
+    let rom_crc = cartridge.crc32;
+    let title = Path::new(rom_path)
+        .file_stem()
+        .map_or_else(|| rom_path.into(), |stem| stem.to_string_lossy());
+    frontend.status.start_game(&title, rom_crc);
+    frontend.title = title.into_owned();
+    frontend.show_title(None);
+    frontend.fps.restart();
+    let vs_game = cartridge.is_vs_system();
+    frontend.rgb_ppu = vs_game;
+    frontend.update_palette();
+    if vs_game {
+        println!("Vs. System game: 5 and 6 insert coins");
+    }
+    let color_order = match cartridge.console {
+        Console::Vs {
+            ppu: VsPpu::Rp2c04(chip),
+            ..
+        } => palette::rp2c04_order(chip),
+        _ => None,
+    };
+    frontend.renderer.set_color_order(color_order);
+    let mut ports = game
+        .as_ref()
+        .and_then(|game| game.controllers)
+        .unwrap_or_default();
+    if let Some(port2) = args.port2 {
+        ports[1] = port2;
+    }
+    frontend.input.power_pad_enabled = ports.contains(&ControllerType::PowerPad);
+    if frontend.input.power_pad_enabled {
+        println!("Power Pad plugged in, on Y U I O / H J K L / N M , . by default");
+    }
+    // The mat's buttons, for the bus after each frame
+    let power_pad = Cell::new(0);
+    let sprite_overflow_bug = frontend.sprite_overflow_bug;
+    let overclock_lines = frontend.overclock_lines;
+    let audio_filters = frontend.audio_config.filters();
+    let expansion_volume = frontend.audio_config.expansion_volume;
+    let pan = frontend.audio_config.pan;
+    // Messages over the picture, from the callback and the frame loop alike
+    let osd = RefCell::new(Osd::new());
+    // How many times the console's speed the game runs at, for playing its sound, or `None`
+    // while paused or fast-forwarding, when it's muted
+    let sound_speed = Cell::new(Some(1.0));
+    // Movies run from power on, so neither the battery save nor an auto-saved state may
+    // change where one starts, and playing or recording one mustn't overwrite them either
+    let movie = frontend.script.is_some()
+        || args.record_movie.is_some() && args.is_command_line_rom(rom_path);
+    if movie {
+        println!("Movie: starting without the battery save or auto-save");
+    }
+    let auto_state = if frontend.auto_save && !movie {
+        save_state::auto_state_file(&paths.states, rom_crc)
+            .map_err(|e| eprintln!("{}, the game won't be saved on exit", e))
+            .ok()
+    } else {
+        None
+    };
+    let mut resume_state = None;
+    let state_history = frontend.state_history;
+    let mut state_slot = 0;
+    let mut undo_load = UndoLoad::new();
+    if let Some(file) = auto_state.as_ref().filter(|file| file.exists()) {
+        match ask_resume(frontend) {
+            Some(true) => match std::fs::read(file) {
+                Ok(state) => resume_state = Some(state),
+                Err(e) => eprintln!("Failed to read {}: {}", file.display(), e),
+            },
+            Some(false) => {}
+            None => return Ok(Exit::Quit),
+        }
+    }
+
+    // --record-movie is for the ROM from the command line, like --patch
+    let recorder = RefCell::new(
+        args.record_movie
+            .as_deref()
+            .filter(|_| args.is_command_line_rom(rom_path))
+            .and_then(|path| {
+                MovieRecorder::create(path)
+                    .map_err(|e| eprintln!("{}, not recording", e))
+                    .ok()
+            }),
+    );
+    let mut movie_checker = MovieChecker::default();
+    let mut replay = ReplayBuffer::new(frontend.replay_seconds, region.frame_rate());
+    // The clip being recorded with Ctrl+F10, and when the frame before was finished
+    let mut clip: Option<ClipRecorder> = None;
+    let mut last_frame_done = Instant::now();
+    // The video being recorded with Ctrl+Shift+F10, which the sound is added to after each
+    // frame
+    let video: RefCell<Option<VideoRecorder>> = RefCell::new(None);
+    let audio_format = audio
+        .as_ref()
+        .map(|audio| (audio.sample_rate(), audio.channels()));
+
+    frontend.frame_stats.resume();
+    // Shared with the debugger, which keeps the window going while it waits for a command
+    let frontend = RefCell::new(frontend);
+    let bus = Bus::try_new(cartridge, |ppu: &NesPPU, [p1, p2]: &mut [Joypad; 2]| {
+        let mut frontend = frontend.borrow_mut();
+        let frontend = &mut **frontend;
+        let emulated = Instant::now();
+        let held_before = p1.buttons();
+        // Back from handling a state saved or loaded while paused: the frame was already done
+        let held = hold_pause.replace(false);
+        if !held {
+            // Sped up past the display's refresh rate, frames that can't be shown aren't drawn
+            let skip = frontend.limiter.fast_forward()
+                && frontend.skipped < frontend.fast_forward_skip
+                || !frontend.limiter.present_due(emulated);
+            frontend.skipped = if skip { frontend.skipped + 1 } else { 0 };
+            if !skip {
+                frontend.renderer.render(ppu, &mut frontend.frame);
+                if frontend.flash {
+                    frontend.frame.data.fill(0xFF);
+                    frontend.flash = false;
+                }
+                if frontend.sprite_zero_marker {
+                    overlay::draw_sprite_zero_marker(ppu, &mut frontend.frame);
+                }
+                if frontend.ppu_overlay {
+                    overlay::draw_ppu_overlay(ppu, &mut frontend.frame);
+                }
+                let recording = clip.is_some() || video.borrow().is_some();
+                let prompt = frontend.rebinding.as_ref().and_then(rebind_prompt);
+                let pinned: Vec<&str> = prompt
+                    .as_deref()
+                    .into_iter()
+                    .chain(recording.then_some("REC"))
+                    .collect();
+                osd.borrow_mut()
+                    .draw(&mut frontend.frame, Instant::now(), &pinned);
+                frontend.present();
+                frontend
+                    .debug_windows
+                    .refresh(ppu, frontend.renderer.colors());
+            }
+            replay.capture(ppu);
+            if let Some(recorder) = clip.as_mut() {
+                // Fast-forwarded frames last as long as they took, so the clip speeds up too
+                let frame_time = frontend.limiter.frame_duration();
+                let seconds = if frontend.limiter.fast_forward() {
+                    emulated
+                        .saturating_duration_since(last_frame_done)
+                        .min(frame_time)
+                } else {
+                    frame_time
+                };
+                if !recorder.capture(ppu, seconds.as_secs_f64()) {
+                    clip = None;
+                }
+            }
+            last_frame_done = emulated;
+            {
+                let mut video = video.borrow_mut();
+                if video.as_mut().is_some_and(|recorder| !recorder.frame(ppu)) {
+                    *video = None;
+                }
+            }
+            frontend.frame_stats.record(emulated, Instant::now());
+            if let Some(fps) = frontend.fps.frame(Instant::now()) {
+                frontend.show_title(Some((fps, region.frame_rate())));
+            }
+            frontend.status.frame();
+        }
+        // Paused, events are handled until a key lets the next frame run
+        let mut was_paused = held;
+        let mut advance = false;
+        loop {
+            let mut browse = false;
+            let mut cycle_scale_mode = false;
+            let mut cycle_crt = false;
+            let mut toggle_fullscreen = false;
+            let mut new_speed = None;
+            for event in frontend.event_pump.poll_iter() {
+                // Focus going to a debug window comes as the game's window losing it and the
+                // other one gaining it, which carries straight on
+                if let Event::Window { win_event, .. } = &event {
+                    match win_event {
+                        WindowEvent::FocusLost
+                            if frontend.pause_in_background && !frontend.paused =>
+                        {
+                            frontend.paused = true;
+                            frontend.focus_paused = true;
+                        }
+                        WindowEvent::FocusGained if frontend.focus_paused => {
+                            frontend.paused = false;
+                            frontend.focus_paused = false;
+                        }
+                        _ => {}
+                    }
+                }
+                match event {
+                    event if frontend.debug_windows.owns(&event) => {
+                        frontend.debug_windows.handle_event(&event)
+                    }
+                    Event::KeyDown {
+                        keycode: Some(key),
+                        repeat: false,
+                        ..
+                    } if frontend.rebinding.is_some() => rebind_key(
+                        &mut frontend.rebinding,
+                        &mut frontend.input,
+                        &frontend.macros,
+                        &frontend.config_file,
+                        &osd,
+                        key,
+                    ),
+                    Event::KeyDown {
+                        keycode: Some(key),
+                        repeat: false,
+                        ..
+                    } if frontend.macros.awaiting_key() => bind_macro(
+                        &mut frontend.macros,
+                        &frontend.input,
+                        &frontend.config_file,
+                        key,
+                    ),
+                    // With debug windows open, closing the game's window doesn't quit by itself
+                    Event::Quit { .. }
+                    | Event::Window {
+                        win_event: WindowEvent::Close,
+                        ..
+                    }
+                    | Event::KeyDown {
+                        keycode: Some(Keycode::Escape),
+                        ..
+                    } => actions.borrow_mut().push(Action::Quit),
+                    // Keeps a size picked by dragging the window's edges when the scale mode
+                    // changes
+                    Event::Window {
+                        win_event: WindowEvent::Resized(_, height),
+                        ..
+                    } if frontend.canvas.window().fullscreen_state() == FullscreenType::Off => {
+                        frontend.window_scale = height as f32 / Frame::HEIGHT as f32
+                    }
+                    Event::KeyDown {
+                        keycode: Some(key @ (Keycode::PageUp | Keycode::PageDown)),
+                        repeat: false,
+                        ..
+                    } => {
+                        if frontend.playlist.len() > 1 {
+                            let forward = key == Keycode::PageDown;
+                            actions.borrow_mut().push(Action::Switch { forward });
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(key @ (Keycode::F1 | Keycode::F11 | Keycode::F12)),
+                        repeat: false,
+                        ..
+                    } => {
+                        let view = match key {
+                            Keycode::F1 => DebugView::PatternTables,
+                            Keycode::F11 => DebugView::Nametables,
+                            _ => DebugView::Oam,
+                        };
+                        if let Err(e) = frontend.debug_windows.toggle(view) {
+                            eprintln!("Failed to open the {} window: {}", view.title(), e);
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(key @ (Keycode::Minus | Keycode::Equals | Keycode::Backspace)),
+                        ..
+                    } => {
+                        let speed = frontend.limiter.speed();
+                        new_speed = Some(match key {
+                            Keycode::Minus => speed.saturating_sub(SPEED_STEP),
+                            Keycode::Equals => speed + SPEED_STEP,
+                            _ => 100,
+                        });
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Tab),
+                        repeat: false,
+                        ..
+                    } => frontend.limiter.set_fast_forward(true),
+                    Event::KeyDown {
+                        keycode: Some(Keycode::P),
+                        repeat: false,
+                        ..
+                    } => {
+                        frontend.paused = !frontend.paused;
+                        frontend.focus_paused = false;
+                        if frontend.paused {
+                            println!("Paused, \\ runs one frame");
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Backslash),
+                        ..
+                    } if frontend.paused => advance = true,
+                    Event::KeyUp {
+                        keycode: Some(Keycode::Tab),
+                        ..
+                    } => frontend.limiter.set_fast_forward(false),
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F2),
+                        keymod,
+                        repeat: false,
+                        ..
+                    } if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) => {
+                        let rebinding = Rebinding::new(0);
+                        print_rebind_prompt(&rebinding);
+                        frontend.rebinding = Some(rebinding);
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F2),
+                        repeat: false,
+                        ..
+                    } => {
+                        if frontend.macros.toggle_recording() {
+                            println!("Recording a macro, press F2 to stop");
+                        } else if frontend.macros.awaiting_key() {
+                            println!("Press a key to bind the macro to, or Esc to drop it");
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F3),
+                        keymod,
+                        repeat: false,
+                        ..
+                    } if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) => {
+                        cycle_scale_mode = true
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F3),
+                        keymod,
+                        repeat: false,
+                        ..
+                    } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => cycle_crt = true,
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Return),
+                        keymod,
+                        repeat: false,
+                        ..
+                    } if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) => toggle_fullscreen = true,
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F3),
+                        ..
+                    } => {
+                        let palette = frontend.renderer.next_palette();
+                        frontend.renderer.set_palette(palette);
+                        notify(&osd, format!("Palette: {}", palette.name()));
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F4),
+                        ..
+                    } => {
+                        let renderer = &mut frontend.renderer;
+                        renderer.hide_background = !renderer.hide_background;
+                        println!(
+                            "Background {}",
+                            if renderer.hide_background {
+                                "hidden"
+                            } else {
+                                "shown"
+                            }
+                        );
+                    }
+                    Event::KeyDown {
+                        keycode:
+                            Some(
+                                key @ (Keycode::F5
+                                | Keycode::F6
+                                | Keycode::F7
+                                | Keycode::F8
+                                | Keycode::F9),
+                            ),
+                        keymod,
+                        repeat: false,
+                        ..
+                    } if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) => match key {
+                        Keycode::F5 => actions.borrow_mut().push(Action::SaveState {
+                            slot: state_slot,
+                            thumbnail: thumbnail(&mut frontend.renderer, ppu),
+                        }),
+                        Keycode::F6 => {
+                            state_slot = (state_slot + 1) % STATE_SLOTS;
+                            notify(&osd, format!("State slot {}", state_slot));
+                        }
+                        Keycode::F7 => actions.borrow_mut().push(Action::LoadState(state_slot)),
+                        Keycode::F8 => actions.borrow_mut().push(Action::UndoLoad),
+                        _ => browse = true,
+                    },
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F5),
+                        ..
+                    } => {
+                        let renderer = &mut frontend.renderer;
+                        renderer.hide_sprites = !renderer.hide_sprites;
+                        println!(
+                            "Sprites {}",
+                            if renderer.hide_sprites {
+                                "hidden"
+                            } else {
+                                "shown"
+                            }
+                        );
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F6),
+                        ..
+                    } => frontend.ppu_overlay = !frontend.ppu_overlay,
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F7),
+                        ..
+                    } => frontend.sprite_zero_marker = !frontend.sprite_zero_marker,
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F8),
+                        ..
+                    } => {
+                        let renderer = &mut frontend.renderer;
+                        renderer.sprite_limit = !renderer.sprite_limit;
+                        println!(
+                            "Sprite limit {}",
+                            if renderer.sprite_limit { "on" } else { "off" }
+                        );
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F9),
+                        ..
+                    } => {
+                        let map = render::nametable_map(ppu, frontend.renderer.colors());
+                        match screenshot::screenshot_file(screenshot_dir, rom_path, "map")
+                            .and_then(|path| screenshot::save_png(&map, &path).map(|_| path))
+                        {
+                            Ok(path) => println!("Saved nametable map to {}", path.display()),
+                            Err(e) => eprintln!("{}", e),
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F10),
+                        keymod,
+                        repeat: false,
+                        ..
+                    } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            let mut video = video.borrow_mut();
+                            if video.take().is_some() {
+                                notify(&osd, "Video recording stopped".to_string());
+                            } else {
+                                match start_video(
+                                    &frontend.recording,
+                                    screenshot_dir,
+                                    rom_path,
+                                    region.frame_rate(),
+                                    audio_format,
+                                    frontend.renderer.colors(),
+                                ) {
+                                    Ok((recorder, writer)) => {
+                                        *video = Some(recorder);
+                                        frontend.writers.push(writer);
+                                        notify(&osd, "Recording video".to_string());
+                                    }
+                                    Err(e) => eprintln!("{}", e),
+                                }
+                            }
+                        } else if clip.take().is_some() {
+                            notify(&osd, "Recording stopped".to_string());
+                        } else {
+                            let RecordingConfig {
+                                format, downscale, ..
+                            } = frontend.recording;
+                            match clip::clip_file(screenshot_dir, rom_path, format).and_then(
+                                |path| {
+                                    let colors = frontend.renderer.colors();
+                                    ClipRecorder::start(path, format, downscale, colors)
+                                },
+                            ) {
+                                Ok((recorder, writer)) => {
+                                    clip = Some(recorder);
+                                    frontend.writers.push(writer);
+                                    notify(&osd, "Recording".to_string());
+                                }
+                                Err(e) => eprintln!("{}", e),
+                            }
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F10),
+                        keymod,
+                        repeat: false,
+                        ..
+                    } if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) => save_replay(
+                        &replay,
+                        frontend.renderer.colors(),
+                        screenshot_dir,
+                        rom_path,
+                    ),
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F10),
+                        ..
+                    } => actions.borrow_mut().push(Action::ExportSram),
+                    Event::KeyDown {
+                        keycode: Some(key @ (Keycode::Num5 | Keycode::Num6)),
+                        repeat: false,
+                        ..
+                    } if vs_game => {
+                        let slot = if key == Keycode::Num5 { 0 } else { 1 };
+                        actions.borrow_mut().push(Action::InsertCoin(slot));
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::R),
+                        keymod,
+                        repeat: false,
+                        ..
+                    } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                        let event = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            ConsoleEvent::Power
+                        } else {
+                            ConsoleEvent::Reset
+                        };
+                        actions.borrow_mut().push(Action::Console(event));
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Home),
+                        repeat: false,
+                        ..
+                    } => println!("{}", frontend.frame_stats.summary()),
+                    Event::KeyDown {
+                        keycode: Some(key),
+                        repeat: false,
+                        ..
+                    } if frontend.macros.is_bound(key) => frontend.macros.play(key),
+                    Event::DropFile { filename, .. } if filename.ends_with(".sav") => actions
+                        .borrow_mut()
+                        .push(Action::ImportSram(PathBuf::from(filename))),
+                    Event::ControllerDeviceAdded { which, .. } => {
+                        if let Ok(controller) = frontend.controller_subsystem.open(which) {
+                            frontend.input.connect_pad(controller.instance_id());
+                            frontend.controllers.push(controller);
+                        }
+                    }
+                    Event::ControllerDeviceRemoved { which, .. } => {
+                        frontend.input.disconnect_pad(which);
+                        frontend
+                            .controllers
+                            .retain(|controller| controller.instance_id() != which);
+                    }
+                    event => {
+                        frontend
+                            .input
+                            .handle_event(&event, &mut [&mut *p1, &mut *p2]);
+                    }
+                }
+            }
+            // These need the whole frontend, which the event loop has borrowed
+            if cycle_scale_mode {
+                let mode = frontend.cycle_scale_mode();
+                notify(&osd, format!("Scale mode: {}", mode.name()));
+            }
+            if cycle_crt {
+                let preset = frontend.cycle_crt();
+                notify(&osd, format!("CRT: {}", preset.name()));
+            }
+            if toggle_fullscreen {
+                frontend.toggle_fullscreen();
+            }
+            if let Some(speed) = new_speed {
+                notify(&osd, format!("Speed {}%", frontend.set_speed(speed)));
+            }
+            // Runs once the events are handled, as it waits for its own
+            if browse {
+                let slots = slot_previews(&paths.states, rom_path);
+                match browse_states(frontend, &slots, state_slot) {
+                    Some(Some(slot)) => {
+                        state_slot = slot;
+                        actions.borrow_mut().push(Action::LoadState(slot));
+                    }
+                    Some(None) => {}
+                    None => actions.borrow_mut().push(Action::Quit),
+                }
+            }
+            if !frontend.paused || advance {
+                break;
+            }
+            // Saving or loading needs the whole machine, so it's handled between frames and
+            // the pause carries on after without running one
+            if !actions.borrow().is_empty() {
+                hold_pause.set(true);
+                return;
+            }
+            was_paused = true;
+            // Redrawn in case the state browser was over it
+            frontend.renderer.render(ppu, &mut frontend.frame);
+            let prompt = frontend.rebinding.as_ref().and_then(rebind_prompt);
+            let pinned: Vec<&str> = prompt.as_deref().into_iter().chain(["PAUSED"]).collect();
+            osd.borrow_mut()
+                .draw(&mut frontend.frame, Instant::now(), &pinned);
+            frontend.present();
+            std::thread::sleep(PAUSE_POLL);
+        }
+        if let Some(script) = frontend.script.as_mut() {
+            let event = script.apply(&mut [&mut *p1, &mut *p2]);
+            // The checksum is for the frame that just ended, so it goes before the event
+            if let Some(check) = script.take_check() {
+                actions.borrow_mut().push(Action::CheckMovie(check));
+            }
+            if let Some(event) = event {
+                actions.borrow_mut().push(Action::Console(event));
+            }
+        }
+        frontend.macros.apply(&mut [&mut *p1, &mut *p2]);
+        frontend.flash = frontend.latency_flash && !(p1.buttons() - held_before).is_empty();
+        record_movie(&recorder, |recorder| {
+            recorder.record_input([p1.buttons(), p2.buttons()])
+        });
+        if !frontend.macros.record([p1.buttons(), p2.buttons()]) {
+            println!(
+                "Macros are limited to {} frames, press a key to bind it to or Esc to drop it",
+                MAX_MACRO_FRAMES
+            );
+        }
+        frontend.reload_config();
+        power_pad.set(frontend.input.power_pad());
+        sound_speed.set(frontend.limiter.sound_speed().filter(|_| !was_paused));
+        frontend.limiter.wait();
+        frontend.frame_stats.resume();
+        if was_paused {
+            frontend.fps.restart();
+        }
+    })?;
+
     let mut cpu = CPU::new(bus);
+    cpu.bus.set_region(region);
+    cpu.bus.set_sprite_overflow_bug(sprite_overflow_bug);
+    cpu.bus.set_overclock_lines(overclock_lines);
+    cpu.bus.set_audio_filters(&audio_filters);
+    cpu.bus.set_expansion_volume(expansion_volume);
+    if let Some(audio) = audio.as_ref() {
+        // Stereo is picked when the device opens, so a config edit takes a restart
+        cpu.bus.set_panning((audio.channels() == 2).then_some(pan));
+        cpu.bus.set_sample_rate(audio.sample_rate());
+    }
+    if let Some(vs) = cpu.bus.vs_system() {
+        vs.dip_switches = args
+            .dip_switches
+            .iter()
+            .fold(0, |switches, n| switches | 1 << (n - 1));
+    }
+    for (port, controller) in ports.into_iter().enumerate() {
+        cpu.bus.set_controller(port, controller);
+    }
+
+    let sav = if cpu.bus.has_battery() && !movie {
+        match sram::sav_file(&paths.saves, rom_path) {
+            Ok(sav) => Some(sav),
+            Err(e) => {
+                eprintln!("{}, the game won't be saved", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(sav) = &sav {
+        let loaded = sram::read(sav)
+            .and_then(|data| data.map_or(Ok(()), |data| cpu.bus.load_prg_ram(&data)));
+        if let Err(e) = loaded {
+            eprintln!("{}", e);
+        }
+    }
     cpu.reset();
-    cpu.run();
+    // --load-state is for the ROM from the command line, like --patch
+    if let Some(path) = args
+        .load_state
+        .as_deref()
+        .filter(|_| args.is_command_line_rom(rom_path))
+    {
+        resume_state = match std::fs::read(path) {
+            Ok(data) => Some(
+                std::str::from_utf8(&data)
+                    .ok()
+                    .and_then(crash_dump::state_from_dump)
+                    .unwrap_or(data),
+            ),
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", path.display(), e);
+                None
+            }
+        };
+    }
+    if let Some(state) = resume_state {
+        match save_state::load(&mut cpu, rom_crc, &state) {
+            Ok(()) => println!("Loaded state from frame {}", cpu.bus.frame_count()),
+            Err(e) => eprintln!("Can't load state: {}", e),
+        }
+    }
+    // With auto-save on, a crash saves the last state from before it rather than whatever
+    // the crash left behind
+    let mut last_good_state = None;
+
+    // Emulation bugs and jammed CPUs surface as panics deep inside the core; catch them here
+    // so the window can explain what happened instead of vanishing
+    let mut trace = TraceLog::new();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut before_instruction = |cpu: &mut CPU| {
+            trace.record(cpu);
+            if let Some(debugger) = debugger.as_mut() {
+                debugger.before_instruction_with(cpu, || {
+                    let open = frontend.borrow_mut().idle();
+                    if !open {
+                        actions.borrow_mut().push(Action::Quit);
+                    }
+                    open
+                });
+            }
+        };
+        let mut last_frame = 0;
+        let mut samples = Vec::new();
+        let mut underruns = audio.as_ref().map_or(0, |audio| audio.stats().underruns);
+        // Frames since this game started, which movies count in
+        let mut movie_frame = 0;
+        while cpu.step_with_callback(&mut before_instruction) {
+            if cpu.bus.frame_count() == last_frame {
+                continue;
+            }
+            last_frame = cpu.bus.frame_count();
+            movie_frame += 1;
+            cpu.bus.set_power_pad(power_pad.get());
+            if let Some(audio) = audio.as_mut() {
+                cpu.bus.take_samples(&mut samples);
+                let mut video = video.borrow_mut();
+                if video
+                    .as_mut()
+                    .is_some_and(|recorder| !recorder.samples(&samples))
+                {
+                    *video = None;
+                }
+                // A video's sound is made at the console's speed to match its frames
+                let speed = sound_speed
+                    .get()
+                    .filter(|&speed| speed == 1.0 || video.is_none());
+                drop(video);
+                if let Some(speed) = speed {
+                    audio.push(&samples);
+                    // Fewer samples a frame sped up, more slowed down, which plays the sound
+                    // higher or lower
+                    cpu.bus
+                        .set_audio_rate_adjustment(audio.rate_adjustment() / speed);
+                    if audio.stats().underruns > underruns {
+                        underruns = audio.stats().underruns;
+                        osd.borrow_mut().show("AUDIO UNDERRUN");
+                    }
+                } else {
+                    // Paused or fast-forwarded, the sound would only crackle, so it's muted.
+                    // Running dry while paused doesn't count as an underrun. Samples are still
+                    // made at the console's speed for a video.
+                    audio.pause();
+                    cpu.bus.set_audio_rate_adjustment(1.0);
+                    underruns = audio.stats().underruns;
+                }
+            }
+            if auto_state.is_some() && last_frame % AUTO_SAVE_INTERVAL == 0 {
+                last_good_state = Some(save_state::save(&cpu, rom_crc));
+            }
+            record_movie(&recorder, |recorder| {
+                recorder.record_check(movie_frame, cpu.bus.ram_checksum())
+            });
+            loop {
+                for action in actions.take() {
+                    match action {
+                        Action::Quit => return Some(Exit::Quit),
+                        Action::Switch { forward } => return Some(Exit::Switch { forward }),
+                        Action::ExportSram => export_sram(&cpu, paths, rom_path),
+                        Action::InsertCoin(slot) => {
+                            if let Some(vs) = cpu.bus.vs_system() {
+                                vs.insert_coin(slot);
+                            }
+                        }
+                        Action::SaveState { slot, thumbnail } => {
+                            let mut state = save_state::save(&cpu, rom_crc);
+                            save_state::add_thumbnail(&mut state, &thumbnail);
+                            match state_history::slot_file(&paths.states, rom_path, slot).and_then(
+                                |file| state_history::save_slot(&file, &state, state_history),
+                            ) {
+                                Ok(()) => notify(&osd, format!("State {} saved", slot)),
+                                Err(e) => eprintln!("{}", e),
+                            }
+                        }
+                        Action::LoadState(slot) => {
+                            let before = save_state::save(&cpu, rom_crc);
+                            match state_history::slot_file(&paths.states, rom_path, slot)
+                                .and_then(|file| state_history::read_slot(&file, 0))
+                                .and_then(|state| {
+                                    let state = state.ok_or("Nothing saved there")?;
+                                    save_state::load(&mut cpu, rom_crc, &state)
+                                }) {
+                                Ok(()) => {
+                                    undo_load.remember(before);
+                                    notify(&osd, format!("State {} loaded", slot));
+                                }
+                                Err(e) => {
+                                    eprintln!("Can't load slot {}: {}", slot, e);
+                                    osd.borrow_mut().show(format!("Can't load state {}", slot));
+                                }
+                            }
+                        }
+                        Action::UndoLoad => match undo_load.take() {
+                            Some(state) => match save_state::load(&mut cpu, rom_crc, &state) {
+                                Ok(()) => notify(&osd, "Went back to before the last load".into()),
+                                Err(e) => eprintln!("Can't undo the load: {}", e),
+                            },
+                            None => notify(&osd, "No state load to undo".into()),
+                        },
+                        Action::Console(event) => {
+                            record_movie(&recorder, |recorder| recorder.record_event(event));
+                            match event {
+                                ConsoleEvent::Reset => {
+                                    cpu.soft_reset();
+                                    notify(&osd, "Reset".into());
+                                }
+                                ConsoleEvent::Power => {
+                                    cpu.power_cycle();
+                                    notify(&osd, "Power cycled".into());
+                                }
+                            }
+                        }
+                        Action::CheckMovie(expected) => {
+                            let checksum = cpu.bus.ram_checksum();
+                            if let Err(e) = movie_checker.check(expected, movie_frame, checksum) {
+                                eprintln!("{}", e);
+                            }
+                        }
+                        Action::ImportSram(path) => {
+                            match sram::read(&path).and_then(|data| {
+                                let data = data.ok_or("File doesn't exist")?;
+                                cpu.bus.load_prg_ram(&data)
+                            }) {
+                                Ok(()) => {
+                                    println!("Imported save RAM from {}", path.display());
+                                    cpu.reset();
+                                }
+                                Err(e) => eprintln!("Can't import {}: {}", path.display(), e),
+                            }
+                        }
+                    }
+                }
+                // Still paused after a state was saved or loaded: back to waiting, showing
+                // the loaded picture, without running a frame
+                if !hold_pause.get() {
+                    break;
+                }
+                cpu.bus.repeat_frame_callback();
+            }
+        }
+        None
+    }));
+
+    if let (Some(recorder), Some(path)) = (recorder.take(), &args.record_movie) {
+        finish_movie(recorder, path);
+    }
+    // Written even after a crash: the game's RAM is probably still fine and is the player's
+    // progress
+    if let Some(sav) = &sav {
+        if let Err(e) = sram::write(sav, cpu.bus.prg_ram()) {
+            eprintln!("{}", e);
+        }
+    }
+    if let Some(file) = &auto_state {
+        let state = match result {
+            Ok(Some(_)) => Some(save_state::save(&cpu, rom_crc)),
+            _ => last_good_state,
+        };
+        // Through a temporary file, so being killed halfway doesn't leave a truncated state
+        // to offer on the next start
+        if let Some(state) = state {
+            match sram::write(file, &state) {
+                Ok(()) => println!("Saved state to {}", file.display()),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    }
+    let error = match result {
+        Ok(Some(exit)) => return Ok(exit),
+        Ok(None) => EmulationError::Crash {
+            message: "CPU executed BRK".to_string(),
+            cpu: CpuSnapshot::of(&cpu),
+        },
+        Err(payload) => EmulationError::from_panic(&*payload, &cpu),
+    };
+    let dump = crash_dump::dump(&error, &cpu, rom_path, rom_crc, &trace);
+    match paths::timestamped_file(&paths.crashes, rom_path, "crash", "txt").and_then(|path| {
+        std::fs::write(&path, dump)
+            .map_err(|e| e.to_string())
+            .map(|_| path)
+    }) {
+        Ok(path) => eprintln!("Wrote crash dump to {}", path.display()),
+        Err(e) => eprintln!("Failed to write crash dump: {}", e),
+    }
+    Err(error)
+}
+
+/// Runs `record` on the movie being recorded, if any. A movie that can't be written stops
+/// recording rather than failing every frame.
+fn record_movie<F>(recorder: &RefCell<Option<MovieRecorder>>, record: F)
+where
+    F: FnOnce(&mut MovieRecorder) -> Result<(), String>,
+{
+    let mut recorder = recorder.borrow_mut();
+    if let Some(Err(e)) = recorder.as_mut().map(record) {
+        eprintln!("{}, stopped recording", e);
+        *recorder = None;
+    }
+}
+
+fn finish_movie(mut recorder: MovieRecorder, path: &Path) {
+    match recorder.finish() {
+        Ok(()) => println!("Recorded movie to {}", path.display()),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+/// Gives `key` to the button being rebound. Once a player has all eight, saves them to the
+/// config file and moves on to player 2. Esc stops, keeping the players already finished.
+/// Hotkeys and macro keys are refused.
+fn rebind_key(
+    rebinding: &mut Option<Rebinding>,
+    input: &mut InputMap,
+    macros: &Macros,
+    config_file: &Path,
+    osd: &RefCell<Osd>,
+    key: Keycode,
+) {
+    let Some(current) = rebinding.as_mut() else {
+        return;
+    };
+    if key == Keycode::Escape {
+        *rebinding = None;
+        notify(osd, "Stopped rebinding".into());
+        return;
+    }
+    if HOTKEYS.contains(&key) {
+        notify(
+            osd,
+            format!("{} is a hotkey, press another key", key.name()),
+        );
+        return;
+    }
+    if macros.is_bound(key) {
+        notify(
+            osd,
+            format!("{} plays a macro, press another key", key.name()),
+        );
+        return;
+    }
+    if !current.press(key) {
+        notify(
+            osd,
+            format!("{} is on another button, press another key", key.name()),
+        );
+        return;
+    }
+    if current.button().is_some() {
+        print_rebind_prompt(current);
+        return;
+    }
+
+    let player = current.player();
+    let saved = Config::load(config_file).and_then(|mut config| {
+        current.apply(&mut config.input);
+        input.reload(&config.input)?;
+        config.save(config_file)
+    });
+    match saved {
+        Ok(()) => {
+            println!(
+                "Saved player {}'s keys to {}",
+                player + 1,
+                config_file.display()
+            );
+            osd.borrow_mut()
+                .show(format!("Saved player {}'s keys", player + 1));
+        }
+        Err(e) => {
+            eprintln!(
+                "Failed to save the keys to {}: {}",
+                config_file.display(),
+                e
+            );
+            osd.borrow_mut().show("Failed to save the keys");
+        }
+    }
+    *rebinding = (player == 0).then(|| Rebinding::new(1));
+    if let Some(next) = rebinding {
+        print_rebind_prompt(next);
+    }
+}
+
+/// The button waiting for a key, pinned over the picture for as long as it waits.
+fn rebind_prompt(rebinding: &Rebinding) -> Option<String> {
+    rebinding.button().map(|button| {
+        format!(
+            "Player {} {}: press a key, Esc stops",
+            rebinding.player() + 1,
+            input::button_name(button)
+        )
+    })
+}
+
+/// `rebind_prompt` for the terminal too, once per button.
+fn print_rebind_prompt(rebinding: &Rebinding) {
+    if let Some(prompt) = rebind_prompt(rebinding) {
+        println!("{}", prompt);
+    }
+}
+
+/// Binds the macro just recorded to `key` and adds it to the config file. Keys that
+/// already do something are refused and the macro keeps waiting for another one.
+fn bind_macro(macros: &mut Macros, input: &InputMap, config_file: &Path, key: Keycode) {
+    if key == Keycode::Escape {
+        macros.discard();
+        println!("Dropped the macro");
+        return;
+    }
+    if HOTKEYS.contains(&key) || input.binding(InputSource::Key(key)).is_some() {
+        println!("{} is already in use, press another key", key.name());
+        return;
+    }
+    let Some(input_macro) = macros.bind(key) else {
+        return;
+    };
+    println!(
+        "Macro of {} frames bound to {}",
+        input_macro.len(),
+        key.name()
+    );
+
+    // Read back from the file rather than saving the settings in use, which include
+    // command line options
+    let saved = Config::load(config_file).and_then(|mut config| {
+        config
+            .macros
+            .retain(|entry| Keycode::from_name(&entry.key) != Some(key));
+        config.macros.push(MacroConfig {
+            key: key.name(),
+            inputs: input_macro.to_lines(),
+        });
+        config.save(config_file)
+    });
+    if let Err(e) = saved {
+        eprintln!(
+            "Failed to save the macro to {}: {}",
+            config_file.display(),
+            e
+        );
+    }
+}
+
+/// Writes the instant replay to a GIF in the screenshots directory, on another thread so the
+/// game keeps going.
+fn save_replay(replay: &ReplayBuffer, colors: &Colors, dir: &Path, rom_path: &str) {
+    if replay.is_empty() {
+        println!("Nothing to replay, replay_seconds is 0 or the game just started");
+        return;
+    }
+    let path = match paths::timestamped_file(dir, rom_path, "replay", "gif") {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let replay = replay.snapshot(colors);
+    std::thread::spawn(move || match replay.save_gif(&path) {
+        Ok(()) => println!(
+            "Saved {} frames of replay to {}",
+            replay.len(),
+            path.display()
+        ),
+        Err(e) => eprintln!("{}", e),
+    });
+}
+
+/// Starts recording a video to the screenshots directory, with the sound if there is any.
+fn start_video(
+    recording: &RecordingConfig,
+    dir: &Path,
+    rom_path: &str,
+    frame_rate: f64,
+    audio: Option<AudioFormat>,
+    colors: &Colors,
+) -> Result<(VideoRecorder, JoinHandle<()>), String> {
+    let path = paths::timestamped_file(dir, rom_path, "video", recording.container.extension())?;
+    let ffmpeg = Some(recording.ffmpeg.clone()).filter(|ffmpeg| !ffmpeg.as_os_str().is_empty());
+    VideoRecorder::start(path, frame_rate, audio, colors, ffmpeg)
+}
+
+/// Writes the current battery RAM next to the regular save with a timestamp in the name.
+fn export_sram(cpu: &CPU, paths: &Paths, rom_path: &str) {
+    if cpu.bus.prg_ram().is_empty() {
+        println!("This cartridge has no save RAM");
+        return;
+    }
+    match paths::timestamped_file(&paths.saves, rom_path, "export", "sav")
+        .and_then(|path| sram::write(&path, cpu.bus.prg_ram()).map(|_| path))
+    {
+        Ok(path) => println!("Exported save RAM to {}", path.display()),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+/// Asks whether to continue from the state saved when the game was last closed. `None` if
+/// the user closed the window instead.
+fn ask_resume(frontend: &mut Frontend) -> Option<bool> {
+    frontend.frame = message_screen(
+        "RESUME GAME?",
+        "This game was saved automatically when it was last closed.",
+        &["Enter: resume   N: start over", "Esc: quit"],
+    );
+    frontend.present();
+    loop {
+        match frontend.event_pump.wait_event() {
+            Event::Quit { .. }
+            | Event::KeyDown {
+                keycode: Some(Keycode::Escape),
+                ..
+            } => return None,
+            Event::KeyDown {
+                keycode: Some(Keycode::Return | Keycode::KpEnter),
+                ..
+            } => return Some(true),
+            Event::KeyDown {
+                keycode: Some(Keycode::N),
+                ..
+            } => return Some(false),
+            Event::Window { .. } => frontend.present(),
+            _ => {}
+        }
+    }
+}
+
+/// What's in each state slot of the game at `rom_path`, for the browser.
+fn slot_previews(states: &Path, rom_path: &str) -> Vec<SlotPreview> {
+    (0..STATE_SLOTS)
+        .map(|slot| {
+            match state_history::slot_file(states, rom_path, slot)
+                .and_then(|file| state_history::read_slot(&file, 0))
+            {
+                Ok(Some(state)) => SlotPreview::Saved(save_state::thumbnail(&state)),
+                Ok(None) => SlotPreview::Empty,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    SlotPreview::Empty
+                }
+            }
+        })
+        .collect()
+}
+
+/// Shows the state slots until one is picked to load (`Some(Some(slot))`) or the browser is
+/// closed (`Some(None)`). `None` if the user closed the window instead.
+fn browse_states(
+    frontend: &mut Frontend,
+    slots: &[SlotPreview],
+    mut selected: u8,
+) -> Option<Option<u8>> {
+    loop {
+        frontend.frame = state_browser(slots, selected as usize);
+        frontend.present();
+        match frontend.event_pump.wait_event() {
+            Event::Quit { .. } => return None,
+            Event::KeyDown {
+                keycode: Some(Keycode::Escape),
+                ..
+            } => return Some(None),
+            Event::KeyDown {
+                keycode: Some(Keycode::Return | Keycode::KpEnter),
+                ..
+            } if matches!(slots[selected as usize], SlotPreview::Saved(_)) => {
+                return Some(Some(selected))
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Up),
+                ..
+            } => selected = (selected + STATE_SLOTS - 1) % STATE_SLOTS,
+            Event::KeyDown {
+                keycode: Some(Keycode::Down),
+                ..
+            } => selected = (selected + 1) % STATE_SLOTS,
+            _ => {}
+        }
+    }
+}
+
+/// Lets the user pick one of the recent ROMs, which are the playlist, or drop another on the
+/// window. `None` if they quit instead.
+fn pick_rom(frontend: &mut Frontend) -> Option<String> {
+    let count = frontend.playlist.len();
+    let mut selected = 0;
+    loop {
+        frontend.frame = rom_picker(frontend.playlist.roms(), selected);
+        frontend.present();
+        match frontend.event_pump.wait_event() {
+            Event::Quit { .. }
+            | Event::KeyDown {
+                keycode: Some(Keycode::Escape),
+                ..
+            } => return None,
+            Event::KeyDown {
+                keycode: Some(Keycode::Return | Keycode::KpEnter),
+                ..
+            } if count > 0 => return Some(frontend.playlist.jump_to(selected).to_string()),
+            Event::KeyDown {
+                keycode: Some(Keycode::Up),
+                ..
+            } if count > 0 => selected = (selected + count - 1) % count,
+            Event::KeyDown {
+                keycode: Some(Keycode::Down),
+                ..
+            } if count > 0 => selected = (selected + 1) % count,
+            Event::DropFile { filename, .. } => return Some(filename),
+            _ => {}
+        }
+    }
+}
+
+/// Puts `rom_path` at the top of the recent ROMs in `recent_file`.
+fn remember_rom(recent_file: &Path, rom_path: &str) {
+    let path = std::fs::canonicalize(rom_path).map_or_else(
+        |_| rom_path.to_string(),
+        |path| path.to_string_lossy().into_owned(),
+    );
+    let saved = RecentRoms::load(recent_file).and_then(|mut recent| {
+        if recent.roms.first() == Some(&path) {
+            return Ok(());
+        }
+        recent.add(&path);
+        recent.save(recent_file)
+    });
+    if let Err(e) = saved {
+        eprintln!(
+            "Failed to add the ROM to the recent ones in {}: {}",
+            recent_file.display(),
+            e
+        );
+    }
+}
+
+/// Shows `error` in the window and waits for the user to reset or drop in another ROM. `None`
+/// means they quit.
+fn show_error(frontend: &mut Frontend, error: &EmulationError) -> Option<ErrorAction> {
+    frontend.frame = error_screen(
+        error,
+        &[
+            "R: reset   Esc: quit",
+            "Drop a ROM on the window to open it",
+        ],
+    );
+    frontend.present();
+    loop {
+        match frontend.event_pump.wait_event() {
+            Event::Quit { .. }
+            | Event::KeyDown {
+                keycode: Some(Keycode::Escape),
+                ..
+            } => return None,
+            Event::KeyDown {
+                keycode: Some(Keycode::R),
+                ..
+            } => return Some(ErrorAction::Reset),
+            Event::DropFile { filename, .. } => return Some(ErrorAction::Open(filename)),
+            // Redraw after the window was covered or resized
+            Event::Window { .. } => frontend.present(),
+            _ => {}
+        }
+    }
 }
@@ -0,0 +1,270 @@
+mod namco108;
+mod namco163;
+mod nrom;
+mod vrc6;
+mod vrc7;
+mod vrc_irq;
+mod vs_board;
+
+use crate::{
+    apu::ExpansionAudio,
+    cartridge::{Mirroring, Rom},
+    error::EmulationError,
+    save_state::{StateReader, StateWriter},
+};
+
+pub use self::{
+    namco108::Namco108, namco163::Namco163, nrom::Nrom, vrc6::Vrc6, vrc7::Vrc7, vs_board::VsBoard,
+};
+
+/// Boards without CHR ROM have this much CHR RAM
+const CHR_RAM_SIZE: usize = 0x2000;
+
+/// The circuitry on a cartridge board between its chips and the console: which PRG and CHR
+/// banks the CPU and the PPU see, how the nametables are mirrored, and the IRQ counters and
+/// sound chips some boards add. The bus owns it and sends it every CPU access to $4020-$FFFF
+/// and every pattern table access.
+pub trait Mapper {
+    fn memory(&self) -> &Memory;
+    fn memory_mut(&mut self) -> &mut Memory;
+
+    /// Reads $4020-$FFFF without side effects, for debuggers.
+    fn cpu_peek(&self, address: u16) -> u8;
+
+    /// Reads $4020-$FFFF. Only boards whose reads change something need more than `cpu_peek`.
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        self.cpu_peek(address)
+    }
+
+    /// Writes $4020-$FFFF, PRG RAM or the board's registers. Returns whether the CHR banks or
+    /// the mirroring changed, so the bus can show the PPU.
+    fn cpu_write(&mut self, address: u16, value: u8) -> bool;
+
+    /// Where in CHR the 1KB of pattern table at `slot` * $400 is banked in from, as an offset
+    /// from `Memory::chr_offset`. The bus copies the banks that move to the PPU.
+    fn chr_slot(&self, slot: usize) -> usize;
+
+    /// Reads the pattern tables at $0000-$1FFF.
+    fn ppu_read(&self, address: u16) -> u8 {
+        let offset = self.chr_slot(address as usize / 0x400) + address as usize % 0x400;
+        self.memory().read_chr(offset)
+    }
+
+    /// Writes the pattern tables, which only sticks with CHR RAM.
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let offset = self.chr_slot(address as usize / 0x400) + address as usize % 0x400;
+        self.memory_mut().write_chr(offset, value);
+    }
+
+    fn mirroring(&self) -> Mirroring;
+
+    /// Runs IRQ counters and the like for `cycles` CPU cycles.
+    fn tick(&mut self, _cycles: u8) {}
+
+    /// Whether the board is holding the IRQ line.
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// Sees $4016 writes, whose OUT lines Vs. System boards use to switch CHR. Returns whether
+    /// the CHR banks changed.
+    fn write_4016(&mut self, _value: u8) -> bool {
+        false
+    }
+
+    /// Sound channels on the board, mixed in with the APU's.
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        None
+    }
+
+    /// Puts the registers back in their power-on state and clears CHR RAM. PRG RAM is left
+    /// to the bus, which keeps it on battery-backed boards.
+    fn power_cycle(&mut self) {
+        self.memory_mut().power_cycle();
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.memory().save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.memory_mut().load_state(reader)
+    }
+}
+
+/// The mapper for the cartridge's board, or `UnsupportedMapper` if it isn't emulated.
+pub fn for_rom(rom: &Rom) -> Result<Box<dyn Mapper>, EmulationError> {
+    match rom.mapper {
+        0 => Ok(Box::new(Nrom::new(rom))),
+        19 => Ok(Box::new(Namco163::new(rom))),
+        24 | 26 => Ok(Box::new(Vrc6::new(rom))),
+        85 => Ok(Box::new(Vrc7::new(rom))),
+        // Mapper 99 also switches PRG on the one board with 40KB of it
+        99 if rom.prg_rom.len() <= 0x8000 => Ok(Box::new(VsBoard::new(rom))),
+        206 => Ok(Box::new(Namco108::new(rom))),
+        mapper => Err(EmulationError::UnsupportedMapper(mapper)),
+    }
+}
+
+/// The usual name of iNES mapper `mapper`, for the common ones.
+pub fn name(mapper: u16) -> Option<&'static str> {
+    Some(match mapper {
+        0 => "NROM",
+        1 => "MMC1",
+        2 => "UxROM",
+        3 => "CNROM",
+        4 => "MMC3",
+        5 => "MMC5",
+        7 => "AxROM",
+        9 => "MMC2",
+        10 => "MMC4",
+        11 => "Color Dreams",
+        19 => "Namco 163",
+        21 | 23 | 25 => "VRC4",
+        22 => "VRC2",
+        24 | 26 => "VRC6",
+        34 => "BNROM / NINA-001",
+        66 => "GxROM",
+        69 => "Sunsoft FME-7",
+        71 => "Codemasters",
+        85 => "VRC7",
+        99 => "Vs. System",
+        206 => "Namco 108",
+        _ => return None,
+    })
+}
+
+/// The chips every board has: PRG ROM, CHR ROM or RAM, and PRG RAM if the header asks for
+/// it. Mappers bank pieces of them in.
+pub struct Memory {
+    prg_rom: Vec<u8>,
+    /// RAM at $6000-$7FFF on most boards. Holds the save game on battery-backed carts.
+    pub prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+}
+
+impl Memory {
+    pub fn new(rom: &Rom) -> Self {
+        let chr_is_ram = rom.chr_rom.is_empty();
+        Memory {
+            prg_rom: rom.prg_rom.clone(),
+            prg_ram: vec![0; rom.prg_ram_size],
+            chr: if chr_is_ram {
+                vec![0; CHR_RAM_SIZE]
+            } else {
+                rom.chr_rom.clone()
+            },
+            chr_is_ram,
+        }
+    }
+
+    /// The byte at `offset` into PRG ROM bank `bank` of `size` bytes. Banks past the end wrap
+    /// around, like a smaller chip ignoring the high address lines.
+    pub fn read_prg(&self, size: usize, bank: usize, offset: u16) -> u8 {
+        self.prg_rom[(bank * size + offset as usize % size) % self.prg_rom.len()]
+    }
+
+    /// Where the byte at `offset` into CHR bank `bank` of `size` bytes is in CHR, wrapping
+    /// like `read_prg`.
+    pub fn chr_offset(&self, size: usize, bank: usize, offset: u16) -> usize {
+        (bank * size + offset as usize % size) % self.chr.len()
+    }
+
+    pub fn read_chr(&self, offset: usize) -> u8 {
+        self.chr[offset]
+    }
+
+    /// Writes CHR RAM. Does nothing to CHR ROM.
+    pub fn write_chr(&mut self, offset: usize, value: u8) {
+        if self.chr_is_ram {
+            self.chr[offset] = value;
+        }
+    }
+
+    /// All of CHR ROM or RAM, for copying banks to the PPU.
+    pub fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    /// How many banks of `size` bytes there are in PRG ROM and CHR.
+    pub fn prg_banks(&self, size: usize) -> usize {
+        (self.prg_rom.len() / size).max(1)
+    }
+
+    pub fn chr_banks(&self, size: usize) -> usize {
+        (self.chr.len() / size).max(1)
+    }
+
+    pub fn chr_is_ram(&self) -> bool {
+        self.chr_is_ram
+    }
+
+    /// Reads PRG RAM at `address`, mirrored over however much there is. Boards without any
+    /// read 0.
+    pub fn read_prg_ram(&self, address: u16) -> u8 {
+        if self.prg_ram.is_empty() {
+            return 0;
+        }
+        self.prg_ram[address as usize % self.prg_ram.len()]
+    }
+
+    pub fn write_prg_ram(&mut self, address: u16, value: u8) {
+        if !self.prg_ram.is_empty() {
+            let len = self.prg_ram.len();
+            self.prg_ram[address as usize % len] = value;
+        }
+    }
+
+    /// Clears CHR RAM. PRG RAM is up to the bus.
+    pub fn power_cycle(&mut self) {
+        if self.chr_is_ram {
+            self.chr.fill(0);
+        }
+    }
+
+    /// PRG RAM, and CHR RAM if the board has it.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.prg_ram);
+        if self.chr_is_ram {
+            writer.bytes(&self.chr);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.bytes_into(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            reader.bytes_into(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_banks_wrap_around() {
+        let mut rom = test_rom();
+        rom.prg_rom = (0..0x8000).map(|i| (i / 0x2000) as u8).collect();
+        rom.chr_rom = Vec::new();
+        let mut memory = Memory::new(&rom);
+        assert_eq!(memory.prg_banks(0x2000), 4);
+        assert_eq!(memory.read_prg(0x2000, 3, 0x9FFF), 3);
+        assert_eq!(memory.read_prg(0x2000, 5, 0), 1);
+
+        assert!(memory.chr_is_ram());
+        assert_eq!(memory.chr_offset(0x400, 9, 0x1401), 0x401);
+        memory.write_chr(0x401, 7);
+        assert_eq!(memory.read_chr(0x401), 7);
+        memory.power_cycle();
+        assert_eq!(memory.read_chr(0x401), 0);
+
+        // No PRG RAM reads as 0 and ignores writes
+        memory.prg_ram.clear();
+        memory.write_prg_ram(0x6000, 1);
+        assert_eq!(memory.read_prg_ram(0x6000), 0);
+    }
+}
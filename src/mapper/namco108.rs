@@ -0,0 +1,134 @@
+use crate::{
+    cartridge::{Mirroring, Rom},
+    save_state::{StateReader, StateWriter},
+};
+
+use super::{Mapper, Memory};
+
+/// Namco 108 and its DxROM boards, mapper 206: the chip the MMC3 grew out of, with the same
+/// bank registers but no IRQ, mirroring control or PRG RAM. Two switchable 8KB PRG banks at
+/// $8000 and $A000 with the last two fixed after them, and CHR in two 2KB and four 1KB banks.
+pub struct Namco108 {
+    memory: Memory,
+    mirroring: Mirroring,
+    /// Which of `banks` $8001 writes, from $8000
+    select: u8,
+    /// R0-R1 the 2KB CHR banks, R2-R5 the 1KB ones, R6-R7 the PRG banks
+    banks: [u8; 8],
+}
+
+impl Namco108 {
+    pub fn new(rom: &Rom) -> Self {
+        Namco108 {
+            memory: Memory::new(rom),
+            mirroring: rom.mirroring,
+            select: 0,
+            banks: [0; 8],
+        }
+    }
+
+    /// The 1KB CHR bank at `slot` * $400
+    fn chr_bank(&self, slot: usize) -> usize {
+        let bank = match slot {
+            // The 2KB banks ignore the low bit
+            0..=3 => self.banks[slot / 2] & 0x3E | (slot & 1) as u8,
+            _ => self.banks[slot - 2],
+        };
+        bank as usize & 0x3F
+    }
+}
+
+impl Mapper for Namco108 {
+    fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    fn cpu_peek(&self, address: u16) -> u8 {
+        let banks = self.memory.prg_banks(0x2000);
+        let bank = match address {
+            0x8000..=0x9FFF => self.banks[6] as usize & 0x0F,
+            0xA000..=0xBFFF => self.banks[7] as usize & 0x0F,
+            0xC000..=0xDFFF => banks - 2,
+            0xE000..=0xFFFF => banks - 1,
+            _ => return 0,
+        };
+        self.memory.read_prg(0x2000, bank, address)
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) -> bool {
+        // Only $8000-$9FFF is decoded, even addresses selecting and odd ones writing
+        match address & 0xE001 {
+            0x8000 => self.select = value & 0x07,
+            0x8001 => {
+                self.banks[self.select as usize] = value;
+                return self.select < 6;
+            }
+            _ => {}
+        }
+        false
+    }
+
+    fn chr_slot(&self, slot: usize) -> usize {
+        self.memory.chr_offset(0x400, self.chr_bank(slot), 0)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn power_cycle(&mut self) {
+        self.select = 0;
+        self.banks = [0; 8];
+        self.memory.power_cycle();
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.memory.save_state(writer);
+        writer.u8(self.select);
+        writer.bytes(&self.banks);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.memory.load_state(reader)?;
+        self.select = reader.u8()? & 0x07;
+        reader.bytes_into(&mut self.banks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_banks() {
+        let mut rom = test_rom();
+        rom.mapper = 206;
+        rom.prg_rom = (0..0x20000).map(|i| (i / 0x2000) as u8).collect();
+        rom.chr_rom = (0..0x10000).map(|i| (i / 0x400) as u8).collect();
+        let mut namco = Namco108::new(&rom);
+
+        for (register, bank) in [(0, 9), (1, 20), (5, 33), (6, 4), (7, 5)] {
+            namco.cpu_write(0x8000, register);
+            namco.cpu_write(0x8001, bank);
+        }
+        assert_eq!(
+            [0x8000, 0xA000, 0xC000, 0xE000].map(|address| namco.cpu_peek(address)),
+            [4, 5, 14, 15]
+        );
+        assert_eq!(
+            [0x0000, 0x0400, 0x0800, 0x0C00, 0x1C00].map(|address| namco.ppu_read(address)),
+            [8, 9, 20, 21, 33]
+        );
+
+        // Mirrors of $8000/$8001 up to $9FFF; $A000 and up do nothing
+        namco.cpu_write(0x9FFE, 6);
+        namco.cpu_write(0x9FFF, 7);
+        namco.cpu_write(0xA001, 1);
+        assert_eq!(namco.cpu_peek(0x8000), 7);
+    }
+}
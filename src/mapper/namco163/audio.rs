@@ -0,0 +1,190 @@
+use crate::{
+    apu::ExpansionAudio,
+    save_state::{StateReader, StateWriter},
+};
+
+/// CPU cycles the chip spends on each channel before moving to the next
+const CYCLES_PER_CHANNEL: u8 = 15;
+/// Level per step of a channel's -8 to 7 sample times its 0-15 volume
+const LEVEL_STEP: f32 = 0.12 / 120.0;
+
+/// The Namco 163's sound: up to eight wavetable channels playing 4-bit samples out of 128
+/// bytes of RAM, which also holds the channels' registers from $40 up. The chip plays one
+/// channel at a time, switching every 15 cycles, so the more are on the quieter each is.
+pub struct Namco163Audio {
+    ram: [u8; 128],
+    /// RAM address for $4800, from $F800
+    address: u8,
+    auto_increment: bool,
+    /// $E000 bit 6 mutes the chip
+    pub enabled: bool,
+    cycles: u8,
+    /// The channel being played, counting down from 7
+    channel: u8,
+    /// Each channel's level when it was last played
+    outputs: [i16; 8],
+}
+
+impl Default for Namco163Audio {
+    fn default() -> Self {
+        Namco163Audio::new()
+    }
+}
+
+impl Namco163Audio {
+    pub fn new() -> Self {
+        Namco163Audio {
+            ram: [0; 128],
+            address: 0,
+            auto_increment: false,
+            enabled: true,
+            cycles: 0,
+            channel: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    /// $F800, `IAAA AAAA`: the RAM address and whether $4800 accesses move it along.
+    pub fn write_address(&mut self, value: u8) {
+        self.address = value & 0x7F;
+        self.auto_increment = value & 0x80 != 0;
+    }
+
+    /// The byte at the RAM address, for reads of $4800 without side effects.
+    pub fn peek_data(&self) -> u8 {
+        self.ram[self.address as usize]
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        let value = self.peek_data();
+        self.advance();
+        value
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.ram[self.address as usize] = value;
+        self.advance();
+    }
+
+    fn advance(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7F;
+        }
+    }
+
+    /// How many channels are on, from channel 7 down. Bits 4-6 of $7F.
+    fn channel_count(&self) -> u8 {
+        (self.ram[0x7F] >> 4 & 0x07) + 1
+    }
+
+    /// Moves channel `channel`'s phase along and returns its level.
+    fn play(&mut self, channel: u8) -> i16 {
+        // Frequency and phase are 18 and 24 bits, interleaved low byte first. The top bits of
+        // the frequency share a byte with the wave's length.
+        let base = 0x40 + channel as usize * 8;
+        let registers = &self.ram[base..base + 8];
+        let frequency = u32::from_le_bytes([registers[0], registers[2], registers[4] & 0x03, 0]);
+        // In samples, each a nibble
+        let length = 256 - (registers[4] & 0xFC) as u32;
+        let offset = registers[6] as u32;
+        let volume = (registers[7] & 0x0F) as i16;
+        let mut phase = u32::from_le_bytes([registers[1], registers[3], registers[5], 0]);
+        phase = (phase + frequency) % (length << 16);
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+
+        // Low nibble first
+        let sample = ((phase >> 16) + offset) as usize & 0xFF;
+        let byte = self.ram[sample / 2];
+        let nibble = if sample & 1 == 0 {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        };
+        (nibble as i16 - 8) * volume
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.ram);
+        writer.u8(self.address);
+        writer.bool(self.auto_increment);
+        writer.bool(self.enabled);
+        writer.u8(self.cycles);
+        writer.u8(self.channel);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.bytes_into(&mut self.ram)?;
+        self.address = reader.u8()? & 0x7F;
+        self.auto_increment = reader.bool()?;
+        self.enabled = reader.bool()?;
+        self.cycles = reader.u8()? % CYCLES_PER_CHANNEL;
+        self.channel = reader.u8()? & 0x07;
+        self.outputs = [0; 8];
+        Ok(())
+    }
+}
+
+impl ExpansionAudio for Namco163Audio {
+    fn mix_audio(&mut self, cycles: u8) -> f32 {
+        if !self.enabled {
+            return 0.0;
+        }
+        let count = self.channel_count();
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_CHANNEL {
+            self.cycles -= CYCLES_PER_CHANNEL;
+            let first = 8 - count;
+            self.channel = if self.channel <= first {
+                7
+            } else {
+                self.channel - 1
+            };
+            self.outputs[self.channel as usize] = self.play(self.channel);
+        }
+        let total: i16 = self.outputs[(8 - count) as usize..].iter().sum();
+        total as f32 / count as f32 * LEVEL_STEP
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wavetable_playback() {
+        let mut audio = Namco163Audio::new();
+        // A 4-sample wave at address 0: 15, 0, 8, 8
+        audio.write_address(0x80);
+        for byte in [0x0F, 0x88] {
+            audio.write_data(byte);
+        }
+        // Channel 7 alone, full volume, advancing one sample every time it's played. $FD is
+        // a length of 256 - $FC samples and bit 16 of the frequency.
+        audio.write_address(0xF8);
+        for byte in [0x00, 0x00, 0x00, 0x00, 0xFD, 0x00, 0x00, 0x0F] {
+            audio.write_data(byte);
+        }
+        assert_eq!(audio.address, 0x00, "wrapped around");
+        audio.write_address(0x7F);
+        assert_eq!(audio.peek_data(), 0x0F);
+
+        let levels: Vec<i16> = (0..5)
+            .map(|_| (audio.mix_audio(CYCLES_PER_CHANNEL) / LEVEL_STEP).round() as i16)
+            .collect();
+        assert_eq!(levels, [-120, 0, 0, 105, -120]);
+
+        // With channel 6 on too, silent, channel 7 is half as loud
+        audio.ram[0x7F] = 0x1F;
+        for expected in [0.0, 0.0, 52.5, -60.0] {
+            let level = audio.mix_audio(CYCLES_PER_CHANNEL * 2) / LEVEL_STEP;
+            assert!(
+                (level - expected).abs() < 0.01,
+                "{} for {}",
+                level,
+                expected
+            );
+        }
+    }
+}
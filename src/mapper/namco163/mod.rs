@@ -0,0 +1,234 @@
+mod audio;
+
+use crate::{
+    apu::ExpansionAudio,
+    cartridge::{Mirroring, Rom},
+    save_state::{StateReader, StateWriter},
+};
+
+use self::audio::Namco163Audio;
+use super::{Mapper, Memory};
+
+/// The IRQ counter fires when it gets here, then stops
+const IRQ_AT: u16 = 0x7FFF;
+
+/// Namco 163 (and the 129, which lacks the sound), mapper 19: three switchable 8KB PRG banks,
+/// eight 1KB CHR banks, nametables picked one by one, a 15-bit CPU cycle IRQ counter and
+/// eight wavetable sound channels. The nametables can be CHR ROM as well as the console's
+/// VRAM, and the pattern tables VRAM as well as CHR ROM; only VRAM nametables are emulated.
+pub struct Namco163 {
+    memory: Memory,
+    prg: [u8; 3],
+    chr: [u8; 8],
+    /// $C000-$DFFF, one per nametable. $E0 and up is a page of VRAM, by the low bit.
+    nametables: [u8; 4],
+    /// What the header says, for nametables out of CHR ROM
+    header_mirroring: Mirroring,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+    audio: Namco163Audio,
+}
+
+impl Namco163 {
+    pub fn new(rom: &Rom) -> Self {
+        Namco163 {
+            memory: Memory::new(rom),
+            prg: [0; 3],
+            chr: [0; 8],
+            nametables: [0; 4],
+            header_mirroring: rom.mirroring,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            audio: Namco163Audio::new(),
+        }
+    }
+}
+
+impl Mapper for Namco163 {
+    fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    fn cpu_peek(&self, address: u16) -> u8 {
+        match address {
+            0x4800..=0x4FFF => self.audio.peek_data(),
+            0x5000..=0x57FF => self.irq_counter as u8,
+            0x5800..=0x5FFF => (self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7,
+            0x6000..=0x7FFF => self.memory.read_prg_ram(address),
+            0x8000..=0xDFFF => {
+                let bank = self.prg[(address - 0x8000) as usize / 0x2000];
+                self.memory.read_prg(0x2000, bank as usize, address)
+            }
+            0xE000..=0xFFFF => {
+                let last = self.memory.prg_banks(0x2000) - 1;
+                self.memory.read_prg(0x2000, last, address)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        match address {
+            0x4800..=0x4FFF => self.audio.read_data(),
+            _ => self.cpu_peek(address),
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) -> bool {
+        match address {
+            0x4800..=0x4FFF => self.audio.write_data(value),
+            // Writing either half of the counter acknowledges the IRQ
+            0x5000..=0x57FF => {
+                self.irq_counter = self.irq_counter & 0x7F00 | value as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = self.irq_counter & 0x00FF | (value as u16 & 0x7F) << 8;
+                self.irq_enabled = value & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7FFF => self.memory.write_prg_ram(address, value),
+            0x8000..=0xBFFF => {
+                self.chr[(address - 0x8000) as usize / 0x800] = value;
+                return true;
+            }
+            0xC000..=0xDFFF => {
+                self.nametables[(address - 0xC000) as usize / 0x800] = value;
+                return true;
+            }
+            0xE000..=0xE7FF => {
+                self.prg[0] = value & 0x3F;
+                self.audio.enabled = value & 0x40 == 0;
+            }
+            0xE800..=0xEFFF => self.prg[1] = value & 0x3F,
+            0xF000..=0xF7FF => self.prg[2] = value & 0x3F,
+            0xF800..=0xFFFF => self.audio.write_address(value),
+            _ => {}
+        }
+        false
+    }
+
+    fn chr_slot(&self, slot: usize) -> usize {
+        self.memory.chr_offset(0x400, self.chr[slot & 0x07] as usize, 0)
+    }
+
+    /// The nametable registers as a mirroring, when they're all VRAM pages in a layout the PPU
+    /// has one for.
+    fn mirroring(&self) -> Mirroring {
+        if self.nametables.iter().any(|&bank| bank < 0xE0) {
+            return self.header_mirroring;
+        }
+        match self.nametables.map(|bank| bank & 1) {
+            [0, 0, 1, 1] => Mirroring::HORIZONTAL,
+            [0, 0, 0, 0] => Mirroring::SINGLESCREEN0,
+            [1, 1, 1, 1] => Mirroring::SINGLESCREEN1,
+            _ => Mirroring::VERTICAL,
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        if self.irq_enabled && self.irq_counter < IRQ_AT {
+            self.irq_counter = (self.irq_counter + cycles as u16).min(IRQ_AT);
+            self.irq_pending = self.irq_counter == IRQ_AT;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg = [0; 3];
+        self.chr = [0; 8];
+        self.nametables = [0; 4];
+        self.irq_counter = 0;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.audio = Namco163Audio::new();
+        self.memory.power_cycle();
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.memory.save_state(writer);
+        writer.bytes(&self.prg);
+        writer.bytes(&self.chr);
+        writer.bytes(&self.nametables);
+        writer.u16(self.irq_counter);
+        writer.bool(self.irq_enabled);
+        writer.bool(self.irq_pending);
+        self.audio.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.memory.load_state(reader)?;
+        reader.bytes_into(&mut self.prg)?;
+        reader.bytes_into(&mut self.chr)?;
+        reader.bytes_into(&mut self.nametables)?;
+        self.irq_counter = reader.u16()?.min(IRQ_AT);
+        self.irq_enabled = reader.bool()?;
+        self.irq_pending = reader.bool()?;
+        self.audio.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_banking_nametables_and_irq() {
+        let mut rom = test_rom();
+        rom.mapper = 19;
+        rom.prg_rom = (0..0x20000).map(|i| (i / 0x2000) as u8).collect();
+        rom.chr_rom = (0..0x20000).map(|i| (i / 0x400) as u8).collect();
+        let mut namco = Namco163::new(&rom);
+
+        namco.cpu_write(0xE000, 1);
+        namco.cpu_write(0xE800, 2);
+        namco.cpu_write(0xF000, 3);
+        assert_eq!(
+            [0x8000, 0xA000, 0xC000, 0xE000].map(|address| namco.cpu_peek(address)),
+            [1, 2, 3, 15]
+        );
+        assert!(namco.cpu_write(0xB800, 77));
+        assert_eq!(namco.ppu_read(0x1C00), 77);
+
+        for (register, bank) in [0xC000, 0xC800, 0xD000, 0xD800]
+            .into_iter()
+            .zip([0xE0, 0xE0, 0xE1, 0xE1])
+        {
+            namco.cpu_write(register, bank);
+        }
+        assert_eq!(namco.mirroring(), Mirroring::HORIZONTAL);
+
+        // Counts up to $7FFF, and is read back through the same registers
+        namco.cpu_write(0x5000, 0xF0);
+        namco.cpu_write(0x5800, 0xFF);
+        assert_eq!(namco.cpu_peek(0x5800), 0xFF);
+        namco.tick(14);
+        assert!(!namco.irq_pending());
+        namco.tick(1);
+        assert!(namco.irq_pending());
+        namco.cpu_write(0x5800, 0xFF);
+        assert!(!namco.irq_pending());
+
+        // Sound RAM through $F800 and $4800
+        namco.cpu_write(0xF800, 0x80);
+        namco.cpu_write(0x4800, 0x12);
+        namco.cpu_write(0x4800, 0x34);
+        namco.cpu_write(0xF800, 0x81);
+        assert_eq!(namco.cpu_read(0x4800), 0x34);
+        assert_eq!(namco.cpu_peek(0x4800), 0x00, "moved on");
+    }
+}
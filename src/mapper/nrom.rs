@@ -0,0 +1,54 @@
+use crate::cartridge::{Mirroring, Rom};
+
+use super::{Mapper, Memory};
+
+/// Mapper 0: 16 or 32KB of PRG ROM at $8000, the smaller size mirrored, 8KB of CHR and
+/// soldered mirroring. No registers at all.
+pub struct Nrom {
+    memory: Memory,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: &Rom) -> Self {
+        Nrom {
+            memory: Memory::new(rom),
+            mirroring: rom.mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    fn cpu_peek(&self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF => self.memory.read_prg_ram(address),
+            0x8000..=0xFFFF => self.memory.read_prg(0x8000, 0, address),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) -> bool {
+        match address {
+            0x6000..=0x7FFF => self.memory.write_prg_ram(address, value),
+            0x8000..=0xFFFF => panic!("Cannot write to ROM"),
+            _ => {}
+        }
+        false
+    }
+
+    fn chr_slot(&self, slot: usize) -> usize {
+        self.memory.chr_offset(0x2000, 0, slot as u16 * 0x400)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
@@ -0,0 +1,274 @@
+use crate::{
+    apu::ExpansionAudio,
+    save_state::{StateReader, StateWriter},
+};
+
+/// Level per step of the three channels' 0-61 sum, putting a pulse at full volume about level
+/// with one of the APU's, as on the Famicom
+const LEVEL_STEP: f32 = 0.12 / 15.0;
+
+/// A VRC6 pulse channel, $9000-$9002 or $A000-$A002. Eight duty cycles, and a digitized mode
+/// that holds the volume as a level.
+#[derive(Default)]
+struct Pulse {
+    /// Plays `volume` the whole time, ignoring the duty
+    digitized: bool,
+    /// On for `duty + 1` of every 16 steps
+    duty: u8,
+    volume: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    /// Counts down from 15
+    step: u8,
+}
+
+impl Pulse {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            // MDDD VVVV
+            0 => {
+                self.digitized = value & 0x80 != 0;
+                self.duty = (value >> 4) & 0x07;
+                self.volume = value & 0x0F;
+            }
+            1 => self.period = self.period & 0x0F00 | value as u16,
+            // E--- FFFF
+            _ => {
+                self.period = self.period & 0x00FF | (value as u16 & 0x0F) << 8;
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle, with the period shifted right by `shift` for $9003's
+    /// frequency scaling.
+    fn clock_timer(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.checked_sub(1).unwrap_or(15);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// The channel's level, 0-15.
+    fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.digitized);
+        writer.u8(self.duty);
+        writer.u8(self.volume);
+        writer.u16(self.period);
+        writer.bool(self.enabled);
+        writer.u16(self.timer);
+        writer.u8(self.step);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.digitized = reader.bool()?;
+        self.duty = reader.u8()? & 0x07;
+        self.volume = reader.u8()? & 0x0F;
+        self.period = reader.u16()? & 0x0FFF;
+        self.enabled = reader.bool()?;
+        self.timer = reader.u16()?;
+        self.step = reader.u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+/// The sawtooth channel, $B000-$B002. An accumulator gains the rate every other step and
+/// drops back to 0 on the 14th.
+#[derive(Default)]
+struct Sawtooth {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Sawtooth {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            // --AA AAAA
+            0 => self.rate = value & 0x3F,
+            1 => self.period = self.period & 0x0F00 | value as u16,
+            // E--- FFFF
+            _ => {
+                self.period = self.period & 0x00FF | (value as u16 & 0x0F) << 8;
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock_timer(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    /// The channel's level, 0-31.
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.rate);
+        writer.u16(self.period);
+        writer.bool(self.enabled);
+        writer.u16(self.timer);
+        writer.u8(self.step);
+        writer.u8(self.accumulator);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.rate = reader.u8()? & 0x3F;
+        self.period = reader.u16()? & 0x0FFF;
+        self.enabled = reader.bool()?;
+        self.timer = reader.u16()?;
+        self.step = reader.u8()? % 14;
+        self.accumulator = reader.u8()?;
+        Ok(())
+    }
+}
+
+/// The VRC6's three sound channels: two pulses and a sawtooth.
+#[derive(Default)]
+pub struct Vrc6Audio {
+    pulse: [Pulse; 2],
+    sawtooth: Sawtooth,
+    /// $9003 bit 0 stops every channel's timer
+    halted: bool,
+    /// $9003 bits 1-2 run the timers 16 or 256 times faster
+    shift: u8,
+}
+
+impl Vrc6Audio {
+    /// Writes $9000-$9003, $A000-$A002 or $B000-$B002, with A0 and A1 already sorted out.
+    pub fn write(&mut self, address: u16, value: u8) {
+        let register = address & 0x03;
+        match address & 0xF003 {
+            0x9003 => {
+                self.halted = value & 0x01 != 0;
+                self.shift = if value & 0x04 != 0 {
+                    8
+                } else if value & 0x02 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0x9000..=0x9002 => self.pulse[0].write(register, value),
+            0xA000..=0xA002 => self.pulse[1].write(register, value),
+            0xB000..=0xB002 => self.sawtooth.write(register, value),
+            _ => {}
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for pulse in &self.pulse {
+            pulse.save_state(writer);
+        }
+        self.sawtooth.save_state(writer);
+        writer.bool(self.halted);
+        writer.u8(self.shift);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        for pulse in self.pulse.iter_mut() {
+            pulse.load_state(reader)?;
+        }
+        self.sawtooth.load_state(reader)?;
+        self.halted = reader.bool()?;
+        self.shift = reader.u8()?.min(8);
+        Ok(())
+    }
+}
+
+impl ExpansionAudio for Vrc6Audio {
+    fn mix_audio(&mut self, cycles: u8) -> f32 {
+        if !self.halted {
+            for _ in 0..cycles {
+                for pulse in self.pulse.iter_mut() {
+                    pulse.clock_timer(self.shift);
+                }
+                self.sawtooth.clock_timer(self.shift);
+            }
+        }
+        let level = self.pulse[0].output() + self.pulse[1].output() + self.sawtooth.output();
+        level as f32 * LEVEL_STEP
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Runs `cycles` CPU cycles and collects the distinct levels in order
+    fn levels(audio: &mut Vrc6Audio, cycles: usize) -> Vec<u8> {
+        let mut levels = Vec::new();
+        for _ in 0..cycles {
+            let level = (audio.mix_audio(1) / LEVEL_STEP).round() as u8;
+            if levels.last() != Some(&level) {
+                levels.push(level);
+            }
+        }
+        levels
+    }
+
+    #[test]
+    fn test_channels() {
+        let mut audio = Vrc6Audio::default();
+        // Duty 3, volume 10, period 0 for a step every cycle
+        audio.write(0x9000, 0x3A);
+        audio.write(0x9001, 0x00);
+        audio.write(0x9002, 0x80);
+        assert_eq!(levels(&mut audio, 32), [0, 10, 0, 10]);
+        audio.write(0x9000, 0xBA);
+        assert_eq!(
+            levels(&mut audio, 32),
+            [10],
+            "digitized mode holds the volume"
+        );
+        audio.write(0x9002, 0x00);
+
+        // The sawtooth adds 12 every other step and resets on the 14th
+        audio.write(0xB000, 12);
+        audio.write(0xB002, 0x80);
+        let saw = [0, 1, 3, 4, 6, 7, 9];
+        assert_eq!(levels(&mut audio, 14), [saw.as_slice(), &[0]].concat());
+
+        audio.write(0x9003, 0x01);
+        assert_eq!(levels(&mut audio, 28), [0], "halted");
+    }
+}
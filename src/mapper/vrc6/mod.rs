@@ -0,0 +1,204 @@
+mod audio;
+
+use crate::{
+    apu::ExpansionAudio,
+    cartridge::{Mirroring, Rom},
+    save_state::{StateReader, StateWriter},
+};
+
+use self::audio::Vrc6Audio;
+use super::{vrc_irq::VrcIrq, Mapper, Memory};
+
+/// Konami's VRC6, mappers 24 and 26 (which swap the A0 and A1 lines): a switchable 16KB and
+/// 8KB of PRG, eight 1KB CHR banks, a scanline IRQ counter and three extra sound channels.
+/// Only CHR banking mode 0 is emulated, the one every game uses.
+pub struct Vrc6 {
+    memory: Memory,
+    /// Mapper 26 wires A0 to the chip's A1 and the other way around
+    swap_lines: bool,
+    prg_16k: u8,
+    prg_8k: u8,
+    chr: [u8; 8],
+    /// $B003, `R-NN MMPP`: PRG RAM enable, nametable source, mirroring and CHR banking mode
+    control: u8,
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+}
+
+impl Vrc6 {
+    pub fn new(rom: &Rom) -> Self {
+        Vrc6 {
+            memory: Memory::new(rom),
+            swap_lines: rom.mapper == 26,
+            prg_16k: 0,
+            prg_8k: 0,
+            chr: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+            audio: Vrc6Audio::default(),
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    fn cpu_peek(&self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.memory.read_prg_ram(address),
+            0x8000..=0xBFFF => self.memory.read_prg(0x4000, self.prg_16k as usize, address),
+            0xC000..=0xDFFF => self.memory.read_prg(0x2000, self.prg_8k as usize, address),
+            0xE000..=0xFFFF => {
+                let last = self.memory.prg_banks(0x2000) - 1;
+                self.memory.read_prg(0x2000, last, address)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) -> bool {
+        if (0x6000..=0x7FFF).contains(&address) {
+            if self.prg_ram_enabled() {
+                self.memory.write_prg_ram(address, value);
+            }
+            return false;
+        }
+        let address = if self.swap_lines {
+            address & !0x03 | (address & 0x01) << 1 | (address & 0x02) >> 1
+        } else {
+            address
+        };
+        match address & 0xF003 {
+            0x8000..=0x8003 => self.prg_16k = value & 0x0F,
+            0xB003 => {
+                self.control = value;
+                return true;
+            }
+            0x9000..=0xB002 => self.audio.write(address, value),
+            0xC000..=0xC003 => self.prg_8k = value & 0x1F,
+            0xD000..=0xD003 => {
+                self.chr[(address & 0x03) as usize] = value;
+                return true;
+            }
+            0xE000..=0xE003 => {
+                self.chr[4 + (address & 0x03) as usize] = value;
+                return true;
+            }
+            0xF000 => self.irq.write_latch(value),
+            0xF001 => self.irq.write_control(value),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+        false
+    }
+
+    fn chr_slot(&self, slot: usize) -> usize {
+        self.memory.chr_offset(0x400, self.chr[slot & 0x07] as usize, 0)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match (self.control >> 2) & 0x03 {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::SINGLESCREEN0,
+            _ => Mirroring::SINGLESCREEN1,
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.irq.tick(cycles);
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_16k = 0;
+        self.prg_8k = 0;
+        self.chr = [0; 8];
+        self.control = 0;
+        self.irq = VrcIrq::new();
+        self.audio = Vrc6Audio::default();
+        self.memory.power_cycle();
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.memory.save_state(writer);
+        writer.u8(self.prg_16k);
+        writer.u8(self.prg_8k);
+        writer.bytes(&self.chr);
+        writer.u8(self.control);
+        self.irq.save_state(writer);
+        self.audio.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.memory.load_state(reader)?;
+        self.prg_16k = reader.u8()? & 0x0F;
+        self.prg_8k = reader.u8()? & 0x1F;
+        reader.bytes_into(&mut self.chr)?;
+        self.control = reader.u8()?;
+        self.irq.load_state(reader)?;
+        self.audio.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_banking_and_swapped_lines() {
+        let mut rom = test_rom();
+        rom.mapper = 26;
+        rom.prg_rom = (0..0x20000).map(|i| (i / 0x2000) as u8).collect();
+        rom.chr_rom = (0..0x10000).map(|i| (i / 0x400) as u8).collect();
+        let mut vrc6 = Vrc6::new(&rom);
+
+        vrc6.cpu_write(0x8000, 3);
+        vrc6.cpu_write(0xC000, 5);
+        assert_eq!(vrc6.cpu_peek(0x8000), 6);
+        assert_eq!(vrc6.cpu_peek(0xBFFF), 7);
+        assert_eq!(vrc6.cpu_peek(0xC000), 5);
+        assert_eq!(vrc6.cpu_peek(0xE000), 15, "last bank fixed");
+
+        // $D001 is the chip's $D002 on mapper 26
+        assert!(vrc6.cpu_write(0xD001, 40));
+        assert_eq!(vrc6.ppu_read(0x0800), 40);
+        assert!(vrc6.cpu_write(0xE003, 41));
+        assert_eq!(vrc6.ppu_read(0x1FFF), 41);
+
+        // Mirroring and PRG RAM through $B003, which is $B003 either way
+        assert_eq!(vrc6.mirroring(), Mirroring::VERTICAL);
+        vrc6.cpu_write(0x6000, 1);
+        assert_eq!(vrc6.cpu_peek(0x6000), 0, "PRG RAM off");
+        vrc6.cpu_write(0xB003, 0x84);
+        assert_eq!(vrc6.mirroring(), Mirroring::HORIZONTAL);
+        vrc6.cpu_write(0x6000, 1);
+        assert_eq!(vrc6.cpu_peek(0x6000), 1);
+
+        // Cycle mode IRQ from $FF, acknowledged through $F002 ($F001 on mapper 26)
+        vrc6.cpu_write(0xF000, 0xFF);
+        vrc6.cpu_write(0xF002, 0x06);
+        vrc6.tick(1);
+        assert!(vrc6.irq_pending());
+        vrc6.cpu_write(0xF001, 0);
+        assert!(!vrc6.irq_pending());
+    }
+}
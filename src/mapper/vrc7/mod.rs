@@ -0,0 +1,206 @@
+mod opll;
+
+use crate::{
+    apu::ExpansionAudio,
+    cartridge::{Mirroring, Rom},
+    save_state::{StateReader, StateWriter},
+};
+
+use self::opll::Opll;
+use super::{vrc_irq::VrcIrq, Mapper, Memory};
+
+/// Konami's VRC7, mapper 85: three switchable 8KB PRG banks, eight 1KB CHR banks, the VRC IRQ
+/// counter and, on Lagrange Point's board, an FM synthesizer.
+pub struct Vrc7 {
+    memory: Memory,
+    prg: [u8; 3],
+    chr: [u8; 8],
+    /// $E000, `RS-- --MM`: PRG RAM enable, sound reset and mirroring
+    control: u8,
+    irq: VrcIrq,
+    opll: Opll,
+}
+
+impl Vrc7 {
+    pub fn new(rom: &Rom) -> Self {
+        Vrc7 {
+            memory: Memory::new(rom),
+            prg: [0; 3],
+            chr: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+            opll: Opll::new(),
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+}
+
+impl Mapper for Vrc7 {
+    fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    fn cpu_peek(&self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.memory.read_prg_ram(address),
+            0x8000..=0xDFFF => {
+                let bank = self.prg[(address - 0x8000) as usize / 0x2000];
+                self.memory.read_prg(0x2000, bank as usize, address)
+            }
+            0xE000..=0xFFFF => {
+                let last = self.memory.prg_banks(0x2000) - 1;
+                self.memory.read_prg(0x2000, last, address)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) -> bool {
+        if (0x6000..=0x7FFF).contains(&address) {
+            if self.prg_ram_enabled() {
+                self.memory.write_prg_ram(address, value);
+            }
+            return false;
+        }
+        match address & 0xF030 {
+            0x9010 => {
+                self.opll.select(value);
+                return false;
+            }
+            0x9030 => {
+                if self.control & 0x40 == 0 {
+                    self.opll.write(value);
+                }
+                return false;
+            }
+            _ => {}
+        }
+        // Lagrange Point's VRC7a picks the second register of each pair with A4, Tiny Toon
+        // Adventures 2's VRC7b with A3
+        let second = address & 0x18 != 0;
+        match (address & 0xF000, second) {
+            (0x8000, false) => self.prg[0] = value & 0x3F,
+            (0x8000, true) => self.prg[1] = value & 0x3F,
+            (0x9000, false) => self.prg[2] = value & 0x3F,
+            (0xA000..=0xD000, _) => {
+                let pair = (address - 0xA000) as usize / 0x1000;
+                self.chr[pair * 2 + second as usize] = value;
+                return true;
+            }
+            (0xE000, false) => {
+                // Resetting the sound chip silences it until the bit is cleared
+                if value & 0x40 != 0 {
+                    self.opll = Opll::new();
+                }
+                self.control = value;
+                return true;
+            }
+            (0xE000, true) => self.irq.write_latch(value),
+            (0xF000, false) => self.irq.write_control(value),
+            (0xF000, true) => self.irq.acknowledge(),
+            _ => {}
+        }
+        false
+    }
+
+    fn chr_slot(&self, slot: usize) -> usize {
+        self.memory.chr_offset(0x400, self.chr[slot & 0x07] as usize, 0)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::SINGLESCREEN0,
+            _ => Mirroring::SINGLESCREEN1,
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.irq.tick(cycles);
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.opll)
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg = [0; 3];
+        self.chr = [0; 8];
+        self.control = 0;
+        self.irq = VrcIrq::new();
+        self.opll = Opll::new();
+        self.memory.power_cycle();
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.memory.save_state(writer);
+        writer.bytes(&self.prg);
+        writer.bytes(&self.chr);
+        writer.u8(self.control);
+        self.irq.save_state(writer);
+        self.opll.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.memory.load_state(reader)?;
+        reader.bytes_into(&mut self.prg)?;
+        reader.bytes_into(&mut self.chr)?;
+        self.control = reader.u8()?;
+        self.irq.load_state(reader)?;
+        self.opll.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_banking_on_both_boards() {
+        let mut rom = test_rom();
+        rom.mapper = 85;
+        rom.prg_rom = (0..0x40000).map(|i| (i / 0x2000) as u8).collect();
+        rom.chr_rom = (0..0x20000).map(|i| (i / 0x400) as u8).collect();
+        let mut vrc7 = Vrc7::new(&rom);
+
+        vrc7.cpu_write(0x8000, 1);
+        vrc7.cpu_write(0x8010, 2);
+        vrc7.cpu_write(0x9000, 3);
+        assert_eq!(
+            [0x8000, 0xA000, 0xC000, 0xE000].map(|address| vrc7.cpu_peek(address)),
+            [1, 2, 3, 31]
+        );
+        vrc7.cpu_write(0x8008, 4);
+        assert_eq!(vrc7.cpu_peek(0xA000), 4, "VRC7b's A3");
+
+        assert!(vrc7.cpu_write(0xA000, 50));
+        assert!(vrc7.cpu_write(0xD010, 51));
+        assert_eq!(vrc7.ppu_read(0x0000), 50);
+        assert_eq!(vrc7.ppu_read(0x1C00), 51);
+
+        vrc7.cpu_write(0xE000, 0x81);
+        assert_eq!(vrc7.mirroring(), Mirroring::HORIZONTAL);
+        vrc7.cpu_write(0x6000, 9);
+        assert_eq!(vrc7.cpu_peek(0x6000), 9);
+
+        vrc7.cpu_write(0xE010, 0xFF);
+        vrc7.cpu_write(0xF000, 0x06);
+        vrc7.tick(1);
+        assert!(vrc7.irq_pending());
+        vrc7.cpu_write(0xF010, 0);
+        assert!(!vrc7.irq_pending());
+    }
+}
@@ -0,0 +1,515 @@
+use std::f32::consts::TAU;
+
+use crate::{
+    apu::ExpansionAudio,
+    save_state::{StateReader, StateWriter},
+};
+
+/// CPU cycles per OPLL sample. The chip runs at 3.58MHz and makes a sample every 72 clocks.
+const CYCLES_PER_SAMPLE: u8 = 36;
+const SAMPLE_RATE: f32 = 49716.0;
+/// Level of one channel's carrier at full amplitude, on the APU's scale
+const CHANNEL_LEVEL: f32 = 0.12;
+/// Attenuation units are 0.375dB, the envelope's step
+const DB: f32 = 1.0 / 0.375;
+/// Past this much attenuation an operator is silent
+const MAX_ATTENUATION: f32 = 127.0;
+
+const TREMOLO_HZ: f32 = 3.7;
+const TREMOLO_DEPTH: f32 = 4.8 * DB;
+const VIBRATO_HZ: f32 = 6.4;
+/// Vibrato swings the pitch this many cents either way
+const VIBRATO_CENTS: f32 = 14.0;
+
+/// Frequency multiples, `MMMM` in the patch
+const MULTIPLES: [f32; 16] = [
+    0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0,
+];
+
+/// Key scaling in dB at the top octave, by the top 4 bits of the F-number
+const KEY_SCALE: [f32; 16] = [
+    0.0, 9.0, 12.0, 13.875, 15.0, 16.125, 16.875, 17.625, 18.0, 18.75, 19.125, 19.5, 19.875, 20.25,
+    20.625, 21.0,
+];
+
+/// The VRC7's built-in instruments 1-15, in the same layout as the custom one at $00-$07. It
+/// has its own set, not the YM2413's.
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27], // Buzzy bell
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12], // Guitar
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12], // Wurly
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27], // Flute
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28], // Clarinet
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4], // Synth
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07], // Trumpet
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17], // Organ
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01], // Bells
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02], // Vibes
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12], // Vibraphone
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16], // Tutti
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02], // Fretless
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6], // Synth bass
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06], // Sweep
+];
+
+/// An instrument: two operators' settings, 0 for the modulator and 1 for the carrier.
+#[derive(Clone, Copy)]
+struct Patch([u8; 8]);
+
+impl Patch {
+    fn tremolo(&self, op: usize) -> bool {
+        self.0[op] & 0x80 != 0
+    }
+
+    fn vibrato(&self, op: usize) -> bool {
+        self.0[op] & 0x40 != 0
+    }
+
+    /// Holds at the sustain level while the key is down, instead of fading like a struck note
+    fn sustained(&self, op: usize) -> bool {
+        self.0[op] & 0x20 != 0
+    }
+
+    fn key_scale_rate(&self, op: usize) -> bool {
+        self.0[op] & 0x10 != 0
+    }
+
+    fn multiple(&self, op: usize) -> f32 {
+        MULTIPLES[(self.0[op] & 0x0F) as usize]
+    }
+
+    fn key_scale_level(&self, op: usize) -> u8 {
+        self.0[2 + op] >> 6
+    }
+
+    /// The modulator's level, in 0.75dB steps
+    fn modulator_level(&self) -> u8 {
+        self.0[2] & 0x3F
+    }
+
+    /// Cuts off the negative half of the sine
+    fn half_sine(&self, op: usize) -> bool {
+        self.0[3] & (0x08 << op) != 0
+    }
+
+    fn feedback(&self) -> u8 {
+        self.0[3] & 0x07
+    }
+
+    fn attack(&self, op: usize) -> u8 {
+        self.0[4 + op] >> 4
+    }
+
+    fn decay(&self, op: usize) -> u8 {
+        self.0[4 + op] & 0x0F
+    }
+
+    fn sustain_level(&self, op: usize) -> u8 {
+        self.0[6 + op] >> 4
+    }
+
+    fn release(&self, op: usize) -> u8 {
+        self.0[6 + op] & 0x0F
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+enum Envelope {
+    Attack,
+    Decay,
+    Sustain,
+    #[default]
+    Release,
+}
+
+impl Envelope {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Envelope::Attack,
+            1 => Envelope::Decay,
+            2 => Envelope::Sustain,
+            _ => Envelope::Release,
+        }
+    }
+}
+
+/// One of a channel's two sine oscillators.
+#[derive(Clone, Copy, Default)]
+struct Operator {
+    /// Position in the wave, in cycles
+    phase: f32,
+    envelope: Envelope,
+    attenuation: f32,
+}
+
+impl Operator {
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.envelope = Envelope::Attack;
+    }
+
+    /// Steps the envelope by one sample at rate `rate` (0-15), scaled by `key_scale`.
+    fn step_envelope(&mut self, rate: u8, key_scale: u8, sustain_level: f32) {
+        let step = envelope_step(rate, key_scale);
+        match self.envelope {
+            Envelope::Attack => {
+                if rate == 15 {
+                    self.attenuation = 0.0;
+                } else {
+                    // Attacks curve, fast at first
+                    self.attenuation -= self.attenuation * step / 4.0;
+                }
+                if self.attenuation < 0.5 {
+                    self.attenuation = 0.0;
+                    self.envelope = Envelope::Decay;
+                }
+            }
+            Envelope::Decay => {
+                self.attenuation += step;
+                if self.attenuation >= sustain_level {
+                    self.envelope = Envelope::Sustain;
+                }
+            }
+            Envelope::Sustain | Envelope::Release => self.attenuation += step,
+        }
+        self.attenuation = self.attenuation.min(MAX_ATTENUATION);
+    }
+
+    /// The wave at `phase` plus `modulation` cycles, attenuated by `attenuation` on top of the
+    /// envelope, from -1 to 1.
+    fn output(&self, modulation: f32, attenuation: f32, half_sine: bool) -> f32 {
+        let attenuation = self.attenuation + attenuation;
+        if attenuation >= MAX_ATTENUATION {
+            return 0.0;
+        }
+        let wave = ((self.phase + modulation) * TAU).sin();
+        if half_sine && wave < 0.0 {
+            return 0.0;
+        }
+        wave * 10f32.powf(-attenuation / DB / 20.0)
+    }
+}
+
+/// Attenuation units an envelope moves per sample at `rate` (0-15). Rates are scaled up for
+/// higher notes by `key_scale`, 0-15 as well. Each 4 steps of the combined rate double it.
+fn envelope_step(rate: u8, key_scale: u8) -> f32 {
+    if rate == 0 {
+        return 0.0;
+    }
+    let rate = (rate * 4 + key_scale).min(63);
+    (4 + rate % 4) as f32 * (1u32 << (rate / 4)) as f32 / 32768.0
+}
+
+#[derive(Clone, Copy, Default)]
+struct Channel {
+    /// 9-bit F-number and 3-bit block: the pitch
+    frequency: u16,
+    block: u8,
+    key: bool,
+    /// Releases slowly when the key comes up, for held notes
+    sustain: bool,
+    instrument: u8,
+    /// Attenuation in 3dB steps
+    volume: u8,
+    /// Modulator and carrier
+    operators: [Operator; 2],
+    /// The modulator's last two outputs, fed back into it
+    feedback: [f32; 2],
+}
+
+impl Channel {
+    /// The key scale for envelope rates
+    fn key_scale(&self, patch: &Patch, op: usize) -> u8 {
+        let scale = self.block << 1 | (self.frequency >> 8) as u8;
+        if patch.key_scale_rate(op) {
+            scale
+        } else {
+            scale >> 2
+        }
+    }
+
+    /// Attenuation for how high the note is, depending on the patch's key scale level
+    fn key_scale_level(&self, patch: &Patch, op: usize) -> f32 {
+        let shift = match patch.key_scale_level(op) {
+            0 => return 0.0,
+            // 1.5, 3 and 6dB per octave
+            level => 3 - level,
+        };
+        let db = KEY_SCALE[(self.frequency >> 5) as usize] - 6.0 * (7 - self.block) as f32;
+        db.max(0.0) * DB / (1 << shift) as f32
+    }
+
+    fn step(&mut self, patch: &Patch, tremolo: f32, vibrato: f32) -> f32 {
+        for op in 0..2 {
+            let pitch = if patch.vibrato(op) { vibrato } else { 1.0 };
+            // The phase is 18 bits, F-number << block / 2 added every sample
+            let increment = self.frequency as f32 * (1 << self.block) as f32 / 2.0 / 262144.0;
+            let key_scale = self.key_scale(patch, op);
+            let operator = &mut self.operators[op];
+            operator.phase = (operator.phase + increment * pitch * patch.multiple(op)).fract();
+
+            let rate = match operator.envelope {
+                Envelope::Attack => patch.attack(op),
+                Envelope::Decay => patch.decay(op),
+                Envelope::Sustain if patch.sustained(op) => 0,
+                Envelope::Sustain => patch.release(op),
+                Envelope::Release if self.sustain => 5,
+                Envelope::Release if patch.sustained(op) => patch.release(op),
+                Envelope::Release => 7,
+            };
+            let sustain_level = patch.sustain_level(op) as f32 * 3.0 * DB;
+            operator.step_envelope(rate, key_scale, sustain_level);
+        }
+
+        let [modulator_level, carrier_level] = [0, 1].map(|op| {
+            let tremolo = if patch.tremolo(op) { tremolo } else { 0.0 };
+            self.key_scale_level(patch, op) + tremolo
+        });
+
+        // Feedback swings the modulator from 1/32 of a cycle up to two cycles either way
+        let feedback = match patch.feedback() {
+            0 => 0.0,
+            feedback => (self.feedback[0] + self.feedback[1]) / 2.0 * (1 << feedback) as f32 / 64.0,
+        };
+        let modulator_level = modulator_level + patch.modulator_level() as f32 * 0.75 * DB;
+        let modulator = self.operators[0].output(feedback, modulator_level, patch.half_sine(0));
+        self.feedback = [self.feedback[1], modulator];
+
+        // A full modulator swings the carrier two cycles either way
+        let carrier_level = carrier_level + self.volume as f32 * 3.0 * DB;
+        self.operators[1].output(modulator * 2.0, carrier_level, patch.half_sine(1))
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.frequency);
+        writer.u8(self.block);
+        writer.bool(self.key);
+        writer.bool(self.sustain);
+        writer.u8(self.instrument);
+        writer.u8(self.volume);
+        for operator in &self.operators {
+            writer.u32(operator.phase.to_bits());
+            writer.u8(operator.envelope as u8);
+            writer.u32(operator.attenuation.to_bits());
+        }
+        for feedback in self.feedback {
+            writer.u32(feedback.to_bits());
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.frequency = reader.u16()? & 0x1FF;
+        self.block = reader.u8()? & 0x07;
+        self.key = reader.bool()?;
+        self.sustain = reader.bool()?;
+        self.instrument = reader.u8()? & 0x0F;
+        self.volume = reader.u8()? & 0x0F;
+        for operator in self.operators.iter_mut() {
+            operator.phase = f32::from_bits(reader.u32()?).fract();
+            operator.envelope = Envelope::from_u8(reader.u8()?);
+            operator.attenuation = f32::from_bits(reader.u32()?).clamp(0.0, MAX_ATTENUATION);
+        }
+        for feedback in self.feedback.iter_mut() {
+            *feedback = f32::from_bits(reader.u32()?).clamp(-1.0, 1.0);
+        }
+        Ok(())
+    }
+}
+
+/// The VRC7's sound: a cut-down Yamaha YM2413 (OPLL) with six two-operator FM channels, 15
+/// built-in instruments and one custom one. Written through a register number at $9010 and
+/// data at $9030.
+pub struct Opll {
+    register: u8,
+    custom: [u8; 8],
+    channels: [Channel; 6],
+    /// Position in the tremolo and vibrato cycles
+    tremolo_phase: f32,
+    vibrato_phase: f32,
+    /// CPU cycles since the last sample
+    cycles: u8,
+    output: f32,
+}
+
+impl Default for Opll {
+    fn default() -> Self {
+        Opll::new()
+    }
+}
+
+impl Opll {
+    pub fn new() -> Self {
+        let silent = Operator {
+            attenuation: MAX_ATTENUATION,
+            ..Operator::default()
+        };
+        Opll {
+            register: 0,
+            custom: [0; 8],
+            channels: [Channel {
+                operators: [silent; 2],
+                ..Channel::default()
+            }; 6],
+            tremolo_phase: 0.0,
+            vibrato_phase: 0.0,
+            cycles: 0,
+            output: 0.0,
+        }
+    }
+
+    pub fn select(&mut self, register: u8) {
+        self.register = register;
+    }
+
+    /// Writes the register picked with `select`.
+    pub fn write(&mut self, value: u8) {
+        let register = self.register;
+        if register < 0x08 {
+            self.custom[register as usize] = value;
+            return;
+        }
+        let Some(channel) = self.channels.get_mut((register & 0x0F) as usize) else {
+            return;
+        };
+        match register >> 4 {
+            1 => channel.frequency = channel.frequency & 0x100 | value as u16,
+            // --ST BBBF
+            2 => {
+                channel.frequency = channel.frequency & 0xFF | (value as u16 & 1) << 8;
+                channel.block = (value >> 1) & 0x07;
+                channel.sustain = value & 0x20 != 0;
+                let key = value & 0x10 != 0;
+                if key && !channel.key {
+                    for operator in channel.operators.iter_mut() {
+                        operator.key_on();
+                    }
+                } else if !key && channel.key {
+                    for operator in channel.operators.iter_mut() {
+                        operator.envelope = Envelope::Release;
+                    }
+                }
+                channel.key = key;
+            }
+            // IIII VVVV
+            3 => {
+                channel.instrument = value >> 4;
+                channel.volume = value & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    fn patch(&self, instrument: u8) -> Patch {
+        match instrument {
+            0 => Patch(self.custom),
+            _ => Patch(PATCHES[instrument as usize - 1]),
+        }
+    }
+
+    fn sample(&mut self) {
+        self.tremolo_phase = (self.tremolo_phase + TREMOLO_HZ / SAMPLE_RATE).fract();
+        self.vibrato_phase = (self.vibrato_phase + VIBRATO_HZ / SAMPLE_RATE).fract();
+        // Tremolo is a triangle wave of attenuation, vibrato a sine of pitch
+        let tremolo = (1.0 - (self.tremolo_phase * 2.0 - 1.0).abs()) * TREMOLO_DEPTH;
+        let vibrato = 2f32.powf((self.vibrato_phase * TAU).sin() * VIBRATO_CENTS / 1200.0);
+
+        let mut output = 0.0;
+        for index in 0..self.channels.len() {
+            let patch = self.patch(self.channels[index].instrument);
+            output += self.channels[index].step(&patch, tremolo, vibrato);
+        }
+        self.output = output * CHANNEL_LEVEL;
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.register);
+        writer.bytes(&self.custom);
+        for channel in &self.channels {
+            channel.save_state(writer);
+        }
+        writer.u32(self.tremolo_phase.to_bits());
+        writer.u32(self.vibrato_phase.to_bits());
+        writer.u8(self.cycles);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.register = reader.u8()?;
+        reader.bytes_into(&mut self.custom)?;
+        for channel in self.channels.iter_mut() {
+            channel.load_state(reader)?;
+        }
+        self.tremolo_phase = f32::from_bits(reader.u32()?).fract();
+        self.vibrato_phase = f32::from_bits(reader.u32()?).fract();
+        self.cycles = reader.u8()? % CYCLES_PER_SAMPLE;
+        self.output = 0.0;
+        Ok(())
+    }
+}
+
+impl ExpansionAudio for Opll {
+    fn mix_audio(&mut self, cycles: u8) -> f32 {
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_SAMPLE {
+            self.cycles -= CYCLES_PER_SAMPLE;
+            self.sample();
+        }
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(opll: &mut Opll, register: u8, value: u8) {
+        opll.select(register);
+        opll.write(value);
+    }
+
+    /// Runs `samples` samples and returns the loudest one
+    fn peak(opll: &mut Opll, samples: usize) -> f32 {
+        (0..samples).fold(0.0, |peak, _| {
+            peak.max(opll.mix_audio(CYCLES_PER_SAMPLE).abs())
+        })
+    }
+
+    #[test]
+    fn test_notes_sound_and_release() {
+        let mut opll = Opll::new();
+        assert_eq!(peak(&mut opll, 1000), 0.0);
+
+        // Flute at full volume on channel 2, key down
+        write(&mut opll, 0x32, 0x40);
+        write(&mut opll, 0x12, 0xAC);
+        write(&mut opll, 0x22, 0x18);
+        let held = peak(&mut opll, 5000);
+        assert!(held > CHANNEL_LEVEL * 0.5, "{}", held);
+
+        // Quieter at a lower volume
+        write(&mut opll, 0x32, 0x48);
+        let quieter = peak(&mut opll, 2000);
+        assert!(quieter < held * 0.1, "{} against {}", quieter, held);
+
+        // Key up, and the note dies away
+        write(&mut opll, 0x22, 0x08);
+        peak(&mut opll, 50_000);
+        assert!(peak(&mut opll, 1000) < 1e-3);
+    }
+
+    #[test]
+    fn test_custom_instrument() {
+        let mut opll = Opll::new();
+        // A plain sine: no modulator, instant attack, held sustain
+        for (register, value) in [0x20, 0x21, 0x3F, 0x00, 0xF0, 0xF0, 0x0F, 0x0F]
+            .into_iter()
+            .enumerate()
+        {
+            write(&mut opll, register as u8, value);
+        }
+        write(&mut opll, 0x30, 0x00);
+        write(&mut opll, 0x10, 0x80);
+        write(&mut opll, 0x20, 0x19);
+        let peak = peak(&mut opll, 5000);
+        assert!((peak - CHANNEL_LEVEL).abs() < 0.01, "{}", peak);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::save_state::{StateReader, StateWriter};
+
+/// CPU cycles per scanline, times 3 so it's whole
+const SCANLINE: i16 = 341;
+
+/// The IRQ counter Konami put in the VRC4, VRC6 and VRC7. It counts up from a latch and raises
+/// an IRQ when it wraps, clocked every CPU cycle or, through a prescaler, every scanline. It
+/// doesn't watch the PPU, so it keeps time with rendering off too.
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    enabled: bool,
+    /// Becomes `enabled` on acknowledge
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    prescaler: i16,
+    pub pending: bool,
+}
+
+impl Default for VrcIrq {
+    fn default() -> Self {
+        VrcIrq::new()
+    }
+}
+
+impl VrcIrq {
+    pub fn new() -> Self {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            prescaler: SCANLINE,
+            pending: false,
+        }
+    }
+
+    pub fn write_latch(&mut self, value: u8) {
+        self.latch = value;
+    }
+
+    /// `---- -MEA`: cycle mode, enable, and enable after acknowledge. Enabling reloads the
+    /// counter.
+    pub fn write_control(&mut self, value: u8) {
+        self.enable_after_ack = value & 0x01 != 0;
+        self.enabled = value & 0x02 != 0;
+        self.cycle_mode = value & 0x04 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = SCANLINE;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    /// Advances by `cycles` CPU cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        if !self.enabled {
+            return;
+        }
+        for _ in 0..cycles {
+            if self.cycle_mode {
+                self.clock();
+            } else {
+                self.prescaler -= 3;
+                if self.prescaler <= 0 {
+                    self.prescaler += SCANLINE;
+                    self.clock();
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.latch);
+        writer.u8(self.counter);
+        writer.bool(self.enabled);
+        writer.bool(self.enable_after_ack);
+        writer.bool(self.cycle_mode);
+        writer.u16(self.prescaler as u16);
+        writer.bool(self.pending);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.latch = reader.u8()?;
+        self.counter = reader.u8()?;
+        self.enabled = reader.bool()?;
+        self.enable_after_ack = reader.bool()?;
+        self.cycle_mode = reader.bool()?;
+        self.prescaler = (reader.u16()? as i16).clamp(1, SCANLINE);
+        self.pending = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_counts_scanlines_and_cycles() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFE);
+        irq.write_control(0x03);
+        // Two scanlines from $FE: one to $FF, one to wrap
+        irq.tick(113);
+        assert!(!irq.pending);
+        irq.tick(115);
+        assert!(irq.pending);
+
+        // Acknowledging with A set keeps it counting from the latch
+        irq.acknowledge();
+        irq.tick(227);
+        assert!(irq.pending);
+
+        irq.write_control(0x04);
+        irq.tick(255);
+        assert!(!irq.pending, "disabled");
+        irq.write_control(0x06);
+        irq.tick(1);
+        assert!(!irq.pending);
+        irq.tick(1);
+        assert!(irq.pending);
+    }
+}
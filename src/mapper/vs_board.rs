@@ -0,0 +1,108 @@
+use crate::{
+    cartridge::{Mirroring, Rom},
+    save_state::{StateReader, StateWriter},
+};
+
+use super::{Mapper, Memory};
+
+/// Mapper 99, the Vs. System's NROM: PRG like NROM, and the OUT2 line of $4016 picking
+/// between two 8KB CHR banks.
+pub struct VsBoard {
+    memory: Memory,
+    mirroring: Mirroring,
+    chr_bank: u8,
+}
+
+impl VsBoard {
+    pub fn new(rom: &Rom) -> Self {
+        VsBoard {
+            memory: Memory::new(rom),
+            mirroring: rom.mirroring,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for VsBoard {
+    fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    fn cpu_peek(&self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF => self.memory.read_prg_ram(address),
+            0x8000..=0xFFFF => self.memory.read_prg(0x8000, 0, address),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) -> bool {
+        match address {
+            0x6000..=0x7FFF => self.memory.write_prg_ram(address, value),
+            0x8000..=0xFFFF => panic!("Cannot write to ROM"),
+            // $4020 is the coin counter, which only clicks
+            _ => {}
+        }
+        false
+    }
+
+    fn chr_slot(&self, slot: usize) -> usize {
+        self.memory
+            .chr_offset(0x2000, self.chr_bank as usize, slot as u16 * 0x400)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn write_4016(&mut self, value: u8) -> bool {
+        let bank = (value >> 2) & 1;
+        if self.memory.chr_banks(0x2000) < 2 || bank == self.chr_bank {
+            return false;
+        }
+        self.chr_bank = bank;
+        true
+    }
+
+    fn power_cycle(&mut self) {
+        self.chr_bank = 0;
+        self.memory.power_cycle();
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.memory.save_state(writer);
+        writer.u8(self.chr_bank);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.memory.load_state(reader)?;
+        self.chr_bank = reader.u8()? & 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_chr_banks() {
+        let mut rom = test_rom();
+        rom.chr_rom = (0..0x4000).map(|i| (i / 0x2000) as u8).collect();
+        let mut board = VsBoard::new(&rom);
+        assert!(!board.write_4016(0x00));
+        assert!(board.write_4016(0x04));
+        assert_eq!(board.ppu_read(0x1FFF), 1);
+        assert!(!board.write_4016(0x05));
+        board.power_cycle();
+        assert_eq!(board.ppu_read(0), 0);
+
+        rom.chr_rom.truncate(0x2000);
+        assert!(!VsBoard::new(&rom).write_4016(0x04));
+    }
+}
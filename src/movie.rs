@@ -0,0 +1,220 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::input_script::{format_line, ConsoleEvent, FrameInput};
+
+/// Writes what the controllers did each frame as an input script that plays the run back,
+/// with a `CHECK <frame> <checksum>` line after every frame so playback can tell the exact
+/// frame where it stopped matching the recording.
+///
+/// Call `record_input` from the frame callback with the input the next frame will see, then
+/// `record_check` and `record_event` once the callback has returned. That puts the checksum
+/// for a frame right before the input read after it, where playback looks for it.
+pub struct MovieRecorder {
+    out: Box<dyn Write>,
+    /// Input and how many frames in a row it was held, not written yet
+    run: Option<(FrameInput, usize)>,
+    /// Input for the frame after the last checksum or event
+    next: Option<FrameInput>,
+}
+
+impl MovieRecorder {
+    pub fn new(out: impl Write + 'static) -> Self {
+        MovieRecorder {
+            out: Box::new(out),
+            run: None,
+            next: None,
+        }
+    }
+
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        let mut recorder = MovieRecorder::new(BufWriter::new(file));
+        recorder.write_line("# Recorded from power on; CHECK lines are RAM checksums")?;
+        Ok(recorder)
+    }
+
+    pub fn record_input(&mut self, input: FrameInput) -> Result<(), String> {
+        match self.next.replace(input) {
+            Some(previous) => self.add_to_run(previous),
+            None => Ok(()),
+        }
+    }
+
+    fn add_to_run(&mut self, input: FrameInput) -> Result<(), String> {
+        match self.run.as_mut() {
+            Some((held, frames)) if *held == input => *frames += 1,
+            _ => {
+                self.flush_run()?;
+                self.run = Some((input, 1));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn record_check(&mut self, frame: usize, checksum: u32) -> Result<(), String> {
+        self.flush_run()?;
+        self.write_line(&format_check(frame, checksum))
+    }
+
+    pub fn record_event(&mut self, event: ConsoleEvent) -> Result<(), String> {
+        self.flush_run()?;
+        self.write_line(match event {
+            ConsoleEvent::Reset => "RESET",
+            ConsoleEvent::Power => "POWER",
+        })
+    }
+
+    /// Writes out the input not written yet. Nothing should be recorded after this.
+    pub fn finish(&mut self) -> Result<(), String> {
+        if let Some(input) = self.next.take() {
+            self.add_to_run(input)?;
+        }
+        self.flush_run()?;
+        self.out
+            .flush()
+            .map_err(|e| format!("Failed to write movie: {}", e))
+    }
+
+    fn flush_run(&mut self) -> Result<(), String> {
+        match self.run.take() {
+            Some((input, frames)) => self.write_line(&format_line(input, frames)),
+            None => Ok(()),
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), String> {
+        writeln!(self.out, "{}", line).map_err(|e| format!("Failed to write movie: {}", e))
+    }
+}
+
+pub fn format_check(frame: usize, checksum: u32) -> String {
+    format!("CHECK {} {:08x}", frame, checksum)
+}
+
+/// Verifies a movie's checksums as it plays. Only the first desync is reported, since every
+/// checksum after it will be off too.
+#[derive(Default)]
+pub struct MovieChecker {
+    last_match: usize,
+    desynced: bool,
+}
+
+impl MovieChecker {
+    /// Compares the RAM checksum after frame `frame` of the movie with `expected`, the
+    /// (frame, checksum) the movie recorded there.
+    pub fn check(
+        &mut self,
+        expected: (usize, u32),
+        frame: usize,
+        checksum: u32,
+    ) -> Result<(), String> {
+        if self.desynced {
+            return Ok(());
+        }
+        let (expected_frame, expected_checksum) = expected;
+        let error = if expected_frame != frame {
+            format!(
+                "the movie has the checksum for frame {} here",
+                expected_frame
+            )
+        } else if checksum != expected_checksum {
+            format!(
+                "RAM checksum is {:08x}, the recording had {:08x}",
+                checksum, expected_checksum
+            )
+        } else {
+            self.last_match = frame;
+            return Ok(());
+        };
+        self.desynced = true;
+        Err(format!(
+            "Movie desynced by frame {} (last matched at frame {}): {}",
+            frame, self.last_match, error
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        input_script::InputScript,
+        joypad::{Joypad, JoypadButton},
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    /// A `Write` whose output stays readable after the recorder that owns it is done.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_checks_come_back_after_their_frame() {
+        let buffer = SharedBuffer::default();
+        let mut recorder = MovieRecorder::new(buffer.clone());
+        let none = JoypadButton::empty();
+        let inputs = [JoypadButton::A, JoypadButton::A, JoypadButton::B, none];
+        for (frame, &input) in inputs.iter().enumerate() {
+            let frame = frame + 1;
+            recorder.record_input([input, none]).unwrap();
+            if frame == 2 {
+                recorder.record_check(frame, 0xabc).unwrap();
+                recorder.record_event(ConsoleEvent::Reset).unwrap();
+            }
+        }
+        recorder.finish().unwrap();
+        let text = String::from_utf8(buffer.0.take()).unwrap();
+        assert_eq!(text, "A\nCHECK 2 00000abc\nRESET\nA\nB\n1:\n");
+
+        // Playing it back, the check and reset turn up in the callback after frame 2
+        let mut script = InputScript::new(std::io::Cursor::new(text));
+        let mut joypad = Joypad::new();
+        let mut played = Vec::new();
+        for frame in 1..=4 {
+            let event = script.apply(&mut [&mut joypad]);
+            played.push((frame, joypad.buttons(), script.take_check(), event));
+        }
+        assert_eq!(
+            played,
+            [
+                (1, JoypadButton::A, None, None),
+                (
+                    2,
+                    JoypadButton::A,
+                    Some((2, 0xabc)),
+                    Some(ConsoleEvent::Reset)
+                ),
+                (3, JoypadButton::B, None, None),
+                (4, none, None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_checker_reports_the_first_desync() {
+        let mut checker = MovieChecker::default();
+        assert!(checker.check((60, 1), 60, 1).is_ok());
+        let error = checker.check((120, 2), 120, 3).unwrap_err();
+        assert!(
+            error.contains("by frame 120 (last matched at frame 60)"),
+            "{}",
+            error
+        );
+        assert!(checker.check((180, 2), 180, 3).is_ok());
+        assert!(MovieChecker::default().check((60, 1), 61, 1).is_err());
+    }
+}
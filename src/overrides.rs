@@ -0,0 +1,295 @@
+use std::{collections::HashMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cartridge::{self, Mirroring, Rom, PRG_RAM_PAGE_SIZE},
+    joypad::ControllerType,
+    region::Region,
+};
+
+pub const OVERRIDES_FILE_NAME: &str = "overrides.toml";
+
+const BUILTIN: &str = include_str!("overrides.toml");
+
+/// Corrections for one game. Anything left out is taken from the ROM header as usual.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct GameOverride {
+    /// CRC32 of PRG + CHR, see `Rom::crc32`
+    pub crc32: u32,
+    /// Only for people reading the file
+    #[serde(default)]
+    pub name: String,
+    pub mirroring: Option<Mirroring>,
+    pub mapper: Option<u16>,
+    /// Bytes of RAM at $6000-$7FFF, for headers that leave it out or get it wrong
+    pub prg_ram_size: Option<usize>,
+    /// Controllers for ports 1 and 2
+    pub controllers: Option<[ControllerType; 2]>,
+    /// For games whose header and file name don't say, or say wrong
+    pub region: Option<Region>,
+}
+
+#[derive(Deserialize, Default)]
+struct OverrideFile {
+    #[serde(default)]
+    game: Vec<GameOverride>,
+}
+
+/// Per-game overrides keyed by ROM CRC32, so known bad dumps work without editing them.
+#[derive(Default)]
+pub struct OverrideDb {
+    games: HashMap<u32, GameOverride>,
+}
+
+impl OverrideDb {
+    /// The overrides that ship with the emulator.
+    pub fn builtin() -> OverrideDb {
+        let mut db = OverrideDb::default();
+        db.merge(BUILTIN)
+            .expect("Built-in override table is invalid");
+        db
+    }
+
+    /// The built-in overrides plus the user's from `path`, which win on conflicts. A missing
+    /// file is not an error.
+    pub fn load(path: &Path) -> Result<OverrideDb, String> {
+        let mut db = OverrideDb::builtin();
+        match std::fs::read_to_string(path) {
+            Ok(text) => db
+                .merge(&text)
+                .map_err(|e| format!("Invalid overrides {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        }
+        Ok(db)
+    }
+
+    /// Adds the entries in `text`, replacing existing ones for the same ROM.
+    pub fn merge(&mut self, text: &str) -> Result<(), String> {
+        let file: OverrideFile = toml::from_str(text).map_err(|e| e.to_string())?;
+        for game in file.game {
+            self.games.insert(game.crc32, game);
+        }
+        Ok(())
+    }
+
+    pub fn lookup(&self, crc32: u32) -> Option<&GameOverride> {
+        self.games.get(&crc32)
+    }
+}
+
+impl GameOverride {
+    /// Patches the header-derived fields of `rom`.
+    pub fn apply(&self, rom: &mut Rom) {
+        if let Some(mirroring) = self.mirroring {
+            rom.mirroring = mirroring;
+        }
+        if let Some(mapper) = self.mapper {
+            rom.mapper = mapper;
+        }
+        if let Some(size) = self.prg_ram_size {
+            rom.prg_ram_size = size;
+        }
+        if let Some(region) = self.region {
+            rom.region = Some(region);
+        }
+    }
+
+    /// Where the ROM header disagrees with this entry, one line per field.
+    pub fn header_fixes(&self, rom: &Rom) -> Vec<String> {
+        let mut fixes = Vec::new();
+        if let Some(mapper) = self.mapper.filter(|&mapper| mapper != rom.mapper) {
+            fixes.push(format!(
+                "mapper: header says {}, should be {}",
+                rom.mapper, mapper
+            ));
+        }
+        if let Some(mirroring) = self.mirroring.filter(|&m| m != rom.mirroring) {
+            fixes.push(format!(
+                "mirroring: header says {:?}, should be {:?}",
+                rom.mirroring, mirroring
+            ));
+        }
+        if let Some(size) = self.prg_ram_size.filter(|&size| size != rom.prg_ram_size) {
+            fixes.push(format!(
+                "PRG RAM: header says {} bytes, should be {}",
+                rom.prg_ram_size, size
+            ));
+        }
+        fixes
+    }
+
+    /// Writes the corrected fields into a raw iNES file's header, leaving the other bits
+    /// alone. A dumping tool's name over the end of the header is cleared first, or the
+    /// fixes would be thrown away with it on the next load.
+    pub fn fix_header(&self, raw: &mut [u8]) -> Result<(), String> {
+        if cartridge::is_dirty_header(raw) {
+            raw[7..16].fill(0);
+        }
+        if let Some(mapper) = self.mapper {
+            if raw[7] & 0x0C == 0x08 {
+                raw[8] = (raw[8] & 0xF0) | (mapper >> 8) as u8;
+            } else if mapper > 0xFF {
+                return Err(format!("Mapper {} needs an NES 2.0 header", mapper));
+            }
+            raw[6] = (raw[6] & 0x0F) | (mapper << 4) as u8;
+            raw[7] = (raw[7] & 0x0F) | (mapper as u8 & 0xF0);
+        }
+        if let Some(mirroring) = self.mirroring {
+            raw[6] &= !0b1001;
+            raw[6] |= match mirroring {
+                // Single-screen boards pick the screen themselves and ignore these bits
+                Mirroring::HORIZONTAL | Mirroring::SINGLESCREEN0 | Mirroring::SINGLESCREEN1 => {
+                    0b0000
+                }
+                Mirroring::VERTICAL => 0b0001,
+                Mirroring::FOURSCREEN => 0b1000,
+            };
+        }
+        if let Some(size) = self.prg_ram_size {
+            if raw[7] & 0x0C == 0x08 {
+                // NES 2.0: a shift count of 64 bytes, in the battery-backed nibble if the
+                // board has a battery
+                let shift = if size == 0 {
+                    0
+                } else {
+                    (size / 64).next_power_of_two().trailing_zeros() as u8
+                };
+                raw[10] = if raw[6] & 0x02 != 0 {
+                    shift << 4
+                } else {
+                    shift
+                };
+            } else {
+                // iNES counts 8KB pages, with 0 meaning one page, so it can't say "none"
+                raw[8] = size.div_ceil(PRG_RAM_PAGE_SIZE) as u8;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_builtin_entries_match_the_bundled_roms() {
+        let db = OverrideDb::builtin();
+        assert!(!db.games.is_empty());
+        for file in [
+            "bins/nestest.nes",
+            "bins/pacman.nes",
+            "bins/super_mario_bros.nes",
+            "bins/Alter_Ego.nes",
+            "bins/snake.nes",
+        ] {
+            let rom = Rom::new(&std::fs::read(file).unwrap()).unwrap();
+            let game = db.lookup(rom.crc32).unwrap_or_else(|| panic!("No entry for {}", file));
+            assert!(game.header_fixes(&rom).is_empty(), "{} disagrees with its entry", file);
+        }
+    }
+
+    #[test]
+    fn test_override_fixes_mirroring() {
+        let mut rom = test_rom();
+        assert_eq!(rom.mirroring, Mirroring::VERTICAL);
+
+        let mut db = OverrideDb::builtin();
+        db.merge(&format!(
+            "[[game]]\ncrc32 = {}\nmirroring = \"horizontal\"\ncontrollers = [\"joypad\", \"unplugged\"]\nregion = \"dendy\"\n",
+            rom.crc32
+        ))
+        .unwrap();
+
+        let game = db.lookup(rom.crc32).unwrap();
+        game.apply(&mut rom);
+        assert_eq!(rom.mirroring, Mirroring::HORIZONTAL);
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.region, Some(Region::Dendy));
+        assert_eq!(
+            game.controllers,
+            Some([ControllerType::Joypad, ControllerType::Unplugged])
+        );
+    }
+
+    #[test]
+    fn test_fix_header() {
+        let mut raw = vec![
+            0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x33, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        raw.extend(vec![0; 0x4000]);
+        let rom = Rom::new(&raw).unwrap();
+        let game = GameOverride {
+            crc32: rom.crc32,
+            mapper: Some(0x42),
+            mirroring: Some(Mirroring::HORIZONTAL),
+            ..Default::default()
+        };
+        assert_eq!(game.header_fixes(&rom).len(), 2);
+
+        game.fix_header(&mut raw).unwrap();
+        let fixed = Rom::new(&raw).unwrap();
+        assert_eq!(fixed.mapper, 0x42);
+        assert_eq!(fixed.mirroring, Mirroring::HORIZONTAL);
+        assert!(fixed.battery);
+        assert!(game.header_fixes(&fixed).is_empty());
+
+        // Only NES 2.0 has room for mappers past 255
+        let big = GameOverride {
+            mapper: Some(0x142),
+            ..game.clone()
+        };
+        assert!(big.fix_header(&mut raw).is_err());
+        raw[7] |= 0x08;
+        big.fix_header(&mut raw).unwrap();
+        assert_eq!(Rom::new(&raw).unwrap().mapper, 0x142);
+    }
+
+    #[test]
+    fn test_fix_dirty_header() {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x11];
+        raw.extend(b"DiskDude!");
+        raw.extend(vec![0; 0x4000]);
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.mapper, 1);
+        let game = GameOverride {
+            crc32: rom.crc32,
+            mapper: Some(0x42),
+            ..Default::default()
+        };
+
+        game.fix_header(&mut raw).unwrap();
+        assert_eq!(&raw[7..16], &[0x40, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let fixed = Rom::new(&raw).unwrap();
+        assert_eq!(fixed.mapper, 0x42);
+        assert_eq!(fixed.mirroring, Mirroring::VERTICAL);
+        assert!(game.header_fixes(&fixed).is_empty());
+    }
+
+    #[test]
+    fn test_prg_ram_size() {
+        let mut raw = vec![
+            0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x02, 0x08, 0, 0, 0x07, 0, 0, 0, 0, 0,
+        ];
+        raw.extend(vec![0; 0x4000]);
+        let mut rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.prg_ram_size, 0x2000);
+        let game = GameOverride {
+            crc32: rom.crc32,
+            prg_ram_size: Some(0x8000),
+            ..Default::default()
+        };
+        assert_eq!(game.header_fixes(&rom).len(), 1);
+
+        // Moves to the battery-backed nibble
+        game.fix_header(&mut raw).unwrap();
+        assert_eq!(raw[10], 0x90);
+        assert_eq!(Rom::new(&raw).unwrap().prg_ram_size, 0x8000);
+
+        game.apply(&mut rom);
+        assert!(game.header_fixes(&rom).is_empty());
+    }
+}
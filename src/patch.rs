@@ -0,0 +1,234 @@
+use std::path::{Path, PathBuf};
+
+// Extensions looked for next to a ROM, in order
+const PATCH_EXTENSIONS: [&str; 2] = ["ips", "bps"];
+
+/// `<rom dir>/<rom file stem>.ips` or `.bps`, whichever exists first.
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    let stem = rom_path.file_stem()?.to_string_lossy();
+    let dir = rom_path.parent().unwrap_or(Path::new(""));
+    PATCH_EXTENSIONS
+        .iter()
+        // Not `with_extension`: file names like "Super Mario Bros. 3" already contain dots
+        .map(|extension| dir.join(format!("{}.{}", stem, extension)))
+        .find(|path| path.is_file())
+}
+
+/// Applies an IPS or BPS patch to a whole ROM file, header included. The format is picked
+/// from the patch's magic bytes.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        Err("Not an IPS or BPS patch".to_string())
+    }
+}
+
+fn take<'a>(patch: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], String> {
+    let bytes = patch
+        .get(*pos..)
+        .and_then(|rest| rest.get(..len))
+        .ok_or("Patch ends in the middle of a record")?;
+    *pos += len;
+    Ok(bytes)
+}
+
+fn be_number(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |number, &byte| number << 8 | byte as usize)
+}
+
+/// IPS: a list of (offset, data) records, with run-length records for repeated bytes and an
+/// optional size to truncate to after the end marker.
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut output = rom.to_vec();
+    let mut pos = 5;
+    loop {
+        let offset = take(patch, &mut pos, 3)?;
+        if offset == b"EOF" {
+            break;
+        }
+        let offset = be_number(offset);
+        let size = be_number(take(patch, &mut pos, 2)?);
+        let (size, data) = if size == 0 {
+            let count = be_number(take(patch, &mut pos, 2)?);
+            let value = take(patch, &mut pos, 1)?[0];
+            (count, vec![value; count])
+        } else {
+            (size, take(patch, &mut pos, size)?.to_vec())
+        };
+        if output.len() < offset + size {
+            output.resize(offset + size, 0);
+        }
+        output[offset..offset + size].copy_from_slice(&data);
+    }
+    if let Ok(size) = take(patch, &mut pos, 3) {
+        output.truncate(be_number(size));
+    }
+    Ok(output)
+}
+
+fn bps_number(patch: &[u8], pos: &mut usize) -> Result<usize, String> {
+    let mut number: usize = 0;
+    let mut shift: usize = 1;
+    loop {
+        let byte = take(patch, pos, 1)?[0];
+        number = (byte as usize & 0x7F)
+            .checked_mul(shift)
+            .and_then(|n| n.checked_add(number))
+            .ok_or("Number too large in patch")?;
+        if byte & 0x80 != 0 {
+            return Ok(number);
+        }
+        shift = shift.checked_shl(7).ok_or("Number too large in patch")?;
+        number = number
+            .checked_add(shift)
+            .ok_or("Number too large in patch")?;
+    }
+}
+
+fn bps_offset(patch: &[u8], pos: &mut usize, base: usize) -> Result<usize, String> {
+    let number = bps_number(patch, pos)?;
+    let delta = number >> 1;
+    let offset = if number & 1 == 1 {
+        base.checked_sub(delta)
+    } else {
+        base.checked_add(delta)
+    };
+    offset.ok_or_else(|| "Copy offset out of range".to_string())
+}
+
+fn crc_at(patch: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(patch[pos..pos + 4].try_into().unwrap())
+}
+
+/// BPS: copy and insert commands that build the patched file, with checksums of the original,
+/// the result and the patch itself, so patching the wrong ROM fails instead of producing junk.
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < 4 + 12 {
+        return Err("Patch is too short".to_string());
+    }
+    let footer = patch.len() - 12;
+    if crc32fast::hash(&patch[..footer + 8]) != crc_at(patch, footer + 8) {
+        return Err("Patch is corrupt (checksum mismatch)".to_string());
+    }
+    if crc32fast::hash(rom) != crc_at(patch, footer) {
+        return Err("Patch is for a different ROM (checksum mismatch)".to_string());
+    }
+
+    let mut pos = 4;
+    let source_size = bps_number(patch, &mut pos)?;
+    let target_size = bps_number(patch, &mut pos)?;
+    let metadata_size = bps_number(patch, &mut pos)?;
+    take(patch, &mut pos, metadata_size)?;
+    if source_size != rom.len() {
+        return Err("Patch is for a different ROM (size mismatch)".to_string());
+    }
+
+    let mut output = Vec::new();
+    let mut source_offset = 0;
+    let mut target_offset = 0;
+    while pos < footer {
+        let action = bps_number(patch, &mut pos)?;
+        let length = (action >> 2) + 1;
+        if output.len() + length > target_size {
+            return Err("Patch writes past the end of the patched ROM".to_string());
+        }
+        match action & 3 {
+            // Source read: the same bytes as the original at this position
+            0 => {
+                let start = output.len();
+                let bytes = rom
+                    .get(start..)
+                    .and_then(|rest| rest.get(..length))
+                    .ok_or("Source read past the end of the ROM")?;
+                output.extend_from_slice(bytes);
+            }
+            // Target read: new bytes from the patch
+            1 => output.extend_from_slice(take(patch, &mut pos, length)?),
+            // Source copy: bytes from anywhere in the original
+            2 => {
+                source_offset = bps_offset(patch, &mut pos, source_offset)?;
+                let bytes = rom
+                    .get(source_offset..)
+                    .and_then(|rest| rest.get(..length))
+                    .ok_or("Source copy past the end of the ROM")?;
+                output.extend_from_slice(bytes);
+                source_offset += length;
+            }
+            // Target copy: bytes already written, byte by byte since the ranges may overlap
+            _ => {
+                target_offset = bps_offset(patch, &mut pos, target_offset)?;
+                for _ in 0..length {
+                    let byte = *output
+                        .get(target_offset)
+                        .ok_or("Target copy past the end of the output")?;
+                    output.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if output.len() != target_size || crc32fast::hash(&output) != crc_at(patch, footer + 4) {
+        return Err("Patched ROM doesn't match the patch's checksum".to_string());
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ips() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend([0, 0, 1, 0, 2, b'X', b'Y']); // 2 bytes at 1
+        patch.extend([0, 0, 5, 0, 0, 0, 3, b'Z']); // 3 Zs at 5, past the end
+        patch.extend(b"EOF");
+        assert_eq!(apply(b"abcd", &patch), Ok(b"aXYd\0ZZZ".to_vec()));
+
+        patch.extend([0, 0, 2]);
+        assert_eq!(apply(b"abcd", &patch), Ok(b"aX".to_vec()));
+
+        assert!(apply(b"abcd", b"PATCH\0\0").is_err());
+    }
+
+    fn bps_encode(patch: &mut Vec<u8>, mut number: usize) {
+        loop {
+            let byte = (number & 0x7F) as u8;
+            number >>= 7;
+            if number == 0 {
+                patch.push(0x80 | byte);
+                return;
+            }
+            patch.push(byte);
+            number -= 1;
+        }
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = b"abcd";
+        let target = b"abXdab";
+        let mut patch = b"BPS1".to_vec();
+        for number in [source.len(), target.len(), 0] {
+            bps_encode(&mut patch, number);
+        }
+        bps_encode(&mut patch, (2 - 1) << 2); // source read "ab"
+        bps_encode(&mut patch, 1); // target read "X"
+        patch.push(b'X');
+        bps_encode(&mut patch, 0); // source read "d"
+        bps_encode(&mut patch, ((2 - 1) << 2) | 2); // source copy "ab" from 0
+        bps_encode(&mut patch, 0);
+        patch.extend(crc32fast::hash(source).to_le_bytes());
+        patch.extend(crc32fast::hash(target).to_le_bytes());
+        patch.extend(crc32fast::hash(&patch).to_le_bytes());
+
+        assert_eq!(apply(source, &patch), Ok(target.to_vec()));
+        assert!(apply(b"abce", &patch).is_err());
+    }
+}
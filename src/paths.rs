@@ -0,0 +1,159 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::{PathsConfig, CONFIG_FILE_NAME, RECENT_FILE_NAME};
+
+const APP_DIR: &str = "rustnes";
+
+/// Where the emulator keeps its files. Defaults to the platform's config and data directories
+/// (XDG on Linux, AppData on Windows, Application Support on macOS).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Paths {
+    pub config_dir: PathBuf,
+    pub saves: PathBuf,
+    pub states: PathBuf,
+    pub screenshots: PathBuf,
+    /// Crash dumps, always under the data directory
+    pub crashes: PathBuf,
+    /// The recent ROM list, also always under the data directory
+    pub recent: PathBuf,
+}
+
+impl Paths {
+    pub fn default_config_dir() -> PathBuf {
+        dirs::config_dir()
+            .map(|dir| dir.join(APP_DIR))
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    fn default_data_dir() -> PathBuf {
+        dirs::data_dir()
+            .map(|dir| dir.join(APP_DIR))
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    /// Resolves the data directories. A `data_dir` given on the command line wins over
+    /// everything in the config file, which in turn wins over the platform defaults.
+    pub fn resolve(config_dir: PathBuf, config: &PathsConfig, data_dir: Option<PathBuf>) -> Paths {
+        if let Some(data_dir) = data_dir {
+            return Paths {
+                config_dir,
+                saves: data_dir.join("saves"),
+                states: data_dir.join("states"),
+                screenshots: data_dir.join("screenshots"),
+                crashes: data_dir.join("crashes"),
+                recent: data_dir.join(RECENT_FILE_NAME),
+            };
+        }
+
+        let data_dir = config
+            .data_dir
+            .clone()
+            .unwrap_or_else(Paths::default_data_dir);
+        Paths {
+            config_dir,
+            saves: config.saves.clone().unwrap_or(data_dir.join("saves")),
+            states: config.states.clone().unwrap_or(data_dir.join("states")),
+            screenshots: config
+                .screenshots
+                .clone()
+                .unwrap_or(data_dir.join("screenshots")),
+            crashes: data_dir.join("crashes"),
+            recent: data_dir.join(RECENT_FILE_NAME),
+        }
+    }
+
+    pub fn config_file(&self) -> PathBuf {
+        self.config_dir.join(CONFIG_FILE_NAME)
+    }
+}
+
+/// `<dir>/<rom file stem>.<extension>`, creating `dir` if needed.
+pub fn rom_file(dir: &Path, rom_path: &str, extension: &str) -> Result<PathBuf, String> {
+    std::fs::create_dir_all(dir)
+        .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let stem = Path::new(rom_path)
+        .file_stem()
+        .ok_or_else(|| format!("Invalid ROM path: {}", rom_path))?;
+    // Not `with_extension`: file names like "Super Mario Bros. 3" already contain dots
+    Ok(dir.join(format!("{}.{}", stem.to_string_lossy(), extension)))
+}
+
+/// `<dir>/<rom file stem>-<kind>-<unix time>.<extension>`, creating `dir` if needed. For
+/// files that shouldn't overwrite each other, like screenshots and exports, so a second one
+/// within the same second gets `-2`, `-3` and so on after the time.
+pub fn timestamped_file(
+    dir: &Path,
+    rom_path: &str,
+    kind: &str,
+    extension: &str,
+) -> Result<PathBuf, String> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let file = rom_file(dir, rom_path, extension)?;
+    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+    Ok(unused_file(dir, &format!("{}-{}-{}", stem, kind, time), extension))
+}
+
+/// `<dir>/<name>.<extension>`, or with `-2`, `-3` and so on after `name` for the first one
+/// that doesn't exist yet.
+fn unused_file(dir: &Path, name: &str, extension: &str) -> PathBuf {
+    let mut path = dir.join(format!("{}.{}", name, extension));
+    let mut n = 1;
+    while path.exists() {
+        n += 1;
+        path = dir.join(format!("{}-{}.{}", name, n, extension));
+    }
+    path
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cli_data_dir_overrides_config() {
+        let config = PathsConfig {
+            saves: Some(PathBuf::from("/config/saves")),
+            ..Default::default()
+        };
+
+        let paths = Paths::resolve(PathBuf::from("/cfg"), &config, None);
+        assert_eq!(paths.saves, PathBuf::from("/config/saves"));
+
+        let paths = Paths::resolve(PathBuf::from("/cfg"), &config, Some(PathBuf::from("/cli")));
+        assert_eq!(paths.saves, PathBuf::from("/cli/saves"));
+        assert_eq!(paths.crashes, PathBuf::from("/cli/crashes"));
+        assert_eq!(paths.recent, PathBuf::from("/cli/recent.toml"));
+        assert_eq!(paths.config_file(), PathBuf::from("/cfg/config.toml"));
+    }
+
+    #[test]
+    fn test_files_in_the_same_second_get_numbered() {
+        let dir = std::env::temp_dir().join("rustnes_unused_file_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = unused_file(&dir, "game-shot-100", "png");
+        assert_eq!(first, dir.join("game-shot-100.png"));
+        std::fs::write(&first, []).unwrap();
+        let second = unused_file(&dir, "game-shot-100", "png");
+        assert_eq!(second, dir.join("game-shot-100-2.png"));
+        std::fs::write(&second, []).unwrap();
+        assert_eq!(
+            unused_file(&dir, "game-shot-100", "png"),
+            dir.join("game-shot-100-3.png")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rom_file_keeps_dots_in_name() {
+        let dir = std::env::temp_dir().join("rustnes_paths_test");
+        let file = rom_file(&dir, "roms/Super Mario Bros. 3 (U).nes", "sav").unwrap();
+        assert_eq!(file, dir.join("Super Mario Bros. 3 (U).sav"));
+    }
+}
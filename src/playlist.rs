@@ -0,0 +1,110 @@
+use std::path::{Path, PathBuf};
+
+/// The .nes files in `dir`, sorted by name.
+pub fn rom_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut roms: Vec<_> = std::fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("nes"))
+        })
+        .collect();
+    roms.sort();
+    Ok(roms)
+}
+
+/// ROMs to switch between while playing, in the order given, with directories replaced by the
+/// ROMs in them. Only empty when the emulator starts without a ROM and has none to offer.
+pub struct Playlist {
+    roms: Vec<String>,
+    current: usize,
+}
+
+impl Playlist {
+    pub fn new(paths: &[String]) -> Result<Self, String> {
+        let mut roms = Vec::new();
+        for path in paths {
+            if !Path::new(path).is_dir() {
+                roms.push(path.clone());
+                continue;
+            }
+            let files = rom_files(Path::new(path))?;
+            if files.is_empty() {
+                return Err(format!("No .nes files in {}", path));
+            }
+            roms.extend(files.iter().map(|file| file.to_string_lossy().into_owned()));
+        }
+        Ok(Playlist { roms, current: 0 })
+    }
+
+    pub fn len(&self) -> usize {
+        self.roms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roms.is_empty()
+    }
+
+    /// Position of the current ROM, from 0.
+    pub fn position(&self) -> usize {
+        self.current
+    }
+
+    pub fn current(&self) -> &str {
+        &self.roms[self.current]
+    }
+
+    pub fn roms(&self) -> &[String] {
+        &self.roms
+    }
+
+    /// Makes the ROM at `position` the current one.
+    pub fn jump_to(&mut self, position: usize) -> &str {
+        self.current = position.min(self.roms.len() - 1);
+        self.current()
+    }
+
+    /// Moves to the next ROM, or the previous one with `forward` false, wrapping around at the
+    /// ends.
+    pub fn step(&mut self, forward: bool) -> &str {
+        let len = self.roms.len();
+        self.current = if forward {
+            (self.current + 1) % len
+        } else {
+            (self.current + len - 1) % len
+        };
+        self.current()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_directories_expand_and_playlist_wraps() {
+        let dir = std::env::temp_dir().join("rustnes_playlist_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["b.nes", "a.NES", "notes.txt"] {
+            std::fs::write(dir.join(name), []).unwrap();
+        }
+        let dir_name = dir.to_string_lossy().into_owned();
+
+        let mut playlist = Playlist::new(&["first.nes".to_string(), dir_name.clone()]).unwrap();
+        assert_eq!(playlist.len(), 3);
+        assert_eq!(playlist.current(), "first.nes");
+        assert!(playlist.step(true).ends_with("a.NES"));
+        assert!(playlist.step(true).ends_with("b.nes"));
+        assert_eq!(playlist.step(true), "first.nes");
+        assert!(playlist.step(false).ends_with("b.nes"));
+        assert_eq!(playlist.position(), 2);
+        assert_eq!(playlist.jump_to(0), "first.nes");
+
+        std::fs::remove_file(dir.join("a.NES")).unwrap();
+        std::fs::remove_file(dir.join("b.nes")).unwrap();
+        assert!(Playlist::new(&[dir_name]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,20 @@
 pub mod registers;
+pub mod tile_cache;
 
-use crate::cartridge::Mirroring;
+use crate::{
+    cartridge::Mirroring,
+    region::Region,
+    save_state::{StateReader, StateWriter},
+};
 
 use self::registers::{
     addr::AddrRegister, control::ControlRegister, mask::MaskRegister, scroll::ScrollRegister,
     status::StatusRegister,
 };
+use self::tile_cache::TileCache;
+
+const CHR_RAM_SIZE: usize = 0x2000;
+const NAME_TABLE_SIZE: usize = 0x400;
 
 pub trait PPU {
     fn write_to_ctrl(&mut self, data: u8);
@@ -23,13 +32,23 @@ pub trait PPU {
 
 pub struct NesPPU {
     pub chr_rom: Vec<u8>,
+    chr_is_ram: bool,
+    pub tile_cache: TileCache,
     pub palette_table: [u8; 32],
-    pub vram: [u8; 2048],
+    /// 2KB inside the console, plus another 2KB on four-screen cartridges
+    pub vram: Vec<u8>,
     pub oam_data: [u8; 256],
     pub oam_addr: u8,
 
     pub mirroring: Mirroring,
 
+    // Every VRAM, palette or CHR write that changes something bumps `write_generation` and
+    // stamps what it touched, so renderers can tell what changed since they last looked.
+    pub write_generation: u64,
+    pub vram_stamps: Vec<u64>,
+    pub palette_stamp: u64,
+    pub chr_stamp: u64,
+
     internal_data_buffer: u8,
 
     pub addr: AddrRegister,
@@ -40,6 +59,25 @@ pub struct NesPPU {
 
     scanline: u16,
     cycles: usize,
+    /// 262 for NTSC, 312 for PAL, whose longer vblank makes up most of the difference
+    scanlines: u16,
+    vblank_line: u16,
+    // (x, scanline) where sprite 0 hit was set this frame, and the same for the frame that
+    // just finished
+    sprite_zero_hit_at: Option<(u8, u16)>,
+    pub last_sprite_zero_hit: Option<(u8, u16)>,
+    // (scanline, dot) of the latest $2002 read this frame, and the same for the frame that
+    // just finished
+    status_read_at: Option<(u16, usize)>,
+    pub last_status_read: Option<(u16, usize)>,
+    /// Set the sprite overflow flag the way the real PPU does, with its buggy OAM scan,
+    /// rather than whenever a scanline has more than 8 sprites
+    pub sprite_overflow_bug: bool,
+    /// Scanlines added after the post-render line for overclocking, which the CPU runs
+    /// through but nothing is drawn on
+    pub extra_lines: u16,
+    /// Extra lines run so far this frame
+    extra_done: u16,
 
     pub nmi_interrupt: Option<u8>,
 }
@@ -49,14 +87,33 @@ impl NesPPU {
         NesPPU::new(vec![0; 2048], Mirroring::HORIZONTAL)
     }
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> NesPPU {
+        // Carts without CHR ROM have 8KB of CHR RAM instead
+        let chr_is_ram = chr_rom.is_empty();
+        let chr_rom = if chr_is_ram {
+            vec![0; CHR_RAM_SIZE]
+        } else {
+            chr_rom
+        };
+        let vram_size = if mirroring == Mirroring::FOURSCREEN {
+            4 * NAME_TABLE_SIZE
+        } else {
+            2 * NAME_TABLE_SIZE
+        };
         NesPPU {
+            tile_cache: TileCache::new(&chr_rom),
             chr_rom,
+            chr_is_ram,
             palette_table: [0; 32],
-            vram: [0; 2048],
+            vram: vec![0; vram_size],
             oam_data: [0; 64 * 4],
             oam_addr: 0,
             mirroring,
 
+            write_generation: 0,
+            vram_stamps: vec![0; vram_size],
+            palette_stamp: 0,
+            chr_stamp: 0,
+
             addr: AddrRegister::new(),
             ctrl: ControlRegister::new(),
             mask: MaskRegister::new(),
@@ -67,23 +124,161 @@ impl NesPPU {
 
             scanline: 0,
             cycles: 0,
+            scanlines: Region::NTSC.scanlines(),
+            vblank_line: Region::NTSC.vblank_line(),
+            sprite_zero_hit_at: None,
+            last_sprite_zero_hit: None,
+            status_read_at: None,
+            last_status_read: None,
+            sprite_overflow_bug: true,
+            extra_lines: 0,
+            extra_done: 0,
 
             nmi_interrupt: None,
         }
     }
 
+    /// Puts the PPU back in its power-on state. CHR RAM is cleared, CHR ROM stays. Everything
+    /// is stamped with a new write generation so renderers redraw from scratch.
+    pub fn power_cycle(&mut self) {
+        let chr_rom = if self.chr_is_ram {
+            Vec::new()
+        } else {
+            std::mem::take(&mut self.chr_rom)
+        };
+        let generation = self.write_generation + 1;
+        let (sprite_overflow_bug, extra_lines) = (self.sprite_overflow_bug, self.extra_lines);
+        let (scanlines, vblank_line) = (self.scanlines, self.vblank_line);
+        *self = NesPPU::new(chr_rom, self.mirroring);
+        self.sprite_overflow_bug = sprite_overflow_bug;
+        self.extra_lines = extra_lines;
+        self.scanlines = scanlines;
+        self.vblank_line = vblank_line;
+        self.write_generation = generation;
+        self.vram_stamps.fill(generation);
+        self.palette_stamp = generation;
+        self.chr_stamp = generation;
+    }
+
+    /// What the console's reset button does to the PPU: PPUCTRL, PPUMASK, the scroll and the
+    /// $2005/$2006 write latch are cleared and a pending NMI is dropped. VRAM, OAM and the
+    /// palette stay.
+    pub fn soft_reset(&mut self) {
+        self.ctrl = ControlRegister::new();
+        self.mask = MaskRegister::new();
+        self.scroll.scroll_x = 0;
+        self.scroll.scroll_y = 0;
+        self.addr.reset_latch();
+        self.scroll.reset_latch();
+        self.internal_data_buffer = 0;
+        self.nmi_interrupt = None;
+    }
+
+    /// CHR isn't included, it belongs to the mapper.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.palette_table);
+        writer.bytes(&self.vram);
+        writer.bytes(&self.oam_data);
+        writer.u8(self.oam_addr);
+        writer.u8(self.internal_data_buffer);
+        self.addr.save_state(writer);
+        writer.u8(self.ctrl.bits());
+        writer.u8(self.mask.bits());
+        writer.u8(self.scroll.scroll_x);
+        writer.u8(self.scroll.scroll_y);
+        writer.bool(self.scroll.latch);
+        writer.u8(self.status.bits());
+        writer.u16(self.scanline);
+        writer.u64(self.cycles as u64);
+        writer.bool(self.nmi_interrupt.is_some());
+        writer.u16(self.extra_done);
+    }
+
+    /// Restores what `save_state` wrote and stamps everything with a new write generation,
+    /// like `power_cycle`, so renderers redraw.
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.bytes_into(&mut self.palette_table)?;
+        reader.bytes_into(&mut self.vram)?;
+        reader.bytes_into(&mut self.oam_data)?;
+        self.oam_addr = reader.u8()?;
+        self.internal_data_buffer = reader.u8()?;
+        self.addr.load_state(reader)?;
+        self.ctrl = ControlRegister::from_bits_retain(reader.u8()?);
+        self.mask = MaskRegister::from_bits_retain(reader.u8()?);
+        self.scroll.scroll_x = reader.u8()?;
+        self.scroll.scroll_y = reader.u8()?;
+        self.scroll.latch = reader.bool()?;
+        self.status = StatusRegister::from_bits_retain(reader.u8()?);
+        self.scanline = reader.u16()?.min(self.scanlines - 1);
+        self.cycles = reader.u64()? as usize;
+        self.nmi_interrupt = reader.bool()?.then_some(1);
+        // Version 1 didn't keep how many overclocking lines the frame had run
+        self.extra_done = if reader.version() >= 2 { reader.u16()? } else { 0 };
+        self.sprite_zero_hit_at = None;
+        self.last_sprite_zero_hit = None;
+        self.status_read_at = None;
+        self.last_status_read = None;
+
+        let generation = self.write_generation + 1;
+        self.write_generation = generation;
+        self.vram_stamps.fill(generation);
+        self.palette_stamp = generation;
+        self.chr_stamp = generation;
+        Ok(())
+    }
+
+    /// Switches to the region's number of scanlines and where vblank starts.
+    pub fn set_region(&mut self, region: Region) {
+        self.scanlines = region.scanlines();
+        self.vblank_line = region.vblank_line();
+        self.scanline = self.scanline.min(self.scanlines - 1);
+    }
+
+    /// Whether the PPU is in one of the overclocking lines, during which the APU stands still.
+    pub fn in_extra_line(&self) -> bool {
+        self.extra_done > 0 && self.scanline == self.vblank_line - 1
+    }
+
+    /// Replaces the 1KB of CHR the PPU sees at `slot` * $400, for boards that switch CHR banks.
+    pub fn load_chr_bank(&mut self, slot: usize, bank: &[u8]) {
+        let start = slot * 0x400;
+        self.chr_rom[start..start + 0x400].copy_from_slice(bank);
+        self.tile_cache
+            .invalidate_range(&self.chr_rom, start as u16, 0x400);
+        self.chr_stamp = self.next_generation();
+    }
+
+    /// Switches the nametable mirroring, for boards that control it. Four-screen VRAM can't be
+    /// switched to or from.
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        if mirroring != self.mirroring {
+            self.mirroring = mirroring;
+            let generation = self.next_generation();
+            self.vram_stamps.fill(generation);
+        }
+    }
+
     pub fn tick(&mut self, cycle: u8) -> bool {
         self.cycles += cycle as usize;
         if self.cycles >= 341 {
 
-            if self.is_sprite_0_hit(self.cycles) {
+            if self.is_sprite_0_hit(self.cycles) && !self.status.is_in_sprite_zero_hit() {
                 self.status.set_sprite_zero_hit(true);
+                self.sprite_zero_hit_at = Some((self.oam_data[3], self.scanline));
+            }
+            if self.scanline < 240 && self.sprite_overflow() {
+                self.status.set_sprite_overflow(true);
             }
 
             self.cycles -= 341;
+            // Extra lines repeat the post-render line, so vblank and the NMI come later
+            if self.scanline == self.vblank_line - 1 && self.extra_done < self.extra_lines {
+                self.extra_done += 1;
+                return false;
+            }
             self.scanline += 1;
 
-            if self.scanline == 241 {
+            if self.scanline == self.vblank_line {
                 self.status.set_vertical_blank(true);
                 self.status.set_sprite_zero_hit(false);
                 if self.ctrl.generate_nmi() {
@@ -91,43 +286,104 @@ impl NesPPU {
                 }
             }
 
-            if self.scanline >= 262 {
+            if self.scanline >= self.scanlines {
                 self.scanline = 0;
+                self.extra_done = 0;
                 self.status.reset_vertical_blank();
                 self.status.set_sprite_zero_hit(false);
+                self.status.set_sprite_overflow(false);
                 self.nmi_interrupt = None;
+                self.last_sprite_zero_hit = self.sprite_zero_hit_at.take();
+                self.last_status_read = self.status_read_at.take();
                 return true;
             }
         }
         false
     }
 
+    /// Scanline being drawn: 0-239 visible, 241-260 vblank and 261 pre-render for NTSC; PAL's
+    /// vblank goes on to 310, with 311 pre-render, and the Dendy's is 291-310.
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    /// PPU cycle within the current scanline (0-340).
+    pub fn dot(&self) -> usize {
+        self.cycles
+    }
+
     fn is_sprite_0_hit(&self, cycle: usize) -> bool {
         let y = self.oam_data[0] as usize;
         let x = self.oam_data[3] as usize;
         (y == self.scanline as usize) && x <= cycle && self.mask.show_sprites()
     }
 
+    /// Whether sprite evaluation on the current scanline finds more than 8 sprites for the
+    /// next one. Only happens while rendering is on.
+    ///
+    /// After the 8th sprite the real PPU keeps checking OAM for more, but it steps the byte it
+    /// compares along with the sprite index, so it reads tile numbers, attributes and X
+    /// positions as if they were Y coordinates. That finds overflows that aren't there and
+    /// misses ones that are, and games and test ROMs depend on it.
+    fn sprite_overflow(&self) -> bool {
+        if !self.mask.show_background() && !self.mask.show_sprites() {
+            return false;
+        }
+        let height = self.ctrl.sprite_size() as u16;
+        let in_range = |y: u8| self.scanline.wrapping_sub(y as u16) < height;
+
+        let mut n = 0;
+        let mut found = 0;
+        while n < 64 && found < 8 {
+            if in_range(self.oam_data[n * 4]) {
+                found += 1;
+            }
+            n += 1;
+        }
+        if found < 8 {
+            return false;
+        }
+        if !self.sprite_overflow_bug {
+            return (n..64).any(|n| in_range(self.oam_data[n * 4]));
+        }
+
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam_data[n * 4 + m]) {
+                return true;
+            }
+            // The bug: m should stay 0 when a sprite is out of range
+            n += 1;
+            m = (m + 1) % 4;
+        }
+        false
+    }
+
     pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
         self.nmi_interrupt.take()
     }
 
+    fn next_generation(&mut self) -> u64 {
+        self.write_generation += 1;
+        self.write_generation
+    }
+
     fn increment_vram_addr(&mut self) {
         self.addr.increment(self.ctrl.vram_addr_increment());
     }
 
-    fn mirror_vram_addr(&mut self, addr: u16) -> u16 {
+    /// Offset into `vram` of logical nametable `table` (0-3, i.e. $2000/$2400/$2800/$2C00)
+    /// under the cartridge's mirroring.
+    pub fn name_table_offset(&self, table: u16) -> usize {
+        self.mirror_vram_addr(0x2000 + table * 0x400) as usize
+    }
+
+    fn mirror_vram_addr(&self, addr: u16) -> u16 {
         let mirrored_vram = addr & 0x2FFF;
         let vram_index = mirrored_vram - 0x2000;
-        let name_table = vram_index / 0x0400;
-        match (&self.mirroring, name_table) {
-            (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) | (Mirroring::HORIZONTAL, 3) => {
-                vram_index - 0x0800
-            }
-            (Mirroring::HORIZONTAL, 2) => vram_index - 0x0400,
-            (Mirroring::HORIZONTAL, 1) => vram_index - 0x0400,
-            _ => vram_index,
-        }
+        let name_table = vram_index / NAME_TABLE_SIZE as u16;
+        let bank = self.mirroring.nametable_banks()[name_table as usize];
+        bank * NAME_TABLE_SIZE as u16 + vram_index % NAME_TABLE_SIZE as u16
     }
 }
 
@@ -171,16 +427,32 @@ impl PPU for NesPPU {
     fn write_to_data(&mut self, data: u8) {
         let addr = self.addr.get();
         match addr {
-            0..=0x1fff => eprintln!("Cannot write to CHR ROM. addr: 0x{:04X}", addr),
+            0..=0x1fff => {
+                if self.chr_is_ram {
+                    self.chr_rom[addr as usize] = data;
+                    self.tile_cache.invalidate(&self.chr_rom, addr);
+                    self.chr_stamp = self.next_generation();
+                } else {
+                    eprintln!("Cannot write to CHR ROM. addr: 0x{:04X}", addr)
+                }
+            }
             0x2000..=0x2FFF => {
-                self.vram[self.mirror_vram_addr(addr) as usize] = data;
+                let idx = self.mirror_vram_addr(addr) as usize;
+                if self.vram[idx] != data {
+                    self.vram[idx] = data;
+                    self.vram_stamps[idx] = self.next_generation();
+                }
             }
             0x3000..=0x3eFF => panic!("0x3000 to 0x3FFF is not usable. addr: 0x{:04X}", addr),
             0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
                 let add_mirror = addr - 0x10;
                 self.palette_table[(add_mirror - 0x3f00) as usize] = data;
+                self.palette_stamp = self.next_generation();
+            }
+            0x3F00..=0x3FFF => {
+                self.palette_table[(addr - 0x3f00) as usize] = data;
+                self.palette_stamp = self.next_generation();
             }
-            0x3F00..=0x3FFF => self.palette_table[(addr - 0x3f00) as usize] = data,
             _ => panic!("Invalid Write PPU address: {:04X}", addr),
         }
         self.increment_vram_addr();
@@ -192,6 +464,7 @@ impl PPU for NesPPU {
 
     fn read_status(&mut self) -> u8 {
         let result = self.status.bits();
+        self.status_read_at = Some((self.scanline, self.cycles));
         self.status.reset_vertical_blank();
         self.addr.reset_latch();
         self.scroll.reset_latch();
@@ -342,6 +615,26 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from B
     }
 
+    #[test]
+    fn test_vram_single_and_four_screen() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::SINGLESCREEN1);
+        for table in 0..4 {
+            assert_eq!(ppu.name_table_offset(table), 0x400);
+        }
+        ppu.write_to_ppu_addr(0x2C);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.vram[0x0405], 0x66);
+
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::FOURSCREEN);
+        assert_eq!(ppu.vram.len(), 0x1000);
+        ppu.write_to_ppu_addr(0x2C);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.vram[0x0C05], 0x66);
+        assert_eq!(ppu.name_table_offset(2), 0x800);
+    }
+
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -363,6 +656,49 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x66);
     }
 
+    #[test]
+    fn test_state_keeps_overclocking_lines_done() {
+        let overclocked = || {
+            let mut ppu = NesPPU::new_empty_rom();
+            ppu.extra_lines = 10;
+            ppu
+        };
+        let mut ppu = overclocked();
+        while ppu.extra_done < 3 {
+            ppu.tick(1);
+        }
+        let mut writer = StateWriter::new();
+        writer.section(b"PPU ", 2, |writer| ppu.save_state(writer));
+        let state = writer.finish();
+
+        let mut loaded = overclocked();
+        StateReader::new(&state)
+            .section(b"PPU ", |reader| loaded.load_state(reader))
+            .unwrap();
+        assert!(loaded.in_extra_line());
+        assert_eq!(loaded.extra_done, 3);
+    }
+
+    #[test]
+    fn test_soft_reset() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0x80);
+        ppu.write_to_mask(0x1E);
+        ppu.nmi_interrupt = Some(1);
+        ppu.vram[0x0105] = 0x66;
+        ppu.write_to_ppu_addr(0x3F);
+
+        ppu.soft_reset();
+        assert_eq!(ppu.ctrl.bits(), 0);
+        assert_eq!(ppu.mask.bits(), 0);
+        assert_eq!(ppu.poll_nmi_interrupt(), None);
+        // The next write is the high byte again
+        ppu.write_to_ppu_addr(0x21);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x66, "VRAM is kept");
+    }
+
     #[test]
     fn test_ppu_vram_mirroring() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -402,6 +738,58 @@ pub mod test {
         assert_eq!(ppu.read_oam_data(), 0x77);
     }
 
+    #[test]
+    fn test_chr_ram_write_updates_tile_cache() {
+        let mut ppu = NesPPU::new(vec![], Mirroring::HORIZONTAL);
+        assert_eq!(ppu.tile_cache.tile(0x0010)[0..8], [0; 8]);
+
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_ppu_addr(0x10);
+        ppu.write_to_data(0b1000_0001); // low bitplane, row 0 of tile 1
+
+        assert_eq!(ppu.tile_cache.tile(0x0010)[0..8], [1, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_mask(0b0001_0000);
+        // Nine sprites on scanline 20, the rest hidden below the screen
+        let mut oam = [0xFF; 256];
+        for sprite in oam.chunks_mut(4).take(9) {
+            sprite[0] = 20;
+        }
+        ppu.oam_data = oam;
+        let run_frame = |ppu: &mut NesPPU| {
+            let mut overflow = false;
+            while !ppu.tick(113) {
+                overflow |= ppu.status.is_in_sprite_overflow();
+            }
+            overflow
+        };
+        assert!(run_frame(&mut ppu));
+        ppu.sprite_overflow_bug = false;
+        assert!(run_frame(&mut ppu));
+
+        // Eight sprites, then one whose Y is off screen but whose tile number reads as in range
+        // once the scan goes diagonal
+        ppu.oam_data[8 * 4] = 0xFF;
+        ppu.oam_data[9 * 4 + 1] = 20;
+        assert!(!run_frame(&mut ppu));
+        ppu.sprite_overflow_bug = true;
+        assert!(run_frame(&mut ppu));
+
+        // A real ninth sprite the buggy scan misses because it is looking at the X byte
+        ppu.oam_data[9 * 4 + 1] = 0xFF;
+        ppu.oam_data[10 * 4] = 20;
+        assert!(!run_frame(&mut ppu));
+        ppu.sprite_overflow_bug = false;
+        assert!(run_frame(&mut ppu));
+
+        ppu.write_to_mask(0);
+        assert!(!run_frame(&mut ppu));
+    }
+
     #[test]
     fn test_oam_dma() {
         let mut ppu = NesPPU::new_empty_rom();
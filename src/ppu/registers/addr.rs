@@ -1,3 +1,4 @@
+use crate::save_state::{StateReader, StateWriter};
 
 pub struct AddrRegister {
     value: (u8, u8),
@@ -46,6 +47,17 @@ impl AddrRegister {
         self.hi_ptr = true;
     }
 
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.get());
+        writer.bool(self.hi_ptr);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.set(reader.u16()?);
+        self.hi_ptr = reader.bool()?;
+        Ok(())
+    }
+
     pub fn get(&self) -> u16 {
         ((self.value.0 as u16) << 8) | (self.value.1 as u16)
     }
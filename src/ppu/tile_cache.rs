@@ -0,0 +1,90 @@
+const TILE_BYTES: usize = 16;
+
+// Each byte spread out so bit n ends up in the lowest bit of byte n of a u64.
+static SPREAD: [u64; 256] = spread_table();
+
+const fn spread_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut bit = 0;
+        while bit < 8 {
+            if byte & (1 << bit) != 0 {
+                table[byte] |= 1 << (bit * 8);
+            }
+            bit += 1;
+        }
+        byte += 1;
+    }
+    table
+}
+
+/// Expands one row of a tile, given its low and high bitplane bytes, into 8 palette indices
+/// ordered left to right.
+pub fn decode_tile_row(low: u8, high: u8) -> [u8; 8] {
+    // Pixel 0 is bit 7, which `SPREAD` puts in the most significant byte
+    (SPREAD[low as usize] | SPREAD[high as usize] << 1).to_be_bytes()
+}
+
+/// Pattern table tiles decoded from their two bitplanes into 8x8 arrays of palette indices (0-3),
+/// so the renderer doesn't have to shift bits out of CHR data for every tile on every frame.
+///
+/// Tiles are decoded eagerly; whoever changes the underlying CHR data (CHR RAM writes, bank
+/// switches) has to call `invalidate` or `rebuild`.
+pub struct TileCache {
+    tiles: Vec<[u8; 64]>,
+}
+
+impl TileCache {
+    pub fn new(chr: &[u8]) -> Self {
+        let mut cache = TileCache { tiles: Vec::new() };
+        cache.rebuild(chr);
+        cache
+    }
+
+    /// Decoded tile starting at pattern table address `addr`.
+    pub fn tile(&self, addr: u16) -> &[u8; 64] {
+        &self.tiles[addr as usize / TILE_BYTES]
+    }
+
+    /// Re-decodes the tile containing pattern table address `addr`.
+    pub fn invalidate(&mut self, chr: &[u8], addr: u16) {
+        let idx = addr as usize / TILE_BYTES;
+        self.tiles[idx] = decode_tile(&chr[idx * TILE_BYTES..(idx + 1) * TILE_BYTES]);
+    }
+
+    /// Re-decodes the tiles in the `len` bytes from pattern table address `addr`, e.g. after a
+    /// CHR bank switch.
+    pub fn invalidate_range(&mut self, chr: &[u8], addr: u16, len: usize) {
+        let start = addr as usize / TILE_BYTES;
+        for idx in start..start + len / TILE_BYTES {
+            self.tiles[idx] = decode_tile(&chr[idx * TILE_BYTES..(idx + 1) * TILE_BYTES]);
+        }
+    }
+
+    /// Re-decodes every tile.
+    pub fn rebuild(&mut self, chr: &[u8]) {
+        self.tiles = chr.chunks_exact(TILE_BYTES).map(decode_tile).collect();
+    }
+}
+
+fn decode_tile(tile: &[u8]) -> [u8; 64] {
+    let mut decoded = [0; 64];
+    for y in 0..=7 {
+        decoded[y * 8..y * 8 + 8].copy_from_slice(&decode_tile_row(tile[y], tile[y + 8]));
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_tile_row() {
+        assert_eq!(
+            decode_tile_row(0b1000_0001, 0b1100_0000),
+            [3, 2, 0, 0, 0, 0, 0, 1]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::{path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// TV system a cartridge was made for. Decides the CPU clock, the frame rate and the timing
+/// tables the APU uses.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Region {
+    #[default]
+    NTSC,
+    PAL,
+    /// The Dendy and other famiclones sold where TVs were PAL: 50Hz frames from PAL's master
+    /// clock, but NTSC's 3 dots per CPU cycle and APU periods, with the extra lines before
+    /// vblank so NTSC games keep their time in it.
+    Dendy,
+}
+
+// Country tags used by GoodNES ("(E)") and No-Intro ("(Europe)") style file names
+#[rustfmt::skip]
+const PAL_TAGS: [&str; 18] = [
+    "E", "Europe", "PAL", "A", "Australia", "G", "Germany", "F", "France", "S", "Spain", "I",
+    "Italy", "Sw", "Sweden", "Scandinavia", "UK", "Netherlands",
+];
+const NTSC_TAGS: [&str; 8] = ["U", "USA", "J", "Japan", "JU", "UJ", "K", "Korea"];
+
+#[rustfmt::skip]
+const NTSC_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+#[rustfmt::skip]
+const PAL_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+#[rustfmt::skip]
+const NTSC_DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+#[rustfmt::skip]
+const PAL_DMC_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+// CPU cycles after the start of a frame counter sequence at which each step is clocked.
+// The 4-step sequence only uses the first four entries.
+const NTSC_FRAME_COUNTER_STEPS: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_FRAME_COUNTER_STEPS: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ntsc" => Ok(Region::NTSC),
+            "pal" => Ok(Region::PAL),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!("Unknown region: {}", s)),
+        }
+    }
+}
+
+/// Guesses the region from country tags in a ROM's file name, e.g. "Elite (E).nes" or
+/// "Elite (Europe).nes". Returns `None` when there are no tags or they disagree.
+pub fn from_filename(path: &str) -> Option<Region> {
+    let name = Path::new(path).file_stem()?.to_str()?;
+    let mut found = None;
+    for group in name.split('(').skip(1) {
+        let Some((tags, _)) = group.split_once(')') else {
+            continue;
+        };
+        for tag in tags.split(',').map(str::trim) {
+            let region = if PAL_TAGS.contains(&tag) {
+                Region::PAL
+            } else if NTSC_TAGS.contains(&tag) {
+                Region::NTSC
+            } else {
+                continue;
+            };
+            match found {
+                Some(other) if other != region => return None,
+                _ => found = Some(region),
+            }
+        }
+    }
+    found
+}
+
+/// Picks the region for a ROM: the header if it says, then the file name, then NTSC.
+pub fn detect(header: Option<Region>, path: &str) -> Region {
+    header.or_else(|| from_filename(path)).unwrap_or_default()
+}
+
+impl Region {
+    /// CPU clock in Hz: the master clock divided by 12 (NTSC), 16 (PAL) or 15 (Dendy).
+    pub fn cpu_clock_hz(&self) -> f64 {
+        match self {
+            Region::NTSC => 21_477_272.0 / 12.0,
+            Region::PAL => 26_601_712.0 / 16.0,
+            Region::Dendy => 26_601_712.0 / 15.0,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Region::NTSC => "NTSC",
+            Region::PAL => "PAL",
+            Region::Dendy => "Dendy",
+        }
+    }
+
+    /// Scanlines per frame, counting vblank and the pre-render line.
+    pub fn scanlines(&self) -> u16 {
+        match self {
+            Region::NTSC => 262,
+            Region::PAL | Region::Dendy => 312,
+        }
+    }
+
+    /// The scanline vblank starts on. The Dendy puts 50 of its extra lines before it.
+    pub fn vblank_line(&self) -> u16 {
+        match self {
+            Region::NTSC | Region::PAL => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /// PPU dots per CPU cycle as a fraction: 3, or 3.2 for PAL.
+    pub fn dots_per_cpu_cycle(&self) -> (u16, u16) {
+        match self {
+            Region::NTSC | Region::Dendy => (3, 1),
+            Region::PAL => (16, 5),
+        }
+    }
+
+    /// CPU cycles per frame: 341 dots on each of the frame's lines, at 3 or 3.2 (PAL) dots a
+    /// cycle. NTSC skips a dot every other frame while rendering, hence the half.
+    pub fn cpu_cycles_per_frame(&self) -> f64 {
+        match self {
+            Region::NTSC => 29_780.5,
+            Region::PAL => 33_247.5,
+            Region::Dendy => 35_464.0,
+        }
+    }
+
+    /// Frames a second, about 60.0988 for NTSC and 50.0070 for PAL and the Dendy.
+    pub fn frame_rate(&self) -> f64 {
+        self.cpu_clock_hz() / self.cpu_cycles_per_frame()
+    }
+
+    /// Noise channel timer periods in CPU cycles, indexed by the low nibble of $400E.
+    pub fn noise_periods(&self) -> &'static [u16; 16] {
+        match self {
+            Region::NTSC | Region::Dendy => &NTSC_NOISE_PERIODS,
+            Region::PAL => &PAL_NOISE_PERIODS,
+        }
+    }
+
+    /// DMC timer periods in CPU cycles, indexed by the low nibble of $4010.
+    pub fn dmc_rates(&self) -> &'static [u16; 16] {
+        match self {
+            Region::NTSC | Region::Dendy => &NTSC_DMC_RATES,
+            Region::PAL => &PAL_DMC_RATES,
+        }
+    }
+
+    pub fn frame_counter_steps(&self) -> &'static [u32; 5] {
+        match self {
+            Region::NTSC | Region::Dendy => &NTSC_FRAME_COUNTER_STEPS,
+            Region::PAL => &PAL_FRAME_COUNTER_STEPS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_region_from_filename() {
+        assert_eq!(from_filename("roms/Elite (E).nes"), Some(Region::PAL));
+        assert_eq!(
+            from_filename("Elite (Europe) (Rev 1).nes"),
+            Some(Region::PAL)
+        );
+        assert_eq!(from_filename("Contra (U) [!].nes"), Some(Region::NTSC));
+        assert_eq!(from_filename("Tetris (USA, Europe).nes"), None);
+        assert_eq!(from_filename("bins/pacman.nes"), None);
+    }
+
+    #[test]
+    fn test_header_region_wins() {
+        assert_eq!(detect(Some(Region::NTSC), "Elite (E).nes"), Region::NTSC);
+        assert_eq!(detect(None, "Elite (E).nes"), Region::PAL);
+        assert_eq!(detect(None, "pacman.nes"), Region::NTSC);
+    }
+
+    #[test]
+    fn test_frame_rates() {
+        assert!((Region::NTSC.frame_rate() - 60.0988).abs() < 0.0001);
+        assert!((Region::PAL.frame_rate() - 50.0070).abs() < 0.0001);
+        assert!((Region::Dendy.frame_rate() - 50.0070).abs() < 0.0001);
+    }
+}
@@ -0,0 +1,202 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::frame::Frame;
+
+/// Output pixels per NES pixel each way, enough for a dark line under every scanline and an
+/// RGB stripe per pixel
+pub const SCALE: usize = 3;
+
+/// Brightness of the dark third of each scanline
+const SCANLINE_LEVEL: f32 = 0.55;
+/// Brightness of the two channels each column of the aperture grille doesn't glow in
+const MASK_LEVEL: f32 = 0.7;
+/// How far the corners bend in, as a fraction of the picture
+const CURVATURE: f32 = 0.06;
+
+/// How `CrtFilter` draws the picture, picked with `--crt` or `video.crt` in config.toml.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CrtPreset {
+    /// The picture as the PPU drew it
+    #[default]
+    Off,
+    /// Dark gaps between the lines
+    Scanlines,
+    /// Scanlines and the red, green and blue stripes of an aperture grille
+    Grille,
+    /// Scanlines and grille on a screen that bulges out like a tube's
+    Curved,
+}
+
+impl CrtPreset {
+    pub const ALL: [CrtPreset; 4] = [
+        CrtPreset::Off,
+        CrtPreset::Scanlines,
+        CrtPreset::Grille,
+        CrtPreset::Curved,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CrtPreset::Off => "off",
+            CrtPreset::Scanlines => "scanlines",
+            CrtPreset::Grille => "grille",
+            CrtPreset::Curved => "curved",
+        }
+    }
+
+    pub fn next(self) -> CrtPreset {
+        let index = CrtPreset::ALL.iter().position(|&p| p == self).unwrap_or(0);
+        CrtPreset::ALL[(index + 1) % CrtPreset::ALL.len()]
+    }
+}
+
+impl FromStr for CrtPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        CrtPreset::ALL
+            .into_iter()
+            .find(|preset| preset.name() == name)
+            .ok_or_else(|| format!("Unknown CRT preset: {}", s))
+    }
+}
+
+/// Where an output pixel takes its color from and how much of each channel it keeps, out of
+/// 256. Pixels off the edge of a curved screen keep nothing.
+#[derive(Clone, Copy)]
+struct Sample {
+    x: u16,
+    y: u16,
+    levels: [u16; 3],
+}
+
+/// Draws frames `SCALE` times bigger the way a CRT would show them. Everything the preset
+/// does is worked out once per output pixel when it's picked, so each frame is a lookup and a
+/// multiply per pixel.
+pub struct CrtFilter {
+    preset: CrtPreset,
+    samples: Vec<Sample>,
+    output: Frame,
+}
+
+impl CrtFilter {
+    pub fn new(preset: CrtPreset) -> Self {
+        let mut crt = CrtFilter {
+            preset: CrtPreset::Off,
+            samples: Vec::new(),
+            output: Frame::with_size(Frame::WIDTH * SCALE, Frame::HEIGHT * SCALE),
+        };
+        crt.set_preset(preset);
+        crt
+    }
+
+    pub fn preset(&self) -> CrtPreset {
+        self.preset
+    }
+
+    pub fn set_preset(&mut self, preset: CrtPreset) {
+        if preset == self.preset && !self.samples.is_empty() {
+            return;
+        }
+        self.preset = preset;
+        let (width, height) = (self.output.width, self.output.height);
+        self.samples = (0..height)
+            .flat_map(|y| (0..width).map(move |x| sample(preset, x, y, width, height)))
+            .collect();
+    }
+
+    /// The NES-sized `frame` drawn with the preset.
+    pub fn apply(&mut self, frame: &Frame) -> &Frame {
+        let width = self.output.width;
+        for (i, sample) in self.samples.iter().enumerate() {
+            let (r, g, b) = frame.pixel(sample.x as usize, sample.y as usize);
+            let level = |value: u8, level: u16| ((value as u16 * level) >> 8) as u8;
+            let rgb = (
+                level(r, sample.levels[0]),
+                level(g, sample.levels[1]),
+                level(b, sample.levels[2]),
+            );
+            self.output.set_pixel(i % width, i / width, rgb);
+        }
+        &self.output
+    }
+}
+
+fn sample(preset: CrtPreset, x: usize, y: usize, width: usize, height: usize) -> Sample {
+    // -1 to 1 across the screen, from the middle of the pixel
+    let mut u = (x as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+    let mut v = (y as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+    if preset == CrtPreset::Curved {
+        (u, v) = (u * (1.0 + CURVATURE * v * v), v * (1.0 + CURVATURE * u * u));
+    }
+    if u.abs() >= 1.0 || v.abs() >= 1.0 {
+        return Sample {
+            x: 0,
+            y: 0,
+            levels: [0; 3],
+        };
+    }
+    let source_x = (u + 1.0) / 2.0 * Frame::WIDTH as f32;
+    let source_y = (v + 1.0) / 2.0 * Frame::HEIGHT as f32;
+
+    let mut levels = [1.0; 3];
+    if preset != CrtPreset::Off && source_y.fract() >= 2.0 / 3.0 {
+        levels = [SCANLINE_LEVEL; 3];
+    }
+    if matches!(preset, CrtPreset::Grille | CrtPreset::Curved) {
+        for (channel, level) in levels.iter_mut().enumerate() {
+            if channel != x % 3 {
+                *level *= MASK_LEVEL;
+            }
+        }
+    }
+    Sample {
+        x: source_x as u16,
+        y: source_y as u16,
+        levels: levels.map(|level| (level * 256.0) as u16),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        let mut frame = Frame::new();
+        frame.fill_rect(0, 0, Frame::WIDTH, Frame::HEIGHT, (200, 200, 200));
+        frame.set_pixel(1, 1, (100, 0, 0));
+
+        let mut crt = CrtFilter::new(CrtPreset::Off);
+        let output = crt.apply(&frame);
+        assert_eq!(output.pixel(3, 3), (100, 0, 0));
+        assert_eq!(output.pixel(5, 5), (100, 0, 0));
+
+        // The last of each pixel's three rows is darker
+        crt.set_preset(CrtPreset::Scanlines);
+        let output = crt.apply(&frame);
+        assert_eq!(output.pixel(0, 0), (200, 200, 200));
+        assert_eq!(output.pixel(0, 1), (200, 200, 200));
+        assert_eq!(output.pixel(0, 2), (109, 109, 109));
+
+        crt.set_preset(CrtPreset::Grille);
+        let output = crt.apply(&frame);
+        assert_eq!(output.pixel(0, 0), (200, 139, 139));
+        assert_eq!(output.pixel(1, 0), (139, 200, 139));
+        assert_eq!(output.pixel(2, 0), (139, 139, 200));
+
+        // The corners bend in past the edge of the picture, the middle stays put
+        crt.set_preset(CrtPreset::Curved);
+        let output = crt.apply(&frame);
+        assert_eq!(output.pixel(0, 0), (0, 0, 0));
+        let middle = output.pixel(Frame::WIDTH * SCALE / 2, Frame::HEIGHT * SCALE / 2);
+        assert_eq!(middle, (200, 139, 139));
+
+        assert_eq!(CrtPreset::Curved.next(), CrtPreset::Off);
+        assert_eq!("Grille".parse(), Ok(CrtPreset::Grille));
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use crate::error::EmulationError;
+
+use super::{
+    font::{self, CELL_HEIGHT},
+    frame::Frame,
+};
+
+const BACKGROUND: (u8, u8, u8) = (0x10, 0x18, 0x48);
+const TITLE: (u8, u8, u8) = (0xFF, 0x60, 0x60);
+const TEXT: (u8, u8, u8) = (0xFF, 0xFF, 0xFF);
+const HINT: (u8, u8, u8) = (0xA0, 0xA8, 0xD0);
+const MARGIN: usize = 8;
+/// How much bigger than a thumbnail the state browser shows it
+const PREVIEW_ZOOM: usize = 2;
+
+/// Full-screen description of `error`, with `hints` (e.g. which key resets) at the bottom.
+pub fn error_screen(error: &EmulationError, hints: &[&str]) -> Frame {
+    let title = match error {
+        EmulationError::InvalidRom { .. } => "CAN'T LOAD ROM",
+        EmulationError::UnsupportedMapper(_) => "UNSUPPORTED CARTRIDGE",
+        EmulationError::UnsupportedConsole(_) => "UNSUPPORTED CONSOLE",
+        EmulationError::Crash { .. } => "EMULATION STOPPED",
+    };
+    message_screen(title, &error.to_string(), hints)
+}
+
+/// What's in a save state slot, for `state_browser`.
+pub enum SlotPreview {
+    Empty,
+    /// A state, with its thumbnail if it has one
+    Saved(Option<Frame>),
+}
+
+/// Full-screen list of save state slots with `selected` highlighted and its thumbnail shown
+/// next to the list.
+pub fn state_browser(slots: &[SlotPreview], selected: usize) -> Frame {
+    let mut frame = message_screen(
+        "LOAD STATE",
+        "",
+        &["Up/Down: pick   Enter: load", "Esc: back to the game"],
+    );
+
+    let top = MARGIN + 2 * CELL_HEIGHT;
+    for (slot, preview) in slots.iter().enumerate() {
+        let line = format!(
+            "{} {} {}",
+            if slot == selected { ">" } else { " " },
+            slot,
+            match preview {
+                SlotPreview::Empty => "-",
+                SlotPreview::Saved(_) => "SAVED",
+            }
+        );
+        let rgb = if slot == selected { TITLE } else { TEXT };
+        font::draw_text(&mut frame, MARGIN, top + slot * CELL_HEIGHT, &line, rgb);
+    }
+
+    let Some(SlotPreview::Saved(thumbnail)) = slots.get(selected) else {
+        return frame;
+    };
+    let Some(thumbnail) = thumbnail else {
+        font::draw_text(&mut frame, Frame::WIDTH / 2, top, "NO PREVIEW", HINT);
+        return frame;
+    };
+    let left = frame.width - MARGIN - thumbnail.width * PREVIEW_ZOOM;
+    for y in 0..thumbnail.height {
+        for x in 0..thumbnail.width {
+            frame.fill_rect(
+                left + x * PREVIEW_ZOOM,
+                top + y * PREVIEW_ZOOM,
+                PREVIEW_ZOOM,
+                PREVIEW_ZOOM,
+                thumbnail.pixel(x, y),
+            );
+        }
+    }
+    frame
+}
+
+/// Full-screen list of recently played ROMs, by file name, with `selected` highlighted.
+pub fn rom_picker(roms: &[String], selected: usize) -> Frame {
+    let mut frame = message_screen(
+        "OPEN A ROM",
+        if roms.is_empty() {
+            "No ROMs played yet. Drop a .nes file on the window, or pass one on the command line."
+        } else {
+            ""
+        },
+        &[
+            "Up/Down: pick   Enter: play   Esc: quit",
+            "Drop a ROM on the window to open it",
+        ],
+    );
+
+    let top = MARGIN + 2 * CELL_HEIGHT;
+    let columns = font::columns(frame.width - 2 * MARGIN);
+    for (n, rom) in roms.iter().enumerate() {
+        let name = Path::new(rom)
+            .file_stem()
+            .map_or_else(|| rom.into(), |stem| stem.to_string_lossy());
+        let line: String = format!("{} {}", if n == selected { ">" } else { " " }, name)
+            .chars()
+            .take(columns)
+            .collect();
+        let rgb = if n == selected { TITLE } else { TEXT };
+        font::draw_text(&mut frame, MARGIN, top + n * CELL_HEIGHT, &line, rgb);
+    }
+    frame
+}
+
+/// Full-screen message with a title, wrapped text and `hints` at the bottom, for questions
+/// and errors shown instead of the game.
+pub fn message_screen(title: &str, text: &str, hints: &[&str]) -> Frame {
+    let mut frame = Frame::new();
+    for pixel in frame.data.chunks_exact_mut(3) {
+        pixel.copy_from_slice(&[BACKGROUND.0, BACKGROUND.1, BACKGROUND.2]);
+    }
+
+    font::draw_text(&mut frame, MARGIN, MARGIN, title, TITLE);
+
+    let columns = font::columns(frame.width - 2 * MARGIN);
+    let mut y = MARGIN + 2 * CELL_HEIGHT;
+    for line in font::wrap(text, columns) {
+        font::draw_text(&mut frame, MARGIN, y, &line, TEXT);
+        y += CELL_HEIGHT;
+    }
+
+    let mut y = frame.height - MARGIN - hints.len() * CELL_HEIGHT;
+    for hint in hints {
+        font::draw_text(&mut frame, MARGIN, y, hint, HINT);
+        y += CELL_HEIGHT;
+    }
+    frame
+}
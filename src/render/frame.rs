@@ -1,24 +1,168 @@
+use super::palette::{Colors, SYSTEM_PALLETE};
+
+/// How a frame stores its pixels. Whoever consumes the frame picks the one it can use as is,
+/// e.g. RGB24 for an SDL texture or indices for a frontend that applies its own palette.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PixelFormat {
+    #[default]
+    Rgb24,
+    /// RGB and an alpha byte that is always 255
+    Rgba32,
+    /// One byte per pixel holding the NES color index (0-63)
+    Indexed,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Rgba32 => 4,
+            PixelFormat::Indexed => 1,
+        }
+    }
+}
 
 pub struct Frame {
     pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
 }
 
 impl Frame {
-    const WIDTH: usize = 256;
-    const HEIGHT: usize = 240;
+    pub const WIDTH: usize = 256;
+    pub const HEIGHT: usize = 240;
 
     pub fn new() -> Self {
+        Frame::with_size(Frame::WIDTH, Frame::HEIGHT)
+    }
+
+    /// A frame of a different size than the NES screen, e.g. for debug views.
+    pub fn with_size(width: usize, height: usize) -> Self {
+        Frame::with_format(width, height, PixelFormat::Rgb24)
+    }
+
+    pub fn with_format(width: usize, height: usize, format: PixelFormat) -> Self {
         Self {
-            data: vec![0; Frame::WIDTH * Frame::HEIGHT * 3],
+            data: vec![0; width * height * format.bytes_per_pixel()],
+            width,
+            height,
+            format,
         }
     }
 
+    /// Bytes per row of pixels.
+    pub fn pitch(&self) -> usize {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    /// Sets a pixel to NES color `color`, which the RGB formats show as `colors[color]`. This
+    /// is how the emulated picture is drawn, so indexed frames keep the exact colors.
+    pub fn set_color(&mut self, x: usize, y: usize, color: u8, colors: &Colors) {
+        let color = color & 0x3F;
+        match self.format {
+            PixelFormat::Indexed => self.write(x, y, &[color]),
+            _ => self.set_pixel(x, y, colors[color as usize]),
+        }
+    }
+
+    /// Sets a pixel to any color, for text and debug overlays. Indexed frames get the closest
+    /// NES color.
     pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
-        let base = (y * Frame::WIDTH + x) * 3;
-        if base + 2 < self.data.len() {
-            self.data[base] = rgb.0;
-            self.data[base + 1] = rgb.1;
-            self.data[base + 2] = rgb.2;
+        match self.format {
+            PixelFormat::Rgb24 => self.write(x, y, &[rgb.0, rgb.1, rgb.2]),
+            PixelFormat::Rgba32 => self.write(x, y, &[rgb.0, rgb.1, rgb.2, 255]),
+            PixelFormat::Indexed => self.write(x, y, &[closest_color(rgb)]),
         }
     }
+
+    fn write(&mut self, x: usize, y: usize, pixel: &[u8]) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let base = (y * self.width + x) * pixel.len();
+        self.data[base..base + pixel.len()].copy_from_slice(pixel);
+    }
+
+    /// The color of a pixel, with indexed frames shown in the standard palette.
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * self.width + x) * self.format.bytes_per_pixel();
+        match self.format {
+            PixelFormat::Indexed => SYSTEM_PALLETE[self.data[base] as usize & 0x3F],
+            _ => (self.data[base], self.data[base + 1], self.data[base + 2]),
+        }
+    }
+
+    /// Fills the whole frame with NES color `color`.
+    pub fn fill_color(&mut self, color: u8, colors: &Colors) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                self.set_color(x, y, color, colors);
+            }
+        }
+    }
+
+    /// Fills a rectangle, clipped to the frame.
+    pub fn fill_rect(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        rgb: (u8, u8, u8),
+    ) {
+        for row in y..(y + height).min(self.height) {
+            for column in x..(x + width).min(self.width) {
+                self.set_pixel(column, row, rgb);
+            }
+        }
+    }
+
+    /// An RGB copy `factor` times smaller, each pixel the average of the block it covers. For
+    /// thumbnails.
+    pub fn downscale(&self, factor: usize) -> Frame {
+        let mut small = Frame::with_size(self.width / factor, self.height / factor);
+        let area = (factor * factor) as u32;
+        for y in 0..small.height {
+            for x in 0..small.width {
+                let mut sum = (0, 0, 0);
+                for (dx, dy) in (0..factor).flat_map(|dy| (0..factor).map(move |dx| (dx, dy))) {
+                    let (r, g, b) = self.pixel(x * factor + dx, y * factor + dy);
+                    sum = (sum.0 + r as u32, sum.1 + g as u32, sum.2 + b as u32);
+                }
+                let average = |sum: u32| (sum / area) as u8;
+                small.set_pixel(x, y, (average(sum.0), average(sum.1), average(sum.2)));
+            }
+        }
+        small
+    }
+
+    /// Copies the frame into a locked texture buffer whose rows are `pitch` bytes apart.
+    pub fn copy_to(&self, buffer: &mut [u8], pitch: usize) {
+        let row_len = self.pitch();
+        if pitch == row_len {
+            buffer[..self.data.len()].copy_from_slice(&self.data);
+            return;
+        }
+        for (row, pixels) in self.data.chunks_exact(row_len).enumerate() {
+            buffer[row * pitch..row * pitch + row_len].copy_from_slice(pixels);
+        }
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The NES color nearest to `rgb` in the standard palette.
+fn closest_color(rgb: (u8, u8, u8)) -> u8 {
+    let distance = |(r, g, b): (u8, u8, u8)| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(r, rgb.0) + d(g, rgb.1) + d(b, rgb.2)
+    };
+    (0..64)
+        .min_by_key(|&color| distance(SYSTEM_PALLETE[color as usize]))
+        .unwrap_or(0)
 }
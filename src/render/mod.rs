@@ -1,10 +1,15 @@
 use crate::{cartridge::Mirroring, ppu::NesPPU};
 
-use frame::Frame;
+use frame::{Frame, PixelFormat};
 
-use self::palette::SYSTEM_PALLETE;
+use self::palette::{Colors, PaletteName, SYSTEM_PALLETE};
 
+pub mod crt;
+pub mod error_screen;
+pub mod font;
 pub mod frame;
+pub mod overlay;
+pub mod osd;
 pub mod palette;
 
 fn bg_pallette(ppu: &NesPPU, attr_table: &[u8], tile_column: usize, tile_row: usize) -> [u8; 4] {
@@ -51,40 +56,66 @@ impl Rect {
     }
 }
 
+/// The background as drawn, and which of its pixels are opaque (not color 0). Opacity decides
+/// whether sprites behind the background show and where sprite 0 hits.
+struct BackgroundLayer {
+    frame: Frame,
+    opaque: Vec<bool>,
+}
+
+impl BackgroundLayer {
+    fn new(width: usize, height: usize, format: PixelFormat) -> Self {
+        BackgroundLayer {
+            frame: Frame::with_format(width, height, format),
+            opaque: vec![false; width * height],
+        }
+    }
+
+    fn set_color(&mut self, x: usize, y: usize, color: u8, colors: &Colors, opaque: bool) {
+        if x >= self.frame.width || y >= self.frame.height {
+            return;
+        }
+        self.frame.set_color(x, y, color, colors);
+        self.opaque[y * self.frame.width + x] = opaque;
+    }
+}
+
 fn render_name_table(
     ppu: &NesPPU,
-    frame: &mut Frame,
-    name_table: &[u8],
+    colors: &Colors,
+    layer: &mut BackgroundLayer,
+    name_table_start: usize,
     view_port: Rect,
-    shift_x: isize,
-    shift_y: isize,
+    (shift_x, shift_y): (isize, isize),
+    dirty_since: Option<u64>,
 ) {
     let bank = ppu.ctrl.bknd_pattern_addr();
 
+    let name_table = &ppu.vram[name_table_start..name_table_start + 0x400];
+    let stamps = &ppu.vram_stamps[name_table_start..name_table_start + 0x400];
     let attr_table = &name_table[0x03c0..0x0400];
 
     for i in 0..0x03c0 {
         let tile_x = i % 32;
         let tile_y = i / 32;
+        if let Some(since) = dirty_since {
+            let attr_stamp = stamps[0x03c0 + tile_y / 4 * 8 + tile_x / 4];
+            if stamps[i] <= since && attr_stamp <= since {
+                continue;
+            }
+        }
         let tile_idx = name_table[i] as u16;
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = ppu.tile_cache.tile(bank + tile_idx * 16);
         let palette = bg_pallette(ppu, attr_table, tile_x, tile_y);
 
         for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-
-            for x in (0..=7).rev() {
-                let color = (1 & lower) << 1 | (1 & upper);
-                upper >>= 1;
-                lower >>= 1;
-
-                let rgb = match color {
-                    0b00 => SYSTEM_PALLETE[ppu.palette_table[0] as usize],
-                    0b01 => SYSTEM_PALLETE[palette[1] as usize],
-                    0b10 => SYSTEM_PALLETE[palette[2] as usize],
-                    0b11 => SYSTEM_PALLETE[palette[3] as usize],
+            for x in 0..=7 {
+                let color = tile[y * 8 + x];
+                let nes_color = match color {
+                    0b00 => ppu.palette_table[0],
+                    0b01 => palette[1],
+                    0b10 => palette[2],
+                    0b11 => palette[3],
                     _ => unreachable!(),
                 };
                 let pixel_x = tile_x * 8 + x;
@@ -94,10 +125,12 @@ fn render_name_table(
                     && pixel_y >= view_port.y1
                     && pixel_y < view_port.y2
                 {
-                    frame.set_pixel(
+                    layer.set_color(
                         (shift_x + pixel_x as isize) as usize,
                         (shift_y + pixel_y as isize) as usize,
-                        rgb,
+                        nes_color,
+                        colors,
+                        color != 0,
                     );
                 }
             }
@@ -105,85 +138,710 @@ fn render_name_table(
     }
 }
 
-pub fn render(ppu: &NesPPU, frame: &mut Frame) {
+/// Draws the background. With `dirty_since` set, only tiles whose nametable or attribute
+/// bytes changed after that PPU write generation are drawn.
+///
+/// The four logical nametables form a 512x480 plane with the one PPUCTRL selects at the top
+/// left and wrapping around at the edges. The screen is a 256x240 window into it starting at
+/// the scroll position, so it overlaps up to four nametables: the selected one, the one to
+/// its right, the one below and the one diagonally across. Mirroring decides which VRAM each
+/// of them shows.
+fn render_background(
+    ppu: &NesPPU,
+    colors: &Colors,
+    layer: &mut BackgroundLayer,
+    dirty_since: Option<u64>,
+) {
     let scroll_x = ppu.scroll.scroll_x as usize;
-    let scroll_y = ppu.scroll.scroll_y as usize;
-
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.ctrl.nametable_addr()) {
-        (Mirroring::VERTICAL, 0x2000)
-        | (Mirroring::VERTICAL, 0x2800)
-        | (Mirroring::HORIZONTAL, 0x2000)
-        | (Mirroring::HORIZONTAL, 0x2400) => (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800]),
-        (Mirroring::VERTICAL, 0x2400)
-        | (Mirroring::VERTICAL, 0x2c00)
-        | (Mirroring::HORIZONTAL, 0x2800)
-        | (Mirroring::HORIZONTAL, 0x2c00) => (&ppu.vram[0x400..0x800], &ppu.vram[0..0x400]),
-        _ => unreachable!(),
-    };
+    // Y scrolls of 240 and up read attribute bytes as tiles; leave that glitch out
+    let scroll_y = (ppu.scroll.scroll_y as usize).min(Frame::HEIGHT - 1);
+    let main_nametable = (ppu.ctrl.nametable_addr() - 0x2000) / 0x400;
 
-    render_name_table(
-        ppu,
-        frame,
-        main_nametable,
-        Rect::new(scroll_x, scroll_y, 256, 240),
-        -(scroll_x as isize),
-        -(scroll_y as isize),
-    );
-    if scroll_x > 0 {
+    for (right, below) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+        let (x1, x2) = if right == 0 {
+            (scroll_x, Frame::WIDTH)
+        } else {
+            (0, scroll_x)
+        };
+        let (y1, y2) = if below == 0 {
+            (scroll_y, Frame::HEIGHT)
+        } else {
+            (0, scroll_y)
+        };
+        if x1 == x2 || y1 == y2 {
+            continue;
+        }
+        let table = main_nametable ^ right ^ (below << 1);
         render_name_table(
             ppu,
-            frame,
-            second_nametable,
-            Rect::new(0, 0, scroll_x, 240),
-            256 - (scroll_x as isize),
-            0,
+            colors,
+            layer,
+            ppu.name_table_offset(table),
+            Rect::new(x1, y1, x2, y2),
+            (
+                (right as usize * Frame::WIDTH) as isize - scroll_x as isize,
+                (below as usize * Frame::HEIGHT) as isize - scroll_y as isize,
+            ),
+            dirty_since,
         );
-    } else if scroll_y > 0 {
+    }
+}
+
+pub fn render(ppu: &NesPPU, frame: &mut Frame) {
+    let mut background = BackgroundLayer::new(frame.width, frame.height, frame.format);
+    render_background(ppu, &SYSTEM_PALLETE, &mut background, None);
+    frame.data.copy_from_slice(&background.frame.data);
+    render_sprites(ppu, &SYSTEM_PALLETE, frame, Some(&background.opaque), true);
+}
+
+/// Draws all four logical nametables side by side ($2000 top left, $2C00 bottom right) into a
+/// 512x480 frame, with mirrored tables repeated. Handy for mapping levels and checking
+/// mirroring.
+pub fn nametable_map(ppu: &NesPPU, colors: &Colors) -> Frame {
+    let mut map = BackgroundLayer::new(2 * Frame::WIDTH, 2 * Frame::HEIGHT, PixelFormat::Rgb24);
+    for table in 0..4 {
+        let start = ppu.name_table_offset(table);
         render_name_table(
             ppu,
-            frame,
-            second_nametable,
-            Rect::new(0, 0, 256, scroll_y),
-            0,
-            240 - (scroll_y as isize),
+            colors,
+            &mut map,
+            start,
+            Rect::new(0, 0, Frame::WIDTH, Frame::HEIGHT),
+            (
+                (table as usize % 2 * Frame::WIDTH) as isize,
+                (table as usize / 2 * Frame::HEIGHT) as isize,
+            ),
+            None,
         );
     }
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
-        let tile_idx = ppu.oam_data[i + 1] as u16;
-        let tile_x = ppu.oam_data[i + 3] as usize;
-        let tile_y = ppu.oam_data[i] as usize;
+    map.frame
+}
+
+/// Draws both pattern tables side by side ($0000 left, $1000 right) into a 256x128 frame, in
+/// the first background palette.
+pub fn pattern_tables(ppu: &NesPPU, colors: &Colors) -> Frame {
+    let mut frame = Frame::with_size(2 * 128, 128);
+    let palette = &ppu.palette_table[0..4];
+    for tile_n in 0..512 {
+        let tile = ppu.tile_cache.tile(tile_n as u16 * 16);
+        let tile_x = tile_n / 256 * 128 + tile_n % 16 * 8;
+        let tile_y = tile_n % 256 / 16 * 8;
+        for (pixel, &value) in tile.iter().enumerate() {
+            let color = palette[value as usize];
+            frame.set_color(tile_x + pixel % 8, tile_y + pixel / 8, color, colors);
+        }
+    }
+    frame
+}
+
+/// Pixels per side of a sprite's cell in `oam_sheet`, room for an 8x16 sprite and a border.
+pub const OAM_CELL: usize = 20;
+const OAM_CELL_BACKGROUND: (u8, u8, u8) = (0x40, 0x40, 0x40);
+
+/// Draws the 64 sprites in OAM unflipped on a grey 8x8 grid, sprite 0 top left, whether or
+/// not they are on screen. 8x16 sprites are drawn whole.
+pub fn oam_sheet(ppu: &NesPPU, colors: &Colors) -> Frame {
+    let mut frame = Frame::with_size(8 * OAM_CELL, 8 * OAM_CELL);
+    frame.fill_rect(0, 0, frame.width, frame.height, OAM_CELL_BACKGROUND);
+    let tall = ppu.ctrl.sprite_size() == 16;
+    for (sprite, oam) in ppu.oam_data.chunks_exact(4).enumerate() {
+        let tile_idx = oam[1] as u16;
+        // 8x16 sprites pick their bank with bit 0 and are an even tile and the one after it
+        let (bank, first_tile, tiles) = if tall {
+            ((tile_idx & 1) * 0x1000, tile_idx & 0xFE, 2)
+        } else {
+            (ppu.ctrl.sprite_pattern_addr(), tile_idx, 1)
+        };
+        let palette = sprite_pallette(ppu, oam[2] & 0b11);
+        let cell_x = sprite % 8 * OAM_CELL + (OAM_CELL - 8) / 2;
+        let cell_y = sprite / 8 * OAM_CELL + (OAM_CELL - 16) / 2;
+
+        for half in 0..tiles {
+            let tile = ppu.tile_cache.tile(bank + (first_tile + half) * 16);
+            for (pixel, &value) in tile.iter().enumerate() {
+                if value == 0 {
+                    continue;
+                }
+                let y = cell_y + half as usize * 8 + pixel / 8;
+                frame.set_color(cell_x + pixel % 8, y, palette[value as usize], colors);
+            }
+        }
+    }
+    frame
+}
+
+/// Everything that decides where background tiles end up on screen. If any of it changes
+/// between frames the whole background has to be redrawn.
+#[derive(PartialEq, Eq, Clone, Copy)]
+struct BackgroundView {
+    scroll_x: u8,
+    scroll_y: u8,
+    nametable: u16,
+    bank: u16,
+    mirroring: Mirroring,
+}
+
+/// Renderer that keeps the previous frame's background and only redraws the nametable tiles
+/// that changed since then, as long as scrolling, pattern bank, palettes and CHR stay the same.
+pub struct Renderer {
+    /// Draw at most 8 sprites per scanline like the real PPU. Turning this off gets rid of
+    /// sprite flicker at the cost of showing sprites games meant to hide.
+    pub sprite_limit: bool,
+    /// Leave out the background or sprites whatever PPUMASK says, to see what each layer
+    /// draws. A hidden background shows as the backdrop color.
+    pub hide_background: bool,
+    pub hide_sprites: bool,
+    palette: PaletteName,
+    colors: Colors,
+    /// The colors from a .pal file, for `PaletteName::File`
+    file_colors: Option<Colors>,
+    /// The PPU's shuffled color numbers, see `palette::rp2c04_order`
+    color_order: Option<&'static [u8; 64]>,
+    background: BackgroundLayer,
+    last_view: Option<BackgroundView>,
+    rendered_at: u64,
+    sprite_zero_hit: Option<(usize, usize)>,
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Renderer {
+            sprite_limit: true,
+            hide_background: false,
+            hide_sprites: false,
+            palette: PaletteName::Default,
+            colors: SYSTEM_PALLETE,
+            file_colors: None,
+            color_order: None,
+            background: BackgroundLayer::new(Frame::WIDTH, Frame::HEIGHT, PixelFormat::Rgb24),
+            last_view: None,
+            rendered_at: 0,
+            sprite_zero_hit: None,
+        }
+    }
+
+    /// The RGB colors frames are drawn with.
+    pub fn colors(&self) -> &Colors {
+        &self.colors
+    }
+
+    /// The first pixel (x, y) of the last frame where sprite 0 covered opaque background, as
+    /// drawn rather than as the PPU timed it.
+    pub fn sprite_zero_hit(&self) -> Option<(usize, usize)> {
+        self.sprite_zero_hit
+    }
+
+    pub fn palette(&self) -> PaletteName {
+        self.palette
+    }
+
+    pub fn set_palette(&mut self, palette: PaletteName) {
+        if palette != self.palette {
+            self.palette = palette;
+            self.update_colors();
+        }
+    }
+
+    /// Sets the colors `PaletteName::File` draws with, or goes back to the default ones.
+    pub fn set_file_palette(&mut self, colors: Option<Colors>) {
+        self.file_colors = colors;
+        if self.palette == PaletteName::File {
+            self.update_colors();
+        }
+    }
+
+    /// Shows color n as standard color `order[n]` in whichever palette is picked, for PPUs
+    /// that shuffle the color numbers. `None` goes back to the standard order.
+    pub fn set_color_order(&mut self, order: Option<&'static [u8; 64]>) {
+        if order != self.color_order {
+            self.color_order = order;
+            self.update_colors();
+        }
+    }
+
+    /// The palette after the current one, skipping `File` without a file loaded.
+    pub fn next_palette(&self) -> PaletteName {
+        match self.palette.next() {
+            PaletteName::File if self.file_colors.is_none() => PaletteName::File.next(),
+            palette => palette,
+        }
+    }
+
+    /// Forgets the cached background, for a new PPU whose write counts start over.
+    pub fn invalidate(&mut self) {
+        self.last_view = None;
+        self.rendered_at = 0;
+    }
+
+    fn update_colors(&mut self) {
+        self.colors = match (self.palette, self.file_colors) {
+            (PaletteName::File, Some(colors)) => colors,
+            (palette, _) => palette.colors(),
+        };
+        if let Some(order) = self.color_order {
+            let colors = self.colors;
+            self.colors = order.map(|n| colors[n as usize]);
+        }
+        // The cached background was drawn with the old colors
+        self.last_view = None;
+    }
+
+    /// Draws the picture into `frame`, in whichever pixel format it has.
+    pub fn render(&mut self, ppu: &NesPPU, frame: &mut Frame) {
+        if self.background.frame.format != frame.format {
+            self.background = BackgroundLayer::new(Frame::WIDTH, Frame::HEIGHT, frame.format);
+            self.last_view = None;
+        }
+        let view = BackgroundView {
+            scroll_x: ppu.scroll.scroll_x,
+            scroll_y: ppu.scroll.scroll_y,
+            nametable: ppu.ctrl.nametable_addr(),
+            bank: ppu.ctrl.bknd_pattern_addr(),
+            mirroring: ppu.mirroring,
+        };
+        // A PPU behind the last frame isn't the one that drew it
+        if ppu.write_generation < self.rendered_at {
+            self.invalidate();
+        }
+        let unchanged = self.last_view == Some(view)
+            && ppu.palette_stamp <= self.rendered_at
+            && ppu.chr_stamp <= self.rendered_at;
+        let dirty_since = if unchanged {
+            Some(self.rendered_at)
+        } else {
+            None
+        };
+
+        render_background(ppu, &self.colors, &mut self.background, dirty_since);
+        self.last_view = Some(view);
+        self.rendered_at = ppu.write_generation;
+
+        // A hidden background is all backdrop, so nothing is behind it
+        let opaque = if self.hide_background {
+            frame.fill_color(ppu.palette_table[0], &self.colors);
+            None
+        } else {
+            frame.data.copy_from_slice(&self.background.frame.data);
+            Some(self.background.opaque.as_slice())
+        };
+        self.sprite_zero_hit = if self.hide_sprites {
+            None
+        } else {
+            render_sprites(ppu, &self.colors, frame, opaque, self.sprite_limit)
+        };
+    }
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The PPU only fetches the first 8 sprites (in OAM order) it finds on each scanline
+const SPRITES_PER_SCANLINE: usize = 8;
 
-        let flip_v = ppu.oam_data[i + 2] >> 7 & 1 == 1;
-        let flip_h = ppu.oam_data[i + 2] >> 6 & 1 == 1;
+/// For every sprite, a bitmask of which of its 8 rows the PPU would actually draw given the
+/// per-scanline limit. Games rely on this for flicker effects and to hide sprites.
+fn visible_sprite_rows(ppu: &NesPPU) -> [u8; 64] {
+    let mut per_scanline = [0; 256];
+    let mut visible = [0; 64];
+    for (sprite, rows) in visible.iter_mut().enumerate() {
+        let tile_y = ppu.oam_data[sprite * 4] as usize;
+        for row in 0..8 {
+            let Some(count) = per_scanline.get_mut(tile_y + row) else {
+                break;
+            };
+            if *count < SPRITES_PER_SCANLINE {
+                *count += 1;
+                *rows |= 1 << row;
+            }
+        }
+    }
+    visible
+}
 
-        let palette_idx = ppu.oam_data[i + 2] & 0b11;
+/// Draws the sprites over the background already in `frame`, whose opaque pixels are
+/// `background`. Like the PPU, the first sprite in OAM order with an opaque pixel at a spot
+/// owns that spot even when it is behind the background there, which hides later sprites
+/// under it too; games use that to mask sprites. Returns the first pixel where sprite 0
+/// covered opaque background.
+fn render_sprites(
+    ppu: &NesPPU,
+    colors: &Colors,
+    frame: &mut Frame,
+    background: Option<&[bool]>,
+    sprite_limit: bool,
+) -> Option<(usize, usize)> {
+    let visible = if sprite_limit {
+        visible_sprite_rows(ppu)
+    } else {
+        [0xFF; 64]
+    };
+    let mut taken = vec![false; frame.width * frame.height];
+    let mut sprite_zero_hit: Option<(usize, usize)> = None;
+
+    for (sprite, oam) in ppu.oam_data.chunks_exact(4).enumerate() {
+        let tile_idx = oam[1] as u16;
+        let tile_x = oam[3] as usize;
+        let tile_y = oam[0] as usize;
+
+        let flip_v = oam[2] >> 7 & 1 == 1;
+        let flip_h = oam[2] >> 6 & 1 == 1;
+        let behind_background = oam[2] >> 5 & 1 == 1;
+
+        let palette_idx = oam[2] & 0b11;
         let sprite_pallete = sprite_pallette(ppu, palette_idx);
         let bank = ppu.ctrl.sprite_pattern_addr();
 
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = ppu.tile_cache.tile(bank + tile_idx * 16);
+        let rows = visible[sprite];
 
         for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-            'inner: for x in (0..=7).rev() {
-                let value = ((lower & 1) << 1) | (upper & 1);
-                upper >>= 1;
-                lower >>= 1;
-                let rgb = match value {
+            let screen_row = if flip_v { 7 - y } else { y };
+            if rows & (1 << screen_row) == 0 {
+                continue;
+            }
+            'inner: for x in 0..=7 {
+                let value = tile[y * 8 + x];
+                let nes_color = match value {
                     0 => continue 'inner,
-                    1 => SYSTEM_PALLETE[sprite_pallete[1] as usize],
-                    2 => SYSTEM_PALLETE[sprite_pallete[2] as usize],
-                    3 => SYSTEM_PALLETE[sprite_pallete[3] as usize],
+                    1 => sprite_pallete[1],
+                    2 => sprite_pallete[2],
+                    3 => sprite_pallete[3],
                     _ => unreachable!(),
                 };
-                match (flip_h, flip_v) {
-                    (false, false) => frame.set_pixel(tile_x + x, tile_y + y, rgb),
-                    (true, false) => frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb),
-                    (false, true) => frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb),
-                    (true, true) => frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb),
+                let screen_x = if flip_h { tile_x + 7 - x } else { tile_x + x };
+                let screen_y = tile_y + screen_row;
+                if screen_x >= frame.width || screen_y >= frame.height {
+                    continue;
+                }
+                let pixel = screen_y * frame.width + screen_x;
+                let over_background = background.is_some_and(|opaque| opaque[pixel]);
+
+                if sprite == 0 && over_background && sprite_zero_hits_at(ppu, screen_x) {
+                    let hit = (screen_y, screen_x);
+                    if sprite_zero_hit.is_none_or(|first| hit < first) {
+                        sprite_zero_hit = Some(hit);
+                    }
+                }
+                if std::mem::replace(&mut taken[pixel], true) {
+                    continue;
+                }
+                if !(behind_background && over_background) {
+                    frame.set_color(screen_x, screen_y, nes_color, colors);
                 }
             }
         }
     }
+    sprite_zero_hit.map(|(y, x)| (x, y))
+}
+
+/// Sprite 0 hits never happen in the last column, nor in the first 8 while either layer is
+/// clipped there.
+fn sprite_zero_hits_at(ppu: &NesPPU, x: usize) -> bool {
+    let clipped = !ppu.mask.leftmost_8pxl_bg() || !ppu.mask.leftmost_8pxl_sprite();
+    x != 255 && !(x < 8 && clipped)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ppu::PPU;
+
+    fn write_vram(ppu: &mut NesPPU, addr: u16, data: u8) {
+        ppu.write_to_ppu_addr((addr >> 8) as u8);
+        ppu.write_to_ppu_addr(addr as u8);
+        ppu.write_to_data(data);
+    }
+
+    #[test]
+    fn test_incremental_render_matches_full_render() {
+        let mut chr = vec![0; 0x2000];
+        chr[16..32].copy_from_slice(&[0xFF; 16]); // tile 1 is solid color 3
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        write_vram(&mut ppu, 0x3F03, 0x16);
+
+        let mut renderer = Renderer::new();
+        let mut incremental = Frame::new();
+        renderer.render(&ppu, &mut incremental);
+
+        write_vram(&mut ppu, 0x2021, 1);
+        write_vram(&mut ppu, 0x23C0, 0xFF);
+        renderer.render(&ppu, &mut incremental);
+
+        let mut full = Frame::new();
+        render(&ppu, &mut full);
+        assert!(incremental.data == full.data);
+    }
+
+    #[test]
+    fn test_new_ppu_redraws_everything() {
+        let mut chr = vec![0; 0x2000];
+        chr[16..32].copy_from_slice(&[0xFF; 16]);
+        let mut first = NesPPU::new(chr.clone(), Mirroring::HORIZONTAL);
+        write_vram(&mut first, 0x3F03, 0x16);
+        for addr in 0x2000..0x2010 {
+            write_vram(&mut first, addr, 1);
+        }
+        let mut renderer = Renderer::new();
+        let mut frame = Frame::new();
+        renderer.render(&first, &mut frame);
+
+        // Fewer writes than the first one made, and none to the tiles it drew
+        let mut second = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        write_vram(&mut second, 0x3F03, 0x16);
+        write_vram(&mut second, 0x2021, 1);
+        renderer.render(&second, &mut frame);
+        let mut full = Frame::new();
+        render(&second, &mut full);
+        assert!(frame.data == full.data);
+
+        // Or as many, which only `invalidate` catches
+        renderer.render(&first, &mut frame);
+        for addr in 0x2021..0x2031 {
+            write_vram(&mut second, addr, 1);
+        }
+        renderer.invalidate();
+        renderer.render(&second, &mut frame);
+        render(&second, &mut full);
+        assert!(frame.data == full.data);
+    }
+
+    #[test]
+    fn test_color_order_shuffles_any_palette() {
+        let mut renderer = Renderer::new();
+        renderer.set_palette(PaletteName::Rgb);
+        renderer.set_color_order(palette::rp2c04_order(1));
+        assert_eq!(renderer.colors()[0], PaletteName::Rgb.colors()[0x35]);
+        renderer.set_palette(PaletteName::Grayscale);
+        assert_eq!(renderer.colors()[0], PaletteName::Grayscale.colors()[0x35]);
+        renderer.set_color_order(None);
+        assert_eq!(renderer.colors()[0], PaletteName::Grayscale.colors()[0]);
+    }
+
+    #[test]
+    fn test_hidden_layers() {
+        let mut chr = vec![0; 0x2000];
+        chr[16..32].copy_from_slice(&[0xFF; 16]);
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        write_vram(&mut ppu, 0x3F00, 0x0F);
+        write_vram(&mut ppu, 0x3F03, 0x16);
+        write_vram(&mut ppu, 0x3F13, 0x2A);
+        write_vram(&mut ppu, 0x2000, 1);
+        // Sprite 0 draws tile 1 at (16, 0)
+        ppu.oam_data[..4].copy_from_slice(&[0, 1, 0, 16]);
+
+        let pixel = |frame: &Frame, x: usize, y: usize| {
+            let base = (y * frame.width + x) * 3;
+            (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+        };
+        let mut renderer = Renderer::new();
+        let mut frame = Frame::new();
+        renderer.render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 16, 0), SYSTEM_PALLETE[0x2A]);
+
+        renderer.hide_background = true;
+        renderer.render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), SYSTEM_PALLETE[0x0F]);
+        assert_eq!(pixel(&frame, 16, 0), SYSTEM_PALLETE[0x2A]);
+
+        renderer.hide_background = false;
+        renderer.hide_sprites = true;
+        renderer.render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 16, 0), SYSTEM_PALLETE[0x0F]);
+
+        // Switching palettes redraws the cached background too
+        renderer.set_palette(PaletteName::Grayscale);
+        renderer.render(&ppu, &mut frame);
+        let gray = PaletteName::Grayscale.colors()[0x16];
+        assert_eq!(gray.0, gray.1);
+        assert_eq!(pixel(&frame, 0, 0), gray);
+
+        // A palette file only shows while `File` is picked
+        let mut colors = SYSTEM_PALLETE;
+        colors[0x16] = (1, 2, 3);
+        renderer.set_file_palette(Some(colors));
+        assert_eq!(pixel(&frame, 0, 0), gray);
+        renderer.set_palette(PaletteName::File);
+        renderer.render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), (1, 2, 3));
+        renderer.set_file_palette(None);
+        renderer.render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), SYSTEM_PALLETE[0x16]);
+        assert_eq!(renderer.next_palette(), PaletteName::Default);
+    }
+
+    #[test]
+    fn test_nametable_map_repeats_mirrored_tables() {
+        let mut chr = vec![0; 0x2000];
+        chr[16..32].copy_from_slice(&[0xFF; 16]);
+        let mut ppu = NesPPU::new(chr, Mirroring::VERTICAL);
+        write_vram(&mut ppu, 0x3F03, 0x16);
+        write_vram(&mut ppu, 0x2000, 1);
+
+        let map = nametable_map(&ppu, &SYSTEM_PALLETE);
+        let pixel = |x: usize, y: usize| {
+            let base = (y * map.width + x) * 3;
+            &map.data[base..base + 3]
+        };
+        let red = SYSTEM_PALLETE[0x16];
+        assert_eq!(pixel(0, 0), [red.0, red.1, red.2]);
+        // $2800 mirrors $2000 with vertical mirroring, $2400 doesn't
+        assert_eq!(pixel(0, 240), [red.0, red.1, red.2]);
+        assert_ne!(pixel(256, 0), [red.0, red.1, red.2]);
+    }
+
+    #[test]
+    fn test_oam_sheet() {
+        let mut chr = vec![0; 0x2000];
+        chr[16..32].copy_from_slice(&[0xFF; 16]);
+        chr[0x1010..0x1020].copy_from_slice(&[0xFF; 16]);
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        write_vram(&mut ppu, 0x3F1B, 0x16);
+        // Sprite 9, tile 1 in sprite palette 2
+        ppu.oam_data[9 * 4 + 1] = 1;
+        ppu.oam_data[9 * 4 + 2] = 2;
+
+        let cell = OAM_CELL;
+        let sheet = oam_sheet(&ppu, &SYSTEM_PALLETE);
+        let red = SYSTEM_PALLETE[0x16];
+        assert_eq!(sheet.pixel(cell + 6, cell + 2), red);
+        assert_eq!(sheet.pixel(cell + 6, cell + 10), OAM_CELL_BACKGROUND);
+
+        // As an 8x16 sprite, tile 1 is the bottom half of tiles $1000 and $1001
+        ppu.write_to_ctrl(0x20);
+        let sheet = oam_sheet(&ppu, &SYSTEM_PALLETE);
+        assert_eq!(sheet.pixel(cell + 6, cell + 2), OAM_CELL_BACKGROUND);
+        assert_eq!(sheet.pixel(cell + 6, cell + 10), red);
+    }
+
+    #[test]
+    fn test_diagonal_scroll_across_four_screens() {
+        let mut chr = vec![0; 0x2000];
+        chr[16..32].copy_from_slice(&[0xFF; 16]);
+        let mut ppu = NesPPU::new(chr, Mirroring::FOURSCREEN);
+        // Every nametable is solid tile 1 in its own palette
+        let colors = [0x16, 0x2A, 0x12, 0x28];
+        for (table, &color) in colors.iter().enumerate() {
+            let start = table * 0x400;
+            ppu.vram[start..start + 0x3C0].fill(1);
+            ppu.vram[start + 0x3C0..start + 0x400].fill(table as u8 * 0x55);
+            write_vram(&mut ppu, 0x3F03 + table as u16 * 4, color);
+        }
+        ppu.scroll.scroll_x = 100;
+        ppu.scroll.scroll_y = 50;
+
+        let table_at = |ppu: &NesPPU, x: usize, y: usize| {
+            let mut frame = Frame::new();
+            render(ppu, &mut frame);
+            let base = (y * frame.width + x) * 3;
+            let rgb = (frame.data[base], frame.data[base + 1], frame.data[base + 2]);
+            colors
+                .iter()
+                .position(|&color| SYSTEM_PALLETE[color as usize] == rgb)
+        };
+        assert_eq!(table_at(&ppu, 0, 0), Some(0));
+        assert_eq!(table_at(&ppu, 200, 0), Some(1));
+        assert_eq!(table_at(&ppu, 0, 200), Some(2));
+        assert_eq!(table_at(&ppu, 200, 200), Some(3));
+
+        // Starting from $2C00 wraps around to the others
+        ppu.write_to_ctrl(0b11);
+        assert_eq!(table_at(&ppu, 0, 0), Some(3));
+        assert_eq!(table_at(&ppu, 200, 0), Some(2));
+        assert_eq!(table_at(&ppu, 0, 200), Some(1));
+        assert_eq!(table_at(&ppu, 200, 200), Some(0));
+
+        // With single-screen mirroring all four show the same VRAM
+        ppu.mirroring = Mirroring::SINGLESCREEN1;
+        assert_eq!(table_at(&ppu, 0, 0), Some(1));
+        assert_eq!(table_at(&ppu, 200, 200), Some(1));
+    }
+
+    #[test]
+    fn test_sprite_priority_and_sprite_zero_hit() {
+        let mut chr = vec![0; 0x2000];
+        chr[16..32].copy_from_slice(&[0xFF; 16]);
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        write_vram(&mut ppu, 0x3F03, 0x16);
+        write_vram(&mut ppu, 0x3F13, 0x2A);
+        write_vram(&mut ppu, 0x3F17, 0x12);
+        // One opaque background tile in the top left corner
+        write_vram(&mut ppu, 0x2000, 1);
+        // Sprite 0 behind the background, sprite 1 in front of it at the same spot
+        ppu.oam_data[..8].copy_from_slice(&[0, 1, 0b0010_0000, 4, 0, 1, 0b01, 4]);
+        ppu.write_to_mask(0b0001_1110);
+
+        let mut renderer = Renderer::new();
+        let mut frame = Frame::new();
+        renderer.render(&ppu, &mut frame);
+        let pixel = |frame: &Frame, x: usize, y: usize| {
+            let base = (y * frame.width + x) * 3;
+            (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+        };
+        // Where the background is opaque sprite 0 hides behind it and masks sprite 1
+        assert_eq!(pixel(&frame, 5, 2), SYSTEM_PALLETE[0x16]);
+        // Elsewhere it shows over the backdrop
+        assert_eq!(pixel(&frame, 10, 2), SYSTEM_PALLETE[0x2A]);
+        assert_eq!(renderer.sprite_zero_hit(), Some((4, 0)));
+
+        let mut full = Frame::new();
+        render(&ppu, &mut full);
+        assert!(full.data == frame.data);
+
+        // Clipping the left 8 pixels leaves no overlap to hit
+        ppu.write_to_mask(0b0001_1000);
+        renderer.render(&ppu, &mut frame);
+        assert_eq!(renderer.sprite_zero_hit(), None);
+    }
+
+    #[test]
+    fn test_pixel_formats_show_the_same_picture() {
+        let mut chr = vec![0; 0x2000];
+        chr[16..32].copy_from_slice(&[0xFF; 16]);
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        write_vram(&mut ppu, 0x3F00, 0x0F);
+        write_vram(&mut ppu, 0x3F03, 0x16);
+        write_vram(&mut ppu, 0x3F13, 0x2A);
+        write_vram(&mut ppu, 0x2000, 1);
+        ppu.oam_data[..4].copy_from_slice(&[20, 1, 0, 16]);
+
+        let mut renderer = Renderer::new();
+        let mut rgb = Frame::new();
+        renderer.render(&ppu, &mut rgb);
+        for format in [PixelFormat::Rgba32, PixelFormat::Indexed] {
+            let mut frame = Frame::with_format(Frame::WIDTH, Frame::HEIGHT, format);
+            renderer.render(&ppu, &mut frame);
+            assert_eq!(
+                frame.data.len(),
+                rgb.data.len() / 3 * format.bytes_per_pixel()
+            );
+            for (x, y) in [(0, 0), (16, 20), (100, 100)] {
+                assert_eq!(frame.pixel(x, y), rgb.pixel(x, y));
+            }
+        }
+        let mut indexed = Frame::with_format(Frame::WIDTH, Frame::HEIGHT, PixelFormat::Indexed);
+        render(&ppu, &mut indexed);
+        assert_eq!(indexed.data[..2], [0x16, 0x16]);
+        assert_eq!(indexed.data[20 * Frame::WIDTH + 16], 0x2A);
+        assert_eq!(indexed.data[100 * Frame::WIDTH + 100], 0x0F);
+    }
+
+    #[test]
+    fn test_ninth_sprite_on_a_scanline_is_dropped() {
+        let mut ppu = NesPPU::new(vec![0; 0x2000], Mirroring::HORIZONTAL);
+        for sprite in 0..9 {
+            ppu.oam_data[sprite * 4] = 10;
+        }
+        // Sprite 9 is one scanline lower, so its last row is only shared with sprite 8
+        ppu.oam_data[9 * 4] = 11;
+
+        let visible = visible_sprite_rows(&ppu);
+        assert_eq!(visible[7], 0xFF);
+        assert_eq!(visible[8], 0);
+        assert_eq!(visible[9], 0b1000_0000);
+    }
 }
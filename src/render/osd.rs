@@ -0,0 +1,134 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use super::{
+    font::{self, CELL_HEIGHT, CELL_WIDTH},
+    frame::Frame,
+};
+
+const BACKGROUND: (u8, u8, u8) = (0x00, 0x00, 0x00);
+const TEXT: (u8, u8, u8) = (0x40, 0xFF, 0x40);
+/// Each line's box, with a border around the text
+const LINE_HEIGHT: usize = CELL_HEIGHT + 2;
+
+/// How long a message stays up, fade included
+const MESSAGE_TIME: Duration = Duration::from_millis(2500);
+/// The end of `MESSAGE_TIME`, over which a message fades out
+const FADE_TIME: Duration = Duration::from_millis(500);
+/// Messages up at once. A new one pushes the oldest off.
+const MAX_MESSAGES: usize = 4;
+
+struct Message {
+    text: String,
+    shown: Instant,
+}
+
+/// Short messages drawn over the picture in the bottom left corner, like "State 2 loaded",
+/// that fade out after a couple of seconds. The newest is at the bottom.
+#[derive(Default)]
+pub struct Osd {
+    messages: VecDeque<Message>,
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Osd::default()
+    }
+
+    /// Shows `text` from now on. Showing a message that is already up starts its time again
+    /// instead of adding it twice.
+    pub fn show(&mut self, text: impl Into<String>) {
+        self.show_at(text.into(), Instant::now());
+    }
+
+    fn show_at(&mut self, text: String, now: Instant) {
+        self.messages.retain(|message| message.text != text);
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(Message { text, shown: now });
+    }
+
+    /// Draws the messages still up at `now`, above `pinned` lines that stay for as long as
+    /// they're passed in, like "PAUSED".
+    pub fn draw(&mut self, frame: &mut Frame, now: Instant, pinned: &[&str]) {
+        self.messages
+            .retain(|message| now.saturating_duration_since(message.shown) < MESSAGE_TIME);
+        let lines: Vec<(&str, f32)> = self
+            .messages
+            .iter()
+            .map(|message| {
+                let age = now.saturating_duration_since(message.shown);
+                (message.text.as_str(), opacity(age))
+            })
+            .chain(pinned.iter().map(|&text| (text, 1.0)))
+            .collect();
+        let top = frame.height.saturating_sub(lines.len() * LINE_HEIGHT);
+        for (i, (text, opacity)) in lines.into_iter().enumerate() {
+            draw_line(frame, top + i * LINE_HEIGHT, text, opacity);
+        }
+    }
+}
+
+/// How solid a message `age` old is drawn, from 1 down to 0 over its fade.
+fn opacity(age: Duration) -> f32 {
+    let left = MESSAGE_TIME.saturating_sub(age);
+    (left.as_secs_f32() / FADE_TIME.as_secs_f32()).min(1.0)
+}
+
+/// Draws `text` in a box at the left edge, mixed with the picture under it by `opacity`.
+fn draw_line(frame: &mut Frame, top: usize, text: &str, opacity: f32) {
+    let width = (text.chars().count() * CELL_WIDTH + 4).min(frame.width);
+    let bottom = (top + LINE_HEIGHT).min(frame.height);
+    let under: Vec<(u8, u8, u8)> = (top..bottom)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| frame.pixel(x, y))
+        .collect();
+    frame.fill_rect(0, top, width, LINE_HEIGHT, BACKGROUND);
+    font::draw_text(frame, 2, top + 2, text, TEXT);
+    if opacity >= 1.0 {
+        return;
+    }
+    let mix = |under: u8, over: u8| (under as f32 + (over as f32 - under as f32) * opacity) as u8;
+    for (i, under) in under.into_iter().enumerate() {
+        let (x, y) = (i % width, top + i / width);
+        let over = frame.pixel(x, y);
+        let rgb = (
+            mix(under.0, over.0),
+            mix(under.1, over.1),
+            mix(under.2, over.2),
+        );
+        frame.set_pixel(x, y, rgb);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_messages_stack_and_fade() {
+        let start = Instant::now();
+        let mut osd = Osd::new();
+        for text in ["1", "2", "3", "4", "2", "5"] {
+            osd.show_at(text.to_string(), start);
+        }
+        let texts: Vec<&str> = osd.messages.iter().map(|m| m.text.as_str()).collect();
+        assert_eq!(texts, ["3", "4", "2", "5"]);
+
+        // Halfway through the fade, the box is half black over a white picture
+        let mut frame = Frame::new();
+        frame.fill_rect(0, 0, Frame::WIDTH, Frame::HEIGHT, (0xFF, 0xFF, 0xFF));
+        let fading = start + MESSAGE_TIME - FADE_TIME / 2;
+        osd.draw(&mut frame, fading, &["PAUSED"]);
+        assert_eq!(frame.pixel(0, Frame::HEIGHT - LINE_HEIGHT), BACKGROUND);
+        let top = Frame::HEIGHT - 5 * LINE_HEIGHT;
+        assert_eq!(frame.pixel(0, top), (0x7F, 0x7F, 0x7F));
+        assert_eq!(frame.pixel(0, top - 1), (0xFF, 0xFF, 0xFF));
+
+        osd.draw(&mut frame, start + MESSAGE_TIME, &[]);
+        assert!(osd.messages.is_empty());
+    }
+}
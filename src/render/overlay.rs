@@ -0,0 +1,90 @@
+use crate::ppu::NesPPU;
+
+use super::{
+    font::{self, CELL_HEIGHT, CELL_WIDTH},
+    frame::Frame,
+};
+
+const BACKGROUND: (u8, u8, u8) = (0x00, 0x00, 0x00);
+const TEXT: (u8, u8, u8) = (0x40, 0xFF, 0x40);
+const MARKER: (u8, u8, u8) = (0xFF, 0x00, 0xFF);
+
+/// Text lines describing the PPU's timing over the last frame: the scanline and dot the game
+/// last read $2002 at, which is usually where it stopped waiting for vblank or sprite 0, the
+/// scroll registers and where sprite 0 hit.
+pub fn ppu_status_lines(ppu: &NesPPU) -> Vec<String> {
+    let status_read = match ppu.last_status_read {
+        Some((scanline, dot)) => format!("SL {} DOT {}", scanline, dot),
+        None => "-".to_string(),
+    };
+    let sprite_zero = match ppu.last_sprite_zero_hit {
+        Some((x, y)) => format!("{},{}", x, y),
+        None => "-".to_string(),
+    };
+    vec![
+        format!("$2002 {}", status_read),
+        format!(
+            "SCR {:3},{:3} NT ${:04X}",
+            ppu.scroll.scroll_x,
+            ppu.scroll.scroll_y,
+            ppu.ctrl.nametable_addr()
+        ),
+        format!("S0 HIT {}", sprite_zero),
+    ]
+}
+
+/// Draws `ppu_status_lines` in a box in the top left corner.
+pub fn draw_ppu_overlay(ppu: &NesPPU, frame: &mut Frame) {
+    let lines = ppu_status_lines(ppu);
+    let width = lines.iter().map(|line| line.len()).max().unwrap_or(0) * CELL_WIDTH;
+    frame.fill_rect(0, 0, width + 4, lines.len() * CELL_HEIGHT + 2, BACKGROUND);
+    for (i, line) in lines.iter().enumerate() {
+        font::draw_text(frame, 2, 2 + i * CELL_HEIGHT, line, TEXT);
+    }
+}
+
+/// Draws a crosshair where sprite 0 hit happened in the last frame, if it did.
+pub fn draw_sprite_zero_marker(ppu: &NesPPU, frame: &mut Frame) {
+    let Some((x, y)) = ppu.last_sprite_zero_hit else {
+        return;
+    };
+    let (x, y) = (x as usize, y as usize);
+    frame.fill_rect(x.saturating_sub(4), y, 9, 1, MARKER);
+    frame.fill_rect(x, y.saturating_sub(4), 1, 9, MARKER);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cartridge::Mirroring, ppu::PPU};
+
+    #[test]
+    fn test_sprite_zero_hit_position_is_kept_for_the_overlay() {
+        let mut ppu = NesPPU::new(vec![0; 0x2000], Mirroring::HORIZONTAL);
+        ppu.write_to_mask(0b0001_0000); // show sprites
+        ppu.oam_data[0] = 30;
+        ppu.oam_data[3] = 100;
+
+        let mut frame_done = false;
+        while !frame_done {
+            frame_done = ppu.tick(3);
+        }
+        assert_eq!(ppu.last_sprite_zero_hit, Some((100, 30)));
+        assert_eq!(ppu_status_lines(&ppu)[2], "S0 HIT 100,30");
+    }
+
+    #[test]
+    fn test_last_status_read_is_kept_for_the_overlay() {
+        let mut ppu = NesPPU::new(vec![0; 0x2000], Mirroring::HORIZONTAL);
+        assert_eq!(ppu_status_lines(&ppu)[0], "$2002 -");
+        for _ in 0..(30 * 341 + 12) / 3 {
+            ppu.tick(3);
+        }
+        ppu.read_status();
+        let mut frame_done = false;
+        while !frame_done {
+            frame_done = ppu.tick(3);
+        }
+        assert_eq!(ppu_status_lines(&ppu)[0], "$2002 SL 30 DOT 12");
+    }
+}
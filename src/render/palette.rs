@@ -1,3 +1,14 @@
+use std::{path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// RGB for each of the 64 colors the PPU can output.
+pub type Colors = [(u8, u8, u8); 64];
+
+/// A .pal file's size with the 64 colors alone, and with all 8 emphasis combinations of them
+const PAL_FILE_SIZE: usize = 64 * 3;
+const PAL_FILE_EMPHASIS_SIZE: usize = 8 * PAL_FILE_SIZE;
+
 #[rustfmt::skip]
 
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
@@ -15,3 +26,343 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
     (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
     (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+/// Built-in palettes, picked with `--palette` or `video.palette` in config.toml.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PaletteName {
+    #[default]
+    Default,
+    /// The RGB PPU of the Vs. System and PlayChoice-10 arcade boards
+    Rgb,
+    /// The default palette by luminance, like a black and white TV
+    Grayscale,
+    /// Adjusted for red-green color blindness (missing green cones, the most common kind)
+    Deuteranopia,
+    /// Adjusted for red-green color blindness (missing red cones)
+    Protanopia,
+    /// Adjusted for blue-yellow color blindness
+    Tritanopia,
+    /// FCEUX's default colors
+    Fceux,
+    /// As decoded by the Sony CXA2025AS chip in many US TVs
+    #[serde(rename = "sony-cxa")]
+    SonyCxa,
+    /// The colors loaded from `video.palette_file`
+    File,
+}
+
+impl PaletteName {
+    pub const ALL: [PaletteName; 9] = [
+        PaletteName::Default,
+        PaletteName::Rgb,
+        PaletteName::Grayscale,
+        PaletteName::Deuteranopia,
+        PaletteName::Protanopia,
+        PaletteName::Tritanopia,
+        PaletteName::Fceux,
+        PaletteName::SonyCxa,
+        PaletteName::File,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PaletteName::Default => "default",
+            PaletteName::Rgb => "rgb",
+            PaletteName::Grayscale => "grayscale",
+            PaletteName::Deuteranopia => "deuteranopia",
+            PaletteName::Protanopia => "protanopia",
+            PaletteName::Tritanopia => "tritanopia",
+            PaletteName::Fceux => "fceux",
+            PaletteName::SonyCxa => "sony-cxa",
+            PaletteName::File => "file",
+        }
+    }
+
+    /// The palette after this one in `ALL`, for cycling through them with a hotkey.
+    pub fn next(self) -> PaletteName {
+        let index = PaletteName::ALL
+            .iter()
+            .position(|&p| p == self)
+            .unwrap_or(0);
+        PaletteName::ALL[(index + 1) % PaletteName::ALL.len()]
+    }
+
+    /// The palette's colors. `File` has none of its own and gives the default ones, the
+    /// renderer keeps the file's.
+    pub fn colors(self) -> Colors {
+        match self {
+            PaletteName::Default => SYSTEM_PALLETE,
+            PaletteName::Rgb => RGB_PPU_LEVELS.map(|levels| {
+                // Three bits per channel, as octal digits
+                let level = |shift: u32| ((levels >> shift & 7) * 255 / 7) as u8;
+                (level(6), level(3), level(0))
+            }),
+            PaletteName::Grayscale => SYSTEM_PALLETE.map(|(r, g, b)| {
+                // Rec. 601 luma
+                let luma = (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000;
+                (luma as u8, luma as u8, luma as u8)
+            }),
+            PaletteName::Deuteranopia => SYSTEM_PALLETE.map(|rgb| daltonize(rgb, &DEUTERANOPIA)),
+            PaletteName::Protanopia => SYSTEM_PALLETE.map(|rgb| daltonize(rgb, &PROTANOPIA)),
+            PaletteName::Tritanopia => SYSTEM_PALLETE.map(|rgb| daltonize(rgb, &TRITANOPIA)),
+            PaletteName::Fceux => FCEUX.map(hex_color),
+            PaletteName::SonyCxa => SONY_CXA.map(hex_color),
+            PaletteName::File => SYSTEM_PALLETE,
+        }
+    }
+}
+
+impl FromStr for PaletteName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        if name == "greyscale" {
+            return Ok(PaletteName::Grayscale);
+        }
+        PaletteName::ALL
+            .into_iter()
+            .find(|palette| palette.name() == name)
+            .ok_or_else(|| format!("Unknown palette: {}", s))
+    }
+}
+
+/// How the Vs. System's RP2C04-000`chip` shuffles the color numbers: color n comes out as
+/// standard color `order[n]`. `None` for chips that don't exist.
+pub fn rp2c04_order(chip: u8) -> Option<&'static [u8; 64]> {
+    RP2C04_ORDER.get((chip as usize).wrapping_sub(1))
+}
+
+/// Reads a .pal file: 64 RGB triplets, or 512 with the colors for each combination of the
+/// emphasis bits after the first 64. Emphasis isn't drawn, so only the first 64 are used.
+pub fn load_file(path: &Path) -> Result<Colors, String> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| format!("Failed to read palette {}: {}", path.display(), e))?;
+    from_pal(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn from_pal(bytes: &[u8]) -> Result<Colors, String> {
+    if bytes.len() != PAL_FILE_SIZE && bytes.len() != PAL_FILE_EMPHASIS_SIZE {
+        return Err(format!(
+            "{} bytes isn't a palette, which has {} or {}",
+            bytes.len(),
+            PAL_FILE_SIZE,
+            PAL_FILE_EMPHASIS_SIZE
+        ));
+    }
+    let mut colors = [(0, 0, 0); 64];
+    for (color, rgb) in colors.iter_mut().zip(bytes.chunks(3)) {
+        *color = (rgb[0], rgb[1], rgb[2]);
+    }
+    Ok(colors)
+}
+
+fn hex_color(rgb: u32) -> (u8, u8, u8) {
+    ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+}
+
+#[rustfmt::skip]
+const FCEUX: [u32; 64] = [
+    0x747474, 0x24188C, 0x0000A8, 0x44009C, 0x8C0074, 0xA80010, 0xA40000, 0x7C0800,
+    0x402C00, 0x004400, 0x005000, 0x003C14, 0x183C5C, 0x000000, 0x000000, 0x000000,
+    0xBCBCBC, 0x0070EC, 0x2038EC, 0x8000F0, 0xBC00BC, 0xE40058, 0xD82800, 0xC84C0C,
+    0x887000, 0x009400, 0x00A800, 0x009038, 0x008088, 0x000000, 0x000000, 0x000000,
+    0xFCFCFC, 0x3CBCFC, 0x5C94FC, 0xCC88FC, 0xF478FC, 0xFC74B4, 0xFC7460, 0xFC9838,
+    0xF0BC3C, 0x80D010, 0x4CDC48, 0x58F898, 0x00E8D8, 0x787878, 0x000000, 0x000000,
+    0xFCFCFC, 0xA8E4FC, 0xC4D4FC, 0xD4C8FC, 0xFCC4FC, 0xFCC4D8, 0xFCBCB0, 0xFCD8A8,
+    0xFCE4A0, 0xE0FCA0, 0xA8F0BC, 0xB0FCCC, 0x9CFCF0, 0xC4C4C4, 0x000000, 0x000000,
+];
+
+#[rustfmt::skip]
+const SONY_CXA: [u32; 64] = [
+    0x585858, 0x00238C, 0x00139B, 0x2D0585, 0x5D0052, 0x7A0017, 0x7A0800, 0x5F1800,
+    0x352A00, 0x093900, 0x003F00, 0x003C22, 0x00325D, 0x000000, 0x000000, 0x000000,
+    0xA1A1A1, 0x0053EE, 0x153CFE, 0x6028E4, 0xA91D98, 0xD41E41, 0xD22C00, 0xAA4400,
+    0x6C5E00, 0x2D7300, 0x007D06, 0x007852, 0x0069A9, 0x000000, 0x000000, 0x000000,
+    0xFFFFFF, 0x1FA5FE, 0x5E89FE, 0xB572FE, 0xFE65F6, 0xFE6790, 0xFE773C, 0xFE9308,
+    0xC4B200, 0x79CA10, 0x3AD54A, 0x11D1A4, 0x06BFFE, 0x424242, 0x000000, 0x000000,
+    0xFFFFFF, 0xA0D9FE, 0xBDCCFE, 0xE1C2FE, 0xFEBCFB, 0xFEBDD0, 0xFEC5A9, 0xFED18E,
+    0xE9DE86, 0xC7E992, 0xA8EEB0, 0x95ECD9, 0x91E4FE, 0xACACAC, 0x000000, 0x000000,
+];
+
+// For each of the RP2C04-0001 to -0004, the standard color each color number shows. Colors
+// the RGB PPU has twice, like its blacks and whites, only appear once.
+#[rustfmt::skip]
+const RP2C04_ORDER: [[u8; 64]; 4] = [
+    [
+        0x35, 0x23, 0x16, 0x22, 0x1C, 0x09, 0x1D, 0x15, 0x20, 0x00, 0x27, 0x05, 0x04, 0x28, 0x08, 0x20,
+        0x21, 0x3E, 0x1F, 0x29, 0x3C, 0x32, 0x36, 0x12, 0x3F, 0x2B, 0x2E, 0x1E, 0x3D, 0x2D, 0x24, 0x01,
+        0x0E, 0x31, 0x33, 0x2A, 0x2C, 0x0C, 0x1B, 0x14, 0x2E, 0x07, 0x34, 0x06, 0x13, 0x02, 0x26, 0x2E,
+        0x2E, 0x19, 0x10, 0x0A, 0x39, 0x03, 0x37, 0x17, 0x0F, 0x11, 0x0B, 0x0D, 0x38, 0x25, 0x18, 0x3A,
+    ],
+    [
+        0x2E, 0x27, 0x18, 0x39, 0x3A, 0x25, 0x1C, 0x31, 0x16, 0x13, 0x38, 0x34, 0x20, 0x23, 0x3C, 0x0B,
+        0x0F, 0x21, 0x06, 0x3D, 0x1B, 0x29, 0x1E, 0x22, 0x1D, 0x24, 0x0E, 0x2B, 0x32, 0x08, 0x2E, 0x03,
+        0x04, 0x36, 0x26, 0x33, 0x11, 0x1F, 0x10, 0x02, 0x14, 0x3F, 0x00, 0x09, 0x12, 0x2E, 0x28, 0x20,
+        0x3E, 0x0D, 0x2A, 0x17, 0x0C, 0x01, 0x15, 0x19, 0x2E, 0x2C, 0x07, 0x37, 0x35, 0x05, 0x0A, 0x2D,
+    ],
+    [
+        0x14, 0x25, 0x3A, 0x10, 0x0B, 0x20, 0x31, 0x09, 0x01, 0x2E, 0x36, 0x08, 0x15, 0x3D, 0x3E, 0x3C,
+        0x22, 0x1C, 0x05, 0x12, 0x19, 0x18, 0x17, 0x1B, 0x00, 0x03, 0x2E, 0x02, 0x16, 0x06, 0x34, 0x35,
+        0x23, 0x0F, 0x0E, 0x37, 0x0D, 0x27, 0x26, 0x20, 0x29, 0x04, 0x21, 0x24, 0x11, 0x2D, 0x2E, 0x1F,
+        0x2C, 0x1E, 0x39, 0x33, 0x07, 0x2A, 0x28, 0x1D, 0x0A, 0x2E, 0x32, 0x38, 0x13, 0x2B, 0x3F, 0x0C,
+    ],
+    [
+        0x18, 0x03, 0x1C, 0x28, 0x2E, 0x35, 0x01, 0x17, 0x10, 0x1F, 0x2A, 0x0E, 0x36, 0x37, 0x0B, 0x39,
+        0x25, 0x1E, 0x12, 0x34, 0x2E, 0x1D, 0x06, 0x26, 0x3E, 0x1B, 0x22, 0x19, 0x04, 0x2E, 0x3A, 0x21,
+        0x05, 0x0A, 0x07, 0x02, 0x13, 0x14, 0x00, 0x15, 0x0C, 0x3D, 0x11, 0x0F, 0x0D, 0x38, 0x2D, 0x24,
+        0x33, 0x20, 0x08, 0x16, 0x3F, 0x2B, 0x20, 0x3C, 0x2E, 0x27, 0x23, 0x31, 0x29, 0x32, 0x2C, 0x09,
+    ],
+];
+
+// The RP2C03's output levels, red, green and blue in one octal digit each
+#[rustfmt::skip]
+const RGB_PPU_LEVELS: [u32; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420,
+    0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630,
+    0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750,
+    0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772,
+    0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+type Matrix = [[f64; 3]; 3];
+
+// Daltonization as described on daltonize.org: simulate what a color blind viewer sees in LMS
+// (cone response) space, then move the colors they can't tell apart into channels they can see.
+#[rustfmt::skip]
+const RGB_TO_LMS: Matrix = [
+    [17.8824, 43.5161, 4.11935],
+    [3.45565, 27.1554, 3.86714],
+    [0.0299566, 0.184309, 1.46709],
+];
+#[rustfmt::skip]
+const LMS_TO_RGB: Matrix = [
+    [0.0809444479, -0.130504409, 0.116721066],
+    [-0.0102485335, 0.0540193266, -0.113614708],
+    [-0.000365296938, -0.00412161469, 0.693511405],
+];
+// The missing cone's response rebuilt from the other two
+#[rustfmt::skip]
+const PROTANOPIA: Matrix = [
+    [0.0, 2.02344, -2.52581],
+    [0.0, 1.0, 0.0],
+    [0.0, 0.0, 1.0],
+];
+#[rustfmt::skip]
+const DEUTERANOPIA: Matrix = [
+    [1.0, 0.0, 0.0],
+    [0.494207, 0.0, 1.24827],
+    [0.0, 0.0, 1.0],
+];
+#[rustfmt::skip]
+const TRITANOPIA: Matrix = [
+    [1.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0],
+    [-0.395913, 0.801109, 0.0],
+];
+
+fn multiply(matrix: &Matrix, v: [f64; 3]) -> [f64; 3] {
+    matrix.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+/// How `rgb` looks with the cone response `deficiency` describes.
+fn simulate(rgb: [f64; 3], deficiency: &Matrix) -> [f64; 3] {
+    let lms = multiply(deficiency, multiply(&RGB_TO_LMS, rgb));
+    multiply(&LMS_TO_RGB, lms)
+}
+
+fn daltonize((r, g, b): (u8, u8, u8), deficiency: &Matrix) -> (u8, u8, u8) {
+    let rgb = [r as f64, g as f64, b as f64];
+    let seen = simulate(rgb, deficiency);
+    let error = [rgb[0] - seen[0], rgb[1] - seen[1], rgb[2] - seen[2]];
+    let shifted = [
+        rgb[0],
+        rgb[1] + 0.7 * error[0] + error[1],
+        rgb[2] + 0.7 * error[0] + error[2],
+    ];
+    let channel = |value: f64| value.round().clamp(0.0, 255.0) as u8;
+    (
+        channel(shifted[0]),
+        channel(shifted[1]),
+        channel(shifted[2]),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    fn as_f64((r, g, b): (u8, u8, u8)) -> [f64; 3] {
+        [r as f64, g as f64, b as f64]
+    }
+
+    #[test]
+    fn test_adjusted_palettes_separate_confused_colors() {
+        // A red and a green of similar brightness
+        let (red, green) = (0x16, 0x1A);
+        for (palette, deficiency) in [
+            (PaletteName::Deuteranopia, &DEUTERANOPIA),
+            (PaletteName::Protanopia, &PROTANOPIA),
+        ] {
+            let seen = |colors: &Colors, index: usize| simulate(as_f64(colors[index]), deficiency);
+            let before = distance(seen(&SYSTEM_PALLETE, red), seen(&SYSTEM_PALLETE, green));
+            let colors = palette.colors();
+            let after = distance(seen(&colors, red), seen(&colors, green));
+            assert!(after > before, "{:?}: {} -> {}", palette, before, after);
+        }
+
+        // Grays look the same to everyone and stay as they are
+        assert_eq!(PaletteName::Tritanopia.colors()[0x30], SYSTEM_PALLETE[0x30]);
+    }
+
+    #[test]
+    fn test_palette_names() {
+        for palette in PaletteName::ALL {
+            assert_eq!(palette.name().parse(), Ok(palette));
+        }
+        assert_eq!(PaletteName::File.next(), PaletteName::Default);
+        assert!("sepia".parse::<PaletteName>().is_err());
+        assert_eq!(PaletteName::SonyCxa.colors()[0x30], (0xFF, 0xFF, 0xFF));
+    }
+
+    #[test]
+    fn test_rp2c04_shows_every_rgb_color() {
+        let rgb = PaletteName::Rgb.colors();
+        let mut standard = rgb.to_vec();
+        standard.sort();
+        standard.dedup();
+        for chip in 1..=4 {
+            let order = rp2c04_order(chip).unwrap();
+            let mut shuffled: Vec<_> = order.iter().map(|&n| rgb[n as usize]).collect();
+            shuffled.sort();
+            shuffled.dedup();
+            assert_eq!(shuffled, standard, "RP2C04-000{}", chip);
+        }
+        assert_eq!(rp2c04_order(1).unwrap()[0], 0x35);
+        assert!(rp2c04_order(0).is_none());
+        assert!(rp2c04_order(5).is_none());
+    }
+
+    #[test]
+    fn test_pal_files() {
+        let bytes: Vec<u8> = (0..PAL_FILE_EMPHASIS_SIZE).map(|i| i as u8).collect();
+        let colors = from_pal(&bytes[..PAL_FILE_SIZE]).unwrap();
+        assert_eq!(colors[1], (3, 4, 5));
+        // The emphasis colors after the first 64 are left out
+        assert_eq!(from_pal(&bytes).unwrap(), colors);
+        assert!(from_pal(&bytes[..PAL_FILE_SIZE - 1]).is_err());
+        assert!(load_file(Path::new("missing.pal")).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use std::{collections::VecDeque, fs::File, path::Path};
+
+use crate::{
+    clip,
+    ppu::NesPPU,
+    render::{
+        self,
+        frame::{Frame, PixelFormat},
+        palette::Colors,
+    },
+};
+
+/// Every this many frames goes into the replay, which GIF timing can only just keep up with:
+/// 20 a second for NTSC.
+const FRAME_STEP: usize = 3;
+
+/// The last few seconds of the picture, for saving as a GIF after something worth showing
+/// happened. Frames are kept as NES color indices, 60KB each.
+pub struct ReplayBuffer {
+    frames: VecDeque<Frame>,
+    capacity: usize,
+    /// Frames since the last one kept
+    counter: usize,
+    /// Seconds each kept frame is shown for
+    frame_seconds: f64,
+}
+
+impl ReplayBuffer {
+    /// A buffer for `seconds` of a game running at `frame_rate`. Zero seconds keeps nothing.
+    pub fn new(seconds: u32, frame_rate: f64) -> Self {
+        let capacity = (seconds as f64 * frame_rate) as usize / FRAME_STEP;
+        ReplayBuffer {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+            counter: 0,
+            frame_seconds: FRAME_STEP as f64 / frame_rate,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Call once a frame. Draws the picture and keeps it if it's the frame's turn, dropping
+    /// the oldest one when full.
+    pub fn capture(&mut self, ppu: &NesPPU) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.counter += 1;
+        if self.counter < FRAME_STEP {
+            return;
+        }
+        self.counter = 0;
+        let mut frame = if self.frames.len() == self.capacity {
+            self.frames.pop_front().unwrap()
+        } else {
+            Frame::with_format(Frame::WIDTH, Frame::HEIGHT, PixelFormat::Indexed)
+        };
+        render::render(ppu, &mut frame);
+        self.frames.push_back(frame);
+    }
+
+    /// A copy of the frames kept, oldest first, that can be written out while the buffer
+    /// keeps filling.
+    pub fn snapshot(&self, colors: &Colors) -> Replay {
+        Replay {
+            frames: self.frames.iter().map(|frame| frame.data.clone()).collect(),
+            frame_seconds: self.frame_seconds,
+            palette: colors.iter().flat_map(|&(r, g, b)| [r, g, b]).collect(),
+        }
+    }
+}
+
+/// Frames taken out of a `ReplayBuffer`, with the colors to show them in.
+pub struct Replay {
+    frames: Vec<Vec<u8>>,
+    frame_seconds: f64,
+    palette: Vec<u8>,
+}
+
+impl Replay {
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Writes the frames to `path` as a looping GIF.
+    pub fn save_gif(&self, path: &Path) -> Result<(), String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        let frames = self.frames.iter().map(|pixels| {
+            let pixels = pixels.iter().map(|color| color & 0x3F).collect();
+            (pixels, self.frame_seconds)
+        });
+        clip::write_gif(file, (Frame::WIDTH, Frame::HEIGHT), &self.palette, frames)
+            .map(|_| ())
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cartridge::Mirroring, render::palette::SYSTEM_PALLETE};
+
+    #[test]
+    fn test_keeps_the_last_frames() {
+        let mut ppu = NesPPU::new(vec![0; 0x2000], Mirroring::HORIZONTAL);
+        // One second at 6 frames a second is two frames kept
+        let mut replay = ReplayBuffer::new(1, 6.0);
+        assert_eq!(replay.frame_seconds, 0.5);
+        for frame in 0..9 {
+            ppu.palette_table[0] = frame;
+            replay.capture(&ppu);
+        }
+        let snapshot = replay.snapshot(&SYSTEM_PALLETE);
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(
+            snapshot
+                .frames
+                .iter()
+                .map(|frame| frame[0])
+                .collect::<Vec<_>>(),
+            [5, 8]
+        );
+        assert_eq!(snapshot.palette.len(), 64 * 3);
+
+        let path = std::env::temp_dir().join("rustnes_replay_test.gif");
+        snapshot.save_gif(&path).unwrap();
+        let gif = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&gif[..6], b"GIF89a");
+        assert!(ReplayBuffer::new(0, 60.0)
+            .snapshot(&SYSTEM_PALLETE)
+            .is_empty());
+    }
+}
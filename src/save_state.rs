@@ -0,0 +1,348 @@
+use std::path::{Path, PathBuf};
+
+use crate::{cpu::CPU, render::frame::Frame};
+
+const MAGIC: &[u8; 8] = b"RNESSTAT";
+/// Bumped when the layout around the sections changes. A change inside one section bumps that
+/// section's version instead, so older states keep loading.
+const VERSION: u8 = 4;
+/// How many times smaller than the screen thumbnails are
+pub const THUMBNAIL_SCALE: usize = 4;
+/// Magic, version and ROM CRC32
+const HEADER_SIZE: usize = MAGIC.len() + 1 + 4;
+
+/// Appends machine state to a byte buffer. Numbers are little-endian, byte arrays are
+/// length-prefixed so a state from a different cartridge fails to load instead of shifting
+/// everything after it.
+///
+/// Each component writes its state as a section, tagged with a name and a version, which
+/// loading finds by name. Sections a build doesn't know are skipped.
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Writes what `save` writes as the section `tag`. Bump `version` when the section's
+    /// layout changes and have its loader read the old layouts too, see
+    /// `StateReader::version`.
+    pub fn section<F>(&mut self, tag: &[u8; 4], version: u8, save: F)
+    where
+        F: FnOnce(&mut StateWriter),
+    {
+        self.data.extend_from_slice(tag);
+        self.u8(version);
+        let len_at = self.data.len();
+        self.u32(0);
+        save(self);
+        let len = (self.data.len() - len_at - 4) as u32;
+        self.data[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reads back what a `StateWriter` wrote, in the same order.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// The version of the section being read
+    version: u8,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader {
+            data,
+            pos: 0,
+            version: 0,
+        }
+    }
+
+    /// The version the section being read was saved with.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Splits the next section off the sections from here on, `None` at the end.
+    fn next_section(&mut self) -> Result<Option<([u8; 4], StateReader<'a>)>, String> {
+        if self.pos == self.data.len() {
+            return Ok(None);
+        }
+        let tag = self.take(4)?.try_into().unwrap();
+        let version = self.u8()?;
+        let len = self.u32()? as usize;
+        let section = StateReader {
+            data: self.take(len)?,
+            pos: 0,
+            version,
+        };
+        Ok(Some((tag, section)))
+    }
+
+    /// Runs `load` on the section `tag`, which has to be among the sections from here on
+    /// and has to be read to the end.
+    pub fn section<T, F>(&self, tag: &[u8; 4], load: F) -> Result<T, String>
+    where
+        F: FnOnce(&mut StateReader<'a>) -> Result<T, String>,
+    {
+        let name = String::from_utf8_lossy(tag);
+        let mut sections = StateReader::new(&self.data[self.pos..]);
+        let mut section = loop {
+            match sections.next_section()? {
+                Some((found, section)) if &found == tag => break section,
+                Some(_) => {}
+                None => return Err(format!("Save state has no {} section", name.trim())),
+            }
+        };
+        let value = load(&mut section)?;
+        if section.pos != section.data.len() {
+            return Err(format!(
+                "Save state's {} section has extra data at the end",
+                name.trim()
+            ));
+        }
+        Ok(value)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..len))
+            .ok_or("Save state is cut short")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A byte array that has to be exactly as long as `target`.
+    pub fn bytes_into(&mut self, target: &mut [u8]) -> Result<(), String> {
+        let len = self.u32()? as usize;
+        if len != target.len() {
+            return Err(format!(
+                "Save state has {} bytes where {} were expected",
+                len,
+                target.len()
+            ));
+        }
+        target.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+}
+
+/// Snapshot of the whole machine, taken between frames. `rom_crc` ties it to the cartridge.
+pub fn save(cpu: &CPU, rom_crc: u32) -> Vec<u8> {
+    let mut writer = StateWriter::new();
+    writer.data.extend_from_slice(MAGIC);
+    writer.u8(VERSION);
+    writer.u32(rom_crc);
+    cpu.save_state(&mut writer);
+    writer.finish()
+}
+
+/// Adds a picture of the screen to a state from `save`, for browsing states. Loading skips it.
+pub fn add_thumbnail(state: &mut Vec<u8>, thumbnail: &Frame) {
+    let mut writer = StateWriter::new();
+    writer.section(b"SHOT", 1, |writer| {
+        writer.u16(thumbnail.width as u16);
+        writer.u16(thumbnail.height as u16);
+        writer.bytes(&thumbnail.data);
+    });
+    state.extend(writer.finish());
+}
+
+/// The picture `add_thumbnail` added to a state, if there is one.
+pub fn thumbnail(state: &[u8]) -> Option<Frame> {
+    if state.len() < HEADER_SIZE || &state[..MAGIC.len()] != MAGIC || state[MAGIC.len()] != VERSION
+    {
+        return None;
+    }
+    let reader = StateReader::new(&state[HEADER_SIZE..]);
+    reader
+        .section(b"SHOT", |reader| {
+            let width = reader.u16()? as usize;
+            let height = reader.u16()? as usize;
+            let mut frame = Frame::with_size(width, height);
+            reader.bytes_into(&mut frame.data)?;
+            Ok(frame)
+        })
+        .ok()
+}
+
+/// Checks that the sections from `reader`'s position on are all there in full.
+fn check_sections(reader: &StateReader) -> Result<(), String> {
+    let mut sections = StateReader::new(&reader.data[reader.pos..]);
+    while sections.next_section()?.is_some() {}
+    Ok(())
+}
+
+/// Restores a snapshot from `save`. On error the machine is left as it was.
+pub fn load(cpu: &mut CPU, rom_crc: u32, data: &[u8]) -> Result<(), String> {
+    let mut reader = StateReader::new(data);
+    if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+        return Err("Not a save state".to_string());
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(format!(
+            "Save state format {} isn't supported, only {}",
+            version, VERSION
+        ));
+    }
+    let crc = reader.u32()?;
+    if crc != rom_crc {
+        return Err(format!(
+            "Save state is for a different ROM (CRC32 {:08X})",
+            crc
+        ));
+    }
+
+    check_sections(&reader)?;
+
+    let backup = save(cpu, rom_crc);
+    let result = cpu.load_state(&mut reader);
+    if result.is_err() {
+        let mut reader = StateReader::new(&backup[HEADER_SIZE..]);
+        cpu.load_state(&mut reader)
+            .expect("a state saved a moment ago loads");
+    }
+    result
+}
+
+/// `<states>/auto-<ROM CRC32>.state`, where the state saved on exit goes. Keyed by CRC so it
+/// follows the game even when the ROM file is renamed or moved.
+pub fn auto_state_file(states: &Path, rom_crc: u32) -> Result<PathBuf, String> {
+    std::fs::create_dir_all(states)
+        .map_err(|e| format!("Failed to create {}: {}", states.display(), e))?;
+    Ok(states.join(format!("auto-{:08X}.state", rom_crc)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bus::Bus, cartridge::test::test_rom, cpu::Mem, joypad::Joypad, ppu::NesPPU};
+
+    #[test]
+    fn test_state_round_trip() {
+        let bus = Bus::new(test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.register_a = 0x42;
+        cpu.mem_write(0x0010, 0x99);
+        cpu.mem_write(0x2006, 0x20);
+        cpu.mem_write(0x2006, 0x05);
+        cpu.mem_write(0x2007, 0x77);
+        let state = save(&cpu, 1234);
+
+        cpu.register_a = 0;
+        cpu.mem_write(0x0010, 0);
+        cpu.mem_write(0x2006, 0x20);
+        cpu.mem_write(0x2006, 0x05);
+        cpu.mem_write(0x2007, 0);
+        load(&mut cpu, 1234, &state).unwrap();
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.mem_read(0x0010), 0x99);
+        assert_eq!(save(&cpu, 1234), state);
+
+        assert!(load(&mut cpu, 4321, &state).is_err());
+        cpu.register_a = 7;
+        assert!(load(&mut cpu, 1234, &state[..state.len() - 1]).is_err());
+        assert_eq!(cpu.register_a, 7);
+    }
+
+    #[test]
+    fn test_thumbnail_is_skipped_by_load() {
+        let bus = Bus::new(test_rom(), |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        let mut state = save(&cpu, 1234);
+        assert!(thumbnail(&state).is_none());
+
+        let mut screen = Frame::new();
+        screen.fill_rect(0, 0, 4, 4, (40, 80, 120));
+        screen.fill_rect(4, 0, 4, 4, (0, 0, 200));
+        add_thumbnail(&mut state, &screen.downscale(4));
+        let small = thumbnail(&state).unwrap();
+        assert_eq!((small.width, small.height), (64, 60));
+        assert_eq!(small.pixel(0, 0), (40, 80, 120));
+        assert_eq!(small.pixel(1, 0), (0, 0, 200));
+
+        load(&mut cpu, 1234, &state).unwrap();
+    }
+
+    #[test]
+    fn test_sections_by_name() {
+        let mut writer = StateWriter::new();
+        writer.section(b"NEW ", 1, |writer| writer.u32(5));
+        writer.section(b"TWO ", 3, |writer| writer.u8(2));
+        writer.section(b"ONE ", 1, |writer| writer.u16(1));
+        let data = writer.finish();
+
+        // Out of order, and skipping what isn't asked for
+        let reader = StateReader::new(&data);
+        assert_eq!(reader.section(b"ONE ", |reader| reader.u16()), Ok(1));
+        assert_eq!(
+            reader.section(b"TWO ", |reader| Ok((reader.version(), reader.u8()?))),
+            Ok((3, 2))
+        );
+        assert!(
+            reader.section(b"ONE ", |reader| reader.u8()).is_err(),
+            "not read to the end"
+        );
+        assert_eq!(
+            reader.section(b"GONE", |reader| reader.u8()),
+            Err("Save state has no GONE section".to_string())
+        );
+    }
+}
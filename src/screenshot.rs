@@ -0,0 +1,47 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    paths::timestamped_file,
+    render::{
+        frame::{Frame, PixelFormat},
+        palette::SYSTEM_PALLETE,
+    },
+};
+
+/// Writes a frame of any size and pixel format to `path` as a PNG. Indexed frames become
+/// palette PNGs with the standard NES colors.
+pub fn save_png(frame: &Frame, path: &Path) -> Result<(), String> {
+    let file =
+        File::create(path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        frame.width as u32,
+        frame.height as u32,
+    );
+    match frame.format {
+        PixelFormat::Rgb24 => encoder.set_color(png::ColorType::Rgb),
+        PixelFormat::Rgba32 => encoder.set_color(png::ColorType::Rgba),
+        PixelFormat::Indexed => {
+            encoder.set_color(png::ColorType::Indexed);
+            let palette: Vec<u8> = SYSTEM_PALLETE
+                .iter()
+                .flat_map(|&(r, g, b)| [r, g, b])
+                .collect();
+            encoder.set_palette(palette);
+        }
+    }
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer
+        .write_image_data(&frame.data)
+        .map_err(|e| e.to_string())
+}
+
+/// `<dir>/<rom file stem>-<kind>-<unix time>.png`, creating `dir` if needed.
+pub fn screenshot_file(dir: &Path, rom_path: &str, kind: &str) -> Result<PathBuf, String> {
+    timestamped_file(dir, rom_path, kind, "png")
+}
@@ -0,0 +1,135 @@
+use sdl2::keyboard::Keycode;
+
+use crate::{
+    cartridge::{Console, Mirroring, Rom},
+    cpu::{Mem, CPU, PROGRAM_START},
+    render::frame::Frame,
+};
+
+// Memory-mapped I/O of the toy machine from the 6502 tutorials (easy6502, the snake game)
+/// A new random number from 1 to 15 before every instruction
+pub const RNG: u16 = 0xFE;
+/// ASCII code of the last key pressed
+pub const LAST_KEY: u16 = 0xFF;
+/// 32x32 pixels, one byte each, row by row
+pub const SCREEN: u16 = 0x0200;
+pub const SCREEN_SIZE: usize = 32;
+
+/// How many instructions run between screen updates. The toy programs have no vblank to wait
+/// for, so this sets the game speed.
+pub const INSTRUCTIONS_PER_FRAME: usize = 250;
+
+const NES_TAG: &[u8] = b"NES\x1A";
+// RAM is $0000-$07FF, so programs loaded at $0600 have to fit in what's left
+const MAX_PROGRAM_SIZE: usize = 0x0800 - PROGRAM_START as usize;
+
+/// Whether `raw` is a bare program to load at $0600 rather than an iNES file.
+pub fn is_raw_program(raw: &[u8]) -> bool {
+    !raw.starts_with(NES_TAG)
+}
+
+/// An empty cartridge whose reset vector points at $0600, to run a bare program from RAM.
+pub fn program_rom() -> Rom {
+    let mut prg_rom = vec![0; 0x8000];
+    prg_rom[0x7FFC..0x7FFE].copy_from_slice(&PROGRAM_START.to_le_bytes());
+    Rom {
+        prg_rom,
+        chr_rom: Vec::new(),
+        mapper: 0,
+        mirroring: Mirroring::HORIZONTAL,
+        region: None,
+        crc32: 0,
+        prg_crc32: 0,
+        chr_crc32: 0,
+        prg_ram_size: 0,
+        battery: false,
+        console: Console::Nes,
+    }
+}
+
+/// Copies a bare program to $0600.
+pub fn load_program(cpu: &mut CPU, program: &[u8]) -> Result<(), String> {
+    if program.len() > MAX_PROGRAM_SIZE {
+        return Err(format!(
+            "Program is {} bytes, at most {} fit at ${:04X}",
+            program.len(),
+            MAX_PROGRAM_SIZE,
+            PROGRAM_START
+        ));
+    }
+    for (i, byte) in program.iter().enumerate() {
+        cpu.mem_write(PROGRAM_START + i as u16, *byte);
+    }
+    Ok(())
+}
+
+/// The value a program sees at $FF for a key: W, A, S and D as their lowercase ASCII codes,
+/// like the tutorials expect.
+pub fn key_code(key: Keycode) -> Option<u8> {
+    match key {
+        Keycode::W | Keycode::Up => Some(b'w'),
+        Keycode::A | Keycode::Left => Some(b'a'),
+        Keycode::S | Keycode::Down => Some(b's'),
+        Keycode::D | Keycode::Right => Some(b'd'),
+        _ => None,
+    }
+}
+
+fn color(byte: u8) -> (u8, u8, u8) {
+    match byte & 0x0F {
+        0 => (0, 0, 0),
+        1 => (255, 255, 255),
+        2 | 9 => (128, 128, 128),
+        3 | 10 => (255, 0, 0),
+        4 | 11 => (0, 255, 0),
+        5 | 12 => (0, 0, 255),
+        6 | 13 => (255, 0, 255),
+        7 | 14 => (255, 255, 0),
+        _ => (0, 255, 255),
+    }
+}
+
+/// Draws the 32x32 screen at $0200 into `frame`. Returns whether anything changed.
+pub fn render_screen(cpu: &CPU, frame: &mut Frame) -> bool {
+    let mut changed = false;
+    for i in 0..SCREEN_SIZE * SCREEN_SIZE {
+        let rgb = color(cpu.bus.peek(SCREEN + i as u16));
+        let base = i * 3;
+        if frame.data[base..base + 3] != [rgb.0, rgb.1, rgb.2] {
+            frame.set_pixel(i % SCREEN_SIZE, i / SCREEN_SIZE, rgb);
+            changed = true;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bus::Bus, joypad::Joypad, ppu::NesPPU};
+
+    #[test]
+    fn test_raw_program_draws_random_pixel() {
+        let bus = Bus::new(
+            program_rom(),
+            |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {},
+        );
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        assert_eq!(cpu.program_counter, PROGRAM_START);
+
+        // LDA $FE; STA $0221; BRK
+        let program = [0xA5, 0xFE, 0x8D, 0x21, 0x02, 0x00];
+        assert!(is_raw_program(&program));
+        load_program(&mut cpu, &program).unwrap();
+        cpu.mem_write(RNG, 3);
+        while cpu.step() {}
+
+        let mut frame = Frame::with_size(SCREEN_SIZE, SCREEN_SIZE);
+        assert!(render_screen(&cpu, &mut frame));
+        assert_eq!(frame.data[(SCREEN_SIZE + 1) * 3..][..3], [255, 0, 0]);
+        assert!(!render_screen(&cpu, &mut frame));
+
+        assert!(load_program(&mut cpu, &[0; 0x201]).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::paths::rom_file;
+
+/// `<saves dir>/<rom file stem>.sav`, where battery RAM is kept between sessions. The file is
+/// a raw dump of PRG RAM, the same format other emulators and flash carts use.
+pub fn sav_file(saves: &Path, rom_path: &str) -> Result<PathBuf, String> {
+    rom_file(saves, rom_path, "sav")
+}
+
+/// Reads a save file, or `None` if there isn't one yet.
+pub fn read(path: &Path) -> Result<Option<Vec<u8>>, String> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+/// Writes a save file, or any other file that mustn't be left half written. Goes through a
+/// temporary file so a crash halfway through can't leave a truncated save behind.
+pub fn write(path: &Path, data: &[u8]) -> Result<(), String> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, data)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_then_read() {
+        let dir = std::env::temp_dir().join("rustnes_sram_test");
+        let path = sav_file(&dir, "roms/Zelda (U).nes").unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(read(&path), Ok(None));
+
+        write(&path, &[1, 2, 3]).unwrap();
+        assert_eq!(read(&path), Ok(Some(vec![1, 2, 3])));
+    }
+}
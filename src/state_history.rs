@@ -0,0 +1,104 @@
+use std::path::{Path, PathBuf};
+
+use crate::{paths::rom_file, sram};
+
+/// `<states dir>/<rom file stem>.state<slot>`
+pub fn slot_file(states: &Path, rom_path: &str, slot: u8) -> Result<PathBuf, String> {
+    rom_file(states, rom_path, &format!("state{}", slot))
+}
+
+/// The file holding the `age`th older version of a slot, `<slot file>.<age>`. Age 0 is the
+/// slot itself.
+fn version_file(path: &Path, age: usize) -> PathBuf {
+    if age == 0 {
+        return path.to_path_buf();
+    }
+    let mut file = path.as_os_str().to_owned();
+    file.push(format!(".{}", age));
+    PathBuf::from(file)
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> Result<(), String> {
+    if from.exists() {
+        std::fs::rename(from, to).map_err(|e| {
+            format!(
+                "Failed to move {} to {}: {}",
+                from.display(),
+                to.display(),
+                e
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Writes a state to its slot. Whatever was in the slot before is kept as `<slot file>.1`,
+/// pushing older versions back, and only the newest `history` of them are kept.
+pub fn save_slot(path: &Path, data: &[u8], history: usize) -> Result<(), String> {
+    if history > 0 {
+        let _ = std::fs::remove_file(version_file(path, history));
+        for age in (0..history).rev() {
+            rename_if_exists(&version_file(path, age), &version_file(path, age + 1))?;
+        }
+    }
+    sram::write(path, data)
+}
+
+/// Reads a slot (`age` 0) or one of its older versions, or `None` if there isn't one.
+pub fn read_slot(path: &Path, age: usize) -> Result<Option<Vec<u8>>, String> {
+    sram::read(&version_file(path, age))
+}
+
+/// The state the game was in right before the last state load, so a load over the wrong
+/// slot can be taken back.
+#[derive(Default)]
+pub struct UndoLoad {
+    before: Option<Vec<u8>>,
+}
+
+impl UndoLoad {
+    pub fn new() -> Self {
+        UndoLoad::default()
+    }
+
+    /// Call with the current state just before loading another one.
+    pub fn remember(&mut self, state: Vec<u8>) {
+        self.before = Some(state);
+    }
+
+    /// The state to go back to, if a state was loaded since the last undo.
+    pub fn take(&mut self) -> Option<Vec<u8>> {
+        self.before.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_overwriting_a_slot_keeps_history() {
+        let dir = std::env::temp_dir().join("rustnes_state_history_test");
+        let path = slot_file(&dir, "roms/Mega Man 2 (U).nes", 1).unwrap();
+        for age in 0..4 {
+            let _ = std::fs::remove_file(version_file(&path, age));
+        }
+
+        for data in 1..=4 {
+            save_slot(&path, &[data], 2).unwrap();
+        }
+        assert_eq!(read_slot(&path, 0), Ok(Some(vec![4])));
+        assert_eq!(read_slot(&path, 1), Ok(Some(vec![3])));
+        assert_eq!(read_slot(&path, 2), Ok(Some(vec![2])));
+        assert_eq!(read_slot(&path, 3), Ok(None));
+    }
+
+    #[test]
+    fn test_undo_load_only_once() {
+        let mut undo = UndoLoad::new();
+        assert_eq!(undo.take(), None);
+        undo.remember(vec![1, 2]);
+        assert_eq!(undo.take(), Some(vec![1, 2]));
+        assert_eq!(undo.take(), None);
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use serde_json::json;
+
+/// Longest gap between frames counted as play time. Anything longer is the game being paused,
+/// stuck in the debugger or waiting on a dialog.
+const MAX_FRAME_GAP: Duration = Duration::from_millis(250);
+/// How often Discord is told about changes, and retried when it isn't running
+const DISCORD_INTERVAL: Duration = Duration::from_secs(15);
+const DISCORD_HANDSHAKE: u32 = 0;
+const DISCORD_FRAME: u32 = 1;
+
+/// What the emulator is doing, as published to overlays and Discord.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Status {
+    /// The ROM's file name without the extension, or `None` before a game is loaded
+    pub title: Option<String>,
+    pub crc32: Option<String>,
+    /// Seconds the current game has been running, not counting pauses
+    pub play_time: u64,
+    pub paused: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    title: Option<String>,
+    crc32: Option<u32>,
+    played: Duration,
+    last_frame: Option<Instant>,
+}
+
+/// The current status, shared with the threads that publish it. Cloning gives another handle
+/// to the same status.
+#[derive(Clone, Default)]
+pub struct StatusHub {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl StatusHub {
+    pub fn new() -> Self {
+        StatusHub::default()
+    }
+
+    /// Starts counting play time for a new game.
+    pub fn start_game(&self, title: &str, crc32: u32) {
+        let mut shared = self.shared.lock().unwrap();
+        *shared = Shared {
+            title: Some(title.to_string()),
+            crc32: Some(crc32),
+            ..Shared::default()
+        };
+    }
+
+    /// Called after every frame the game runs.
+    pub fn frame(&self) {
+        let mut shared = self.shared.lock().unwrap();
+        let now = Instant::now();
+        if let Some(last) = shared.last_frame {
+            shared.played += now.duration_since(last).min(MAX_FRAME_GAP);
+        }
+        shared.last_frame = Some(now);
+    }
+
+    /// The game counts as paused when it hasn't run a frame for a while.
+    pub fn status(&self) -> Status {
+        let shared = self.shared.lock().unwrap();
+        Status {
+            title: shared.title.clone(),
+            crc32: shared.crc32.map(|crc| format!("{:08X}", crc)),
+            play_time: shared.played.as_secs(),
+            paused: shared
+                .last_frame
+                .is_none_or(|last| last.elapsed() > MAX_FRAME_GAP),
+        }
+    }
+}
+
+/// Serves the status as JSON to every HTTP request on `address`, from a background thread.
+/// Any path works; browser sources in streaming software can fetch it from another origin.
+pub fn serve_http(address: &str, hub: StatusHub) -> Result<(), String> {
+    let listener = TcpListener::bind(address)
+        .map_err(|e| format!("Failed to serve the status on {}: {}", address, e))?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = answer(stream, &hub) {
+                eprintln!("Status request failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn answer(mut stream: TcpStream, hub: &StatusHub) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    // The request itself doesn't matter, only that it has arrived
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") && request.len() < 16 * 1024 {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let body = serde_json::to_string(&hub.status()).unwrap_or_default();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+         Access-Control-Allow-Origin: *\r\nCache-Control: no-store\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+/// Shows the game as Discord Rich Presence under the application `client_id`, from a
+/// background thread. Discord doesn't have to be running; it's looked for every few seconds.
+pub fn start_discord(client_id: String, hub: StatusHub) {
+    thread::spawn(move || {
+        let mut connection = None;
+        let mut shown = None;
+        loop {
+            let status = hub.status();
+            let changed = shown.as_ref().is_none_or(|shown: &Status| {
+                shown.title != status.title || shown.paused != status.paused
+            });
+            if connection.is_none() {
+                connection = discord::connect(&client_id);
+                shown = None;
+            }
+            if let (Some(ipc), true) = (connection.as_mut(), changed || shown.is_none()) {
+                match discord::set_activity(ipc, &status) {
+                    Ok(()) => shown = Some(status),
+                    Err(_) => connection = None,
+                }
+            }
+            thread::sleep(DISCORD_INTERVAL);
+        }
+    });
+}
+
+/// A message to Discord's local IPC socket: opcode and length, little endian, then JSON.
+fn discord_message(opcode: u32, payload: &serde_json::Value) -> Vec<u8> {
+    let json = payload.to_string();
+    let mut message = Vec::with_capacity(8 + json.len());
+    message.extend(opcode.to_le_bytes());
+    message.extend((json.len() as u32).to_le_bytes());
+    message.extend(json.as_bytes());
+    message
+}
+
+fn activity(status: &Status) -> serde_json::Value {
+    let Some(title) = &status.title else {
+        return json!({ "details": "Choosing a game" });
+    };
+    if status.paused {
+        return json!({ "details": title, "state": "Paused" });
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    json!({
+        "details": title,
+        "state": "Playing",
+        "timestamps": { "start": now.saturating_sub(status.play_time) },
+    })
+}
+
+mod discord {
+    use super::*;
+
+    pub trait Ipc: Read + Write + Send {}
+    impl<T: Read + Write + Send> Ipc for T {}
+
+    pub fn connect(client_id: &str) -> Option<Box<dyn Ipc>> {
+        let mut ipc = open()?;
+        let handshake = json!({ "v": 1, "client_id": client_id });
+        ipc.write_all(&discord_message(DISCORD_HANDSHAKE, &handshake))
+            .ok()?;
+        read_reply(&mut ipc).ok()?;
+        Some(ipc)
+    }
+
+    pub fn set_activity(ipc: &mut Box<dyn Ipc>, status: &Status) -> std::io::Result<()> {
+        let command = json!({
+            "cmd": "SET_ACTIVITY",
+            "args": { "pid": std::process::id(), "activity": activity(status) },
+            "nonce": format!("{}", status.play_time),
+        });
+        ipc.write_all(&discord_message(DISCORD_FRAME, &command))?;
+        read_reply(ipc)
+    }
+
+    fn read_reply(ipc: &mut Box<dyn Ipc>) -> std::io::Result<()> {
+        let mut header = [0; 8];
+        ipc.read_exact(&mut header)?;
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        std::io::copy(&mut ipc.take(length as u64), &mut std::io::sink())?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn open() -> Option<Box<dyn Ipc>> {
+        use std::os::unix::net::UnixStream;
+
+        let dirs: Vec<String> = ["XDG_RUNTIME_DIR", "TMPDIR", "TMP", "TEMP"]
+            .iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .chain(std::iter::once("/tmp".to_string()))
+            .collect();
+        // Flatpak and Snap installs put the socket in a subdirectory
+        for dir in &dirs {
+            for subdir in ["", "app/com.discordapp.Discord/", "snap.discord/"] {
+                for n in 0..10 {
+                    let path = format!("{}/{}discord-ipc-{}", dir, subdir, n);
+                    if let Ok(stream) = UnixStream::connect(&path) {
+                        stream.set_read_timeout(Some(Duration::from_secs(5))).ok()?;
+                        return Some(Box::new(stream));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    #[cfg(windows)]
+    fn open() -> Option<Box<dyn Ipc>> {
+        (0..10).find_map(|n| {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(format!(r"\\?\pipe\discord-ipc-{}", n))
+                .ok()
+                .map(|pipe| Box::new(pipe) as Box<dyn Ipc>)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status_over_http() {
+        let hub = StatusHub::new();
+        assert_eq!(hub.status().title, None);
+        assert!(hub.status().paused);
+        hub.start_game("Pac-Man (USA)", 0xABC);
+        hub.frame();
+        hub.frame();
+        assert!(!hub.status().paused);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_hub = hub.clone();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            answer(stream, &server_hub).unwrap();
+        });
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        let status: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(status["title"], "Pac-Man (USA)");
+        assert_eq!(status["crc32"], "00000ABC");
+        assert_eq!(status["paused"], false);
+    }
+
+    #[test]
+    fn test_discord_messages() {
+        let message = discord_message(DISCORD_FRAME, &json!({ "cmd": "X" }));
+        assert_eq!(&message[..8], &[1, 0, 0, 0, 11, 0, 0, 0]);
+        assert_eq!(&message[8..], br#"{"cmd":"X"}"#);
+
+        let status = Status {
+            title: Some("Zelda".to_string()),
+            crc32: None,
+            play_time: 60,
+            paused: true,
+        };
+        assert_eq!(
+            activity(&status),
+            json!({ "details": "Zelda", "state": "Paused" })
+        );
+    }
+}
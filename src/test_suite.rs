@@ -0,0 +1,306 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    rc::Rc,
+};
+
+use crate::{
+    bus::Bus,
+    cartridge::Rom,
+    cpu::CPU,
+    error::EmulationError,
+    headless::frame_hash,
+    joypad::Joypad,
+    playlist::rom_files,
+    ppu::NesPPU,
+    region::{self, Region},
+    render::{frame::Frame, Renderer},
+};
+
+/// Expected final frames for ROMs that don't report through $6000, one `<file> <frames>
+/// <hash>` line each
+pub const HASHES_FILE_NAME: &str = "hashes.txt";
+
+// Status protocol of blargg's test ROMs: $6000 holds the status, $6001-$6003 a signature that
+// says the protocol is in use, and $6004 on a NUL-terminated message
+const STATUS: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const MESSAGE: u16 = 0x6004;
+const MAX_MESSAGE_LENGTH: u16 = 0x1000;
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_NEEDS_RESET: u8 = 0x81;
+// The ROMs ask for the reset button to be held at least 100ms
+const RESET_DELAY_FRAMES: usize = 6;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Passed,
+    /// The ROM's result code and message, or the frame hash that didn't match
+    Failed(String),
+    /// Neither a $6000 result nor an expected hash; the final frame's hash is kept so it can
+    /// be added to the hashes file once checked by eye
+    NoResult {
+        frames: usize,
+        hash: u64,
+    },
+    /// The ROM couldn't be loaded or emulation crashed
+    Error(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "PASS"),
+            Outcome::Failed(reason) => write!(f, "FAIL  {}", reason),
+            Outcome::NoResult { frames, hash } => {
+                write!(
+                    f,
+                    "????  no result, frame {} hashed to {:016x}",
+                    frames, hash
+                )
+            }
+            Outcome::Error(reason) => write!(f, "ERROR {}", reason),
+        }
+    }
+}
+
+/// Parses a hashes file into file name -> (frames, hash).
+pub fn parse_hashes(text: &str) -> Result<HashMap<String, (usize, u64)>, String> {
+    let mut hashes = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || format!("Line {}: expected `<file> <frames> <hash>`", number + 1);
+        // File names can contain spaces, so the numbers are taken from the end
+        let mut fields = line.rsplitn(3, char::is_whitespace);
+        let hash = fields.next().ok_or_else(invalid)?;
+        let frames = fields.next().ok_or_else(invalid)?;
+        let name = fields.next().ok_or_else(invalid)?.trim();
+        let hash = u64::from_str_radix(hash.trim_start_matches("0x"), 16).map_err(|_| invalid())?;
+        let frames = frames.parse().map_err(|_| invalid())?;
+        hashes.insert(name.to_string(), (frames, hash));
+    }
+    Ok(hashes)
+}
+
+fn message(cpu: &CPU) -> String {
+    let bytes: Vec<u8> = (MESSAGE..MESSAGE + MAX_MESSAGE_LENGTH)
+        .map(|addr| cpu.bus.peek(addr))
+        .take_while(|&byte| byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+/// Runs a ROM until it reports a result at $6000, for `expected`'s frame count if given, or
+/// for at most `max_frames`.
+pub fn run_rom(
+    rom: Rom,
+    region: Region,
+    expected: Option<(usize, u64)>,
+    max_frames: usize,
+) -> Outcome {
+    let frame = Rc::new(RefCell::new(Frame::new()));
+    let target = Rc::clone(&frame);
+    let mut renderer = Renderer::new();
+    let bus = match Bus::try_new(rom, move |ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {
+        renderer.render(ppu, &mut target.borrow_mut());
+    }) {
+        Ok(bus) => bus,
+        Err(e) => return Outcome::Error(e.to_string()),
+    };
+    let mut cpu = CPU::new(bus);
+    cpu.bus.set_region(region);
+    cpu.reset();
+
+    let frames = expected.map_or(max_frames, |(frames, _)| frames);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut reset_at = None;
+        let mut last_frame = 0;
+        while cpu.bus.frame_count() < frames {
+            if !cpu.step() {
+                return Some(Outcome::Error("CPU executed BRK".to_string()));
+            }
+            if cpu.bus.frame_count() == last_frame {
+                continue;
+            }
+            last_frame = cpu.bus.frame_count();
+
+            let signature = [1, 2, 3].map(|i| cpu.bus.peek(STATUS + i));
+            if signature != SIGNATURE {
+                continue;
+            }
+            match cpu.bus.peek(STATUS) {
+                STATUS_RUNNING => {}
+                STATUS_NEEDS_RESET => {
+                    let at = *reset_at.get_or_insert(last_frame + RESET_DELAY_FRAMES);
+                    if last_frame >= at {
+                        reset_at = None;
+                        cpu.reset();
+                    }
+                }
+                0 => return Some(Outcome::Passed),
+                code => {
+                    return Some(Outcome::Failed(format!(
+                        "result {}: {}",
+                        code,
+                        message(&cpu)
+                    )))
+                }
+            }
+        }
+        None
+    }));
+
+    match result {
+        Ok(Some(outcome)) => outcome,
+        Ok(None) => {
+            let hash = frame_hash(&frame.borrow());
+            match expected {
+                Some((_, expected)) if hash == expected => Outcome::Passed,
+                Some((frames, expected)) => Outcome::Failed(format!(
+                    "frame {} hashed to {:016x}, expected {:016x}",
+                    frames, hash, expected
+                )),
+                None => Outcome::NoResult { frames, hash },
+            }
+        }
+        Err(payload) => Outcome::Error(EmulationError::from_panic(&*payload, &cpu).to_string()),
+    }
+}
+
+/// Runs every .nes file in `dir`, sorted by name, calling `on_result` as each one finishes.
+pub fn run_dir<F>(dir: &Path, max_frames: usize, mut on_result: F) -> Result<Vec<Outcome>, String>
+where
+    F: FnMut(&str, &Outcome),
+{
+    let hashes_file = dir.join(HASHES_FILE_NAME);
+    let hashes = match std::fs::read_to_string(&hashes_file) {
+        Ok(text) => parse_hashes(&text).map_err(|e| format!("{}: {}", hashes_file.display(), e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", hashes_file.display(), e)),
+    };
+
+    let roms = rom_files(dir)?;
+
+    // The ROMs' own panics are reported as results, not printed over the report
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let outcomes = roms
+        .iter()
+        .map(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let outcome = match std::fs::read(path)
+                .map_err(|e| e.to_string())
+                .and_then(|raw| Rom::new(&raw))
+            {
+                Ok(rom) => {
+                    let region = region::detect(rom.region, &path.to_string_lossy());
+                    run_rom(rom, region, hashes.get(name.as_ref()).copied(), max_frames)
+                }
+                Err(e) => Outcome::Error(e),
+            };
+            on_result(&name, &outcome);
+            outcome
+        })
+        .collect();
+    panic::set_hook(hook);
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::{Console, Mirroring};
+
+    /// An NROM cartridge with 8KB of PRG RAM running `program` from $8000.
+    fn rom_with_program(program: &[u8]) -> Rom {
+        let mut prg_rom = vec![0xEA; 0x8000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0x80]);
+        Rom {
+            prg_rom,
+            chr_rom: vec![0; 0x2000],
+            mapper: 0,
+            mirroring: Mirroring::HORIZONTAL,
+            region: None,
+            crc32: 0,
+            prg_crc32: 0,
+            chr_crc32: 0,
+            prg_ram_size: 0x2000,
+            battery: false,
+            console: Console::Nes,
+        }
+    }
+
+    /// A program that writes the $6000 signature, "hi" as the message and `status`, then
+    /// loops forever.
+    fn reporting_program(status: u8) -> Vec<u8> {
+        let mut program = Vec::new();
+        for (addr, value) in [
+            (0x6001u16, 0xDE),
+            (0x6002, 0xB0),
+            (0x6003, 0x61),
+            (0x6004, b'h'),
+            (0x6005, b'i'),
+            (0x6006, 0),
+            (0x6000, status),
+        ] {
+            // LDA #value; STA addr
+            program.extend([0xA9, value, 0x8D, addr as u8, (addr >> 8) as u8]);
+        }
+        // JMP to itself
+        let here = 0x8000 + program.len() as u16;
+        program.extend([0x4C, here as u8, (here >> 8) as u8]);
+        program
+    }
+
+    #[test]
+    fn test_status_protocol() {
+        let outcome = run_rom(
+            rom_with_program(&reporting_program(0)),
+            Region::NTSC,
+            None,
+            10,
+        );
+        assert_eq!(outcome, Outcome::Passed);
+        let outcome = run_rom(
+            rom_with_program(&reporting_program(3)),
+            Region::NTSC,
+            None,
+            10,
+        );
+        assert_eq!(outcome, Outcome::Failed("result 3: hi".to_string()));
+    }
+
+    #[test]
+    fn test_hash_expectations() {
+        let rom = || rom_with_program(&[0x4C, 0x00, 0x80]);
+        let Outcome::NoResult { frames, hash } = run_rom(rom(), Region::NTSC, None, 5) else {
+            panic!("a ROM that only loops has no result");
+        };
+        assert_eq!(frames, 5);
+        assert_eq!(
+            run_rom(rom(), Region::NTSC, Some((5, hash)), 100),
+            Outcome::Passed
+        );
+        assert!(matches!(
+            run_rom(rom(), Region::NTSC, Some((5, hash ^ 1)), 100),
+            Outcome::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn test_parse_hashes() {
+        let hashes =
+            parse_hashes("# comment\nsprite hit.nes 120 0x71e889439230f6c0\n\nb.nes 5 ff\n")
+                .unwrap();
+        assert_eq!(hashes["sprite hit.nes"], (120, 0x71e889439230f6c0));
+        assert_eq!(hashes["b.nes"], (5, 0xff));
+        assert!(parse_hashes("a.nes 5").is_err());
+    }
+}
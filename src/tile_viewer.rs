@@ -1,10 +1,25 @@
+use std::path::{Path, PathBuf};
+
 use sdl2::{event::Event, keyboard::Keycode, pixels::PixelFormatEnum};
 
 use crate::{
     cartridge::Rom,
-    render::{frame::Frame, palette::SYSTEM_PALLETE},
+    paths::rom_file,
+    ppu::tile_cache::decode_tile_row,
+    render::{
+        frame::{Frame, PixelFormat},
+        palette::SYSTEM_PALLETE,
+    },
+    screenshot::save_png,
 };
 
+/// NES colors for pixel values 0-3 when no game palette is picked.
+pub const DEBUG_PALETTE: [u8; 4] = [0x01, 0x23, 0x27, 0x30];
+
+const PATTERN_TABLE_SIZE: usize = 0x1000;
+// Tiles per row and column of an exported sheet
+const SHEET_TILES: usize = 16;
+
 pub fn show_tile(chr_rom: &Vec<u8>, bank: usize, tile_n: usize) -> Frame {
     assert!(bank <= 1);
 
@@ -14,26 +29,10 @@ pub fn show_tile(chr_rom: &Vec<u8>, bank: usize, tile_n: usize) -> Frame {
     let tile = &chr_rom[(bank + tile_n * 16)..=(bank + tile_n * 16 + 15)];
 
     for y in 0..=7 {
-        let mut upper = tile[y];
-        let mut lower = tile[y + 8];
-
-        for x in (0..=7).rev() {
-            let color = ((upper & 1) << 1) | (lower & 1);
-            upper >>= 1;
-            lower >>= 1;
-
-            let rgb = match color {
-                0b00 => SYSTEM_PALLETE[0x01],
-                0b01 => SYSTEM_PALLETE[0x23],
-                0b10 => SYSTEM_PALLETE[0x27],
-                0b11 => SYSTEM_PALLETE[0x30],
-                _ => panic!(
-                    "Color can only be 0b00, 0b01, 0b10 or 0b11. Got 0b{:b}",
-                    color
-                ),
-            };
-
-            frame.set_pixel(x, y, rgb);
+        let row = decode_tile_row(tile[y], tile[y + 8]);
+
+        for (x, &color) in row.iter().enumerate() {
+            frame.set_color(x, y, DEBUG_PALETTE[color as usize], &SYSTEM_PALLETE);
         }
     }
 
@@ -57,23 +56,11 @@ pub fn show_tile_bank(chr_rom: &Vec<u8>, bank: usize) -> Frame {
         let tile = &chr_rom[(bank + tile_n * 16)..=(bank + tile_n * 16 + 15)];
 
         for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-
-            for x in (0..=7).rev() {
-                let color = ((upper & 1) << 1) | (lower & 1);
-                upper >>= 1;
-                lower >>= 1;
-
-                let rgb = match color {
-                    0b00 => SYSTEM_PALLETE[0x01],
-                    0b01 => SYSTEM_PALLETE[0x23],
-                    0b10 => SYSTEM_PALLETE[0x27],
-                    0b11 => SYSTEM_PALLETE[0x30],
-                    _ => unreachable!(),
-                };
-
-                frame.set_pixel(tile_x + x, tile_y + y, rgb);
+            let row = decode_tile_row(tile[y], tile[y + 8]);
+
+            for (x, &color) in row.iter().enumerate() {
+                let color = DEBUG_PALETTE[color as usize];
+                frame.set_color(tile_x + x, tile_y + y, color, &SYSTEM_PALLETE);
             }
         }
         tile_x += 10;
@@ -82,6 +69,116 @@ pub fn show_tile_bank(chr_rom: &Vec<u8>, bank: usize) -> Frame {
     frame
 }
 
+/// One 4KB pattern table as a 16x16 tile sheet (128x128 pixels), colored with four NES
+/// palette indices.
+pub fn tile_sheet(pattern_table: &[u8], palette: [u8; 4], format: PixelFormat) -> Frame {
+    let mut frame = Frame::with_format(SHEET_TILES * 8, SHEET_TILES * 8, format);
+    for (tile_n, tile) in pattern_table.chunks_exact(16).enumerate() {
+        let tile_x = tile_n % SHEET_TILES * 8;
+        let tile_y = tile_n / SHEET_TILES * 8;
+        for y in 0..8 {
+            let row = decode_tile_row(tile[y], tile[y + 8]);
+            for (x, &color) in row.iter().enumerate() {
+                let color = palette[color as usize];
+                frame.set_color(tile_x + x, tile_y + y, color, &SYSTEM_PALLETE);
+            }
+        }
+    }
+    frame
+}
+
+/// Colors for an exported tile sheet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SheetPalette {
+    Debug,
+    /// Four NES color indices
+    Colors([u8; 4]),
+    /// One of the game's eight palettes, read from palette RAM after it has run a while
+    Game {
+        sprite: bool,
+        index: usize,
+    },
+}
+
+impl SheetPalette {
+    /// Parses `debug`, a game palette from `bg0` to `bg3` or `sp0` to `sp3`, or four NES
+    /// colors in hex like `0F,16,27,30`.
+    pub fn parse(text: &str) -> Result<SheetPalette, String> {
+        let text = text.trim().to_ascii_lowercase();
+        if text == "debug" {
+            return Ok(SheetPalette::Debug);
+        }
+        let game = |prefix: &str, sprite: bool| {
+            let index = text.strip_prefix(prefix)?.parse().ok()?;
+            (index < 4).then_some(SheetPalette::Game { sprite, index })
+        };
+        if let Some(palette) = game("bg", false).or_else(|| game("sp", true)) {
+            return Ok(palette);
+        }
+
+        let colors = text
+            .split(',')
+            .map(|color| {
+                u8::from_str_radix(color.trim().trim_start_matches('$'), 16)
+                    .ok()
+                    .filter(|&color| color < 0x40)
+                    .ok_or_else(|| format!("Invalid NES color: {}", color))
+            })
+            .collect::<Result<Vec<u8>, String>>()?;
+        colors.try_into().map(SheetPalette::Colors).map_err(|_| {
+            "A palette is debug, bg0-bg3, sp0-sp3 or four colors like 0F,16,27,30".to_string()
+        })
+    }
+
+    pub fn is_game(&self) -> bool {
+        matches!(self, SheetPalette::Game { .. })
+    }
+
+    /// The four NES colors, taking game palettes from `palette_table` (the PPU's palette RAM).
+    pub fn colors(&self, palette_table: &[u8; 32]) -> [u8; 4] {
+        match *self {
+            SheetPalette::Debug => DEBUG_PALETTE,
+            SheetPalette::Colors(colors) => colors,
+            SheetPalette::Game { sprite, index } => {
+                let start = if sprite { 0x11 } else { 0x01 } + index * 4;
+                [
+                    palette_table[0],
+                    palette_table[start],
+                    palette_table[start + 1],
+                    palette_table[start + 2],
+                ]
+            }
+        }
+    }
+}
+
+/// Writes every 4KB pattern table in `chr` as a tile sheet to `<dir>/<rom name>-chr<n>.png`.
+pub fn export_sheets(
+    chr: &[u8],
+    rom_path: &str,
+    dir: &Path,
+    palette: [u8; 4],
+) -> Result<Vec<PathBuf>, String> {
+    if chr.is_empty() {
+        return Err(
+            "The cartridge has no CHR ROM; run the game with --frames to export its CHR RAM"
+                .to_string(),
+        );
+    }
+    let file = rom_file(dir, rom_path, "png")?;
+    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+    chr.chunks(PATTERN_TABLE_SIZE)
+        .enumerate()
+        .map(|(n, pattern_table)| {
+            let path = dir.join(format!("{}-chr{}.png", stem, n));
+            // Indexed, as the sheets only use NES colors; makes for small PNGs
+            let sheet = tile_sheet(pattern_table, palette, PixelFormat::Indexed);
+            save_png(&sheet, &path)?;
+            Ok(path)
+        })
+        .collect()
+}
+
 pub fn display_tile_bank(rom_path: &str, bank: usize) {
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -106,7 +203,9 @@ pub fn display_tile_bank(rom_path: &str, bank: usize) {
 
     let tile_frame = show_tile_bank(&cartridge.chr_rom, bank);
 
-    texture.update(None, &tile_frame.data, 256 * 3).unwrap();
+    texture
+        .update(None, &tile_frame.data, tile_frame.pitch())
+        .unwrap();
     canvas.copy(&texture, None, None).unwrap();
     canvas.present();
 
@@ -123,3 +222,49 @@ pub fn display_tile_bank(rom_path: &str, bank: usize) {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sheet_palette() {
+        assert_eq!(SheetPalette::parse("debug"), Ok(SheetPalette::Debug));
+        assert_eq!(
+            SheetPalette::parse("0F, $16,27,30"),
+            Ok(SheetPalette::Colors([0x0F, 0x16, 0x27, 0x30]))
+        );
+        assert!(SheetPalette::parse("0F,16,27").is_err());
+        assert!(SheetPalette::parse("0F,16,27,40").is_err());
+        assert!(SheetPalette::parse("bg4").is_err());
+
+        let mut palette_table = [0; 32];
+        palette_table[0] = 0x0F;
+        palette_table[0x15..0x18].copy_from_slice(&[0x16, 0x27, 0x30]);
+        assert_eq!(
+            SheetPalette::parse("SP1").unwrap().colors(&palette_table),
+            [0x0F, 0x16, 0x27, 0x30]
+        );
+    }
+
+    #[test]
+    fn test_tile_sheet_layout() {
+        let mut pattern_table = vec![0; PATTERN_TABLE_SIZE];
+        // Tile 17: second row, second column, top-left pixel set in the low plane
+        pattern_table[17 * 16] = 0x80;
+        let sheet = tile_sheet(&pattern_table, [0x0F, 0x30, 0x30, 0x30], PixelFormat::Rgb24);
+        assert_eq!((sheet.width, sheet.height), (128, 128));
+        assert_eq!(sheet.pixel(8, 8), SYSTEM_PALLETE[0x30]);
+        assert_eq!(sheet.pixel(9, 8), SYSTEM_PALLETE[0x0F]);
+
+        // The same sheet as NES color indices
+        let sheet = tile_sheet(
+            &pattern_table,
+            [0x0F, 0x30, 0x30, 0x30],
+            PixelFormat::Indexed,
+        );
+        assert_eq!(sheet.data.len(), 128 * 128);
+        assert_eq!(sheet.data[8 * 128 + 8], 0x30);
+        assert_eq!(sheet.pixel(9, 8), SYSTEM_PALLETE[0x0F]);
+    }
+}
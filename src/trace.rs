@@ -20,7 +20,8 @@ pub fn trace(cpu: &mut CPU) -> String {
         | AddressingMode::Accumulator => (0, 0),
         _ => {
             let (addr, _) = cpu.get_actual_address(&opcode.addr_mode, begin + 1);
-            (addr, cpu.mem_read(addr))
+            // Not mem_read: tracing `LDA $2002` must not clear vblank before the LDA runs
+            (addr, cpu.bus.peek(addr))
         }
     };
 
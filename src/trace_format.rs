@@ -0,0 +1,299 @@
+use std::{fmt::Write as _, str::FromStr};
+
+/// Column layout of a CPU trace log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// This emulator's debugger and nestest.log:
+    /// `C000  4C F5 C5  JMP $C5F5    A:00 X:00 Y:00 P:24 SP:FD CYC:7`
+    Nestest,
+    /// FCEUX's trace logger: `$C000:4C F5 C5  JMP $C5F5    A:00 X:00 Y:00 S:FD P:nvUbdIzc`
+    Fceux,
+    /// Mesen's trace logger:
+    /// `C000  $4C $F5 $C5  JMP $C5F5    A:00 X:00 Y:00 S:FD P:nvUbdIzc Cycle:7`
+    Mesen,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nestest" | "rustnes" => Ok(TraceFormat::Nestest),
+            "fceux" => Ok(TraceFormat::Fceux),
+            "mesen" => Ok(TraceFormat::Mesen),
+            _ => Err(format!(
+                "Unknown trace format: {} (try nestest, fceux or mesen)",
+                s
+            )),
+        }
+    }
+}
+
+/// Column the registers start at, so they line up whatever the instruction
+const REGISTER_COLUMN: usize = 48;
+const FLAG_LETTERS: &[u8; 8] = b"nvubdizc";
+
+/// One instruction from a trace log, in whatever layout it was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceLine {
+    pub program_counter: u16,
+    /// The instruction's bytes, when the log has them
+    pub bytes: Vec<u8>,
+    pub disassembly: String,
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub status: u8,
+    pub stack_pointer: u8,
+    /// CPU cycles since power on, when the log has them
+    pub cycles: Option<u64>,
+}
+
+impl TraceLine {
+    /// Reads a line in any of the `TraceFormat` layouts. Returns `None` for lines that aren't
+    /// instructions, like headers and FCEUX's "Log Start" markers.
+    pub fn parse(line: &str) -> Option<TraceLine> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let (start, program_counter, first_byte) = find_address(&tokens)?;
+        let mut rest = &tokens[start + 1..];
+
+        let mut bytes: Vec<u8> = first_byte.into_iter().collect();
+        while let Some(byte) = rest.first().and_then(|token| parse_byte(token)) {
+            if bytes.len() == 3 {
+                break;
+            }
+            bytes.push(byte);
+            rest = &rest[1..];
+        }
+
+        let registers_at = rest.iter().position(|token| token.starts_with("A:"))?;
+        let disassembly = rest[..registers_at].join(" ");
+        let mut registers = [None; 5];
+        let mut cycles = None;
+        for token in &rest[registers_at..] {
+            let Some((key, value)) = token.split_once(':') else {
+                continue;
+            };
+            let slot = match key {
+                "A" => 0,
+                "X" => 1,
+                "Y" => 2,
+                "P" => {
+                    registers[3] = parse_status(value);
+                    continue;
+                }
+                "S" | "SP" => 4,
+                "CYC" | "Cycle" => {
+                    cycles = value.parse().ok();
+                    continue;
+                }
+                _ => continue,
+            };
+            registers[slot] = u8::from_str_radix(value, 16).ok();
+        }
+        let [a, x, y, p, s] = registers;
+        Some(TraceLine {
+            program_counter,
+            bytes,
+            disassembly,
+            register_a: a?,
+            register_x: x?,
+            register_y: y?,
+            status: p?,
+            stack_pointer: s?,
+            cycles,
+        })
+    }
+
+    /// Writes the line in `format`. Without `disassembly` the instruction text is left out,
+    /// since emulators don't agree on how to show operands; logs from different emulators
+    /// then diff cleanly on everything else.
+    pub fn format(&self, format: TraceFormat, disassembly: bool) -> String {
+        let bytes = |prefix: &str| {
+            self.bytes
+                .iter()
+                .map(|byte| format!("{}{:02X}", prefix, byte))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let text = if disassembly {
+            self.disassembly.as_str()
+        } else {
+            ""
+        };
+        let mut line = match format {
+            // Mnemonics right-aligned, so unofficial opcodes' `*` sits in the gap
+            TraceFormat::Nestest => {
+                let (mnemonic, operands) = text.split_once(' ').unwrap_or((text, ""));
+                format!(
+                    "{:04X}  {:8} {:>4} {}",
+                    self.program_counter,
+                    bytes(""),
+                    mnemonic,
+                    operands
+                )
+            }
+            TraceFormat::Fceux => {
+                format!("${:04X}:{:8}  {}", self.program_counter, bytes(""), text)
+            }
+            TraceFormat::Mesen => {
+                format!("{:04X}  {:11}  {}", self.program_counter, bytes("$"), text)
+            }
+        };
+        let width = line.trim_end().len().max(REGISTER_COLUMN - 1);
+        line.truncate(line.trim_end().len());
+        let _ = write!(
+            line,
+            "{:pad$} A:{:02X} X:{:02X} Y:{:02X} ",
+            "",
+            self.register_a,
+            self.register_x,
+            self.register_y,
+            pad = width - line.len()
+        );
+        let _ = match format {
+            TraceFormat::Nestest => {
+                write!(line, "P:{:02X} SP:{:02X}", self.status, self.stack_pointer)
+            }
+            TraceFormat::Fceux | TraceFormat::Mesen => write!(
+                line,
+                "S:{:02X} P:{}",
+                self.stack_pointer,
+                format_status(self.status)
+            ),
+        };
+        match (format, self.cycles) {
+            (TraceFormat::Nestest, Some(cycles)) => {
+                let _ = write!(line, " CYC:{}", cycles);
+            }
+            (TraceFormat::Mesen, Some(cycles)) => {
+                let _ = write!(line, " Cycle:{}", cycles);
+            }
+            _ => {}
+        }
+        line
+    }
+}
+
+/// Where the instruction's address is: `$C000:4C` in FCEUX logs, which may have counters
+/// before it, or a bare `C000` starting the line. Returns the token's index, the address and,
+/// for FCEUX, the first byte that shares its token.
+fn find_address(tokens: &[&str]) -> Option<(usize, u16, Option<u8>)> {
+    for (i, token) in tokens.iter().enumerate() {
+        if let Some((address, byte)) = token
+            .strip_prefix('$')
+            .and_then(|token| token.split_once(':'))
+        {
+            let address = parse_address(address)?;
+            return Some((i, address, parse_byte(byte)));
+        }
+    }
+    let first = tokens.first()?;
+    Some((0, parse_address(first)?, None))
+}
+
+fn parse_address(token: &str) -> Option<u16> {
+    if token.len() != 4 {
+        return None;
+    }
+    u16::from_str_radix(token, 16).ok()
+}
+
+fn parse_byte(token: &str) -> Option<u8> {
+    let token = token.strip_prefix('$').unwrap_or(token);
+    if token.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(token, 16).ok()
+}
+
+/// The status register as two hex digits or as flag letters like `nvUbdIzc`, capitals set.
+fn parse_status(value: &str) -> Option<u8> {
+    if value.len() == 2 {
+        return u8::from_str_radix(value, 16).ok();
+    }
+    if value.len() != 8 {
+        return None;
+    }
+    let mut status = 0;
+    for (i, letter) in value.bytes().enumerate() {
+        if letter.to_ascii_lowercase() != FLAG_LETTERS[i] && letter != b'-' {
+            return None;
+        }
+        if letter.is_ascii_uppercase() {
+            status |= 0x80 >> i;
+        }
+    }
+    Some(status)
+}
+
+fn format_status(status: u8) -> String {
+    FLAG_LETTERS
+        .iter()
+        .enumerate()
+        .map(|(i, &letter)| {
+            if status & (0x80 >> i) != 0 {
+                letter.to_ascii_uppercase() as char
+            } else {
+                letter as char
+            }
+        })
+        .collect()
+}
+
+/// Rewrites every instruction in `log` in `format`. Returns the new log and how many lines
+/// were dropped for not being instructions.
+pub fn convert(log: &str, format: TraceFormat, disassembly: bool) -> (String, usize) {
+    let mut out = String::new();
+    let mut skipped = 0;
+    for line in log.lines() {
+        match TraceLine::parse(line) {
+            Some(trace_line) => {
+                out.push_str(&trace_line.format(format, disassembly));
+                out.push('\n');
+            }
+            None => skipped += 1,
+        }
+    }
+    (out, skipped)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_converting_between_layouts() {
+        let nestest = "C72D  90 04     BCC $C733                       A:00 X:00 Y:00 P:27 SP:FB PPU:  4,185 CYC:128";
+        let line = TraceLine::parse(nestest).unwrap();
+        assert_eq!(line.program_counter, 0xC72D);
+        assert_eq!(line.bytes, [0x90, 0x04]);
+        assert_eq!(line.disassembly, "BCC $C733");
+        assert_eq!((line.status, line.stack_pointer), (0x27, 0xFB));
+        assert_eq!(line.cycles, Some(128));
+
+        let fceux = line.format(TraceFormat::Fceux, true);
+        assert_eq!(
+            fceux,
+            "$C72D:90 04     BCC $C733                       A:00 X:00 Y:00 S:FB P:nvUbdIZC"
+        );
+        let mesen = line.format(TraceFormat::Mesen, false);
+        assert_eq!(
+            mesen,
+            "C72D  $90 $04                                   A:00 X:00 Y:00 S:FB P:nvUbdIZC Cycle:128"
+        );
+        for text in [&fceux, &mesen] {
+            let back = TraceLine::parse(text).unwrap();
+            assert_eq!(back.status, 0x27);
+            assert_eq!(back.bytes, line.bytes);
+        }
+        assert_eq!(TraceLine::parse(&fceux).unwrap().cycles, None);
+
+        // FCEUX counters before the address, and a line that isn't an instruction
+        let counted = "c7         i0  $C000:4C F5 C5  JMP $C5F5  A:00 X:00 Y:00 S:FD P:nvUbdIzc";
+        let line = TraceLine::parse(counted).unwrap();
+        assert_eq!((line.program_counter, line.status), (0xC000, 0x24));
+        assert_eq!(line.disassembly, "JMP $C5F5");
+        assert_eq!(convert("Log Start\n", TraceFormat::Nestest, true).1, 1);
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ppu::NesPPU,
+    render::{
+        self,
+        frame::{Frame, PixelFormat},
+        palette::Colors,
+    },
+};
+
+/// The kind of file ffmpeg makes of a recording.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    #[default]
+    Mp4,
+    Mkv,
+}
+
+impl Container {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "mkv",
+        }
+    }
+}
+
+/// What the emulation loop sends the writing thread.
+enum Chunk {
+    /// NES color indices
+    Frame(Vec<u8>),
+    Samples(Vec<f32>),
+}
+
+/// The sound's sample rate and channel count.
+pub type AudioFormat = (u32, u8);
+
+/// Records the picture and sound of a game as the emulator makes them, one video frame for
+/// every emulated frame at the console's frame rate, so fast-forwarded and slowed down parts
+/// come out at normal speed. They're written to a Y4M and a WAV file on another thread, which
+/// ffmpeg joins into `path` (an .mp4 or .mkv) once the recording stops. Without ffmpeg, or if
+/// it fails, the two files are kept. Dropping the recorder stops it.
+pub struct VideoRecorder {
+    sender: Sender<Chunk>,
+    frame: Frame,
+}
+
+impl VideoRecorder {
+    /// Starts recording to `path`, with the picture in `colors` and, if there's sound,
+    /// samples in `audio`'s format. `ffmpeg` is the program to encode with, `None` to only
+    /// write the Y4M and WAV files. The writing thread is returned to wait for before
+    /// quitting, as encoding can take a while after the recording stops.
+    pub fn start(
+        path: PathBuf,
+        frame_rate: f64,
+        audio: Option<AudioFormat>,
+        colors: &Colors,
+        ffmpeg: Option<PathBuf>,
+    ) -> Result<(Self, JoinHandle<()>), String> {
+        let video_path = path.with_extension("y4m");
+        let audio_path = path.with_extension("wav");
+        let create = |path: &Path| {
+            File::create(path)
+                .map(BufWriter::new)
+                .map_err(|e| format!("Failed to create {}: {}", path.display(), e))
+        };
+        let mut video = create(&video_path)?;
+        let mut wav = audio
+            .map(|format| {
+                let mut file = create(&audio_path)?;
+                write_wav_header(&mut file, format, 0)
+                    .map_err(|e| format!("Failed to write {}: {}", audio_path.display(), e))?;
+                Ok::<_, String>((file, format))
+            })
+            .transpose()?;
+        write_y4m_header(&mut video, frame_rate)
+            .map_err(|e| format!("Failed to write {}: {}", video_path.display(), e))?;
+
+        let colors = *colors;
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::spawn(move || {
+            let written = write_chunks(receiver, &mut video, wav.as_mut(), &colors);
+            let written = written.and_then(|frames| {
+                video.flush()?;
+                if let Some((file, format)) = wav.as_mut() {
+                    let length = file.stream_position()? as u32 - WAV_HEADER_SIZE;
+                    file.seek(SeekFrom::Start(0))?;
+                    write_wav_header(file, *format, length)?;
+                    file.flush()?;
+                }
+                Ok(frames)
+            });
+            let frames = match written {
+                Ok(frames) => frames,
+                Err(e) => {
+                    eprintln!("Failed to write the video: {}", e);
+                    return;
+                }
+            };
+            drop((video, wav));
+            let sources = [video_path.as_path(), audio_path.as_path()];
+            let sources = &sources[..if audio.is_some() { 2 } else { 1 }];
+            match ffmpeg.map(|ffmpeg| encode(&ffmpeg, sources, &path)) {
+                Some(Ok(())) => {
+                    for source in sources {
+                        fs::remove_file(source).ok();
+                    }
+                    println!("Saved {} frames of video to {}", frames, path.display());
+                }
+                Some(Err(e)) => {
+                    let kept: Vec<String> =
+                        sources.iter().map(|s| s.display().to_string()).collect();
+                    eprintln!("{}, kept {}", e, kept.join(" and "))
+                }
+                None => println!(
+                    "Saved {} frames of video to {}",
+                    frames,
+                    video_path.display()
+                ),
+            }
+        });
+        let recorder = VideoRecorder {
+            sender,
+            frame: Frame::with_format(Frame::WIDTH, Frame::HEIGHT, PixelFormat::Indexed),
+        };
+        Ok((recorder, writer))
+    }
+
+    /// Call once for every emulated frame. Returns false once the writing thread has given
+    /// up, which it has printed why.
+    pub fn frame(&mut self, ppu: &NesPPU) -> bool {
+        render::render(ppu, &mut self.frame);
+        self.sender
+            .send(Chunk::Frame(self.frame.data.clone()))
+            .is_ok()
+    }
+
+    /// Call with the sound made since the last call, as the APU makes it.
+    pub fn samples(&mut self, samples: &[f32]) -> bool {
+        self.sender.send(Chunk::Samples(samples.to_vec())).is_ok()
+    }
+}
+
+fn write_chunks(
+    chunks: Receiver<Chunk>,
+    video: &mut impl Write,
+    mut wav: Option<&mut (BufWriter<File>, AudioFormat)>,
+    colors: &Colors,
+) -> io::Result<usize> {
+    let mut frames = 0;
+    for chunk in chunks {
+        match chunk {
+            Chunk::Frame(pixels) => {
+                write_y4m_frame(video, &pixels, colors)?;
+                frames += 1;
+            }
+            Chunk::Samples(samples) => {
+                if let Some((file, _)) = wav.as_mut() {
+                    write_samples(file, &samples)?;
+                }
+            }
+        }
+    }
+    Ok(frames)
+}
+
+/// Runs ffmpeg to encode `sources` into `output`, with the codecs it picks for the file type.
+/// The picture is 4:2:0 for players that can't show anything else.
+fn encode(ffmpeg: &Path, sources: &[&Path], output: &Path) -> Result<(), String> {
+    let mut command = Command::new(ffmpeg);
+    command.args(["-y", "-loglevel", "error"]);
+    for source in sources {
+        command.arg("-i").arg(source);
+    }
+    let status = command
+        .args(["-pix_fmt", "yuv420p"])
+        .arg(output)
+        .status()
+        .map_err(|e| format!("Failed to run {}: {}", ffmpeg.display(), e))?;
+    if !status.success() {
+        return Err(format!("{} failed with {}", ffmpeg.display(), status));
+    }
+    Ok(())
+}
+
+/// The frame rate as a fraction, to a thousandth of a frame a second.
+fn write_y4m_header(out: &mut impl Write, frame_rate: f64) -> io::Result<()> {
+    writeln!(
+        out,
+        "YUV4MPEG2 W{} H{} F{}:1000 Ip A1:1 C444",
+        Frame::WIDTH,
+        Frame::HEIGHT,
+        (frame_rate * 1000.0).round() as u32
+    )
+}
+
+/// A frame of NES colors as full resolution Y, U and V planes, BT.601 studio range.
+fn write_y4m_frame(out: &mut impl Write, pixels: &[u8], colors: &Colors) -> io::Result<()> {
+    let yuv: Vec<[u8; 3]> = pixels
+        .iter()
+        .map(|&color| rgb_to_yuv(colors[color as usize & 0x3F]))
+        .collect();
+    out.write_all(b"FRAME\n")?;
+    for plane in 0..3 {
+        let bytes: Vec<u8> = yuv.iter().map(|pixel| pixel[plane]).collect();
+        out.write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+fn rgb_to_yuv((r, g, b): (u8, u8, u8)) -> [u8; 3] {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    [y as u8, u as u8, v as u8]
+}
+
+const WAV_HEADER_SIZE: u32 = 44;
+
+/// A 16-bit PCM WAV header for `length` bytes of samples. The length is filled in once the
+/// recording stops.
+fn write_wav_header(
+    out: &mut impl Write,
+    (sample_rate, channels): AudioFormat,
+    length: u32,
+) -> io::Result<()> {
+    let block_align = channels as u16 * 2;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(length + WAV_HEADER_SIZE - 8).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&(channels as u16).to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&length.to_le_bytes())
+}
+
+fn write_samples(out: &mut impl Write, samples: &[f32]) -> io::Result<()> {
+    let bytes: Vec<u8> = samples
+        .iter()
+        .flat_map(|sample| ((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())
+        .collect();
+    out.write_all(&bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::render::palette::SYSTEM_PALLETE;
+
+    #[test]
+    fn test_y4m_and_wav() {
+        let mut y4m = Vec::new();
+        write_y4m_header(&mut y4m, 60.0988).unwrap();
+        assert_eq!(y4m, b"YUV4MPEG2 W256 H240 F60099:1000 Ip A1:1 C444\n");
+
+        // White, then black
+        let mut pixels = vec![0x30; Frame::WIDTH * Frame::HEIGHT];
+        pixels[1] = 0x0D;
+        y4m.clear();
+        write_y4m_frame(&mut y4m, &pixels, &SYSTEM_PALLETE).unwrap();
+        let planes = &y4m[6..];
+        let plane = Frame::WIDTH * Frame::HEIGHT;
+        assert_eq!(planes.len(), 3 * plane);
+        assert_eq!(
+            [planes[0], planes[plane], planes[2 * plane]],
+            [235, 128, 128]
+        );
+        assert_eq!(planes[1], 16);
+
+        let mut wav = Vec::new();
+        write_wav_header(&mut wav, (48_000, 2), 8).unwrap();
+        write_samples(&mut wav, &[0.0, 1.0, -2.0, 0.5]).unwrap();
+        assert_eq!(wav.len(), WAV_HEADER_SIZE as usize + 8);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[4..8], &44u32.to_le_bytes());
+        assert_eq!(&wav[28..32], &192_000u32.to_le_bytes());
+        let samples: Vec<i16> = wav[44..]
+            .chunks(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        assert_eq!(samples, [0, 32767, -32767, 16383]);
+    }
+}
@@ -0,0 +1,76 @@
+/// Frames a coin stays on the slot's sensor. Much longer and some games report a jam.
+const COIN_FRAMES: u8 = 3;
+
+/// The Vs. System's arcade hardware around the NES: two coin slots and eight DIP switches read
+/// through the controller ports. Mapper 99's CHR bank switch is on the cartridge, see
+/// `mapper::VsBoard`.
+pub struct VsSystem {
+    /// Switch 1 in bit 0 up to switch 8 in bit 7, on when set. What they do is up to the game,
+    /// usually difficulty, lives and coins per credit.
+    pub dip_switches: u8,
+    /// Frames each coin slot's sensor stays on
+    coins: [u8; 2],
+}
+
+impl Default for VsSystem {
+    fn default() -> Self {
+        VsSystem::new()
+    }
+}
+
+impl VsSystem {
+    pub fn new() -> Self {
+        VsSystem {
+            dip_switches: 0,
+            coins: [0; 2],
+        }
+    }
+
+    /// Drops a coin into slot 0 or 1.
+    pub fn insert_coin(&mut self, slot: usize) {
+        self.coins[slot] = COIN_FRAMES;
+    }
+
+    pub fn end_frame(&mut self) {
+        for coin in self.coins.iter_mut() {
+            *coin = coin.saturating_sub(1);
+        }
+    }
+
+    /// Bits the board puts on $4016 reads next to the controller: DIP switches 1 and 2 in
+    /// bits 3-4 and the coin slots in bits 5-6.
+    pub fn read_4016(&self) -> u8 {
+        let coins = (self.coins[0] > 0) as u8 | ((self.coins[1] > 0) as u8) << 1;
+        (self.dip_switches & 0x03) << 3 | coins << 5
+    }
+
+    /// DIP switches 3 to 8, in bits 2-7 of $4017 reads.
+    pub fn read_4017(&self) -> u8 {
+        self.dip_switches & 0xFC
+    }
+
+    /// Empties the coin slots, like turning the machine off and on.
+    pub fn power_cycle(&mut self) {
+        self.coins = [0; 2];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_coins_and_switches() {
+        let mut vs = VsSystem::new();
+        vs.dip_switches = 0b1000_0011;
+        assert_eq!(vs.read_4016(), 0b0001_1000);
+        assert_eq!(vs.read_4017(), 0b1000_0000);
+
+        vs.insert_coin(1);
+        for _ in 0..COIN_FRAMES {
+            assert_eq!(vs.read_4016() & 0x60, 0x40);
+            vs.end_frame();
+        }
+        assert_eq!(vs.read_4016() & 0x60, 0);
+    }
+}
@@ -0,0 +1,147 @@
+use rust_nes::{
+    bus::Bus,
+    cartridge::Rom,
+    cpu::{Mem, CPU},
+    headless,
+    input_script::ConsoleEvent,
+    joypad::Joypad,
+    ppu::NesPPU,
+    region::Region,
+    render::{self, frame::Frame},
+    save_state, simple,
+};
+
+// (rom, frames to run, expected frame hash)
+//
+// Only freely redistributable ROMs belong here. If a renderer change is supposed to alter the
+// output, check the new frames by eye and update the hashes.
+const GOLDEN_FRAMES: [(&str, usize, u64); 1] = [("bins/nestest.nes", 120, 0x71e889439230f6c0)];
+
+#[test]
+fn test_frames_match_golden_hashes() {
+    for (path, frames, expected) in GOLDEN_FRAMES {
+        let raw = std::fs::read(path).expect("Failed to read ROM");
+        let rom = Rom::new(&raw).expect("Failed to load ROM");
+
+        let frame = headless::run_frames(rom, frames).unwrap();
+        let hash = headless::frame_hash(&frame);
+        assert_eq!(
+            hash, expected,
+            "{} frame {} hashed to {:#018x}, expected {:#018x}",
+            path, frames, hash, expected
+        );
+    }
+}
+
+#[test]
+fn test_headless_run_is_deterministic() {
+    let raw = std::fs::read("bins/nestest.nes").expect("Failed to read ROM");
+
+    let first = headless::run_frames(Rom::new(&raw).unwrap(), 30).unwrap();
+    let second = headless::run_frames(Rom::new(&raw).unwrap(), 30).unwrap();
+    assert_eq!(headless::frame_hash(&first), headless::frame_hash(&second));
+}
+
+#[test]
+fn test_frame_callback_sees_every_frame() {
+    let raw = std::fs::read("bins/nestest.nes").expect("Failed to read ROM");
+
+    let mut hashes = Vec::new();
+    let last =
+        headless::run_frames_with(Rom::new(&raw).unwrap(), Region::NTSC, 10, |n, frame, _| {
+            hashes.push((n, headless::frame_hash(frame)));
+            None
+        })
+        .unwrap();
+    assert_eq!(hashes.len(), 10);
+    assert_eq!(hashes[9], (10, headless::frame_hash(&last)));
+}
+
+#[test]
+fn test_stopping_early_counts_the_frames_run() {
+    let raw = std::fs::read("bins/nestest.nes").expect("Failed to read ROM");
+
+    let (_, ran) = headless::run_frames_checked(
+        Rom::new(&raw).unwrap(),
+        Region::NTSC,
+        10,
+        |_, _, _| None,
+        |n, _| n < 4,
+    )
+    .unwrap();
+    assert_eq!(ran, 4);
+}
+
+#[test]
+fn test_power_cycle_starts_over() {
+    let raw = std::fs::read("bins/nestest.nes").expect("Failed to read ROM");
+
+    let fresh = headless::run_frames(Rom::new(&raw).unwrap(), 40).unwrap();
+    assert_ne!(
+        headless::frame_hash(&fresh),
+        headless::frame_hash(&Frame::new())
+    );
+    let power_cycled =
+        headless::run_frames_with(Rom::new(&raw).unwrap(), Region::NTSC, 80, |n, _, _| {
+            (n == 40).then_some(ConsoleEvent::Power)
+        })
+        .unwrap();
+    assert_eq!(
+        headless::frame_hash(&fresh),
+        headless::frame_hash(&power_cycled)
+    );
+}
+
+#[test]
+fn test_loaded_state_replays_the_same_frames() {
+    let raw = std::fs::read("bins/nestest.nes").expect("Failed to read ROM");
+    let rom = Rom::new(&raw).unwrap();
+    let crc = rom.crc32;
+
+    let last_hash = std::cell::Cell::new(0);
+    let bus = Bus::new(rom, |ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {
+        let mut frame = Frame::new();
+        render::render(ppu, &mut frame);
+        last_hash.set(headless::frame_hash(&frame));
+    });
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    let run_until = |cpu: &mut CPU, frames: usize| {
+        while cpu.bus.frame_count() < frames {
+            assert!(cpu.step());
+        }
+    };
+
+    run_until(&mut cpu, 20);
+    let state = save_state::save(&cpu, crc);
+    run_until(&mut cpu, 40);
+    let expected = last_hash.get();
+
+    save_state::load(&mut cpu, crc, &state).unwrap();
+    assert_eq!(cpu.bus.frame_count(), 20);
+    run_until(&mut cpu, 40);
+    assert_eq!(last_hash.get(), expected);
+}
+
+#[test]
+fn test_snake_draws_in_simple_mode() {
+    let raw = std::fs::read("bins/snake.nes").expect("Failed to read ROM");
+    assert!(!simple::is_raw_program(&raw));
+    let bus = Bus::new(
+        Rom::new(&raw).unwrap(),
+        |_ppu: &NesPPU, _joypads: &mut [Joypad; 2]| {},
+    );
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    let mut rng = 0;
+    let mut randomize = |cpu: &mut CPU| {
+        rng = rng % 15 + 1;
+        cpu.mem_write(simple::RNG, rng);
+    };
+    for _ in 0..simple::INSTRUCTIONS_PER_FRAME * 10 {
+        assert!(cpu.step_with_callback(&mut randomize));
+    }
+
+    let mut frame = Frame::with_size(simple::SCREEN_SIZE, simple::SCREEN_SIZE);
+    assert!(simple::render_screen(&cpu, &mut frame));
+}